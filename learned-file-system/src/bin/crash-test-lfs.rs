@@ -0,0 +1,71 @@
+use std::process::exit;
+use learned_file_system::crash_test::{crash_test, mkfs_image, WorkloadOp};
+use learned_file_system::mkfs::MkfsOptions;
+
+/// Epochs with more writes than this only get the variants with one write left out
+const DEFAULT_MAX_REORDERED: usize = 8;
+
+fn usage() -> ! {
+    println!("usage: crash-test-lfs [-b block-size] [-n blocks] [-O features] [-r max-reordered] workload");
+    println!("             -b block-size   - bytes per block (default 4096)");
+    println!("             -n blocks       - size of the test image (default 2048)");
+    println!("             -O features     - features of the test image, as for mkfs.lfs");
+    println!("             -r max-reordered - largest number of writes between syncs to try every order of (default {})", DEFAULT_MAX_REORDERED);
+    println!("             workload        - file with one operation per line: mkdir path, create path,");
+    println!("                               write path offset len, truncate path size, unlink path,");
+    println!("                               rmdir path or rename from to");
+    exit(1);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("crash-test-lfs: {}", message);
+    exit(1);
+}
+
+fn parse_number(arg: &str) -> u64 {
+    arg.parse().unwrap_or_else(|_| fail(format!("invalid number {}", arg)))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut options = MkfsOptions { num_blocks: 2048, ..MkfsOptions::default() };
+    let mut max_reordered = DEFAULT_MAX_REORDERED;
+    let mut positional = vec![];
+
+    let mut arg_idx = 1;
+    while arg_idx < args.len() {
+        let arg = args[arg_idx].as_str();
+        if !arg.starts_with('-') {
+            positional.push(arg);
+            arg_idx += 1;
+            continue;
+        }
+        let value = args.get(arg_idx + 1).unwrap_or_else(|| usage());
+        match arg {
+            "-b" => options.block_size = parse_number(value) as u32,
+            "-n" => options.num_blocks = parse_number(value) as u32,
+            "-O" => options.set_features(value).unwrap_or_else(|e| fail(e.to_string())),
+            "-r" => max_reordered = parse_number(value) as usize,
+            _ => usage(),
+        }
+        arg_idx += 2;
+    }
+    let workload_name = match positional.as_slice() {
+        [workload_name] => *workload_name,
+        _ => usage(),
+    };
+
+    let text = std::fs::read_to_string(workload_name).unwrap_or_else(|e| fail(format!("{}: {}", workload_name, e)));
+    let workload = WorkloadOp::parse_workload(&text).unwrap_or_else(|e| fail(format!("{}: {}", workload_name, e)));
+    let image = mkfs_image(&options).unwrap_or_else(|e| fail(e.to_string()));
+    let report = crash_test(image, options.block_size as usize, &workload, max_reordered).unwrap_or_else(|e| fail(e.to_string()));
+
+    for failure in &report.failures {
+        println!("{}", failure);
+    }
+    println!("{}: {} writes in {} epochs, {} crash points, {} inconsistent",
+             workload_name, report.num_writes, report.num_epochs, report.num_crash_points, report.failures.len());
+    if !report.failures.is_empty() {
+        exit(1);
+    }
+}
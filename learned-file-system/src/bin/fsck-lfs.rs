@@ -0,0 +1,57 @@
+use std::fs::OpenOptions;
+use std::process::exit;
+use learned_file_system::{image_block_size, LearnedFileSystem};
+use learned_file_system::utils::block_file::BlockFileWrapper;
+
+/// Exit codes, following fsck(8)
+const EXIT_CLEAN: i32 = 0;
+const EXIT_CORRECTED: i32 = 1;
+const EXIT_UNCORRECTED: i32 = 4;
+const EXIT_USAGE: i32 = 16;
+
+fn usage() -> ! {
+    println!("usage: fsck.lfs [-r | -p] image");
+    println!("             -r    - repair the problems found");
+    println!("             -p    - the same, as fsck(8) passes it");
+    println!("             image - image file to check");
+    exit(EXIT_USAGE);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("fsck.lfs: {}", message);
+    exit(EXIT_UNCORRECTED);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (repair, image_name) = match args.iter().skip(1).map(|arg| arg.as_str()).collect::<Vec<_>>().as_slice() {
+        ["-r" | "-p", image_name] => (true, image_name.to_string()),
+        [image_name] if !image_name.starts_with('-') => (false, image_name.to_string()),
+        _ => usage(),
+    };
+
+    let image = OpenOptions::new().read(true).write(repair).open(&image_name)
+        .unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let block_size = image_block_size(&image).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let mut file_system = LearnedFileSystem::new(BlockFileWrapper::new(block_size, image), String::new());
+    if !repair {
+        file_system.set_read_only();
+    }
+    let report = file_system.fsck(repair).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    println!("{}: {} files, {} directories, {} blocks in use",
+             image_name, report.num_files, report.num_dirs, report.num_blocks_used);
+
+    if report.problems.is_empty() {
+        exit(EXIT_CLEAN);
+    }
+    if repair {
+        println!("{}: {} problems corrected", image_name, report.problems.len());
+        exit(EXIT_CORRECTED);
+    }
+    println!("{}: {} problems found, run with -r to repair", image_name, report.problems.len());
+    exit(EXIT_UNCORRECTED);
+}
@@ -0,0 +1,36 @@
+use std::process::exit;
+use learned_file_system::disk_spec::DiskSpec;
+
+fn usage() -> ! {
+    println!("usage: gen-disk [-q] spec.in output.img");
+    println!("             -q         - don't list the files written");
+    println!("             spec.in    - image spec, see test/disk1.in for the format");
+    println!("             output.img - image file to write");
+    exit(1);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("gen-disk: {}", message);
+    exit(1);
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let quiet = args.first().is_some_and(|arg| arg == "-q");
+    if quiet {
+        args.remove(0);
+    }
+    let (spec_name, image_name) = match args.as_slice() {
+        [spec_name, image_name] => (spec_name, image_name),
+        _ => usage(),
+    };
+
+    let spec_text = std::fs::read_to_string(spec_name).unwrap_or_else(|e| fail(format!("{}: {}", spec_name, e)));
+    let spec = DiskSpec::parse(&spec_text).unwrap_or_else(|e| fail(format!("{}: {}", spec_name, e)));
+    if !quiet {
+        for inode in &spec.inodes {
+            println!("inode {} {} blocks {:?}", inode.inode, inode.path, inode.blocks);
+        }
+    }
+    std::fs::write(image_name, spec.build()).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+}
@@ -0,0 +1,50 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::process::exit;
+use learned_file_system::{image_block_size, LearnedFileSystem};
+use learned_file_system::utils::block_file::BlockFileWrapper;
+
+fn usage() -> ! {
+    println!("usage: lfs-copy in image host-dir [image-dir]");
+    println!("       lfs-copy out image host-dir [image-dir]");
+    println!("             in        - copy the contents of host-dir into image-dir of the image");
+    println!("             out       - copy the contents of image-dir of the image into host-dir");
+    println!("             image     - image file");
+    println!("             host-dir  - directory on the host");
+    println!("             image-dir - absolute path of a directory in the image (default /)");
+    exit(1);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("lfs-copy: {}", message);
+    exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (copy_in, image_name, host_dir, image_dir) = match args.iter().skip(1).map(|arg| arg.as_str()).collect::<Vec<_>>().as_slice() {
+        [direction @ ("in" | "out"), image_name, host_dir] => (*direction == "in", image_name.to_string(), host_dir.to_string(), String::from("/")),
+        [direction @ ("in" | "out"), image_name, host_dir, image_dir] => (*direction == "in", image_name.to_string(), host_dir.to_string(), image_dir.to_string()),
+        _ => usage(),
+    };
+
+    let image = OpenOptions::new().read(true).write(copy_in).open(&image_name)
+        .unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let block_size = image_block_size(&image).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let mut file_system = LearnedFileSystem::new(BlockFileWrapper::new(block_size, image), String::new());
+    if !copy_in {
+        file_system.set_read_only();
+    }
+    file_system.load().unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let dir = file_system.lookup_path(&image_dir).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+
+    if !copy_in {
+        file_system.extract(dir, Path::new(&host_dir)).unwrap_or_else(|e| fail(e.to_string()));
+        return;
+    }
+    let report = file_system.populate(Path::new(&host_dir), dir).unwrap_or_else(|e| fail(e.to_string()));
+    for path in &report.skipped {
+        eprintln!("lfs-copy: skipped {}, which is not a regular file or directory", path.display());
+    }
+    println!("{}: copied {} files and {} directories", image_name, report.num_files, report.num_dirs);
+}
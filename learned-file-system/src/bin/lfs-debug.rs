@@ -0,0 +1,82 @@
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::process::exit;
+use learned_file_system::{image_block_size, LearnedFileSystem};
+use learned_file_system::utils::block_file::BlockFileWrapper;
+
+fn usage() -> ! {
+    println!("usage: lfs-debug [-w] [-R command] image");
+    println!("             -w         - open the image for writing, which the set*, free*, sif, ssv and zap commands need");
+    println!("             -R command - run a single command and exit, instead of reading commands from stdin");
+    println!("             image      - image file to inspect");
+    println!("Run help at the prompt for the list of commands");
+    exit(1);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("lfs-debug: {}", message);
+    exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut writable = false;
+    let mut request = None;
+    let mut positional = vec![];
+
+    let mut arg_idx = 1;
+    while arg_idx < args.len() {
+        match args[arg_idx].as_str() {
+            "-w" => writable = true,
+            "-R" => {
+                request = Some(args.get(arg_idx + 1).unwrap_or_else(|| usage()).clone());
+                arg_idx += 1;
+            }
+            arg if arg.starts_with('-') => usage(),
+            arg => positional.push(arg.to_string()),
+        }
+        arg_idx += 1;
+    }
+    let image_name = match positional.as_slice() {
+        [image_name] => image_name,
+        _ => usage(),
+    };
+
+    let image = OpenOptions::new().read(true).write(writable).open(image_name)
+        .unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let block_size = image_block_size(&image).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let mut file_system = LearnedFileSystem::new(BlockFileWrapper::new(block_size, image), String::new());
+    if !writable {
+        file_system.set_read_only();
+    }
+    // A damaged image is still worth looking at block by block
+    if let Err(e) = file_system.load() {
+        eprintln!("lfs-debug: {}: {}", image_name, e);
+    }
+
+    let stdout = std::io::stdout();
+    if let Some(request) = request {
+        if let Err(e) = file_system.debug_command(&request, writable, &mut stdout.lock()) {
+            fail(e.to_string());
+        }
+        return;
+    }
+
+    let stdin = std::io::stdin();
+    loop {
+        print!("lfs-debug: ");
+        stdout.lock().flush().unwrap_or_else(|e| fail(e.to_string()));
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => fail(e.to_string()),
+        }
+        if matches!(line.trim(), "quit" | "q") {
+            break;
+        }
+        if let Err(e) = file_system.debug_command(&line, writable, &mut stdout.lock()) {
+            eprintln!("lfs-debug: {}", e);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use std::io::Write;
+use std::path::Path;
+use std::process::exit;
+use learned_file_system::trace::{read_trace, TraceFormat, CSV_HEADER};
+
+fn usage() -> ! {
+    println!("usage: lfs-trace [-f format] trace-file");
+    println!("             -f format  - csv or jsonl (default csv)");
+    println!("             trace-file - trace written by learned-file-system -t, in any format. Files it was");
+    println!("                          rotated to are read first");
+    exit(1);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("lfs-trace: {}", message);
+    exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut format = TraceFormat::Csv;
+    let mut positional = vec![];
+
+    let mut arg_idx = 1;
+    while arg_idx < args.len() {
+        match args[arg_idx].as_str() {
+            "-f" => {
+                let value = args.get(arg_idx + 1).unwrap_or_else(|| usage());
+                format = match TraceFormat::from_name(value) {
+                    Ok(TraceFormat::Binary) | Err(_) => usage(),
+                    Ok(format) => format,
+                };
+                arg_idx += 1;
+            }
+            arg if arg.starts_with('-') => usage(),
+            arg => positional.push(arg.to_string()),
+        }
+        arg_idx += 1;
+    }
+    let trace_name = match positional.as_slice() {
+        [trace_name] => trace_name,
+        _ => usage(),
+    };
+
+    let records = read_trace(Path::new(trace_name)).unwrap_or_else(|e| fail(format!("{}: {}", trace_name, e)));
+    let mut out = std::io::BufWriter::new(std::io::stdout().lock());
+    let result = (|| {
+        if format == TraceFormat::Csv {
+            writeln!(out, "{}", CSV_HEADER)?;
+        }
+        for record in &records {
+            let line = if format == TraceFormat::Csv { record.to_csv() } else { record.to_json() };
+            out.write_all(line.as_bytes())?;
+        }
+        out.flush()
+    })();
+    result.unwrap_or_else(|e| fail(e.to_string()));
+}
@@ -0,0 +1,110 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::process::exit;
+use learned_file_system::ROOT_INODE;
+use learned_file_system::mkfs::{mkfs, MkfsOptions};
+use learned_file_system::utils::block_file::BlockFileWrapper;
+
+fn usage() -> ! {
+    println!("usage: mkfs.lfs [-b block-size] [-L label] [-N inodes] [-I inode-size] [-O features] [-J journal-blocks] [-C cow-blocks] [-d root-dir] image [size]");
+    println!("             -b block-size - bytes per block, a power of two from 1024 to 65536 (default 4096)");
+    println!("             -L label      - volume label of up to 32 bytes");
+    println!("             -N inodes     - size of the inode table, 0 for one inode per block like gen-disk.py");
+    println!("             -I inode-size - bytes per inode table entry (default 256)");
+    println!("             -O features   - comma separated list out of extents, var_dirents, dir_index,");
+    println!("                             learned_index, has_journal, cow, or none");
+    println!("                             (default extents,var_dirents,dir_index,has_journal)");
+    println!("             -J journal-blocks - size of the journal, at least 16 (default 1/64 of the image, up to 1024)");
+    println!("             -C cow-blocks - size of the copy-on-write area, at least 32 (default 1/64 of the image, up to 1024)");
+    println!("             -d root-dir   - copy the contents of a host directory into the new image");
+    println!("             image         - image file to create or overwrite");
+    println!("             size          - number of blocks, or bytes with a K, M or G suffix.");
+    println!("                             Defaults to the size of an existing image");
+    exit(1);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("mkfs.lfs: {}", message);
+    exit(1);
+}
+
+fn parse_number(arg: &str) -> u64 {
+    arg.parse().unwrap_or_else(|_| fail(format!("invalid number {}", arg)))
+}
+
+/// Size argument in bytes. A bare number counts blocks
+fn parse_size(arg: &str, block_size: u64) -> u64 {
+    let (digits, multiplier) = match arg.chars().last() {
+        Some('K') | Some('k') => (&arg[..arg.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&arg[..arg.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&arg[..arg.len() - 1], 1 << 30),
+        _ => (arg, block_size),
+    };
+    parse_number(digits) * multiplier
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut options = MkfsOptions::default();
+    let mut root_dir = None;
+    let mut positional = vec![];
+
+    let mut arg_idx = 1;
+    while arg_idx < args.len() {
+        let arg = args[arg_idx].as_str();
+        if !arg.starts_with('-') {
+            positional.push(arg);
+            arg_idx += 1;
+            continue;
+        }
+        let value = args.get(arg_idx + 1).unwrap_or_else(|| usage());
+        match arg {
+            "-b" => options.block_size = parse_number(value) as u32,
+            "-L" => options.label = value.clone(),
+            "-N" => options.inode_count = Some(parse_number(value) as u32),
+            "-I" => options.inode_size = parse_number(value) as u32,
+            "-O" => options.set_features(value).unwrap_or_else(|e| fail(e.to_string())),
+            "-J" => options.journal_blocks = Some(parse_number(value) as u32),
+            "-C" => options.cow_blocks = Some(parse_number(value) as u32),
+            "-d" => root_dir = Some(value.clone()),
+            _ => usage(),
+        }
+        arg_idx += 2;
+    }
+
+    let (image_name, size_arg) = match positional.as_slice() {
+        [image_name] => (*image_name, None),
+        [image_name, size_arg] => (*image_name, Some(*size_arg)),
+        _ => usage(),
+    };
+
+    options.validate().unwrap_or_else(|e| fail(e.to_string()));
+    // SAFETY: getuid and getgid cannot fail
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    options.root_uid = u16::try_from(uid).unwrap_or_else(|_| fail(format!("uid {} does not fit in 16 bits", uid)));
+    options.root_gid = u16::try_from(gid).unwrap_or_else(|_| fail(format!("gid {} does not fit in 16 bits", gid)));
+
+    let image = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(image_name)
+        .unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let size = match size_arg {
+        Some(size_arg) => parse_size(size_arg, options.block_size as u64),
+        None => image.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+    };
+    let num_blocks = size / options.block_size as u64;
+    if num_blocks == 0 || num_blocks > u32::MAX as u64 {
+        fail(format!("cannot make a file system of {} bytes with {} byte blocks", size, options.block_size));
+    }
+    options.num_blocks = num_blocks as u32;
+
+    image.set_len(num_blocks * options.block_size as u64)
+        .unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let device = BlockFileWrapper::new(options.block_size as usize, image);
+    let mut file_system = mkfs(device, &options).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+
+    if let Some(root_dir) = root_dir {
+        let report = file_system.populate(Path::new(&root_dir), ROOT_INODE).unwrap_or_else(|e| fail(e.to_string()));
+        for path in report.skipped {
+            eprintln!("mkfs.lfs: skipped {}, which is not a regular file or directory", path.display());
+        }
+    }
+}
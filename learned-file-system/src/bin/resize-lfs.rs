@@ -0,0 +1,86 @@
+use std::ffi::CString;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::process::exit;
+use learned_file_system::{image_block_size, LearnedFileSystem, GROW_XATTR};
+use learned_file_system::utils::block_file::BlockFileWrapper;
+
+fn usage() -> ! {
+    println!("usage: resize-lfs [-f] image size");
+    println!("       resize-lfs mountpoint");
+    println!("             -f         - resize even if the image looks mounted or was not cleanly unmounted");
+    println!("             image      - image file to grow or shrink, which must not be mounted");
+    println!("             size       - new number of blocks, or bytes with a K, M or G suffix");
+    println!("             mountpoint - grow a mounted image to the size of its image file,");
+    println!("                          after extending the file with truncate(1) or the like");
+    exit(1);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("resize-lfs: {}", message);
+    exit(1);
+}
+
+fn parse_number(arg: &str) -> u64 {
+    arg.parse().unwrap_or_else(|_| fail(format!("invalid number {}", arg)))
+}
+
+/// Size argument in bytes. A bare number counts blocks
+fn parse_size(arg: &str, block_size: u64) -> u64 {
+    let (digits, multiplier) = match arg.chars().last() {
+        Some('K') | Some('k') => (&arg[..arg.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&arg[..arg.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&arg[..arg.len() - 1], 1 << 30),
+        _ => (arg, block_size),
+    };
+    parse_number(digits) * multiplier
+}
+
+/// Ask the mounted file system to take up its whole image file
+fn grow_mounted(mountpoint: &str) {
+    let path = CString::new(mountpoint).unwrap_or_else(|_| usage());
+    let name = CString::new(GROW_XATTR).unwrap();
+    // SAFETY: both strings are NUL terminated and the value is empty
+    if unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), std::ptr::null(), 0, 0) } != 0 {
+        fail(format!("{}: {}", mountpoint, std::io::Error::last_os_error()));
+    }
+    println!("{}: grown to the size of the image file", mountpoint);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let (force, image_name, size_arg) = match args.iter().skip(1).map(|arg| arg.as_str()).collect::<Vec<_>>().as_slice() {
+        [mountpoint] if Path::new(mountpoint).is_dir() => return grow_mounted(mountpoint),
+        ["-f", image_name, size_arg] => (true, image_name.to_string(), size_arg.to_string()),
+        [image_name, size_arg] if !image_name.starts_with('-') => (false, image_name.to_string(), size_arg.to_string()),
+        _ => usage(),
+    };
+
+    let image = OpenOptions::new().read(true).write(true).open(&image_name)
+        .unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let block_size = image_block_size(&image).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e))) as u64;
+    let size = parse_size(&size_arg, block_size);
+    let num_blocks = size / block_size;
+    if num_blocks == 0 || num_blocks > u32::MAX as u64 {
+        fail(format!("cannot make a file system of {} bytes with {} byte blocks", size, block_size));
+    }
+    let old_len = image.metadata().map(|metadata| metadata.len()).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let new_len = num_blocks * block_size;
+
+    // The device has to cover both sizes while blocks are moved
+    let host_image = image.try_clone().unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    if new_len > old_len {
+        host_image.set_len(new_len).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    }
+    let mut file_system = LearnedFileSystem::new(BlockFileWrapper::new(block_size as usize, image), String::new());
+    if let Err(e) = file_system.resize(num_blocks as u32, force) {
+        if new_len > old_len {
+            let _ = host_image.set_len(old_len);
+        }
+        fail(format!("{}: {}", image_name, e));
+    }
+    if new_len < old_len {
+        host_image.set_len(new_len).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    }
+    println!("{}: resized to {} blocks of {} bytes", image_name, num_blocks, block_size);
+}
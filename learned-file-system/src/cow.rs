@@ -0,0 +1,440 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Error, ErrorKind};
+use crate::FS_MAGIC_NUM;
+use crate::structs::superblock::FsSuperBlock;
+use crate::utils::block_file::BlockFile;
+use crate::utils::cache_policy::AccessHint;
+use crate::utils::cached_block_file::PolicyStats;
+use crate::utils::{div_ceil, read_u32};
+
+/// "LFSM"
+const MAP_MAGIC: u32 = 0x4c46534d;
+/// Magic, next map block, number of pairs and a reserved field, ahead of the
+/// (home location, copy) pairs in a map block
+const MAP_HEADER_SIZE: usize = 16;
+/// Areas smaller than this fill up after a handful of operations
+pub const MIN_COW_BLOCKS: u32 = 32;
+
+/// Copy-on-write metadata updates, sitting between the journal and the device.
+///
+/// The area starts with the second superblock copy, and the rest of it holds copies of
+/// metadata blocks. A map from home locations to copies says which blocks have one, and
+/// reads follow it. During a transaction every block written gets a fresh copy, so the
+/// committed state is never overwritten. Blocks allocated during the transaction are not
+/// part of the committed state and are written in place. Committing writes a new map and then the
+/// superblock copy not holding the current state, with the next generation number and the
+/// new map. Whichever copy has the higher generation and a valid checksum is current, so a
+/// crash leaves either the old or the new state.
+///
+/// Once the area is half full, the copies are written back to their home locations, which
+/// the committed state does not refer to, and an empty map is committed. A transaction that
+/// runs out of room writes back the committed copies it does not need itself and commits
+/// the committed state again without them. If that is not enough, the transaction is
+/// dropped and fails with OutOfMemory.
+///
+/// Outside a transaction blocks are written where the map says they are, in place.
+pub(crate) struct Cow<BF: BlockFile> {
+    pub(crate) device: BF,
+    /// (first block, number of blocks) of the area, None if the image updates in place
+    region: Option<(u32, u32)>,
+    /// The current superblock, with any changes of the open transaction
+    super_block: Vec<u8>,
+    /// The superblock as of the last commit
+    committed_super_block: Vec<u8>,
+    generation: u32,
+    /// Home location to copy, as of the last commit
+    committed: BTreeMap<usize, u32>,
+    committed_map_blocks: Vec<u32>,
+    /// Home location to copy, including the open transaction
+    map: BTreeMap<usize, u32>,
+    /// Copies made during the open transaction, which can be written again in place
+    fresh: BTreeSet<u32>,
+    /// Area blocks holding a copy in either map, or the committed map
+    used: BTreeSet<u32>,
+    /// Blocks allocated during the open transaction that the committed state does not use,
+    /// which are written in place
+    unused: BTreeSet<usize>,
+    /// Blocks freed during the open transaction, which the committed state may still use
+    freed: BTreeSet<usize>,
+    in_transaction: bool,
+    changed: bool,
+    /// Whether a block of the open transaction found no room, which drops the transaction
+    overflowed: bool,
+}
+
+/// Superblock copy that is intact and belongs to a copy-on-write image
+fn valid_copy(data: &[u8]) -> Option<FsSuperBlock> {
+    let super_block = FsSuperBlock::from(data);
+    let valid = super_block.magic == FS_MAGIC_NUM && super_block.is_cow()
+        && super_block.block_size() as usize == data.len() && super_block.cow_checksum == super_block.compute_checksum();
+    if valid { Some(super_block) } else { None }
+}
+
+impl <BF: BlockFile> Cow<BF> {
+    pub fn new(device: BF) -> Self {
+        Cow {
+            device, region: None, super_block: vec![], committed_super_block: vec![], generation: 0, committed: BTreeMap::new(), committed_map_blocks: vec![],
+            map: BTreeMap::new(), fresh: BTreeSet::new(), used: BTreeSet::new(),
+            unused: BTreeSet::new(), freed: BTreeSet::new(), in_transaction: false, changed: false, overflowed: false,
+        }
+    }
+
+    /// Use the area from `start` on and load the current superblock copy and map, or
+    /// write everything in place for None. Does nothing if the area stays the same.
+    pub fn set_region(&mut self, region: Option<(u32, u32)>) -> std::io::Result<()> {
+        if region == self.region {
+            return Ok(());
+        }
+        self.region = None;
+        self.committed.clear();
+        self.committed_map_blocks.clear();
+        self.map.clear();
+        self.fresh.clear();
+        self.used.clear();
+        let (start, _) = match region {
+            Some(region) => region,
+            None => return Ok(()),
+        };
+
+        let copies = [self.device.block_read(0)?, self.device.block_read(start as usize)?];
+        let (super_block, data) = match (valid_copy(&copies[0]), valid_copy(&copies[1])) {
+            (Some(first), Some(second)) if (second.cow_generation.wrapping_sub(first.cow_generation) as i32) > 0 => (second, &copies[1]),
+            (Some(first), _) => (first, &copies[0]),
+            (None, Some(second)) => (second, &copies[1]),
+            (None, None) => return Err(Error::new(ErrorKind::InvalidData, "no intact superblock copy")),
+        };
+        self.region = region;
+        self.generation = super_block.cow_generation;
+        self.super_block = data.clone();
+        self.committed_super_block = data.clone();
+
+        let mut map_block = super_block.cow_map;
+        while map_block != 0 {
+            if !self.in_area(map_block) || self.committed_map_blocks.contains(&map_block) {
+                return Err(Error::new(ErrorKind::InvalidData, format!("bad copy-on-write map block {}", map_block)));
+            }
+            let data = self.device.block_read(map_block as usize)?;
+            let num_pairs = read_u32(&data, 2) as usize;
+            if read_u32(&data, 0) != MAP_MAGIC || num_pairs > self.pairs_per_block() {
+                return Err(Error::new(ErrorKind::InvalidData, format!("bad copy-on-write map block {}", map_block)));
+            }
+            for idx in 0..num_pairs {
+                let copy = read_u32(&data, MAP_HEADER_SIZE / 4 + idx * 2 + 1);
+                if !self.in_area(copy) {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("copy-on-write map refers to block {} outside the area", copy)));
+                }
+                self.committed.insert(read_u32(&data, MAP_HEADER_SIZE / 4 + idx * 2) as usize, copy);
+            }
+            self.committed_map_blocks.push(map_block);
+            map_block = read_u32(&data, 1);
+        }
+        self.map = self.committed.clone();
+        self.used = self.committed.values().chain(self.committed_map_blocks.iter()).copied().collect();
+        Ok(())
+    }
+
+    /// Whether `block` is one of the blocks copies and maps go to
+    fn in_area(&self, block: u32) -> bool {
+        self.region.is_some_and(|(start, num_blocks)| block > start && block < start + num_blocks)
+    }
+
+    fn area_blocks(&self) -> usize {
+        self.region.map_or(0, |(_, num_blocks)| num_blocks as usize - 1)
+    }
+
+    fn pairs_per_block(&self) -> usize {
+        (self.device.block_size() - MAP_HEADER_SIZE) / 8
+    }
+
+    /// Block the superblock copy of the given generation goes to
+    fn super_block_slot(&self, generation: u32) -> usize {
+        match self.region {
+            Some((start, _)) if generation % 2 == 1 => start as usize,
+            _ => 0,
+        }
+    }
+
+    /// Hold back changes to the committed state until `commit`. Does nothing on images that
+    /// update in place.
+    pub fn begin(&mut self) {
+        self.in_transaction = self.region.is_some();
+        self.changed = false;
+        self.overflowed = false;
+        self.unused.clear();
+        self.freed.clear();
+    }
+
+    /// Note that the file system allocated `block_address`. Unless it was freed earlier in
+    /// the open transaction, the committed state does not use it and it needs no copy.
+    pub fn block_allocated(&mut self, block_address: usize) {
+        if self.in_transaction && !self.freed.contains(&block_address) {
+            self.unused.insert(block_address);
+        }
+    }
+
+    /// Note that the file system freed `block_address`
+    pub fn block_freed(&mut self, block_address: usize) {
+        if self.in_transaction {
+            self.unused.remove(&block_address);
+            self.freed.insert(block_address);
+        }
+    }
+
+    /// Write regular file data, which is not copied. It goes to the home location, which
+    /// stops being mapped to a copy.
+    pub fn write_data<T: AsRef<[u8]>>(&mut self, buf: T, block_address: usize) -> std::io::Result<usize> {
+        if !self.in_transaction {
+            return self.block_write(buf, block_address);
+        }
+        if let Some(copy) = self.map.remove(&block_address) {
+            self.release(copy);
+            self.changed = true;
+        }
+        self.device.block_write(buf, block_address)
+    }
+
+    /// Where the device holds the current contents of `block_address` right now
+    pub fn physical_block(&self, block_address: usize) -> usize {
+        self.map.get(&block_address).map_or(block_address, |copy| *copy as usize)
+    }
+
+    /// Forget a copy made during the open transaction
+    fn release(&mut self, copy: u32) {
+        if self.fresh.remove(&copy) {
+            self.used.remove(&copy);
+        }
+    }
+
+    fn allocate(&mut self) -> Option<u32> {
+        let (start, num_blocks) = self.region?;
+        let block = ((start + 1)..(start + num_blocks)).find(|block| !self.used.contains(block))?;
+        self.used.insert(block);
+        Some(block)
+    }
+
+    /// Make the open transaction the committed state. Blocks for which `keep` is false were
+    /// freed during the transaction and lose their copies. A transaction that ran out of
+    /// room is dropped instead and fails with OutOfMemory.
+    pub fn commit(&mut self, keep: impl Fn(usize) -> bool) -> std::io::Result<()> {
+        if !self.in_transaction {
+            return Ok(());
+        }
+        self.in_transaction = false;
+        if self.overflowed {
+            self.map = self.committed.clone();
+            self.fresh.clear();
+            self.used = self.committed.values().chain(self.committed_map_blocks.iter()).copied().collect();
+            self.super_block = self.committed_super_block.clone();
+            return Err(Error::new(ErrorKind::OutOfMemory, "the copy-on-write area is full"));
+        }
+        let freed: Vec<(usize, u32)> = self.map.iter().filter(|(block_address, _)| !keep(**block_address))
+            .map(|(block_address, copy)| (*block_address, *copy))
+            .collect();
+        for (block_address, copy) in freed {
+            self.map.remove(&block_address);
+            self.release(copy);
+            self.changed = true;
+        }
+        if !self.changed {
+            return Ok(());
+        }
+
+        self.write_root()?;
+        if self.used.len() * 2 > self.area_blocks() {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// Write the map and then a superblock copy pointing at it
+    fn write_root(&mut self) -> std::io::Result<()> {
+        let pairs: Vec<(usize, u32)> = self.map.iter().map(|(block_address, copy)| (*block_address, *copy)).collect();
+        let map_blocks = self.write_map(&pairs)?;
+        // File data, copies and map all have to be on disk before the superblock points at them
+        self.device.sync()?;
+        self.write_super_block(map_blocks.first().copied().unwrap_or(0))?;
+
+        self.committed = self.map.clone();
+        self.committed_map_blocks = map_blocks;
+        self.fresh.clear();
+        self.used = self.committed.values().chain(self.committed_map_blocks.iter()).copied().collect();
+        Ok(())
+    }
+
+    /// Write (home location, copy) pairs to newly allocated map blocks, returning them
+    fn write_map(&mut self, pairs: &[(usize, u32)]) -> std::io::Result<Vec<u32>> {
+        let num_map_blocks = div_ceil(pairs.len(), self.pairs_per_block());
+        let map_blocks: Vec<u32> = (0..num_map_blocks).map(|_| self.allocate())
+            .collect::<Option<Vec<u32>>>()
+            .ok_or(Error::new(ErrorKind::OutOfMemory, "no room for the copy-on-write map"))?;
+        for (idx, chunk) in pairs.chunks(self.pairs_per_block()).enumerate() {
+            let mut data = vec![0u8; self.device.block_size()];
+            let next = map_blocks.get(idx + 1).copied().unwrap_or(0);
+            for (field_idx, field) in [MAP_MAGIC, next, chunk.len() as u32].iter().enumerate() {
+                data[(field_idx * 4)..(field_idx * 4 + 4)].copy_from_slice(&field.to_le_bytes());
+            }
+            for (pair_idx, (block_address, copy)) in chunk.iter().enumerate() {
+                let offset = MAP_HEADER_SIZE + pair_idx * 8;
+                data[offset..(offset + 4)].copy_from_slice(&(*block_address as u32).to_le_bytes());
+                data[(offset + 4)..(offset + 8)].copy_from_slice(&copy.to_le_bytes());
+            }
+            self.device.block_write(&data, map_blocks[idx] as usize)?;
+        }
+        Ok(map_blocks)
+    }
+
+    /// Write the superblock to the copy that is not current, with the next generation
+    fn write_super_block(&mut self, map_head: u32) -> std::io::Result<()> {
+        let mut super_block = FsSuperBlock::from(self.super_block.as_slice());
+        super_block.cow_generation = self.generation.wrapping_add(1);
+        super_block.cow_map = map_head;
+        super_block.cow_checksum = super_block.compute_checksum();
+        let super_block_data: Vec<u8> = super_block.into();
+        self.device.block_write(&super_block_data, self.super_block_slot(self.generation.wrapping_add(1)))?;
+        self.device.sync()?;
+        self.generation = self.generation.wrapping_add(1);
+        self.super_block = super_block_data.clone();
+        self.committed_super_block = super_block_data;
+        Ok(())
+    }
+
+    /// Write every copy to its home location and commit an empty map
+    fn checkpoint(&mut self) -> std::io::Result<()> {
+        let committed: Vec<(usize, u32)> = self.committed.iter().map(|(block_address, copy)| (*block_address, *copy)).collect();
+        for (block_address, copy) in committed {
+            let data = self.device.block_read(copy as usize)?;
+            self.device.block_write(&data, block_address)?;
+        }
+        self.map.clear();
+        self.write_root()
+    }
+
+    /// Make room during a transaction: write every committed copy of a block the
+    /// transaction has its own copy of, or has not changed, to its home location, and
+    /// commit the committed state again without those copies. The open transaction and its
+    /// superblock stay as they are.
+    fn checkpoint_committed(&mut self) -> std::io::Result<()> {
+        let (written_back, kept): (Vec<_>, Vec<_>) = self.committed.iter()
+            .map(|(block_address, copy)| (*block_address, *copy))
+            .partition(|(block_address, _)| self.map.contains_key(block_address));
+        if written_back.is_empty() {
+            return Ok(());
+        }
+        for (block_address, copy) in written_back.iter() {
+            let data = self.device.block_read(*copy as usize)?;
+            self.device.block_write(&data, *block_address)?;
+            if self.map.get(block_address) == Some(copy) {
+                self.map.remove(block_address);
+            }
+        }
+        let map_blocks = self.write_map(&kept)?;
+        self.device.sync()?;
+        let open_super_block = std::mem::replace(&mut self.super_block, self.committed_super_block.clone());
+        let result = self.write_super_block(map_blocks.first().copied().unwrap_or(0));
+        self.super_block = open_super_block;
+        result?;
+
+        self.committed = kept.into_iter().collect();
+        self.committed_map_blocks = map_blocks;
+        self.used = self.committed.values().chain(self.committed_map_blocks.iter()).chain(self.fresh.iter()).copied().collect();
+        Ok(())
+    }
+
+    /// Whether the area has room for one more copy, and the map that includes it
+    fn has_room_for_copy(&self, new_entry: bool) -> bool {
+        let map_len = self.map.len() + if new_entry { 1 } else { 0 };
+        self.area_blocks() - self.used.len() > div_ceil(map_len, self.pairs_per_block())
+    }
+}
+
+impl <BF: BlockFile> BlockFile for Cow<BF> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.device.num_blocks()
+    }
+
+    fn block_read_in_place<T: AsMut<[u8]>>(&self, mut buf: T, block_address: usize) -> std::io::Result<usize> {
+        if self.region.is_none() {
+            return self.device.block_read_in_place(buf, block_address);
+        }
+        if block_address == 0 {
+            let buf = buf.as_mut();
+            let len = buf.len().min(self.super_block.len());
+            buf[..len].copy_from_slice(&self.super_block[..len]);
+            return Ok(len);
+        }
+        match self.map.get(&block_address) {
+            Some(copy) => self.device.block_read_in_place(buf, *copy as usize),
+            None => self.device.block_read_in_place(buf, block_address),
+        }
+    }
+
+    fn block_write<T: AsRef<[u8]>>(&mut self, buf: T, block_address: usize) -> std::io::Result<usize> {
+        if self.region.is_none() {
+            return self.device.block_write(buf, block_address);
+        }
+        if buf.as_ref().len() != self.device.block_size() {
+            return Err(Error::from(ErrorKind::Other));
+        }
+        if block_address == 0 {
+            self.super_block = buf.as_ref().to_vec();
+            if self.in_transaction {
+                self.changed = true;
+            } else {
+                self.write_super_block(self.committed_map_blocks.first().copied().unwrap_or(0))?;
+            }
+            return Ok(buf.as_ref().len());
+        }
+
+        let current_copy = self.map.get(&block_address).copied();
+        if !self.in_transaction || current_copy.is_some_and(|copy| self.fresh.contains(&copy)) {
+            return self.device.block_write(buf, current_copy.map_or(block_address, |copy| copy as usize));
+        }
+
+        self.changed = true;
+        if self.unused.contains(&block_address) {
+            return self.device.block_write(buf, block_address);
+        }
+        // Leave room for the map the commit writes
+        if !self.has_room_for_copy(current_copy.is_none()) {
+            // Safe if the committed state has a copy, since the home location is unused then
+            if self.committed.contains_key(&block_address) {
+                self.map.remove(&block_address);
+                return self.device.block_write(buf, block_address);
+            }
+            self.checkpoint_committed()?;
+            if !self.has_room_for_copy(true) {
+                self.overflowed = true;
+                return Err(Error::new(ErrorKind::OutOfMemory, "the copy-on-write area is full"));
+            }
+        }
+        let copy = self.allocate().unwrap();
+        self.fresh.insert(copy);
+        self.map.insert(block_address, copy);
+        self.device.block_write(buf, copy as usize)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.device.sync()
+    }
+
+    fn hint_access(&self, hint: AccessHint) {
+        self.device.hint_access(hint)
+    }
+
+    fn prefetch(&self, block_address: usize, hint: AccessHint) {
+        if self.region.is_none() {
+            return self.device.prefetch(block_address, hint);
+        }
+        if block_address != 0 {
+            self.device.prefetch(self.physical_block(block_address), hint)
+        }
+    }
+
+    fn cache_stats(&self) -> Vec<PolicyStats> {
+        self.device.cache_stats()
+    }
+}
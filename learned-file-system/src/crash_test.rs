@@ -0,0 +1,304 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use crate::{LearnedFileSystem, SetattrRequest};
+use crate::fsck::FsckProblem;
+use crate::mkfs::{mkfs, MkfsOptions};
+use crate::utils::block_file::{BlockFile, MemoryBlockFile};
+use crate::utils::crash_block_file::{CrashBlockFile, CrashPoint};
+
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// One step of a crash test workload, with absolute paths in the image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WorkloadOp {
+    Mkdir(String),
+    Create(String),
+    /// Write `len` bytes of a fixed pattern at `offset`
+    Write { path: String, offset: u64, len: usize },
+    Truncate { path: String, size: u64 },
+    Unlink(String),
+    Rmdir(String),
+    Rename { from: String, to: String },
+}
+
+impl WorkloadOp {
+    /// Parse one line of a workload file: `mkdir path`, `create path`,
+    /// `write path offset len`, `truncate path size`, `unlink path`, `rmdir path` or
+    /// `rename from to`
+    pub fn parse(line: &str) -> std::io::Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid workload line {:?}", line));
+        let number = |arg: &str| arg.parse::<u64>().map_err(|_| invalid());
+        let words: Vec<&str> = line.split_whitespace().collect();
+        Ok(match words.as_slice() {
+            ["mkdir", path] => WorkloadOp::Mkdir(path.to_string()),
+            ["create", path] => WorkloadOp::Create(path.to_string()),
+            ["write", path, offset, len] => WorkloadOp::Write { path: path.to_string(), offset: number(offset)?, len: number(len)? as usize },
+            ["truncate", path, size] => WorkloadOp::Truncate { path: path.to_string(), size: number(size)? },
+            ["unlink", path] => WorkloadOp::Unlink(path.to_string()),
+            ["rmdir", path] => WorkloadOp::Rmdir(path.to_string()),
+            ["rename", from, to] => WorkloadOp::Rename { from: from.to_string(), to: to.to_string() },
+            _ => return Err(invalid()),
+        })
+    }
+
+    /// Parse a workload file, skipping blank lines and lines starting with #
+    pub fn parse_workload(text: &str) -> std::io::Result<Vec<Self>> {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(WorkloadOp::parse)
+            .collect()
+    }
+}
+
+/// Crash point whose image is not consistent
+#[derive(Clone, Debug)]
+pub struct CrashFailure {
+    pub point: CrashPoint,
+    /// What fsck found, or why the image could not be loaded
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for CrashFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.point {
+            CrashPoint::Prefix(num_writes) => write!(f, "crash after {} writes", num_writes)?,
+            CrashPoint::Reordered { epoch, applied } => write!(f, "crash in epoch {} with only writes {:?}", epoch, applied)?,
+        }
+        write!(f, ": {}", self.problems.join("; "))
+    }
+}
+
+/// What `crash_test` found
+#[derive(Clone, Debug, Default)]
+pub struct CrashReport {
+    pub num_writes: usize,
+    pub num_epochs: usize,
+    pub num_crash_points: usize,
+    pub failures: Vec<CrashFailure>,
+}
+
+/// Split an absolute path into its parent directory and last name
+fn split_path(path: &str) -> std::io::Result<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    match path.rfind('/') {
+        Some(idx) if idx + 1 < path.len() => Ok((&path[..idx], &path[(idx + 1)..])),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("{}: not an absolute path to a file", path))),
+    }
+}
+
+/// Byte `idx` of what a workload writes at `offset`
+fn pattern_byte(offset: u64, idx: usize) -> u8 {
+    ((offset + idx as u64) % 251) as u8
+}
+
+/// Running workloads and checking the images crashes leave behind.
+impl <BF: BlockFile> LearnedFileSystem<BF> {
+    /// Perform one workload step the way the FUSE handler for it would, as one transaction
+    pub fn run_workload_op(&mut self, op: &WorkloadOp) -> std::io::Result<()> {
+        let result = match op {
+            WorkloadOp::Mkdir(path) | WorkloadOp::Create(path) => {
+                let (parent, name) = split_path(path)?;
+                let parent = self.lookup_path(parent)?;
+                let mode = if matches!(op, WorkloadOp::Mkdir(_)) { S_IFDIR | 0o755 } else { S_IFREG | 0o644 };
+                self.transaction(|fs| fs.create_inode(parent, OsStr::new(name), mode, 0, 0).map(|_| ()).map_err(crate::translate_io_error))
+            }
+            WorkloadOp::Write { path, offset, len } => {
+                let inode = self.lookup_path(path)?;
+                let data: Vec<u8> = (0..*len).map(|idx| pattern_byte(*offset, idx)).collect();
+                self.transaction(|fs| fs.do_write(inode, *offset as i64, &data).map(|_| ()))
+            }
+            WorkloadOp::Truncate { path, size } => {
+                let inode = self.lookup_path(path)?;
+                self.transaction(|fs| fs.do_setattr(inode, SetattrRequest { size: Some(*size), ..SetattrRequest::default() }).map(|_| ()))
+            }
+            WorkloadOp::Unlink(path) | WorkloadOp::Rmdir(path) => {
+                let (parent, name) = split_path(path)?;
+                let parent = self.lookup_path(parent)?;
+                let is_dir = matches!(op, WorkloadOp::Rmdir(_));
+                self.transaction(|fs| fs.do_unlink(parent, OsStr::new(name), is_dir))
+            }
+            WorkloadOp::Rename { from, to } => {
+                let (from_parent, from_name) = split_path(from)?;
+                let (to_parent, to_name) = split_path(to)?;
+                let from_parent = self.lookup_path(from_parent)?;
+                let to_parent = self.lookup_path(to_parent)?;
+                self.transaction(|fs| fs.do_rename(from_parent, OsStr::new(from_name), to_parent, OsStr::new(to_name)))
+            }
+        };
+        result.map_err(|errno| {
+            let e = Error::from_raw_os_error(errno);
+            Error::new(e.kind(), format!("{:?}: {}", op, e))
+        })
+    }
+}
+
+/// Problems that are expected after any crash, which a mount or fsck -r fixes
+fn expected_after_crash(problem: &FsckProblem) -> bool {
+    matches!(problem, FsckProblem::NotClean | FsckProblem::FreeCounts { .. })
+}
+
+/// Mount `image`, run `workload` and unmount, recording every block write. Then rebuild
+/// the image for every crash point, see `CrashBlockFile::crash_points`, and check each
+/// with fsck after the usual recovery. Problems every crash leaves, such as the image not
+/// being clean, are not failures.
+pub fn crash_test(image: Vec<u8>, block_size: usize, workload: &[WorkloadOp], max_reordered: usize) -> std::io::Result<CrashReport> {
+    let device = CrashBlockFile::new(MemoryBlockFile::new(block_size, image))?;
+    let mut file_system = LearnedFileSystem::new(device, String::new());
+    file_system.mount()?;
+    for op in workload {
+        file_system.run_workload_op(op)?;
+    }
+    file_system.unmount()?;
+    let recording = file_system.block_system.device.device;
+
+    let points = recording.crash_points(max_reordered);
+    let mut report = CrashReport {
+        num_writes: recording.writes().len(),
+        num_epochs: recording.num_epochs(),
+        num_crash_points: points.len(),
+        failures: vec![],
+    };
+    for point in points {
+        let mut crashed = LearnedFileSystem::new(MemoryBlockFile::new(block_size, recording.image_at(&point)), String::new());
+        let problems = match crashed.fsck(false) {
+            Ok(fsck_report) => fsck_report.problems.iter()
+                .filter(|problem| !expected_after_crash(problem))
+                .map(|problem| problem.to_string())
+                .collect(),
+            Err(e) => vec![e.to_string()],
+        };
+        if !problems.is_empty() {
+            report.failures.push(CrashFailure { point, problems });
+        }
+    }
+    Ok(report)
+}
+
+/// A new image made by mkfs, held in memory
+pub fn mkfs_image(options: &MkfsOptions) -> std::io::Result<Vec<u8>> {
+    let device = MemoryBlockFile::new(options.block_size as usize, vec![0u8; options.num_blocks as usize * options.block_size as usize]);
+    Ok(mkfs(device, options)?.block_system.device.device.into_data())
+}
+
+/// Load the image on `device` afresh, as the next mount would, checking that fsck finds
+/// nothing wrong with it
+#[cfg(test)]
+pub(crate) fn reopen_clean(device: MemoryBlockFile) -> LearnedFileSystem<MemoryBlockFile> {
+    let mut file_system = LearnedFileSystem::new(device, String::new());
+    let report = file_system.fsck(false).unwrap();
+    assert!(report.problems.is_empty(), "{:?}", report.problems);
+    file_system
+}
+
+/// Make an image with `options`, run `workload` on it, unmount and reopen it
+#[cfg(test)]
+pub(crate) fn run_and_reopen(options: &MkfsOptions, workload: &str) -> LearnedFileSystem<MemoryBlockFile> {
+    let mut file_system = LearnedFileSystem::new(MemoryBlockFile::new(options.block_size as usize, mkfs_image(options).unwrap()), String::new());
+    file_system.mount().unwrap();
+    for op in WorkloadOp::parse_workload(workload).unwrap() {
+        file_system.run_workload_op(&op).unwrap();
+    }
+    file_system.unmount().unwrap();
+    reopen_clean(file_system.block_system.device.device)
+}
+
+/// Check that the file at `path` holds what a workload writes for `write path offset len`
+#[cfg(test)]
+pub(crate) fn check_written<BF: BlockFile>(file_system: &LearnedFileSystem<BF>, path: &str, offset: u64, len: usize) {
+    let node = file_system.get_inode(file_system.lookup_path(path).unwrap()).unwrap();
+    let data = file_system.read_file_bytes(&node, offset as usize, len).unwrap();
+    assert!(data.iter().enumerate().all(|(idx, byte)| *byte == pattern_byte(offset, idx)) && data.len() == len, "{}", path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORKLOAD: &str = "
+        mkdir /d
+        create /d/f
+        write /d/f 0 5000
+        write /d/f 3000 100
+        rename /d/f /g
+        create /d/h
+        unlink /d/h
+        rmdir /d
+    ";
+
+    fn check_features(features: &str) {
+        let mut options = MkfsOptions { num_blocks: 512, block_size: 1024, ..MkfsOptions::default() };
+        options.set_features(features).unwrap();
+        let workload = WorkloadOp::parse_workload(WORKLOAD).unwrap();
+        let report = crash_test(mkfs_image(&options).unwrap(), 1024, &workload, 4).unwrap();
+        assert!(report.num_writes > 0 && report.num_epochs > 1);
+        assert!(report.failures.is_empty(), "{}: {}", features,
+                report.failures.iter().map(|failure| failure.to_string()).collect::<Vec<_>>().join("\n"));
+    }
+
+    #[test]
+    fn journaled_image_survives_crashes() {
+        check_features("extents,var_dirents,dir_index,has_journal");
+    }
+
+    #[test]
+    fn cow_image_survives_crashes() {
+        check_features("extents,var_dirents,dir_index,cow");
+    }
+
+    #[test]
+    fn cow_area_fills_up() {
+        // Retraining the learned index of a directory with long names rewrites more of its
+        // blocks than the area has room for next to the copies already committed
+        let mut options = MkfsOptions { num_blocks: 512, block_size: 1024, inode_count: Some(256), cow_blocks: Some(36), ..MkfsOptions::default() };
+        options.set_features("extents,var_dirents,learned_index,cow").unwrap();
+        let mut text = String::from("mkdir /d\n");
+        for idx in 0..100 {
+            text += &format!("create /d/{}{:03}\n", "x".repeat(250), idx);
+        }
+        let workload = WorkloadOp::parse_workload(&text).unwrap();
+        let report = crash_test(mkfs_image(&options).unwrap(), 1024, &workload, 4).unwrap();
+        assert!(report.failures.is_empty(), "{}",
+                report.failures.iter().map(|failure| failure.to_string()).collect::<Vec<_>>().join("\n"));
+    }
+
+    #[test]
+    fn cow_area_overflow_is_dropped() {
+        let mut options = MkfsOptions { num_blocks: 512, block_size: 1024, inode_count: Some(256), cow_blocks: Some(32), ..MkfsOptions::default() };
+        options.set_features("extents,var_dirents,learned_index,cow").unwrap();
+        let mut file_system = LearnedFileSystem::new(MemoryBlockFile::new(1024, mkfs_image(&options).unwrap()), String::new());
+        file_system.mount().unwrap();
+        file_system.run_workload_op(&WorkloadOp::Mkdir("/d".to_string())).unwrap();
+
+        // Eventually retraining rewrites more blocks than the whole area holds
+        let mut created = vec![];
+        let (path, error) = loop {
+            assert!(created.len() < 200, "no transaction outgrew the area");
+            let path = format!("/d/{}{:03}", "x".repeat(250), created.len());
+            match file_system.run_workload_op(&WorkloadOp::Create(path.clone())) {
+                Ok(()) => created.push(path),
+                Err(e) => break (path, e),
+            }
+        };
+        assert_eq!(error.kind(), ErrorKind::StorageFull);
+        assert!(file_system.lookup_path(&path).is_err());
+        for path in created.iter() {
+            file_system.lookup_path(path).unwrap();
+        }
+        file_system.unmount().unwrap();
+        let report = file_system.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn parse_workload() {
+        assert_eq!(WorkloadOp::parse_workload("# comment\n\nwrite /a 10 20\nrename /a /b\n").unwrap(), vec![
+            WorkloadOp::Write { path: "/a".to_string(), offset: 10, len: 20 },
+            WorkloadOp::Rename { from: "/a".to_string(), to: "/b".to_string() },
+        ]);
+        assert!(WorkloadOp::parse("write /a ten 20").is_err());
+        assert!(WorkloadOp::parse("chmod /a").is_err());
+    }
+}
@@ -0,0 +1,514 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Write};
+use crate::{LearnedFileSystem, ROOT_INODE};
+use crate::mkfs::FEATURE_NAMES;
+use crate::structs::dir_model::DirModel;
+use crate::structs::dirent::{DirectoryEntry, DirentSlot, FIXED_DIRENT_SIZE, parse_var_dirent_block};
+use crate::structs::extent::{extents_from_blocks, ExtentNode};
+use crate::structs::fsinode::{FSINode, INODE_FLAG_EXTENTS, INODE_FLAG_HTREE, INODE_FLAG_LEARNED_INDEX, INODE_FLAG_VAR_DIRENTS};
+use crate::structs::htree::HTreeNode;
+use crate::structs::superblock::{format_uuid, FsSuperBlock, FEATURE_INCOMPAT_RECOVER, FS_STATE_DIRTY};
+use crate::utils::block_file::BlockFile;
+
+/// Commands understood by `debug_command`, as (synopsis, description)
+pub const DEBUG_COMMANDS: [(&str, &str); 16] = [
+    ("stats", "show the superblock"),
+    ("stat <inode>", "show an inode and the blocks it maps"),
+    ("ls <dir>", "list every record of a directory, free ones included"),
+    ("bd <block>", "dump a block as hex"),
+    ("decode <block> <what>", "decode a block as super, bitmap, inodes, dirents, var_dirents, extent, htree or model"),
+    ("icheck <block>...", "show which inode uses each block"),
+    ("freemap", "list the free blocks and inodes"),
+    ("setb <block>", "mark a block in use"),
+    ("freeb <block>", "mark a block free"),
+    ("seti <inode>", "mark an inode in use"),
+    ("freei <inode>", "mark an inode free"),
+    ("sif <inode> <field> <value>", "set mode, flags, uid, gid, size, ctime, mtime, single_indirect, double_indirect or block[N] of an inode"),
+    ("ssv <field> <value>", "set a superblock field, named as in stats"),
+    ("zap <block> <offset> <hex>", "overwrite bytes of a block"),
+    ("help", "show this list"),
+    ("quit", "leave"),
+];
+
+const INODE_FLAG_NAMES: [(u16, &str); 4] = [
+    (INODE_FLAG_EXTENTS, "extents"),
+    (INODE_FLAG_VAR_DIRENTS, "var_dirents"),
+    (INODE_FLAG_HTREE, "htree"),
+    (INODE_FLAG_LEARNED_INDEX, "learned_index"),
+];
+
+fn invalid_input(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+/// Decimal, or hex with a 0x prefix
+fn parse_number(arg: &str) -> std::io::Result<u64> {
+    let parsed = match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => arg.parse(),
+    };
+    parsed.map_err(|_| invalid_input(format!("invalid number {}", arg)))
+}
+
+fn parse_u32(arg: &str) -> std::io::Result<u32> {
+    u32::try_from(parse_number(arg)?).map_err(|_| invalid_input(format!("{} does not fit in 32 bits", arg)))
+}
+
+fn parse_hex_bytes(arg: &str) -> std::io::Result<Vec<u8>> {
+    if !arg.len().is_multiple_of(2) {
+        return Err(invalid_input(format!("odd number of hex digits in {}", arg)));
+    }
+    (0..arg.len()).step_by(2)
+        .map(|idx| u8::from_str_radix(&arg[idx..(idx + 2)], 16).map_err(|_| invalid_input(format!("invalid hex {}", arg))))
+        .collect()
+}
+
+/// Sorted indices as a list of ranges, like "3-10 15 20-22"
+fn format_ranges(indices: impl Iterator<Item=u32>) -> String {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for index in indices {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == index => *end = index,
+            _ => ranges.push((index, index)),
+        }
+    }
+    let ranges: Vec<String> = ranges.iter()
+        .map(|(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect();
+    if ranges.is_empty() { String::from("none") } else { ranges.join(" ") }
+}
+
+fn format_features(bits: u32, pick: fn(&(&str, u32, u32, u32)) -> u32) -> String {
+    let mut names: Vec<String> = FEATURE_NAMES.iter()
+        .filter(|feature| bits & pick(feature) != 0)
+        .map(|feature| feature.0.to_string())
+        .collect();
+    let known = FEATURE_NAMES.iter().fold(0, |known, feature| known | pick(feature));
+    if bits & !known != 0 {
+        names.push(format!("unknown 0x{:x}", bits & !known));
+    }
+    format!("0x{:x} ({})", bits, names.join(" "))
+}
+
+fn format_inode_flags(flags: u16) -> String {
+    let names: Vec<&str> = INODE_FLAG_NAMES.iter().filter(|(flag, _)| flags & flag != 0).map(|(_, name)| *name).collect();
+    format!("0x{:x} ({})", flags, names.join(" "))
+}
+
+fn print_superblock(out: &mut dyn Write, super_block: &FsSuperBlock) -> std::io::Result<()> {
+    writeln!(out, "magic 0x{:08x}, version {}, {} blocks of {} bytes",
+             super_block.magic, super_block.version, super_block.disk_size, super_block.block_size())?;
+    writeln!(out, "incompat_features {}", format_features(super_block.incompat_features, |feature| feature.1))?;
+    writeln!(out, "ro_compat_features {}", format_features(super_block.ro_compat_features, |feature| feature.2))?;
+    writeln!(out, "compat_features {}", format_features(super_block.compat_features, |feature| feature.3))?;
+    let (bitmap_start, bitmap_blocks) = super_block.bitmap_location();
+    writeln!(out, "bitmap_start {}, bitmap_blocks {}", bitmap_start, bitmap_blocks)?;
+    if super_block.has_inode_table() {
+        writeln!(out, "inode_count {}, inode_size {}, inode_table_start {}, inode_table_blocks {}",
+                 super_block.inode_count, super_block.inode_size, super_block.inode_table_start, super_block.inode_table_blocks)?;
+        writeln!(out, "inode_bitmap_start {}, inode_bitmap_blocks {}", super_block.inode_bitmap_start, super_block.inode_bitmap_blocks)?;
+    } else {
+        writeln!(out, "no inode table, inode N is block N")?;
+    }
+    if super_block.has_journal() {
+        writeln!(out, "journal_start {}, journal_blocks {}{}", super_block.journal_start, super_block.journal_blocks,
+                 if super_block.incompat_features & FEATURE_INCOMPAT_RECOVER != 0 { ", needs recovery" } else { "" })?;
+    }
+    if super_block.is_cow() {
+        writeln!(out, "cow_start {}, cow_blocks {}, cow_generation {}, cow_map {}",
+                 super_block.cow_start, super_block.cow_blocks, super_block.cow_generation, super_block.cow_map)?;
+    }
+    writeln!(out, "free_blocks_count {}, free_inodes_count {}", super_block.free_blocks_count, super_block.free_inodes_count)?;
+    writeln!(out, "uuid {}, label {:?}", format_uuid(&super_block.uuid), super_block.label())?;
+    writeln!(out, "state 0x{:x} ({}), mount_count {}, last_mount_time {}, last_write_time {}",
+             super_block.state, if super_block.state & FS_STATE_DIRTY != 0 { "dirty" } else { "clean" },
+             super_block.mount_count, super_block.last_mount_time, super_block.last_write_time)
+}
+
+fn print_inode_summary(out: &mut dyn Write, inode: u64, node: &FSINode) -> std::io::Result<()> {
+    writeln!(out, "inode {}: mode 0{:o}, flags {}, uid {}, gid {}, size {}, ctime {}, mtime {}",
+             inode, node.mode, format_inode_flags(node.flags), node.uid, node.gid, node.size, node.ctime, node.mtime)
+}
+
+fn print_extent_node(out: &mut dyn Write, node: &ExtentNode, indent: &str) -> std::io::Result<()> {
+    writeln!(out, "{}depth {}, {} entries", indent, node.depth, node.num_entries())?;
+    for extent in &node.extents {
+        writeln!(out, "{}  logical {}-{} -> physical {}-{}", indent, extent.logical_start, extent.logical_start + extent.length - 1,
+                 extent.physical_start, extent.physical_start + extent.length - 1)?;
+    }
+    for child in &node.children {
+        writeln!(out, "{}  logical {}- -> node in block {}", indent, child.logical_start, child.child_block)?;
+    }
+    Ok(())
+}
+
+/// One line per entry. Adjacent free records share a line, so empty fixed-size slots don't
+/// drown out the entries
+fn print_dirent_slots(out: &mut dyn Write, slots: &[DirentSlot]) -> std::io::Result<()> {
+    let mut free_run: Option<(usize, usize, usize)> = None;
+    let print_free_run = |out: &mut dyn Write, (offset, len, count): (usize, usize, usize)| match count {
+        1 => writeln!(out, "  {:>8} {:>6}  <free>", offset, len),
+        _ => writeln!(out, "  {:>8} {:>6}  <free, {} records>", offset, len, count),
+    };
+    for slot in slots {
+        match &slot.entry {
+            Ok(DirectoryEntry { inode_ptr, name, file_type }) => {
+                if let Some(run) = free_run.take() {
+                    print_free_run(out, run)?;
+                }
+                writeln!(out, "  {:>8} {:>6}  inode {:>8}  type {}  {:?}", slot.offset, slot.rec_len, inode_ptr, file_type, name)?;
+            }
+            Err(()) => match free_run.as_mut() {
+                Some((offset, len, count)) if *offset + *len == slot.offset => { *len += slot.rec_len; *count += 1; }
+                _ => {
+                    if let Some(run) = free_run.replace((slot.offset, slot.rec_len, 1)) {
+                        print_free_run(out, run)?;
+                    }
+                }
+            },
+        }
+    }
+    if let Some(run) = free_run {
+        print_free_run(out, run)?;
+    }
+    Ok(())
+}
+
+/// Hex dump, 16 bytes to a line, with runs of identical lines collapsed into a "*"
+fn print_hex(out: &mut dyn Write, data: &[u8]) -> std::io::Result<()> {
+    let mut previous: Option<&[u8]> = None;
+    let mut skipping = false;
+    for (line_idx, line) in data.chunks(16).enumerate() {
+        if previous == Some(line) {
+            if !skipping {
+                writeln!(out, "*")?;
+                skipping = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        skipping = false;
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = line.iter().map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' }).collect();
+        writeln!(out, "{:06x}  {:<47}  {}", line_idx * 16, hex.join(" "), text)?;
+    }
+    writeln!(out, "{:06x}", data.len())
+}
+
+/// Low-level inspection and patching of an image, for `lfs-debug`. Unlike the FUSE entry
+/// points, none of this trusts the image to be consistent.
+impl <BF: BlockFile> LearnedFileSystem<BF> {
+    /// Inode number given either as a number or as an absolute path
+    fn debug_parse_inode(&self, arg: &str) -> std::io::Result<u64> {
+        if arg.starts_with('/') { self.lookup_path(arg) } else { parse_number(arg) }
+    }
+
+    fn debug_check_inode(&self, inode: u64) -> std::io::Result<()> {
+        let in_range = match self.inode_allocation_bitmask.as_ref() {
+            Some(inode_bitmask) => inode < inode_bitmask.num_indices() as u64,
+            None => inode < self.block_system.num_blocks() as u64,
+        };
+        if !in_range {
+            return Err(invalid_input(format!("inode {} is out of range", inode)));
+        }
+        Ok(())
+    }
+
+    fn debug_check_block(&self, block: u32) -> std::io::Result<()> {
+        if block as usize >= self.block_system.num_blocks() {
+            return Err(invalid_input(format!("block {} is out of range", block)));
+        }
+        Ok(())
+    }
+
+    fn debug_stat(&self, out: &mut dyn Write, inode: u64) -> std::io::Result<()> {
+        self.debug_check_inode(inode)?;
+        let node = self.get_inode(inode)?;
+        print_inode_summary(out, inode, &node)?;
+        if node.is_extent_mapped() {
+            let mut to_visit = vec![(String::from("extent tree root in inode"), node.extent_root())];
+            while let Some((location, tree_node)) = to_visit.pop() {
+                writeln!(out, "{}:", location)?;
+                match tree_node {
+                    Ok(tree_node) => {
+                        print_extent_node(out, &tree_node, "  ")?;
+                        for child in tree_node.children.iter().rev() {
+                            to_visit.push((format!("extent tree node in block {}", child.child_block), self.read_extent_node(child.child_block)));
+                        }
+                    }
+                    Err(e) => writeln!(out, "  unreadable: {}", e)?,
+                }
+            }
+        } else {
+            let direct: Vec<String> = node.pointers.iter().enumerate()
+                .filter(|(_, block)| **block != 0)
+                .map(|(idx, block)| format!("[{}]={}", idx, block))
+                .collect();
+            writeln!(out, "direct pointers: {}", if direct.is_empty() { String::from("none") } else { direct.join(" ") })?;
+            writeln!(out, "single_indirect {}, double_indirect {}", node.single_indirect, node.double_indirect)?;
+        }
+
+        let data = self.mapped_blocks(inode, &node);
+        writeln!(out, "{} data blocks:", data.len())?;
+        for extent in extents_from_blocks(&data) {
+            writeln!(out, "  logical {}-{} -> physical {}-{}", extent.logical_start, extent.logical_start + extent.length - 1,
+                     extent.physical_start, extent.physical_start + extent.length - 1)?;
+        }
+        Ok(())
+    }
+
+    fn debug_ls(&self, out: &mut dyn Write, inode: u64) -> std::io::Result<()> {
+        self.debug_check_inode(inode)?;
+        let node = self.get_inode(inode)?;
+        print_inode_summary(out, inode, &node)?;
+        if node.has_htree_index() || node.has_learned_index() {
+            writeln!(out, "block 0 holds the {} index", if node.has_htree_index() { "hash" } else { "learned" })?;
+        }
+        let contents = self.dir_contents_from_blocks(&node, &self.mapped_blocks(inode, &node));
+        let slots = self.parse_dirents(&node, &contents);
+        writeln!(out, "  {:>8} {:>6}", "offset", "length")?;
+        print_dirent_slots(out, &slots)?;
+
+        // Variable-length blocks whose records stop short of the end of the block
+        if node.has_var_dirents() {
+            for block_start in (0..contents.len()).step_by(self.block_size) {
+                let block_end = (block_start + self.block_size).min(contents.len());
+                let parsed_end = slots.iter()
+                    .filter(|slot| slot.offset >= block_start && slot.offset < block_end)
+                    .map(|slot| slot.offset + slot.rec_len)
+                    .max()
+                    .unwrap_or(block_start);
+                if parsed_end < block_end {
+                    writeln!(out, "  {:>8} {:>6}  <unparsable>", parsed_end, block_end - parsed_end)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn debug_decode(&self, out: &mut dyn Write, block: u32, what: &str) -> std::io::Result<()> {
+        self.debug_check_block(block)?;
+        let data = self.block_system.block_read(block as usize)?;
+        match what {
+            "super" => print_superblock(out, &FsSuperBlock::from(data.as_slice()))?,
+            "bitmap" => {
+                let set_bits = (0..(data.len() * 8) as u32).filter(|bit| data[*bit as usize / 8] & (1 << (bit % 8)) != 0);
+                writeln!(out, "bits set, counted from the start of this block: {}", format_ranges(set_bits))?;
+            }
+            "inodes" => {
+                let table_start = self.inode_table_start_block as u32;
+                let inodes_per_block = self.block_size / self.inode_size;
+                for (idx, inode_bytes) in data.chunks_exact(self.inode_size).enumerate() {
+                    if inode_bytes.iter().all(|byte| *byte == 0) {
+                        continue;
+                    }
+                    let inode = match self.inode_allocation_bitmask {
+                        Some(_) if block >= table_start => ((block - table_start) as usize * inodes_per_block + idx) as u64,
+                        _ => block as u64,
+                    };
+                    print_inode_summary(out, inode, &FSINode::from(inode_bytes))?;
+                }
+            }
+            "dirents" => {
+                let slots: Vec<DirentSlot> = data.chunks_exact(FIXED_DIRENT_SIZE).enumerate()
+                    .map(|(idx, dirent)| DirentSlot { offset: idx * FIXED_DIRENT_SIZE, rec_len: FIXED_DIRENT_SIZE, entry: DirectoryEntry::try_from(dirent) })
+                    .filter(|slot| slot.entry.is_ok())
+                    .collect();
+                print_dirent_slots(out, &slots)?;
+            }
+            "var_dirents" => print_dirent_slots(out, &parse_var_dirent_block(&data, 0))?,
+            "extent" => match ExtentNode::try_from(data.as_slice()) {
+                Ok(node) => print_extent_node(out, &node, "")?,
+                Err(_) => writeln!(out, "not an extent tree node")?,
+            },
+            "htree" => match HTreeNode::try_from(data.as_slice()) {
+                Ok(node) => {
+                    writeln!(out, "depth {}, {} entries", node.depth, node.entries.len())?;
+                    for entry in &node.entries {
+                        writeln!(out, "  hash 0x{:08x} -> block {}", entry.hash, entry.block)?;
+                    }
+                }
+                Err(_) => writeln!(out, "not a hash index node")?,
+            },
+            "model" => match DirModel::try_from(data.as_slice()) {
+                Ok(model) => {
+                    writeln!(out, "{} segments, max error {}, {} data blocks, {} indexed and {} unindexed names",
+                             model.segments.len(), model.max_error, model.num_data_blocks, model.num_indexed, model.num_unindexed)?;
+                    for segment in &model.segments {
+                        writeln!(out, "  from hash 0x{:08x}: block = {} * hash + {}", segment.first_hash, segment.slope, segment.intercept)?;
+                    }
+                }
+                Err(_) => writeln!(out, "not a learned directory model")?,
+            },
+            _ => return Err(invalid_input(format!("cannot decode a block as {}", what))),
+        }
+        Ok(())
+    }
+
+    fn debug_icheck(&mut self, out: &mut dyn Write, blocks: &[u32]) -> std::io::Result<()> {
+        let owners = self.block_owners()?;
+        for block in blocks {
+            match owners.get(block) {
+                Some(0) => writeln!(out, "block {}: file system metadata", block)?,
+                Some(owner) => writeln!(out, "block {}: inode {}", block, owner)?,
+                None => writeln!(out, "block {}: not in use", block)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn debug_freemap(&self, out: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(out, "{} free blocks: {}", self.block_allocation_bitmask.num_free_indices(),
+                 format_ranges(self.block_allocation_bitmask.free_block_iter()))?;
+        if let Some(inode_bitmask) = self.inode_allocation_bitmask.as_ref() {
+            writeln!(out, "{} free inodes: {}", inode_bitmask.num_free_indices(), format_ranges(inode_bitmask.free_block_iter()))?;
+        }
+        Ok(())
+    }
+
+    fn debug_set_bit(&mut self, is_inode: bool, index: u32, in_use: bool) -> std::io::Result<()> {
+        let bitmask = if is_inode {
+            self.inode_allocation_bitmask.as_mut().ok_or_else(|| invalid_input(String::from("the image has no inode table, use setb and freeb")))?
+        } else {
+            &mut self.block_allocation_bitmask
+        };
+        if index as usize >= bitmask.num_indices() {
+            return Err(invalid_input(format!("{} is out of range", index)));
+        }
+        if in_use { bitmask.set_bit(index) } else { bitmask.clear_bit(index) }
+        self.write_dirty_bitmask_blocks()
+    }
+
+    fn debug_set_inode_field(&mut self, inode: u64, field: &str, value: &str) -> std::io::Result<()> {
+        self.debug_check_inode(inode)?;
+        let mut node = self.get_inode(inode)?;
+        let pointer_idx = field.strip_prefix("block[").and_then(|rest| rest.strip_suffix(']'));
+        match (field, pointer_idx) {
+            ("mode", _) => node.mode = parse_u32(value)? & 0xFFFF,
+            ("flags", _) => node.flags = parse_u32(value)? as u16,
+            ("uid", _) => node.uid = parse_u32(value)? as u16,
+            ("gid", _) => node.gid = parse_u32(value)? as u16,
+            ("size", _) => node.size = parse_number(value)?,
+            ("ctime", _) => node.ctime = parse_u32(value)?,
+            ("mtime", _) => node.mtime = parse_u32(value)?,
+            ("single_indirect", _) => node.single_indirect = parse_u32(value)?,
+            ("double_indirect", _) => node.double_indirect = parse_u32(value)?,
+            (_, Some(pointer_idx)) => {
+                let pointer_idx = parse_number(pointer_idx)? as usize;
+                let num_pointers = node.pointers.len();
+                *node.pointers.get_mut(pointer_idx).ok_or_else(|| invalid_input(format!("inodes have {} direct pointers", num_pointers)))? = parse_u32(value)?;
+            }
+            _ => return Err(invalid_input(format!("unknown inode field {}", field))),
+        }
+        self.write_inode(inode, node)
+    }
+
+    /// Fields are written as given, without recomputing the free counts like a regular
+    /// superblock write would
+    fn debug_set_super_value(&mut self, field: &str, value: &str) -> std::io::Result<()> {
+        let mut super_block = self.get_superblock()?;
+        let mut fields: BTreeMap<&str, &mut u32> = [
+            ("magic", &mut super_block.magic), ("disk_size", &mut super_block.disk_size), ("version", &mut super_block.version),
+            ("incompat_features", &mut super_block.incompat_features), ("ro_compat_features", &mut super_block.ro_compat_features),
+            ("compat_features", &mut super_block.compat_features), ("bitmap_start", &mut super_block.bitmap_start),
+            ("bitmap_blocks", &mut super_block.bitmap_blocks), ("inode_table_start", &mut super_block.inode_table_start),
+            ("inode_table_blocks", &mut super_block.inode_table_blocks), ("inode_bitmap_start", &mut super_block.inode_bitmap_start),
+            ("inode_bitmap_blocks", &mut super_block.inode_bitmap_blocks), ("inode_size", &mut super_block.inode_size),
+            ("inode_count", &mut super_block.inode_count), ("block_size", &mut super_block.block_size),
+            ("free_blocks_count", &mut super_block.free_blocks_count), ("free_inodes_count", &mut super_block.free_inodes_count),
+            ("last_mount_time", &mut super_block.last_mount_time), ("last_write_time", &mut super_block.last_write_time),
+            ("journal_start", &mut super_block.journal_start), ("journal_blocks", &mut super_block.journal_blocks),
+            ("cow_start", &mut super_block.cow_start), ("cow_blocks", &mut super_block.cow_blocks),
+        ].into_iter().collect();
+        match (fields.remove(field), field) {
+            (Some(slot), _) => *slot = parse_u32(value)?,
+            (None, "state") => super_block.state = parse_u32(value)? as u16,
+            (None, "mount_count") => super_block.mount_count = parse_u32(value)? as u16,
+            (None, "label") => super_block.set_label(value)?,
+            (None, _) => return Err(invalid_input(format!("unknown superblock field {}", field))),
+        }
+
+        // Keep the rest of block 0 as it is, even if the block size was just changed
+        let super_block_data: Vec<u8> = super_block.into();
+        let mut block = self.block_system.block_read(0)?;
+        let len = block.len().min(super_block_data.len());
+        block[..len].copy_from_slice(&super_block_data[..len]);
+        self.block_system.block_write(&block, 0)?;
+        Ok(())
+    }
+
+    fn debug_zap(&mut self, block: u32, offset: usize, bytes: &[u8]) -> std::io::Result<()> {
+        self.debug_check_block(block)?;
+        if offset + bytes.len() > self.block_size {
+            return Err(invalid_input(format!("{} bytes at offset {} do not fit in a block", bytes.len(), offset)));
+        }
+        let mut data = self.block_system.block_read(block as usize)?;
+        data[offset..(offset + bytes.len())].copy_from_slice(bytes);
+        self.block_system.block_write(&data, block as usize)?;
+        Ok(())
+    }
+
+    /// Run one `lfs-debug` command (see DEBUG_COMMANDS), writing what it prints to `out`.
+    /// Commands that change the image fail unless `writable` is set.
+    pub fn debug_command(&mut self, line: &str, writable: bool, out: &mut dyn Write) -> std::io::Result<()> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match args.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Ok(()),
+        };
+        let expect_args = |count: usize| if args.len() == count { Ok(()) } else {
+            Err(invalid_input(format!("{} takes {} arguments", command, count)))
+        };
+        let is_write = matches!(command, "setb" | "freeb" | "seti" | "freei" | "sif" | "ssv" | "zap");
+        if is_write && !writable {
+            return Err(Error::new(ErrorKind::PermissionDenied, "the image is open read-only"));
+        }
+
+        match command {
+            "stats" => print_superblock(out, &self.get_superblock()?)?,
+            "stat" => { expect_args(1)?; self.debug_stat(out, self.debug_parse_inode(args[0])?)? }
+            "ls" => {
+                let inode = match args {
+                    [] => ROOT_INODE,
+                    [dir] => self.debug_parse_inode(dir)?,
+                    _ => return Err(invalid_input(String::from("ls takes at most 1 argument"))),
+                };
+                self.debug_ls(out, inode)?
+            }
+            "bd" => {
+                expect_args(1)?;
+                let block = parse_u32(args[0])?;
+                self.debug_check_block(block)?;
+                print_hex(out, &self.block_system.block_read(block as usize)?)?
+            }
+            "decode" => { expect_args(2)?; self.debug_decode(out, parse_u32(args[0])?, args[1])? }
+            "icheck" => {
+                let blocks = args.iter().map(|arg| parse_u32(arg)).collect::<std::io::Result<Vec<u32>>>()?;
+                self.debug_icheck(out, &blocks)?
+            }
+            "freemap" => self.debug_freemap(out)?,
+            "setb" | "freeb" => { expect_args(1)?; self.debug_set_bit(false, parse_u32(args[0])?, command == "setb")? }
+            "seti" | "freei" => {
+                expect_args(1)?;
+                let inode = self.debug_parse_inode(args[0])?;
+                self.debug_set_bit(true, u32::try_from(inode).unwrap_or(u32::MAX), command == "seti")?
+            }
+            "sif" => { expect_args(3)?; self.debug_set_inode_field(self.debug_parse_inode(args[0])?, args[1], args[2])? }
+            "ssv" => { expect_args(2)?; self.debug_set_super_value(args[0], args[1])? }
+            "zap" => { expect_args(3)?; self.debug_zap(parse_u32(args[0])?, parse_number(args[1])? as usize, &parse_hex_bytes(args[2])?)? }
+            "help" => for (synopsis, description) in DEBUG_COMMANDS.iter() {
+                writeln!(out, "{:<30} {}", synopsis, description)?;
+            },
+            _ => return Err(invalid_input(format!("unknown command {}, try help", command))),
+        }
+
+        // Pick up whatever a write changed, such as bitmap blocks patched with zap. An image
+        // broken on purpose may no longer load, which is fine for further inspection.
+        if is_write {
+            if let Err(e) = self.load() {
+                writeln!(out, "warning: the image no longer loads: {}", e)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+use std::ffi::OsStr;
+use std::io::{Error, ErrorKind};
+use crate::LearnedFileSystem;
+use crate::structs::dirent::{DirectoryEntry, DirentLookup, pack_var_dirent_block, parse_var_dirent_block, var_dirent_len};
+use crate::structs::fsinode::{FSINode, INODE_FLAG_HTREE};
+use crate::structs::htree::{HTreeEntry, HTreeNode, name_hash};
+use crate::utils::block_file::BlockFile;
+use crate::utils::div_ceil;
+
+/// Where to split a sorted, overflowing leaf: both halves must fit in a block, and we
+/// prefer not to separate names with equal hashes, then halves of similar size
+fn htree_split_point(entries: &[DirectoryEntry], block_size: usize) -> usize {
+    let lens: Vec<usize> = entries.iter().map(|e| var_dirent_len(e.name.len())).collect();
+    let total: usize = lens.iter().sum();
+
+    let mut prefix = 0;
+    let mut best: Option<((bool, usize), usize)> = None;
+    for split in 1..entries.len() {
+        prefix += lens[split - 1];
+        if prefix > block_size || total - prefix > block_size {
+            continue;
+        }
+        let splits_equal_hashes = name_hash(&entries[split - 1].name) == name_hash(&entries[split].name);
+        let score = (splits_equal_hashes, prefix.abs_diff(total / 2));
+        if best.is_none_or(|(best_score, _)| score < best_score) {
+            best = Some((score, split));
+        }
+    }
+
+    best.map(|(_, split)| split).unwrap_or(entries.len() / 2)
+}
+
+/// Hash tree index for large variable-length directories.
+///
+/// Logical block 0 of an indexed directory is the root index node, pointing either at leaf
+/// blocks or at one level of index nodes. Leaves are ordinary variable-length directory
+/// blocks, and index nodes look like one big free record, so a linear scan of the
+/// directory still sees every entry.
+impl <BF: BlockFile> LearnedFileSystem<BF> {
+    pub(crate) fn read_dir_block(&self, dir: &FSINode, logical_block: u32) -> std::io::Result<Vec<u8>> {
+        self.read_file_bytes(dir, logical_block as usize * self.block_size, self.block_size)
+    }
+
+    /// NOTE: Does not write back the directory inode itself
+    pub(crate) fn write_dir_block(&mut self, dir: &mut FSINode, logical_block: u32, block: &[u8]) -> std::io::Result<()> {
+        self.write_file_data(dir, logical_block as usize * self.block_size, block)?;
+        Ok(())
+    }
+
+    pub(crate) fn num_dir_blocks(&self, dir: &FSINode) -> u32 {
+        div_ceil(dir.size, self.block_size as u64) as u32
+    }
+
+    fn read_htree_node(&self, dir: &FSINode, logical_block: u32) -> std::io::Result<HTreeNode> {
+        HTreeNode::try_from(self.read_dir_block(dir, logical_block)?.as_slice()).map_err(|_| Error::from(ErrorKind::InvalidData))
+    }
+
+    /// Leaf blocks that may hold a name with the given hash
+    fn htree_candidate_leaves(&self, dir: &FSINode, hash: u32) -> std::io::Result<Vec<u32>> {
+        let root = self.read_htree_node(dir, 0)?;
+        let mut leaves = vec![];
+        for entry in &root.entries[root.candidate_entries(hash)] {
+            if root.depth == 0 {
+                leaves.push(entry.block);
+            } else {
+                let node = self.read_htree_node(dir, entry.block)?;
+                leaves.extend(node.entries[node.candidate_entries(hash)].iter().map(|e| e.block));
+            }
+        }
+        Ok(leaves)
+    }
+
+    /// Like `find_dirent`, but only reads the index and the leaves the name could be in.
+    /// The returned slots are those of the candidate leaves.
+    pub(crate) fn htree_find_dirent(&self, dir: &FSINode, name: &OsStr) -> std::io::Result<DirentLookup> {
+        let mut slots = vec![];
+        for leaf in self.htree_candidate_leaves(dir, name_hash(name))? {
+            slots.extend(parse_var_dirent_block(&self.read_dir_block(dir, leaf)?, leaf as usize * self.block_size));
+        }
+        let found = self.find_dirent_in_list(&slots, name);
+        Ok((slots, found))
+    }
+
+    /// NOTE: Does not write back the directory inode itself
+    pub(crate) fn htree_insert_dirent(&mut self, dir: &mut FSINode, dirent: DirectoryEntry) -> std::io::Result<()> {
+        let hash = name_hash(&dirent.name);
+        let max_entries = HTreeNode::max_entries(self.block_size);
+
+        let root = self.read_htree_node(dir, 0)?;
+        let root_idx = root.insertion_entry(hash);
+        let (parent_block, mut parent) = if root.depth == 0 {
+            (0, root.clone())
+        } else {
+            let node_block = root.entries[root_idx].block;
+            (node_block, self.read_htree_node(dir, node_block)?)
+        };
+        let parent_idx = parent.insertion_entry(hash);
+        let leaf_block = parent.entries[parent_idx].block;
+
+        let leaf_slots = parse_var_dirent_block(&self.read_dir_block(dir, leaf_block)?, leaf_block as usize * self.block_size);
+        if let Some(idx) = self.first_free_dirent_idx(dir, &leaf_slots, var_dirent_len(dirent.name.len())) {
+            return self.insert_into_var_slot(dir, &leaf_slots[idx], dirent);
+        }
+
+        // The leaf is full, so it gets split. Make sure the index can take the new leaf first.
+        if root.depth > 0 && parent.entries.len() >= max_entries && root.entries.len() >= max_entries {
+            return Err(Error::from(ErrorKind::OutOfMemory));
+        }
+
+        let mut entries: Vec<DirectoryEntry> = leaf_slots.into_iter().filter_map(|slot| slot.entry.ok()).collect();
+        entries.push(dirent);
+        entries.sort_by_key(|e| name_hash(&e.name));
+        let split = htree_split_point(&entries, self.block_size);
+
+        let new_leaf_block = self.num_dir_blocks(dir);
+        self.write_dir_block(dir, leaf_block, &pack_var_dirent_block(&entries[..split], self.block_size))?;
+        self.write_dir_block(dir, new_leaf_block, &pack_var_dirent_block(&entries[split..], self.block_size))?;
+
+        parent.entries.insert(parent_idx + 1, HTreeEntry { hash: name_hash(&entries[split].name), block: new_leaf_block });
+        if parent.entries.len() <= max_entries {
+            return self.write_dir_block(dir, parent_block, &parent.to_bytes(self.block_size));
+        }
+
+        // The index node overflowed as well, so split it in two
+        let right_entries = parent.entries.split_off(parent.entries.len() / 2);
+        let right_hash = right_entries[0].hash;
+        let right = HTreeNode { depth: 0, entries: right_entries };
+
+        if root.depth == 0 {
+            // Grow the tree by a level, keeping the root in block 0
+            let left_block = self.num_dir_blocks(dir);
+            self.write_dir_block(dir, left_block, &HTreeNode { depth: 0, entries: parent.entries }.to_bytes(self.block_size))?;
+            self.write_dir_block(dir, left_block + 1, &right.to_bytes(self.block_size))?;
+            let new_root = HTreeNode {
+                depth: 1,
+                entries: vec![HTreeEntry { hash: 0, block: left_block }, HTreeEntry { hash: right_hash, block: left_block + 1 }],
+            };
+            return self.write_dir_block(dir, 0, &new_root.to_bytes(self.block_size));
+        }
+
+        let right_block = self.num_dir_blocks(dir);
+        self.write_dir_block(dir, parent_block, &parent.to_bytes(self.block_size))?;
+        self.write_dir_block(dir, right_block, &right.to_bytes(self.block_size))?;
+        let mut root = root;
+        root.entries.insert(root_idx + 1, HTreeEntry { hash: right_hash, block: right_block });
+        self.write_dir_block(dir, 0, &root.to_bytes(self.block_size))
+    }
+
+    /// Rewrite a linear variable-length directory as an indexed one.
+    /// NOTE: Does not write back the directory inode itself
+    pub(crate) fn htree_convert(&mut self, dir: &mut FSINode) -> std::io::Result<()> {
+        let max_entries = HTreeNode::max_entries(self.block_size);
+
+        let mut entries = self.get_valid_dirents(dir)?;
+        entries.sort_by_key(|e| name_hash(&e.name));
+
+        let mut leaves: Vec<Vec<DirectoryEntry>> = vec![vec![]];
+        let mut leaf_bytes = 0;
+        for entry in entries {
+            let len = var_dirent_len(entry.name.len());
+            if leaf_bytes + len > self.block_size {
+                leaves.push(vec![]);
+                leaf_bytes = 0;
+            }
+            leaf_bytes += len;
+            leaves.last_mut().unwrap().push(entry);
+        }
+
+        if div_ceil(leaves.len(), max_entries) > max_entries {
+            return Err(Error::from(ErrorKind::OutOfMemory));
+        }
+
+        let old_num_blocks = self.num_dir_blocks(dir);
+        let mut leaf_entries = vec![];
+        for (leaf_idx, leaf) in leaves.iter().enumerate() {
+            let block = leaf_idx as u32 + 1;
+            let hash = if leaf_idx == 0 { 0 } else { name_hash(&leaf[0].name) };
+            self.write_dir_block(dir, block, &pack_var_dirent_block(leaf, self.block_size))?;
+            leaf_entries.push(HTreeEntry { hash, block });
+        }
+
+        let mut next_block = leaves.len() as u32 + 1;
+        let root = if leaf_entries.len() <= max_entries {
+            HTreeNode { depth: 0, entries: leaf_entries }
+        } else {
+            let mut node_entries = vec![];
+            for chunk in leaf_entries.chunks(max_entries) {
+                self.write_dir_block(dir, next_block, &HTreeNode { depth: 0, entries: chunk.to_vec() }.to_bytes(self.block_size))?;
+                node_entries.push(HTreeEntry { hash: chunk[0].hash, block: next_block });
+                next_block += 1;
+            }
+            HTreeNode { depth: 1, entries: node_entries }
+        };
+        self.write_dir_block(dir, 0, &root.to_bytes(self.block_size))?;
+
+        if next_block < old_num_blocks {
+            self.truncate_to_num_blocks(dir, next_block)?;
+            dir.size = next_block as u64 * self.block_size as u64;
+        }
+        dir.flags |= INODE_FLAG_HTREE;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use crate::crash_test::{check_written, run_and_reopen};
+    use crate::mkfs::MkfsOptions;
+
+    #[test]
+    fn htree_round_trip() {
+        let mut options = MkfsOptions { num_blocks: 2048, block_size: 1024, inode_count: Some(512), ..MkfsOptions::default() };
+        options.set_features("extents,var_dirents,dir_index,has_journal").unwrap();
+        let name = |idx: usize| format!("{}{:04}", "h".repeat(60), idx);
+        let mut workload = String::from("mkdir /d\n");
+        for idx in 0..400 {
+            workload += &format!("create /d/{}\n", name(idx));
+        }
+        for idx in (0..400).step_by(7) {
+            workload += &format!("unlink /d/{}\n", name(idx));
+        }
+        workload += &format!("write /d/{} 0 3000\n", name(1));
+        let file_system = run_and_reopen(&options, &workload);
+
+        let dir = file_system.get_inode(file_system.lookup_path("/d").unwrap()).unwrap();
+        assert!(dir.has_htree_index());
+        assert!(file_system.num_dir_blocks(&dir) > 20);
+        for idx in 0..400 {
+            let (_, found) = file_system.htree_find_dirent(&dir, &OsString::from(name(idx))).unwrap();
+            assert_eq!(found.is_some(), idx % 7 != 0, "{}", name(idx));
+        }
+        check_written(&file_system, &format!("/d/{}", name(1)), 0, 3000);
+    }
+}
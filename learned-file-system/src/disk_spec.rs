@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{Error, ErrorKind};
+use crate::{FS_BLOCK_SIZE, FS_MAGIC_NUM};
+use crate::structs::dirent::{DirectoryEntry, DIRENT_TYPE_UNKNOWN, FIXED_DIRENT_SIZE, FIXED_NAME_MAX};
+use crate::structs::fsinode::{FSINode, num_direct_pointers};
+use crate::structs::superblock::FsSuperBlock;
+use crate::utils::bitmask::BitMaskBlock;
+
+/// Python's `random` module seeded with hash(path) + block offset fills the data blocks with
+/// letters from this alphabet. gen-disk.py picks them with randint(0, 50), so 'Z' never shows up.
+const DATA_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// gen-disk.py always writes one bitmap block
+const MAX_SPEC_BLOCKS: u32 = (FS_BLOCK_SIZE * 8) as u32;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpecDirent {
+    /// False for `-name` entries, which are written with inode 0 and the valid bit clear
+    pub valid: bool,
+    pub name: String,
+    pub inode: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpecInode {
+    /// Line of the spec this came from, for error messages
+    pub line: usize,
+    pub inode: u32,
+    /// Full path of the file. Only used to seed the file contents
+    pub path: String,
+    pub uid: u16,
+    pub gid: u16,
+    pub mode: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    pub size: u32,
+    pub blocks: Vec<u32>,
+    /// Directory entries, None for a regular file
+    pub entries: Option<Vec<SpecDirent>>,
+}
+
+/// Image spec in the format of test/disk*.in, which builds into the same bytes as test/gen-disk.py.
+///
+/// A spec is a list of lines:
+///   `# comment`
+///   `$name value`  defines a variable, the value is an integer in Python's `int(value, 0)` syntax
+///   `size blocks`  sets the size of the image
+///   `file inode path uid gid mode ctime mtime size blocks`
+///   `dir inode path uid gid mode ctime mtime size blocks entries...`
+/// where `blocks` is a comma separated list of block numbers and every entry is either
+/// `name,inode` or `-name` for a deleted one. Numeric fields can refer to variables.
+/// Images are in the original format: 4 KiB blocks, one bitmap block and one inode per block.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiskSpec {
+    pub num_blocks: u32,
+    pub inodes: Vec<SpecInode>,
+}
+
+fn spec_error(line: usize, message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("line {}: {}", line, message))
+}
+
+/// Integer in the syntax of Python 2's `int(s, 0)`: an optional sign, then decimal, `0x` hex,
+/// `0o` or `0` octal, or `0b` binary
+fn parse_python_int(s: &str) -> Option<i64> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let lower = digits.to_ascii_lowercase();
+    let (radix, digits) = if let Some(hex) = lower.strip_prefix("0x") {
+        (16, hex)
+    } else if let Some(octal) = lower.strip_prefix("0o") {
+        (8, octal)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        (2, binary)
+    } else if lower.len() > 1 && lower.starts_with('0') {
+        (8, &lower[1..])
+    } else {
+        (10, lower.as_str())
+    };
+    if digits.is_empty() || digits.starts_with(['+', '-']) {
+        return None;
+    }
+    let value = i64::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -value } else { value })
+}
+
+/// Python 2's hash() of a string on a 64 bit build without hash randomization
+fn python2_str_hash(s: &[u8]) -> i64 {
+    if s.is_empty() {
+        return 0;
+    }
+    let mut hash = (s[0] as i64) << 7;
+    for byte in s {
+        hash = hash.wrapping_mul(1000003) ^ *byte as i64;
+    }
+    hash ^= s.len() as i64;
+    if hash == -1 { -2 } else { hash }
+}
+
+const MT_N: usize = 624;
+const MT_M: usize = 397;
+
+/// The Mersenne Twister behind Python 2's `random`, with just enough of the module around it
+/// to reproduce `seed` and `randint`
+struct PythonRandom {
+    state: [u32; MT_N],
+    index: usize,
+}
+
+impl PythonRandom {
+    fn seed(seed: i128) -> Self {
+        // The absolute value of the seed, split into 32 bit words starting from the lowest
+        let mut remaining = seed.unsigned_abs();
+        let mut key = vec![];
+        while remaining != 0 {
+            key.push(remaining as u32);
+            remaining >>= 32;
+        }
+        if key.is_empty() {
+            key.push(0);
+        }
+
+        let mut state = [0u32; MT_N];
+        state[0] = 19650218;
+        for i in 1..MT_N {
+            state[i] = 1812433253u32.wrapping_mul(state[i - 1] ^ (state[i - 1] >> 30)).wrapping_add(i as u32);
+        }
+
+        let (mut i, mut j) = (1, 0);
+        for _ in 0..MT_N.max(key.len()) {
+            state[i] = (state[i] ^ (state[i - 1] ^ (state[i - 1] >> 30)).wrapping_mul(1664525))
+                .wrapping_add(key[j]).wrapping_add(j as u32);
+            i += 1;
+            j += 1;
+            if i >= MT_N {
+                state[0] = state[MT_N - 1];
+                i = 1;
+            }
+            if j >= key.len() {
+                j = 0;
+            }
+        }
+        for _ in 0..(MT_N - 1) {
+            state[i] = (state[i] ^ (state[i - 1] ^ (state[i - 1] >> 30)).wrapping_mul(1566083941)).wrapping_sub(i as u32);
+            i += 1;
+            if i >= MT_N {
+                state[0] = state[MT_N - 1];
+                i = 1;
+            }
+        }
+        state[0] = 0x80000000;
+
+        PythonRandom { state, index: MT_N }
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        if self.index >= MT_N {
+            for i in 0..MT_N {
+                let y = (self.state[i] & 0x80000000) | (self.state[(i + 1) % MT_N] & 0x7fffffff);
+                self.state[i] = self.state[(i + MT_M) % MT_N] ^ (y >> 1) ^ if y & 1 != 0 { 0x9908b0df } else { 0 };
+            }
+            self.index = 0;
+        }
+
+        let mut y = self.state[self.index];
+        self.index += 1;
+        y ^= y >> 11;
+        y ^= (y << 7) & 0x9d2c5680;
+        y ^= (y << 15) & 0xefc60000;
+        y ^ (y >> 18)
+    }
+
+    /// `random.random()`, a float in [0, 1) with 53 random bits
+    fn next_f64(&mut self) -> f64 {
+        let high = (self.next_u32() >> 5) as f64;
+        let low = (self.next_u32() >> 6) as f64;
+        (high * 67108864.0 + low) * (1.0 / 9007199254740992.0)
+    }
+
+    /// `random.randint(low, high)` for small ranges
+    fn randint(&mut self, low: u32, high: u32) -> u32 {
+        low + (self.next_f64() * (high - low + 1) as f64) as u32
+    }
+}
+
+/// Content gen-disk.py writes to block `offset` of the file at `path`
+fn file_block(path: &str, offset: usize) -> Vec<u8> {
+    let mut random = PythonRandom::seed(python2_str_hash(path.as_bytes()) as i128 + offset as i128);
+    (0..FS_BLOCK_SIZE)
+        .map(|_| DATA_CHARS[random.randint(0, 50) as usize])
+        .collect()
+}
+
+/// Parses the fields of one spec line
+struct LineParser<'a> {
+    line: usize,
+    variables: &'a HashMap<String, i64>,
+}
+
+impl LineParser<'_> {
+    fn error(&self, message: String) -> Error {
+        spec_error(self.line, message)
+    }
+
+    fn number<T: TryFrom<i64>>(&self, field: &str, what: &str) -> std::io::Result<T> {
+        let value = if field.starts_with('$') {
+            *self.variables.get(field).ok_or_else(|| self.error(format!("undefined variable {}", field)))?
+        } else {
+            parse_python_int(field).ok_or_else(|| self.error(format!("invalid {} {:?}", what, field)))?
+        };
+        T::try_from(value).map_err(|_| self.error(format!("{} {} is out of range", what, value)))
+    }
+
+    fn block_list(&self, field: &str) -> std::io::Result<Vec<u32>> {
+        field.split(',')
+            .map(|block| block.parse().map_err(|_| self.error(format!("invalid block number {:?}", block))))
+            .collect()
+    }
+
+    fn dirent(&self, field: &str) -> std::io::Result<SpecDirent> {
+        let (valid, name, inode) = match field.strip_prefix('-') {
+            Some(name) => (false, name, 0),
+            None => {
+                let (name, inode) = field.split_once(',')
+                    .ok_or_else(|| self.error(format!("directory entry {:?} is neither name,inode nor -name", field)))?;
+                let inode = inode.parse().map_err(|_| self.error(format!("invalid inode number {:?}", inode)))?;
+                (true, name, inode)
+            }
+        };
+        if name.len() > FIXED_NAME_MAX {
+            return Err(self.error(format!("name {:?} is longer than {} bytes", name, FIXED_NAME_MAX)));
+        }
+        Ok(SpecDirent { valid, name: name.to_string(), inode })
+    }
+
+    /// The fields of a `file` or `dir` line after the keyword
+    fn inode(&self, fields: &[&str], is_dir: bool) -> std::io::Result<SpecInode> {
+        if fields.len() < 9 || (!is_dir && fields.len() > 9) {
+            return Err(self.error(format!("expected inode path uid gid mode ctime mtime size blocks{}",
+                                          if is_dir { " entries..." } else { "" })));
+        }
+        let mode: u32 = self.number(fields[4], "mode")?;
+        if mode > 0xFFFF {
+            return Err(self.error(format!("mode {:#o} is out of range", mode)));
+        }
+        let blocks = self.block_list(fields[8])?;
+        if blocks.len() > num_direct_pointers(FS_BLOCK_SIZE) {
+            return Err(self.error(format!("{} blocks do not fit in an inode", blocks.len())));
+        }
+        let entries = if is_dir {
+            Some(fields[9..].iter().map(|field| self.dirent(field)).collect::<std::io::Result<Vec<_>>>()?)
+        } else {
+            None
+        };
+
+        Ok(SpecInode {
+            line: self.line,
+            inode: fields[0].parse().map_err(|_| self.error(format!("invalid inode number {:?}", fields[0])))?,
+            path: fields[1].to_string(),
+            uid: self.number(fields[2], "uid")?,
+            gid: self.number(fields[3], "gid")?,
+            mode,
+            ctime: self.number(fields[5], "ctime")?,
+            mtime: self.number(fields[6], "mtime")?,
+            size: self.number(fields[7], "size")?,
+            blocks,
+            entries,
+        })
+    }
+}
+
+impl DiskSpec {
+    pub fn parse(spec: &str) -> std::io::Result<Self> {
+        let mut variables = HashMap::new();
+        let mut num_blocks = None;
+        let mut inodes = vec![];
+
+        for (line_idx, line) in spec.lines().enumerate() {
+            let line_num = line_idx + 1;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() || fields[0].starts_with('#') {
+                continue;
+            }
+
+            let parser = LineParser { line: line_num, variables: &variables };
+            match fields[0] {
+                variable if variable.starts_with('$') => {
+                    if fields.len() != 2 {
+                        return Err(spec_error(line_num, format!("expected {} value", variable)));
+                    }
+                    let value = parse_python_int(fields[1])
+                        .ok_or_else(|| spec_error(line_num, format!("invalid value {:?} for {}", fields[1], variable)))?;
+                    variables.insert(variable.to_string(), value);
+                }
+                "size" => {
+                    let size = match fields.as_slice() {
+                        [_, size] => size.parse::<u32>().ok(),
+                        _ => None,
+                    };
+                    match size {
+                        Some(size) if (3..=MAX_SPEC_BLOCKS).contains(&size) => num_blocks = Some(size),
+                        _ => return Err(spec_error(line_num, format!("expected size followed by 3 to {} blocks", MAX_SPEC_BLOCKS))),
+                    }
+                }
+                "file" => inodes.push(parser.inode(&fields[1..], false)?),
+                "dir" => inodes.push(parser.inode(&fields[1..], true)?),
+                keyword => return Err(spec_error(line_num, format!("unknown keyword {:?}", keyword))),
+            }
+        }
+
+        let spec = DiskSpec {
+            num_blocks: num_blocks.ok_or_else(|| Error::new(ErrorKind::InvalidData, "missing size line"))?,
+            inodes,
+        };
+        spec.check_blocks()?;
+        Ok(spec)
+    }
+
+    /// Every inode and data block has to be inside the image and used only once
+    fn check_blocks(&self) -> std::io::Result<()> {
+        let mut owners: HashMap<u32, &SpecInode> = HashMap::new();
+        for inode in &self.inodes {
+            for block in std::iter::once(inode.inode).chain(inode.blocks.iter().copied()) {
+                if block < 2 || block >= self.num_blocks {
+                    return Err(spec_error(inode.line, format!("block {} is outside the usable blocks 2 to {}", block, self.num_blocks - 1)));
+                }
+                if let Some(owner) = owners.insert(block, inode) {
+                    return Err(spec_error(inode.line, format!("block {} is already used by {} on line {}", block, owner.path, owner.line)));
+                }
+            }
+
+            let entries_per_block = FS_BLOCK_SIZE / FIXED_DIRENT_SIZE;
+            if let Some(entries) = &inode.entries {
+                if entries.len() > inode.blocks.len() * entries_per_block {
+                    return Err(spec_error(inode.line, format!("{} entries do not fit in {} blocks", entries.len(), inode.blocks.len())));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// gen-disk.py reuses one ctypes dirent for a whole block, and assigning a shorter name to it
+    /// only NUL-terminates the name. The tail of a longer name before it stays in the record.
+    fn dir_block(entries: &[SpecDirent], offset: usize) -> Vec<u8> {
+        let entries_per_block = FS_BLOCK_SIZE / FIXED_DIRENT_SIZE;
+        let mut block = vec![0u8; FS_BLOCK_SIZE];
+        let mut name_field = [0u8; FIXED_DIRENT_SIZE - 4];
+        for (entry_idx, entry) in entries.iter().skip(offset * entries_per_block).take(entries_per_block).enumerate() {
+            let dirent = DirectoryEntry { inode_ptr: entry.inode, name: OsString::from(&entry.name), file_type: DIRENT_TYPE_UNKNOWN };
+            let mut dirent_bytes: Vec<u8> = dirent.into();
+            if !entry.valid {
+                dirent_bytes[0] &= !1;
+            }
+            name_field[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+            name_field[entry.name.len()] = 0;
+            dirent_bytes[4..].copy_from_slice(&name_field);
+            block[(entry_idx * FIXED_DIRENT_SIZE)..((entry_idx + 1) * FIXED_DIRENT_SIZE)].copy_from_slice(&dirent_bytes);
+        }
+        block
+    }
+
+    /// The image gen-disk.py would write for this spec
+    pub fn build(&self) -> Vec<u8> {
+        let mut image = vec![0u8; self.num_blocks as usize * FS_BLOCK_SIZE];
+        let mut write_block = |block: u32, data: &[u8]| {
+            let start = block as usize * FS_BLOCK_SIZE;
+            image[start..(start + data.len())].copy_from_slice(data);
+        };
+
+        let super_block = FsSuperBlock { magic: FS_MAGIC_NUM, disk_size: self.num_blocks, ..FsSuperBlock::default() };
+        let super_block_data: Vec<u8> = super_block.into();
+        write_block(0, &super_block_data);
+
+        let mut bitmap = BitMaskBlock::new(self.num_blocks as usize, &vec![0u8; FS_BLOCK_SIZE], FS_BLOCK_SIZE);
+        bitmap.set_bit(0);
+        bitmap.set_bit(1);
+
+        for inode in &self.inodes {
+            let mut node = FSINode {
+                uid: inode.uid,
+                gid: inode.gid,
+                mode: inode.mode,
+                ctime: inode.ctime,
+                mtime: inode.mtime,
+                size: inode.size as u64,
+                ..FSINode::empty(FS_BLOCK_SIZE)
+            };
+            node.pointers[..inode.blocks.len()].copy_from_slice(&inode.blocks);
+            let inode_data: Vec<u8> = node.into();
+            write_block(inode.inode, &inode_data);
+            bitmap.set_bit(inode.inode);
+
+            for (offset, block) in inode.blocks.iter().enumerate() {
+                let data = match &inode.entries {
+                    Some(entries) => Self::dir_block(entries, offset),
+                    None => file_block(&inode.path, offset),
+                };
+                write_block(*block, &data);
+                bitmap.set_bit(*block);
+            }
+        }
+        write_block(1, bitmap.bitmap_block(0));
+
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::crc32;
+
+    const DISK1: &str = include_str!("../../test/disk1.in");
+    const DISK2: &str = include_str!("../../test/disk2.in");
+
+    fn parse_error(spec: &str) -> String {
+        DiskSpec::parse(spec).expect_err("spec should not parse").to_string()
+    }
+
+    #[test]
+    fn python_ints() {
+        assert_eq!(parse_python_int("1565283152"), Some(1565283152));
+        assert_eq!(parse_python_int("0"), Some(0));
+        assert_eq!(parse_python_int("040777"), Some(0o40777));
+        assert_eq!(parse_python_int("0o17"), Some(15));
+        assert_eq!(parse_python_int("0x1F"), Some(31));
+        assert_eq!(parse_python_int("-0b101"), Some(-5));
+        assert_eq!(parse_python_int("+12"), Some(12));
+        assert_eq!(parse_python_int(""), None);
+        assert_eq!(parse_python_int("0x"), None);
+        assert_eq!(parse_python_int("--1"), None);
+        assert_eq!(parse_python_int("0x-1"), None);
+        assert_eq!(parse_python_int("09"), None);
+        assert_eq!(parse_python_int("12a"), None);
+    }
+
+    /// Values from Python 2.7 on a 64 bit build
+    #[test]
+    fn python2_hashes() {
+        assert_eq!(python2_str_hash(b""), 0);
+        assert_eq!(python2_str_hash(b"a"), 12416037344);
+        assert_eq!(python2_str_hash(b"abc"), 1453079729188098211);
+        assert_eq!(python2_str_hash(b"/dir2/file.4k+"), 6701672558351350933);
+    }
+
+    #[test]
+    fn mersenne_twister() {
+        // The reference output of mt19937ar.c for init_by_array({0x123, 0x234, 0x345, 0x456})
+        let mut random = PythonRandom::seed(0x123 + (0x234 << 32) + (0x345 << 64) + (0x456 << 96));
+        assert_eq!([random.next_u32(), random.next_u32(), random.next_u32()], [1067595299, 955945823, 477289528]);
+
+        // random.seed(n) and random.randint(0, 50) in Python 2.7
+        assert_eq!(PythonRandom::seed(0).next_f64(), 0.8444218515250481);
+        let mut random = PythonRandom::seed(-7);
+        assert_eq!((0..5).map(|_| random.randint(0, 50)).collect::<Vec<_>>(), [16, 7, 33, 3, 27]);
+        let mut random = PythonRandom::seed((1 << 100) + 12345);
+        assert_eq!((0..5).map(|_| random.randint(0, 50)).collect::<Vec<_>>(), [22, 15, 35, 0, 7]);
+        let mut random = PythonRandom::seed(python2_str_hash(b"/file.1k") as i128 + 1);
+        assert_eq!((0..5).map(|_| random.randint(0, 50)).collect::<Vec<_>>(), [1, 35, 31, 10, 7]);
+    }
+
+    /// CRC-32 of the images gen-disk.py writes for the specs in test/
+    #[test]
+    fn same_images_as_gen_disk_py() {
+        for (spec, image_crc) in [(DISK1, 0xf331b1a7), (DISK2, 0xf4859ba3)] {
+            let image = DiskSpec::parse(spec).unwrap().build();
+            assert_eq!(image.len(), 400 * FS_BLOCK_SIZE);
+            assert_eq!(crc32(&image), image_crc);
+        }
+    }
+
+    #[test]
+    fn file_contents() {
+        // CRCs listed at the end of disk1.in
+        assert_eq!(crc32(&file_block("/file.10", 0)[..10]), 855202508);
+        assert_eq!(crc32(&file_block("/file.1k", 0)[..1000]), 1786485602);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(parse_error("size 10\nfolder 2 / 0 0 040777 0 0 4096 3\n"), "line 2: unknown keyword \"folder\"");
+        assert_eq!(parse_error("size 10\n\ndir 2 / 0 0 040777 0 0 4096 3 a,4\nfile 4 /a 0 0 0100666 0 0 10 3\n"),
+                   "line 4: block 3 is already used by / on line 3");
+        assert_eq!(parse_error("size 10\ndir 2 / 0 0 040777 0 0 4096 3 twenty-eight-byte-file-name!,4\n"),
+                   "line 2: name \"twenty-eight-byte-file-name!\" is longer than 27 bytes");
+        assert_eq!(parse_error("size 10\nfile 4 /a 0 0 $mode 0 0 10 3\n"), "line 2: undefined variable $mode");
+        assert_eq!(parse_error("size 10\nfile 4 /a 0 0 0100666 0 0 10 12\n"), "line 2: block 12 is outside the usable blocks 2 to 9");
+        assert_eq!(parse_error("$t\n"), "line 1: expected $t value");
+        assert_eq!(parse_error("# nothing\n"), "missing size line");
+    }
+}
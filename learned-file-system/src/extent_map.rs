@@ -0,0 +1,358 @@
+use std::io::{Error, ErrorKind};
+use crate::LearnedFileSystem;
+use crate::structs::extent::{Extent, ExtentIndex, ExtentNode, extents_from_blocks, insert_extent, lookup_extent};
+use crate::structs::fsinode::FSINode;
+use crate::utils::block_file::BlockFile;
+use crate::utils::div_ceil;
+
+/// Read, write and truncate paths for extent-mapped inodes.
+///
+/// Lookups walk the tree from the root in the inode. Writes add their new extents along a
+/// single root-to-leaf path, splitting nodes that overflow. Truncation flattens the tree into
+/// a sorted extent list, edits the list and stores it back, reusing the blocks of the old tree
+/// where possible.
+impl <BF: BlockFile> LearnedFileSystem<BF> {
+    pub(crate) fn read_extent_node(&self, block: u32) -> std::io::Result<ExtentNode> {
+        let node_bytes = self.block_system.block_read(block as usize)?;
+        ExtentNode::try_from(node_bytes.as_slice()).map_err(|_| Error::from(ErrorKind::InvalidData))
+    }
+
+    /// Leaf that maps a logical block if anything does, or the leftmost leaf for blocks before
+    /// the first extent
+    fn extent_leaf(&self, file: &FSINode, logical_block_num: u32) -> std::io::Result<ExtentNode> {
+        let mut node = file.extent_root()?;
+        while node.depth > 0 {
+            if node.children.is_empty() {
+                return Ok(ExtentNode::empty_leaf());
+            }
+            let child_idx = node.children.partition_point(|c| c.logical_start <= logical_block_num).saturating_sub(1);
+            node = self.read_extent_node(node.children[child_idx].child_block)?;
+        }
+        Ok(node)
+    }
+
+    /// Physical block backing a logical block of an extent-mapped file, or 0 if it is a hole
+    pub(crate) fn get_extent_block_pointer(&self, file: &FSINode, logical_block_num: usize) -> std::io::Result<u32> {
+        let logical_block_num = match u32::try_from(logical_block_num) {
+            Ok(num) => num,
+            Err(_) => return Ok(0),
+        };
+        Ok(lookup_extent(&self.extent_leaf(file, logical_block_num)?.extents, logical_block_num).unwrap_or(0))
+    }
+
+    /// Add an extent of newly mapped blocks to the tree of the file. Only the nodes on the path
+    /// to its leaf are rewritten, along with the new halves of nodes that overflow. The blocks
+    /// for those are allocated before anything is written.
+    /// NOTE: Does not write back the inode itself
+    fn insert_extent_in_tree(&mut self, file: &mut FSINode, new_extent: Extent) -> std::io::Result<()> {
+        let root_max_entries = ExtentNode::max_entries(file.block_map_size());
+        let node_max_entries = ExtentNode::max_entries(self.block_size);
+
+        // Interior nodes from the root down, with their block (0 for the root), the index of
+        // the child on the path and whether they changed
+        let mut path: Vec<(u32, ExtentNode, usize, bool)> = vec![];
+        let mut node = file.extent_root()?;
+        let mut block = 0;
+        while node.depth > 0 {
+            if node.children.is_empty() {
+                return Err(Error::from(ErrorKind::InvalidData));
+            }
+            let child_idx = node.children.partition_point(|c| c.logical_start <= new_extent.logical_start).saturating_sub(1);
+            let child_block = node.children[child_idx].child_block;
+            // An extent before all others moves the start of the leftmost subtree
+            let moves_start = new_extent.logical_start < node.children[child_idx].logical_start;
+            if moves_start {
+                node.children[child_idx].logical_start = new_extent.logical_start;
+            }
+            path.push((block, node, child_idx, moves_start));
+            node = self.read_extent_node(child_block)?;
+            block = child_block;
+        }
+        insert_extent(&mut node.extents, new_extent);
+
+        // Every overflowing node but the root splits in two, and an overflowing root moves
+        // into a block of its own
+        let mut num_new_blocks = 0;
+        let mut num_entries = node.num_entries();
+        let mut levels = path.iter().rev();
+        loop {
+            match levels.next() {
+                Some((_, parent, _, _)) if num_entries > node_max_entries => {
+                    num_new_blocks += 1;
+                    num_entries = parent.num_entries() + 1;
+                }
+                Some(_) => break,
+                None => {
+                    if num_entries > root_max_entries {
+                        num_new_blocks += 1;
+                    }
+                    break;
+                }
+            }
+        }
+        let goal = if block != 0 { block } else { new_extent.physical_end() };
+        let mut new_blocks = if num_new_blocks > 0 { self.allocate_blocks_near(num_new_blocks, goal)? } else { vec![] }.into_iter();
+
+        let mut changed = true;
+        while let Some((parent_block, mut parent, child_idx, moves_start)) = path.pop() {
+            let mut parent_changed = moves_start;
+            if node.num_entries() > node_max_entries {
+                let right = node.split_off_half();
+                let right_block = new_blocks.next().ok_or(Error::from(ErrorKind::Other))?;
+                self.block_system.block_write(right.to_bytes(self.block_size), right_block as usize)?;
+                parent.children.insert(child_idx + 1, ExtentIndex { logical_start: right.logical_start(), child_block: right_block });
+                parent_changed = true;
+            }
+            if changed {
+                self.block_system.block_write(node.to_bytes(self.block_size), block as usize)?;
+            }
+            changed = parent_changed;
+            node = parent;
+            block = parent_block;
+        }
+
+        if node.num_entries() > root_max_entries {
+            let child_block = new_blocks.next().ok_or(Error::from(ErrorKind::Other))?;
+            self.block_system.block_write(node.to_bytes(self.block_size), child_block as usize)?;
+            node = ExtentNode { depth: node.depth + 1, extents: vec![], children: vec![ExtentIndex { logical_start: node.logical_start(), child_block }] };
+            changed = true;
+        }
+        if changed {
+            file.set_extent_root(&node);
+        }
+        Ok(())
+    }
+
+    /// All extents of the file in logical order, along with the blocks holding the
+    /// non-root nodes of the tree
+    pub(crate) fn collect_extents(&self, file: &FSINode) -> std::io::Result<(Vec<Extent>, Vec<u32>)> {
+        let mut extents = vec![];
+        let mut tree_blocks = vec![];
+        let mut to_visit = vec![file.extent_root()?];
+
+        while let Some(node) = to_visit.pop() {
+            if node.depth == 0 {
+                extents.extend(node.extents);
+            } else {
+                // Push in reverse so the leftmost child is visited first
+                for child in node.children.iter().rev() {
+                    tree_blocks.push(child.child_block);
+                    to_visit.push(self.read_extent_node(child.child_block)?);
+                }
+            }
+        }
+
+        Ok((extents, tree_blocks))
+    }
+
+    /// Replace the extent tree of the file with one holding `extents`. The blocks of the
+    /// old tree are reused first, then more are allocated or the leftovers freed.
+    pub(crate) fn store_extents(&mut self, file: &mut FSINode, extents: Vec<Extent>, mut old_tree_blocks: Vec<u32>) -> std::io::Result<()> {
+        let root_max_entries = ExtentNode::max_entries(file.block_map_size());
+        let node_max_entries = ExtentNode::max_entries(self.block_size);
+
+        let mut num_tree_blocks = 0;
+        let mut num_entries = extents.len();
+        while num_entries > root_max_entries {
+            num_entries = div_ceil(num_entries, node_max_entries);
+            num_tree_blocks += num_entries;
+        }
+
+        if num_tree_blocks > old_tree_blocks.len() {
+            let goal = old_tree_blocks.last().copied().unwrap_or(0);
+            let new_blocks = self.allocate_blocks_near(num_tree_blocks - old_tree_blocks.len(), goal)?;
+            old_tree_blocks.extend(new_blocks);
+        } else {
+            let unused_blocks = old_tree_blocks.split_off(num_tree_blocks);
+            if !unused_blocks.is_empty() {
+                self.free_blocks(&unused_blocks)?;
+            }
+        }
+        let mut tree_blocks = old_tree_blocks.into_iter();
+
+        if extents.len() <= root_max_entries {
+            file.set_extent_root(&ExtentNode { depth: 0, extents, children: vec![] });
+            return Ok(());
+        }
+
+        let mut children = vec![];
+        for leaf_extents in extents.chunks(node_max_entries) {
+            let block = tree_blocks.next().ok_or(Error::from(ErrorKind::Other))?;
+            let leaf = ExtentNode { depth: 0, extents: leaf_extents.to_vec(), children: vec![] };
+            self.block_system.block_write(leaf.to_bytes(self.block_size), block as usize)?;
+            children.push(ExtentIndex { logical_start: leaf_extents[0].logical_start, child_block: block });
+        }
+
+        let mut depth = 1;
+        while children.len() > root_max_entries {
+            let mut parents = vec![];
+            for node_children in children.chunks(node_max_entries) {
+                let block = tree_blocks.next().ok_or(Error::from(ErrorKind::Other))?;
+                let node = ExtentNode { depth, extents: vec![], children: node_children.to_vec() };
+                self.block_system.block_write(node.to_bytes(self.block_size), block as usize)?;
+                parents.push(ExtentIndex { logical_start: node_children[0].logical_start, child_block: block });
+            }
+            children = parents;
+            depth += 1;
+        }
+
+        file.set_extent_root(&ExtentNode { depth, extents: vec![], children });
+        Ok(())
+    }
+
+    /// NOTE: Does not write back the inode itself
+    pub(crate) fn write_file_data_extents(&mut self, file: &mut FSINode, offset: usize, data: &[u8]) -> std::io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if div_ceil(offset + data.len(), self.block_size) > u32::MAX as usize {
+            return Err(Error::from(ErrorKind::FileTooLarge));
+        }
+
+        let first_block = (offset / self.block_size) as u32;
+        let last_block = ((offset + data.len() - 1) / self.block_size) as u32;
+        let mut physical_blocks = vec![];
+        for logical_block_num in first_block..=last_block {
+            physical_blocks.push(self.get_extent_block_pointer(file, logical_block_num as usize)?);
+        }
+        let unmapped_blocks: Vec<u32> = (first_block..=last_block)
+            .filter(|logical_block_num| physical_blocks[(logical_block_num - first_block) as usize] == 0)
+            .collect();
+
+        if let Some(first_unmapped) = unmapped_blocks.first() {
+            // Try to continue the extent right before the first block we are about to map
+            let leaf = self.extent_leaf(file, *first_unmapped)?;
+            let preceding = leaf.extents.partition_point(|e| e.logical_start <= *first_unmapped);
+            let goal = if preceding > 0 { leaf.extents[preceding - 1].physical_end() } else { 0 };
+
+            let new_blocks = self.allocate_blocks_near(unmapped_blocks.len(), goal)?;
+            let mapping: Vec<(u32, u32)> = unmapped_blocks.iter().copied().zip(new_blocks.iter().copied()).collect();
+            let mut num_inserted = 0;
+            for new_extent in extents_from_blocks(&mapping) {
+                if let Err(e) = self.insert_extent_in_tree(file, new_extent) {
+                    self.free_blocks(&new_blocks[num_inserted..])?;
+                    return Err(e);
+                }
+                num_inserted += new_extent.length as usize;
+            }
+            for (logical_block_num, physical_block) in mapping {
+                physical_blocks[(logical_block_num - first_block) as usize] = physical_block;
+            }
+        }
+
+        let mut total_written = 0;
+        let mut file_ptr = offset;
+        while total_written < data.len() {
+            let logical_block_num = file_ptr / self.block_size;
+            let block_offset = file_ptr % self.block_size;
+            let write_length = (self.block_size - block_offset).min(data.len() - total_written);
+
+            let physical_block = physical_blocks[logical_block_num - first_block as usize];
+            self.write_file_chunk(file, logical_block_num, physical_block as usize, block_offset, &data[total_written..(total_written + write_length)])?;
+
+            total_written += write_length;
+            file_ptr += write_length;
+        }
+
+        file.size = file.size.max(file_ptr as u64);
+
+        Ok(total_written)
+    }
+
+    pub(crate) fn truncate_extents(&mut self, file: &mut FSINode, num_blocks: u32) -> std::io::Result<()> {
+        let (extents, tree_blocks) = self.collect_extents(file)?;
+
+        let mut kept_extents = vec![];
+        let mut blocks_to_dealloc = vec![];
+        for extent in extents {
+            if extent.logical_end() <= num_blocks {
+                kept_extents.push(extent);
+                continue;
+            }
+
+            let num_kept = num_blocks.saturating_sub(extent.logical_start);
+            blocks_to_dealloc.extend((extent.physical_start + num_kept)..extent.physical_end());
+            if num_kept > 0 {
+                kept_extents.push(Extent { length: num_kept, ..extent });
+            }
+        }
+
+        if blocks_to_dealloc.is_empty() {
+            return Ok(());
+        }
+
+        self.store_extents(file, kept_extents, tree_blocks)?;
+        self.free_blocks(&blocks_to_dealloc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use crate::ROOT_INODE;
+    use crate::mkfs::{mkfs, MkfsOptions};
+    use crate::utils::block_file::MemoryBlockFile;
+    use super::*;
+
+    const BLOCK_SIZE: usize = 1024;
+
+    fn file_system() -> LearnedFileSystem<MemoryBlockFile> {
+        let mut options = MkfsOptions { num_blocks: 4096, block_size: BLOCK_SIZE as u32, ..MkfsOptions::default() };
+        options.set_features("extents").unwrap();
+        mkfs(MemoryBlockFile::new(BLOCK_SIZE, vec![0u8; 4096 * BLOCK_SIZE]), &options).unwrap()
+    }
+
+    /// Every other block, so no two extents merge
+    fn write_fragmented(fs: &mut LearnedFileSystem<MemoryBlockFile>, file: &mut FSINode, num_extents: usize) {
+        for idx in 0..num_extents {
+            fs.write_file_data(file, idx * 2 * BLOCK_SIZE, &(idx as u32).to_le_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn tree_grows_by_splitting() {
+        let mut fs = file_system();
+        let (_, mut file) = fs.create_inode(ROOT_INODE, OsStr::new("f"), 0o100644, 0, 0).unwrap();
+        let root_max_entries = ExtentNode::max_entries(file.block_map_size());
+        let num_extents = root_max_entries * ExtentNode::max_entries(BLOCK_SIZE) + 1;
+        write_fragmented(&mut fs, &mut file, num_extents);
+
+        assert_eq!(file.extent_root().unwrap().depth, 2);
+        let (extents, _) = fs.collect_extents(&file).unwrap();
+        assert_eq!(extents.len(), num_extents);
+        assert!(extents.windows(2).all(|pair| pair[0].logical_end() <= pair[1].logical_start));
+        for idx in 0..num_extents {
+            assert_eq!(fs.read_file_bytes(&file, idx * 2 * BLOCK_SIZE, 4).unwrap(), (idx as u32).to_le_bytes());
+            assert_eq!(fs.get_extent_block_pointer(&file, idx * 2 + 1).unwrap(), 0);
+        }
+
+        // An extent before all others goes into the leftmost leaf
+        let mut front = fs.create_inode(ROOT_INODE, OsStr::new("g"), 0o100644, 0, 0).unwrap().1;
+        fs.write_file_data(&mut front, 4 * BLOCK_SIZE, b"late").unwrap();
+        fs.write_file_data(&mut front, 0, b"early").unwrap();
+        assert_eq!(fs.read_file_bytes(&front, 0, 5).unwrap(), b"early");
+        assert_eq!(fs.read_file_bytes(&front, 4 * BLOCK_SIZE, 4).unwrap(), b"late");
+    }
+
+    #[test]
+    fn writes_only_touch_their_path() {
+        let mut fs = file_system();
+        let (_, mut file) = fs.create_inode(ROOT_INODE, OsStr::new("f"), 0o100644, 0, 0).unwrap();
+        let num_extents = ExtentNode::max_entries(file.block_map_size()) * 3;
+        write_fragmented(&mut fs, &mut file, num_extents);
+
+        let (_, tree_blocks) = fs.collect_extents(&file).unwrap();
+        assert_eq!(file.extent_root().unwrap().depth, 1);
+        let read_tree = |fs: &LearnedFileSystem<MemoryBlockFile>| -> Vec<Vec<u8>> {
+            tree_blocks.iter().map(|block| fs.block_system.block_read(*block as usize).unwrap()).collect()
+        };
+        let before = read_tree(&fs);
+        let free_before = fs.block_allocation_bitmask.num_free_indices();
+
+        fs.write_file_data(&mut file, num_extents * 2 * BLOCK_SIZE, b"x").unwrap();
+        let after = read_tree(&fs);
+        let num_changed = before.iter().zip(&after).filter(|(old, new)| old != new).count();
+        assert_eq!(num_changed, 1);
+        assert_eq!(fs.block_allocation_bitmask.num_free_indices(), free_before - 1);
+    }
+}
@@ -0,0 +1,724 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use crate::{FS_VERSION, LearnedFileSystem, ROOT_INODE};
+use crate::structs::dir_model::DirModel;
+use crate::structs::dirent::{DirectoryEntry, DIRENT_TYPE_DIRECTORY, dirent_type_of_mode};
+use crate::structs::extent::{Extent, ExtentNode};
+use crate::structs::fsinode::{FSINode, INODE_FLAG_HTREE, INODE_FLAG_LEARNED_INDEX, pointers_per_block};
+use crate::structs::htree::HTreeNode;
+use crate::structs::superblock::{FsSuperBlock, FEATURE_INCOMPAT_RECOVER, FS_STATE_DIRTY};
+use crate::utils::block_file::BlockFile;
+use crate::utils::div_ceil;
+
+/// Name of the directory orphaned inodes are linked into, under the root
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// Owner recorded for the superblock, bitmaps and inode table
+pub(crate) const METADATA_OWNER: u64 = 0;
+
+/// One inconsistency found by `fsck`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// The last session did not unmount cleanly
+    NotClean,
+    /// The journal holds a transaction that was never written in place, so the rest of the
+    /// report may be off. Replayed by any read-write load, repair included
+    JournalNeedsRecovery,
+    /// Directory entry that cannot stay. Cleared on repair
+    BadDirent { dir: u64, name: OsString, inode: u64, reason: &'static str },
+    /// Block pointer past the end of the disk. Dropped on repair
+    BadBlock { inode: u64, block: u32 },
+    /// Block already used by another inode, or by the file system itself if `owner` is 0.
+    /// Dropped from the later inode on repair
+    DuplicateBlock { inode: u64, block: u32, owner: u64 },
+    /// Blocks mapped past the size of the file. Truncated on repair
+    BlocksPastEnd { inode: u64, size: u64, num_blocks: u32 },
+    /// Unreadable hash index or learned model. The directory goes back to linear lookups on repair
+    BadDirIndex { dir: u64 },
+    /// Inode in use but not reachable from the root. Linked into lost+found on repair
+    Orphan { inode: u64 },
+    /// Allocation bitmap disagrees with the blocks actually in use
+    BlockBitmap { block: u32, marked_used: bool },
+    /// Inode bitmap disagrees with the inodes actually in use
+    InodeBitmap { inode: u64, marked_used: bool },
+    /// Free counts in the superblock are out of date
+    FreeCounts { recorded_blocks: u32, actual_blocks: u32, recorded_inodes: u32, actual_inodes: u32 },
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsckProblem::NotClean => write!(f, "file system was not cleanly unmounted"),
+            FsckProblem::JournalNeedsRecovery => write!(f, "journal holds a transaction that has not been replayed"),
+            FsckProblem::BadDirent { dir, name, inode, reason } =>
+                write!(f, "entry {:?} of directory {} points to inode {}: {}", name, dir, inode, reason),
+            FsckProblem::BadBlock { inode, block } => write!(f, "inode {} points to block {} past the end of the disk", inode, block),
+            FsckProblem::DuplicateBlock { inode, block, owner: METADATA_OWNER } =>
+                write!(f, "inode {} points to block {}, which holds file system metadata", inode, block),
+            FsckProblem::DuplicateBlock { inode, block, owner } =>
+                write!(f, "inode {} points to block {}, which inode {} already uses", inode, block, owner),
+            FsckProblem::BlocksPastEnd { inode, size, num_blocks } =>
+                write!(f, "inode {} has blocks mapped past its size of {} bytes ({} blocks)", inode, size, num_blocks),
+            FsckProblem::BadDirIndex { dir } => write!(f, "directory {} has a damaged index", dir),
+            FsckProblem::Orphan { inode } => write!(f, "inode {} is in use but not linked from any directory", inode),
+            FsckProblem::BlockBitmap { block, marked_used: true } => write!(f, "block {} is marked in use but unused", block),
+            FsckProblem::BlockBitmap { block, marked_used: false } => write!(f, "block {} is in use but marked free", block),
+            FsckProblem::InodeBitmap { inode, marked_used: true } => write!(f, "inode {} is marked in use but unused", inode),
+            FsckProblem::InodeBitmap { inode, marked_used: false } => write!(f, "inode {} is in use but marked free", inode),
+            FsckProblem::FreeCounts { recorded_blocks, actual_blocks, recorded_inodes, actual_inodes } =>
+                write!(f, "superblock records {} free blocks and {} free inodes instead of {} and {}",
+                       recorded_blocks, recorded_inodes, actual_blocks, actual_inodes),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FsckReport {
+    pub problems: Vec<FsckProblem>,
+    pub num_files: usize,
+    pub num_dirs: usize,
+    /// Blocks in use, including the file system metadata
+    pub num_blocks_used: usize,
+}
+
+/// What a pass over the image found. Blocks are claimed by the first inode that reaches them.
+#[derive(Default)]
+struct FsckState {
+    disk_size: u32,
+    problems: Vec<FsckProblem>,
+    /// Inode using every block in use, METADATA_OWNER for the file system's own blocks
+    block_owners: HashMap<u32, u64>,
+    /// Blocks each inode points to that belong to someone else
+    rejected_blocks: BTreeMap<u64, BTreeSet<u32>>,
+    /// Inodes reachable from the root, and those that get linked into lost+found
+    linked: BTreeSet<u64>,
+    /// Inodes whose mapping needs repair, and the size their mapping should be cut to
+    inodes_to_fix: BTreeMap<u64, Option<u32>>,
+    /// (directory, byte offset of the entry) of entries to clear
+    dirents_to_clear: Vec<(u64, usize)>,
+    indexes_to_clear: Vec<u64>,
+    orphans: Vec<u64>,
+    lost_and_found: Option<u64>,
+    num_files: usize,
+    num_dirs: usize,
+}
+
+impl FsckState {
+    fn claim(&mut self, inode: u64, block: u32) -> bool {
+        if block >= self.disk_size {
+            self.problems.push(FsckProblem::BadBlock { inode, block });
+            self.inodes_to_fix.entry(inode).or_insert(None);
+            return false;
+        }
+        if let Some(owner) = self.block_owners.get(&block) {
+            self.problems.push(FsckProblem::DuplicateBlock { inode, block, owner: *owner });
+            self.rejected_blocks.entry(inode).or_default().insert(block);
+            self.inodes_to_fix.entry(inode).or_insert(None);
+            return false;
+        }
+        self.block_owners.insert(block, inode);
+        true
+    }
+
+    /// Forget a claimed block that turned out to be unusable
+    fn unclaim(&mut self, inode: u64, block: u32) {
+        self.block_owners.remove(&block);
+        self.rejected_blocks.entry(inode).or_default().insert(block);
+        self.inodes_to_fix.entry(inode).or_insert(None);
+    }
+
+    fn is_usable_block(&self, inode: u64, block: u32) -> bool {
+        block != 0 && block < self.disk_size && !self.rejected_blocks.get(&inode).is_some_and(|rejected| rejected.contains(&block))
+    }
+}
+
+fn claim_in(state: &mut Option<&mut FsckState>, inode: u64, disk_size: u32, block: u32) -> bool {
+    match state.as_deref_mut() {
+        Some(state) => state.claim(inode, block),
+        None => block < disk_size,
+    }
+}
+
+fn is_dir(node: &FSINode) -> bool {
+    dirent_type_of_mode(node.mode) == DIRENT_TYPE_DIRECTORY
+}
+
+fn has_known_type(node: &FSINode) -> bool {
+    matches!(node.mode & 0o170000, 0o040000 | 0o100000)
+}
+
+/// Consistency check and repair.
+///
+/// The check walks the tree from the root directory, claiming every block an inode points
+/// to for that inode, then goes over the inodes that are in use but were not reached. The
+/// bitmaps are compared with what was claimed. Repair rebuilds the bitmaps from the claims
+/// first, so that fixing inodes and filling lost+found can allocate and free blocks safely.
+impl <BF: BlockFile> LearnedFileSystem<BF> {
+    /// Data blocks of the inode as (logical block, physical block) pairs. With a state every
+    /// block the inode points to, including indirect blocks and extent tree nodes, is claimed
+    /// for the inode and only followed if that succeeds. Without one, blocks are followed as
+    /// long as they are on the disk.
+    fn fsck_inode_blocks(&self, inode: u64, node: &FSINode, disk_size: u32, mut state: Option<&mut FsckState>) -> Vec<(u32, u32)> {
+        let mut data = vec![];
+
+        if node.is_extent_mapped() {
+            let mut to_visit = match node.extent_root() {
+                Ok(root) => vec![root],
+                Err(_) => return data,
+            };
+            while let Some(tree_node) = to_visit.pop() {
+                for extent in &tree_node.extents {
+                    for block_idx in 0..extent.length.min(disk_size) {
+                        let physical = extent.physical_start.saturating_add(block_idx);
+                        if claim_in(&mut state, inode, disk_size, physical) {
+                            data.push((extent.logical_start + block_idx, physical));
+                        } else if physical >= disk_size {
+                            break;
+                        }
+                    }
+                }
+                for child in tree_node.children.iter().rev() {
+                    if !claim_in(&mut state, inode, disk_size, child.child_block) {
+                        continue;
+                    }
+                    match self.read_extent_node(child.child_block) {
+                        Ok(child_node) => to_visit.push(child_node),
+                        Err(_) => if let Some(state) = state.as_deref_mut() {
+                            state.unclaim(inode, child.child_block);
+                        },
+                    }
+                }
+            }
+            return data;
+        }
+
+        let num_direct = node.pointers.len() as u32;
+        let pointers_per_block = pointers_per_block(self.block_size) as u32;
+        for (idx, block) in node.pointers.iter().enumerate() {
+            if *block != 0 && claim_in(&mut state, inode, disk_size, *block) {
+                data.push((idx as u32, *block));
+            }
+        }
+        if node.single_indirect != 0 && claim_in(&mut state, inode, disk_size, node.single_indirect) {
+            for (idx, block) in self.read_indirect_block(node.single_indirect).unwrap_or_default().into_iter().enumerate() {
+                if block != 0 && claim_in(&mut state, inode, disk_size, block) {
+                    data.push((num_direct + idx as u32, block));
+                }
+            }
+        }
+        if node.double_indirect != 0 && claim_in(&mut state, inode, disk_size, node.double_indirect) {
+            for (outer_idx, indirect_block) in self.read_indirect_block(node.double_indirect).unwrap_or_default().into_iter().enumerate() {
+                if indirect_block == 0 || !claim_in(&mut state, inode, disk_size, indirect_block) {
+                    continue;
+                }
+                for (inner_idx, block) in self.read_indirect_block(indirect_block).unwrap_or_default().into_iter().enumerate() {
+                    if block != 0 && claim_in(&mut state, inode, disk_size, block) {
+                        data.push((num_direct + pointers_per_block * (1 + outer_idx as u32) + inner_idx as u32, block));
+                    }
+                }
+            }
+        }
+        data
+    }
+
+    /// Data blocks of the inode that lie on the disk, as (logical block, physical block) pairs.
+    /// Unlike the regular lookups this copes with damaged block maps.
+    pub(crate) fn mapped_blocks(&self, inode: u64, node: &FSINode) -> Vec<(u32, u32)> {
+        self.fsck_inode_blocks(inode, node, self.block_allocation_bitmask.num_indices() as u32, None)
+    }
+
+    /// Contents of a directory read through the given (logical block, physical block) pairs
+    pub(crate) fn dir_contents_from_blocks(&self, dir: &FSINode, data: &[(u32, u32)]) -> Vec<u8> {
+        let mut contents = vec![0u8; dir.size as usize];
+        for (logical, physical) in data {
+            let start = *logical as usize * self.block_size;
+            if start >= contents.len() {
+                continue;
+            }
+            let end = (start + self.block_size).min(contents.len());
+            if let Ok(block) = self.block_system.block_read(*physical as usize) {
+                contents[start..end].copy_from_slice(&block[..(end - start)]);
+            }
+        }
+        contents
+    }
+
+    fn fsck_dir_index_ok(&self, dir: &FSINode, contents: &[u8]) -> bool {
+        let num_blocks = div_ceil(contents.len(), self.block_size);
+        if num_blocks == 0 {
+            return false;
+        }
+        let read_node = |block: u32| -> Option<HTreeNode> {
+            let start = block as usize * self.block_size;
+            if block as usize >= num_blocks { None } else { HTreeNode::try_from(&contents[start..(start + self.block_size)]).ok() }
+        };
+
+        if dir.has_htree_index() {
+            let root = match read_node(0) {
+                Some(root) => root,
+                None => return false,
+            };
+            return root.entries.iter().all(|entry| match root.depth {
+                0 => (entry.block as usize) < num_blocks,
+                _ => read_node(entry.block).is_some_and(|node| node.entries.iter().all(|leaf| (leaf.block as usize) < num_blocks)),
+            });
+        }
+        if dir.has_learned_index() {
+            return DirModel::try_from(&contents[..self.block_size]).is_ok_and(|model| (model.num_data_blocks as usize) < num_blocks);
+        }
+        true
+    }
+
+    /// Inode numbers are in range and marked in use
+    fn fsck_inode_reason(&self, inode: u64, disk_size: u32) -> Option<&'static str> {
+        match self.inode_allocation_bitmask.as_ref() {
+            Some(inode_bitmask) if inode < 2 || inode >= inode_bitmask.num_indices() as u64 => Some("inode number out of range"),
+            Some(inode_bitmask) if inode_bitmask.is_free(inode as u32) => Some("inode is not in use"),
+            None if inode < 2 || inode >= disk_size as u64 => Some("inode number out of range"),
+            None if self.block_allocation_bitmask.is_free(inode as u32) => Some("inode is not in use"),
+            _ => None,
+        }
+    }
+
+    /// Claim the blocks of a reachable inode, and walk it if it is a directory
+    fn fsck_walk(&self, state: &mut FsckState, inode: u64, node: &FSINode) {
+        let mut to_visit = vec![(inode, node.clone())];
+        while let Some((inode, node)) = to_visit.pop() {
+            state.linked.insert(inode);
+            let data = self.fsck_inode_blocks(inode, &node, state.disk_size, Some(state));
+
+            let num_blocks = data.iter().map(|(logical, _)| logical + 1).max().unwrap_or(0);
+            let size_blocks = div_ceil(node.size, self.block_size as u64);
+            if num_blocks as u64 > size_blocks {
+                state.problems.push(FsckProblem::BlocksPastEnd { inode, size: node.size, num_blocks });
+                state.inodes_to_fix.insert(inode, Some(size_blocks as u32));
+            }
+
+            if !is_dir(&node) {
+                state.num_files += 1;
+                continue;
+            }
+            state.num_dirs += 1;
+
+            let contents = self.dir_contents_from_blocks(&node, &data);
+            if (node.has_htree_index() || node.has_learned_index()) && !self.fsck_dir_index_ok(&node, &contents) {
+                state.problems.push(FsckProblem::BadDirIndex { dir: inode });
+                state.indexes_to_clear.push(inode);
+            }
+
+            for slot in self.parse_dirents(&node, &contents) {
+                let entry = match slot.entry {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                let target = entry.inode_ptr as u64;
+                let mut reason = self.fsck_inode_reason(target, state.disk_size);
+                if reason.is_none() && state.linked.contains(&target) {
+                    reason = Some(if target == ROOT_INODE { "link to the root directory" } else { "inode is already linked" });
+                }
+                let target_node = match reason {
+                    None => match self.get_inode(target) {
+                        Ok(target_node) if has_known_type(&target_node) => Some(target_node),
+                        _ => { reason = Some("inode is not a file or directory"); None }
+                    },
+                    Some(_) => None,
+                };
+                if reason.is_none() && self.inode_allocation_bitmask.is_none() && !state.claim(target, target as u32) {
+                    reason = Some("inode block is already used");
+                }
+
+                match (reason, target_node) {
+                    (None, Some(target_node)) => {
+                        if inode == ROOT_INODE && entry.name == OsStr::new(LOST_AND_FOUND) && is_dir(&target_node) {
+                            state.lost_and_found = Some(target);
+                        }
+                        state.linked.insert(target);
+                        to_visit.push((target, target_node));
+                    }
+                    (reason, _) => {
+                        state.problems.push(FsckProblem::BadDirent {
+                            dir: inode, name: entry.name, inode: target, reason: reason.unwrap_or_default(),
+                        });
+                        state.dirents_to_clear.push((inode, slot.offset));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Inodes marked in use but not reached from the root, whose type is known. Only images
+    /// with an inode table can tell unreached inodes apart from unused blocks.
+    fn fsck_find_orphans(&self, state: &mut FsckState) {
+        let inode_bitmask = match self.inode_allocation_bitmask.as_ref() {
+            Some(inode_bitmask) => inode_bitmask,
+            None => return,
+        };
+        let unreached: Vec<(u64, FSINode)> = (ROOT_INODE + 1..inode_bitmask.num_indices() as u64)
+            .filter(|inode| !inode_bitmask.is_free(*inode as u32) && !state.linked.contains(inode))
+            .filter_map(|inode| self.get_inode(inode).ok().filter(has_known_type).map(|node| (inode, node)))
+            .collect();
+
+        // Link the tops of orphaned subtrees, so their children keep their place
+        let mut referenced = BTreeSet::new();
+        for (inode, node) in unreached.iter().filter(|(_, node)| is_dir(node)) {
+            let data = self.fsck_inode_blocks(*inode, node, state.disk_size, None);
+            let contents = self.dir_contents_from_blocks(node, &data);
+            referenced.extend(self.parse_dirents(node, &contents).into_iter().filter_map(|slot| slot.entry.ok()).map(|e| e.inode_ptr as u64));
+        }
+        let (tops, rest): (Vec<_>, Vec<_>) = unreached.into_iter().partition(|(inode, _)| !referenced.contains(inode));
+        for (inode, node) in tops.into_iter().chain(rest) {
+            if state.linked.contains(&inode) {
+                continue;
+            }
+            state.problems.push(FsckProblem::Orphan { inode });
+            state.orphans.push(inode);
+            self.fsck_walk(state, inode, &node);
+        }
+    }
+
+    /// Drop the block pointers `fsck` rejected for a pointer-mapped inode, leaving holes
+    fn fsck_drop_pointers(&mut self, inode: u64, node: &mut FSINode, state: &FsckState) -> std::io::Result<()> {
+        let is_bad = |block: u32| block != 0 && !state.is_usable_block(inode, block);
+        let fix_indirect = |file_system: &mut Self, block: u32| -> std::io::Result<()> {
+            let mut pointers = file_system.read_indirect_block(block)?;
+            if pointers.iter().any(|ptr| is_bad(*ptr)) {
+                pointers.iter_mut().filter(|ptr| is_bad(**ptr)).for_each(|ptr| *ptr = 0);
+                file_system.write_indirect_block(block, &pointers)?;
+            }
+            Ok(())
+        };
+
+        node.pointers.iter_mut().filter(|ptr| is_bad(**ptr)).for_each(|ptr| *ptr = 0);
+        if is_bad(node.single_indirect) {
+            node.single_indirect = 0;
+        } else if node.single_indirect != 0 {
+            fix_indirect(self, node.single_indirect)?;
+        }
+        if is_bad(node.double_indirect) {
+            node.double_indirect = 0;
+        } else if node.double_indirect != 0 {
+            fix_indirect(self, node.double_indirect)?;
+            for indirect_block in self.read_indirect_block(node.double_indirect)? {
+                if indirect_block != 0 {
+                    fix_indirect(self, indirect_block)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rebuild the extent tree of an inode without the blocks `fsck` rejected
+    fn fsck_drop_extents(&mut self, inode: u64, node: &mut FSINode, state: &FsckState) -> std::io::Result<()> {
+        let mut extents = vec![];
+        let mut tree_blocks = vec![];
+        let mut to_visit = vec![node.extent_root().unwrap_or_else(|_| ExtentNode::empty_leaf())];
+        while let Some(tree_node) = to_visit.pop() {
+            for extent in &tree_node.extents {
+                // Split the extent into runs of usable blocks
+                let mut run: Option<Extent> = None;
+                for block_idx in 0..extent.length.min(state.disk_size) {
+                    let physical = extent.physical_start.saturating_add(block_idx);
+                    if state.is_usable_block(inode, physical) {
+                        match run.as_mut() {
+                            Some(run) => run.length += 1,
+                            None => run = Some(Extent { logical_start: extent.logical_start + block_idx, physical_start: physical, length: 1 }),
+                        }
+                    } else if let Some(run) = run.take() {
+                        extents.push(run);
+                    }
+                }
+                extents.extend(run);
+            }
+            for child in tree_node.children.iter().rev() {
+                if !state.is_usable_block(inode, child.child_block) {
+                    continue;
+                }
+                if let Ok(child_node) = self.read_extent_node(child.child_block) {
+                    tree_blocks.push(child.child_block);
+                    to_visit.push(child_node);
+                }
+            }
+        }
+        self.store_extents(node, extents, tree_blocks)
+    }
+
+    fn fsck_repair(&mut self, state: &FsckState) -> std::io::Result<()> {
+        for block in 0..state.disk_size {
+            match (state.block_owners.contains_key(&block), self.block_allocation_bitmask.is_free(block)) {
+                (true, true) => self.block_allocation_bitmask.set_bit(block),
+                (false, false) => self.block_allocation_bitmask.clear_bit(block),
+                _ => {}
+            }
+        }
+        if let Some(inode_bitmask) = self.inode_allocation_bitmask.as_mut() {
+            for inode in 0..inode_bitmask.num_indices() as u32 {
+                let in_use = inode < ROOT_INODE as u32 || state.linked.contains(&(inode as u64));
+                match (in_use, inode_bitmask.is_free(inode)) {
+                    (true, true) => inode_bitmask.set_bit(inode),
+                    (false, false) => inode_bitmask.clear_bit(inode),
+                    _ => {}
+                }
+            }
+        }
+        self.write_dirty_bitmask_blocks()?;
+
+        for (inode, keep_blocks) in &state.inodes_to_fix {
+            let mut node = self.get_inode(*inode)?;
+            if node.is_extent_mapped() {
+                self.fsck_drop_extents(*inode, &mut node, state)?;
+            } else {
+                self.fsck_drop_pointers(*inode, &mut node, state)?;
+            }
+            if let Some(keep_blocks) = keep_blocks {
+                self.truncate_to_num_blocks(&mut node, *keep_blocks)?;
+            }
+            self.write_inode(*inode, node)?;
+        }
+
+        for dir in &state.indexes_to_clear {
+            let mut node = self.get_inode(*dir)?;
+            node.flags &= !(INODE_FLAG_HTREE | INODE_FLAG_LEARNED_INDEX);
+            self.write_inode(*dir, node)?;
+        }
+
+        for (dir, offset) in &state.dirents_to_clear {
+            let mut node = self.get_inode(*dir)?;
+            // Look the slots up again, clearing an entry can merge it into the one before
+            let slots = self.get_dirents_incl_gaps(&node)?;
+            if let Some(idx) = slots.iter().position(|slot| slot.offset == *offset) {
+                self.remove_dirent(&mut node, &slots, idx)?;
+                self.write_inode(*dir, node)?;
+            }
+        }
+
+        if !state.orphans.is_empty() {
+            self.fsck_link_orphans(state)?;
+        }
+        Ok(())
+    }
+
+    fn fsck_link_orphans(&mut self, state: &FsckState) -> std::io::Result<()> {
+        let (lost_and_found, mut lost_and_found_node) = match state.lost_and_found {
+            Some(inode) => (inode, self.get_inode(inode)?),
+            None => {
+                let inode = self.allocate_inode()?;
+                let node = self.new_inode(0o40700, 0, 0);
+                self.write_inode(inode, node.clone())?;
+
+                let mut root = self.get_inode(ROOT_INODE)?;
+                let (root_slots, _) = self.find_dirent(&root, OsStr::new(LOST_AND_FOUND))?;
+                let dirent = DirectoryEntry { inode_ptr: inode as u32, name: OsString::from(LOST_AND_FOUND), file_type: DIRENT_TYPE_DIRECTORY };
+                self.insert_dirent(&mut root, &root_slots, dirent)?;
+                self.write_inode(ROOT_INODE, root)?;
+                (inode, node)
+            }
+        };
+
+        for orphan in &state.orphans {
+            let name = OsString::from(format!("#{}", orphan));
+            let (slots, existing) = self.find_dirent(&lost_and_found_node, &name)?;
+            if existing.is_some() {
+                continue;
+            }
+            let file_type = dirent_type_of_mode(self.get_inode(*orphan)?.mode);
+            self.insert_dirent(&mut lost_and_found_node, &slots, DirectoryEntry { inode_ptr: *orphan as u32, name, file_type })?;
+        }
+        self.write_inode(lost_and_found, lost_and_found_node)
+    }
+
+    /// Load the image and walk it, without comparing the result with the bitmaps yet
+    fn fsck_scan(&mut self) -> std::io::Result<(FsSuperBlock, FsckState)> {
+        let super_block = self.load()?;
+        let mut state = FsckState { disk_size: super_block.disk_size, ..FsckState::default() };
+        if super_block.state & FS_STATE_DIRTY != 0 {
+            state.problems.push(FsckProblem::NotClean);
+        }
+        // Only possible when read-only, since loading replays the journal otherwise
+        if super_block.incompat_features & FEATURE_INCOMPAT_RECOVER != 0 && self.block_system.committed_transaction()?.is_some() {
+            state.problems.push(FsckProblem::JournalNeedsRecovery);
+        }
+
+        let (bit_mask_start, bit_mask_blocks) = super_block.bitmap_location();
+        let metadata_blocks = std::iter::once(0)
+            .chain(bit_mask_start..(bit_mask_start + bit_mask_blocks))
+            .chain(super_block.inode_bitmap_start..(super_block.inode_bitmap_start + super_block.inode_bitmap_blocks))
+            .chain(super_block.inode_table_start..(super_block.inode_table_start + super_block.inode_table_blocks))
+            .chain(if super_block.has_journal() { super_block.journal_start..(super_block.journal_start + super_block.journal_blocks) } else { 0..0 })
+            .chain(if super_block.is_cow() { super_block.cow_start..(super_block.cow_start + super_block.cow_blocks) } else { 0..0 });
+        for block in metadata_blocks {
+            state.block_owners.insert(block, METADATA_OWNER);
+        }
+
+        let root = self.get_inode(ROOT_INODE)?;
+        if !is_dir(&root) || self.fsck_inode_reason(ROOT_INODE, state.disk_size).is_some() {
+            return Err(Error::new(ErrorKind::InvalidData, "root directory is damaged"));
+        }
+        if self.inode_allocation_bitmask.is_none() {
+            state.claim(ROOT_INODE, ROOT_INODE as u32);
+        }
+        self.fsck_walk(&mut state, ROOT_INODE, &root);
+        self.fsck_find_orphans(&mut state);
+        Ok((super_block, state))
+    }
+
+    /// Inode using each block that is in use, reached either from the root or from an
+    /// orphaned inode. Blocks holding file system metadata belong to inode 0.
+    pub(crate) fn block_owners(&mut self) -> std::io::Result<HashMap<u32, u64>> {
+        Ok(self.fsck_scan()?.1.block_owners)
+    }
+
+    /// Check the file system and report every inconsistency found. With `repair`, fix them
+    /// as well: the bitmaps are rebuilt, bad block pointers and directory entries are
+    /// dropped, and orphaned inodes are linked into /lost+found.
+    pub fn fsck(&mut self, repair: bool) -> std::io::Result<FsckReport> {
+        let (super_block, mut state) = self.fsck_scan()?;
+        if repair && self.read_only {
+            return Err(Error::new(ErrorKind::ReadOnlyFilesystem, "the file system is read-only"));
+        }
+
+        for block in 0..state.disk_size {
+            let in_use = state.block_owners.contains_key(&block);
+            if in_use == self.block_allocation_bitmask.is_free(block) {
+                state.problems.push(FsckProblem::BlockBitmap { block, marked_used: !in_use });
+            }
+        }
+        let free_blocks = state.disk_size as usize - state.block_owners.len();
+        // Without an inode table every free block can hold an inode
+        let free_inodes = match self.inode_allocation_bitmask.as_ref() {
+            Some(inode_bitmask) => {
+                for inode in (ROOT_INODE as u32)..(inode_bitmask.num_indices() as u32) {
+                    let in_use = state.linked.contains(&(inode as u64));
+                    if in_use == inode_bitmask.is_free(inode) {
+                        state.problems.push(FsckProblem::InodeBitmap { inode: inode as u64, marked_used: !in_use });
+                    }
+                }
+                inode_bitmask.num_indices() - ROOT_INODE as usize - state.linked.len()
+            }
+            None => free_blocks,
+        };
+        // Older images don't keep free counts
+        if super_block.version >= FS_VERSION && (super_block.free_blocks_count as usize != free_blocks || super_block.free_inodes_count as usize != free_inodes) {
+            state.problems.push(FsckProblem::FreeCounts {
+                recorded_blocks: super_block.free_blocks_count, actual_blocks: free_blocks as u32,
+                recorded_inodes: super_block.free_inodes_count, actual_inodes: free_inodes as u32,
+            });
+        }
+
+        if repair && !state.problems.is_empty() {
+            self.fsck_repair(&state)?;
+            if super_block.version >= FS_VERSION {
+                let mut super_block = super_block;
+                super_block.state &= !FS_STATE_DIRTY;
+                self.write_superblock(super_block)?;
+            }
+        }
+
+        Ok(FsckReport {
+            num_files: state.num_files,
+            num_dirs: state.num_dirs,
+            num_blocks_used: state.block_owners.len(),
+            problems: state.problems,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mkfs::{mkfs, MkfsOptions};
+    use crate::utils::block_file::MemoryBlockFile;
+    use super::*;
+
+    /// Mounted image holding the files /a and /b and the directory /d. The files map their
+    /// blocks with pointers, which are easy to corrupt
+    fn test_file_system() -> LearnedFileSystem<MemoryBlockFile> {
+        let mut options = MkfsOptions { num_blocks: 512, block_size: 1024, inode_count: Some(64), ..MkfsOptions::default() };
+        options.set_features("var_dirents").unwrap();
+        let mut file_system = mkfs(MemoryBlockFile::new(1024, vec![0u8; 512 * 1024]), &options).unwrap();
+        file_system.mount().unwrap();
+        for name in ["a", "b"] {
+            let (inode, mut node) = file_system.create_inode(ROOT_INODE, OsStr::new(name), 0o100644, 0, 0).unwrap();
+            file_system.write_file_data(&mut node, 0, &[name.as_bytes()[0]; 3000]).unwrap();
+            file_system.write_inode(inode, node).unwrap();
+        }
+        file_system.create_inode(ROOT_INODE, OsStr::new("d"), 0o40755, 0, 0).unwrap();
+        file_system
+    }
+
+    /// Unmount, repair expecting `problem` to be among those found, and check again
+    fn check_repaired(file_system: &mut LearnedFileSystem<MemoryBlockFile>, problem: impl Fn(&FsckProblem) -> bool) {
+        file_system.unmount().unwrap();
+        let report = file_system.fsck(true).unwrap();
+        assert!(report.problems.iter().any(problem), "{:?}", report.problems);
+        let report = file_system.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn clean_image() {
+        let mut file_system = test_file_system();
+        file_system.unmount().unwrap();
+        let report = file_system.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+        assert_eq!((report.num_files, report.num_dirs), (2, 2));
+    }
+
+    #[test]
+    fn block_marked_free() {
+        let mut file_system = test_file_system();
+        let node = file_system.get_inode(file_system.lookup_path("/a").unwrap()).unwrap();
+        let block = node.pointers[0];
+        file_system.block_allocation_bitmask.clear_bit(block);
+        file_system.write_dirty_bitmask_blocks().unwrap();
+
+        check_repaired(&mut file_system, |problem| *problem == FsckProblem::BlockBitmap { block, marked_used: false });
+        assert!(!file_system.block_allocation_bitmask.is_free(block));
+    }
+
+    #[test]
+    fn second_link() {
+        let mut file_system = test_file_system();
+        let file = file_system.lookup_path("/a").unwrap();
+        let dir = file_system.lookup_path("/d").unwrap();
+        let mut dir_node = file_system.get_inode(dir).unwrap();
+        let (slots, _) = file_system.find_dirent(&dir_node, OsStr::new("link")).unwrap();
+        let dirent = DirectoryEntry { inode_ptr: file as u32, name: OsString::from("link"), file_type: dirent_type_of_mode(0o100644) };
+        file_system.insert_dirent(&mut dir_node, &slots, dirent).unwrap();
+        file_system.write_inode(dir, dir_node).unwrap();
+
+        check_repaired(&mut file_system, |problem| matches!(problem,
+            FsckProblem::BadDirent { dir: d, inode, reason: "inode is already linked", .. } if *d == dir && *inode == file));
+        assert!(file_system.lookup_path("/d/link").is_err());
+        assert_eq!(file_system.lookup_path("/a").unwrap(), file);
+    }
+
+    #[test]
+    fn orphaned_inode() {
+        let mut file_system = test_file_system();
+        let file = file_system.lookup_path("/b").unwrap();
+        let mut root = file_system.get_inode(ROOT_INODE).unwrap();
+        let (slots, existing) = file_system.find_dirent(&root, OsStr::new("b")).unwrap();
+        file_system.remove_dirent(&mut root, &slots, existing.unwrap().0).unwrap();
+        file_system.write_inode(ROOT_INODE, root).unwrap();
+
+        check_repaired(&mut file_system, |problem| *problem == FsckProblem::Orphan { inode: file });
+        assert_eq!(file_system.lookup_path(&format!("/{}/#{}", LOST_AND_FOUND, file)).unwrap(), file);
+        let node = file_system.get_inode(file).unwrap();
+        assert_eq!(file_system.read_file_bytes(&node, 0, 3000).unwrap(), vec![b'b'; 3000]);
+    }
+
+    #[test]
+    fn block_used_twice() {
+        let mut file_system = test_file_system();
+        let block = file_system.get_inode(file_system.lookup_path("/a").unwrap()).unwrap().pointers[0];
+        let file = file_system.lookup_path("/b").unwrap();
+        let mut node = file_system.get_inode(file).unwrap();
+        node.pointers[0] = block;
+        file_system.write_inode(file, node).unwrap();
+
+        check_repaired(&mut file_system, |problem| matches!(problem, FsckProblem::DuplicateBlock { block: b, .. } if *b == block));
+        let owners = file_system.block_owners().unwrap();
+        assert!(owners.contains_key(&block));
+    }
+}
@@ -0,0 +1,322 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Error, ErrorKind};
+use crate::cow::Cow;
+use crate::utils::block_file::BlockFile;
+use crate::utils::cache_policy::AccessHint;
+use crate::utils::cached_block_file::PolicyStats;
+use crate::utils::{crc32, read_u32};
+
+/// "LFSJ"
+const JOURNAL_MAGIC: u32 = 0x4c46534a;
+const BLOCK_TYPE_DESCRIPTOR: u32 = 1;
+const BLOCK_TYPE_COMMIT: u32 = 2;
+/// Magic, block type, sequence number and number of blocks, ahead of the block numbers in a
+/// descriptor block
+const DESCRIPTOR_HEADER_SIZE: usize = 16;
+/// Journals smaller than this cannot hold a useful transaction
+pub const MIN_JOURNAL_BLOCKS: u32 = 16;
+
+/// Write-ahead journal for metadata, sitting between the file system and its device.
+///
+/// While a transaction is open, block writes are held back and reads see them. Committing
+/// writes the transaction to the journal region as a descriptor block listing the home
+/// block numbers, a copy of each block and a commit block with a checksum over both. Once
+/// that is on disk the blocks are written to their home locations. The journal only ever
+/// holds the latest transaction, starting at its first block, so a crash leaves at most
+/// that one to replay.
+///
+/// Regular file data is written straight to the device with `write_data`, ahead of the
+/// commit of the metadata pointing at it.
+///
+/// Images that update metadata copy-on-write instead have no journal region, and
+/// transactions are passed on to the copy-on-write layer below.
+pub(crate) struct Journal<BF: BlockFile> {
+    pub(crate) device: Cow<BF>,
+    /// (first block, number of blocks) of the journal region, None if the image has none
+    region: Option<(u32, u32)>,
+    /// Sequence number of the next transaction
+    sequence: u32,
+    in_transaction: bool,
+    /// Blocks written during the open transaction, by block number
+    pending: BTreeMap<usize, Vec<u8>>,
+    /// Blocks read or written since `record_touched`, while recording
+    touched: RefCell<Option<BTreeSet<usize>>>,
+}
+
+/// A committed transaction as (sequence number, blocks by home location)
+type Transaction = (u32, Vec<(usize, Vec<u8>)>);
+
+impl <BF: BlockFile> Journal<BF> {
+    pub fn new(device: Cow<BF>) -> Self {
+        Journal { device, region: None, sequence: 0, in_transaction: false, pending: BTreeMap::new(), touched: RefCell::new(None) }
+    }
+
+    /// Use the blocks from `start` on as the journal, or write everything in place for None
+    pub fn set_region(&mut self, region: Option<(u32, u32)>) {
+        self.region = region;
+        if let Some(transaction_sequence) = self.committed_transaction().ok().flatten().map(|(sequence, _)| sequence) {
+            self.sequence = transaction_sequence.wrapping_add(1);
+        }
+    }
+
+    /// Most blocks a single transaction can hold
+    fn capacity(&self, journal_blocks: u32) -> usize {
+        (journal_blocks as usize - 2).min((self.device.block_size() - DESCRIPTOR_HEADER_SIZE) / 4)
+    }
+
+    /// Hold back block writes until `commit`. Does nothing without a journal.
+    pub fn begin(&mut self) {
+        self.in_transaction = self.region.is_some();
+        self.device.begin();
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.in_transaction
+    }
+
+    /// Write regular file data, which is not journaled. Replaces anything written to the
+    /// block earlier in the transaction, such as the zeroes it got when it was allocated.
+    pub fn write_data<T: AsRef<[u8]>>(&mut self, buf: T, block_address: usize) -> std::io::Result<usize> {
+        self.touch(block_address);
+        self.pending.remove(&block_address);
+        self.device.write_data(buf, block_address)
+    }
+
+    /// Note that the file system allocated `block_address`, see `Cow::block_allocated`
+    pub fn block_allocated(&mut self, block_address: usize) {
+        self.device.block_allocated(block_address);
+    }
+
+    /// Note that the file system freed `block_address`, see `Cow::block_freed`
+    pub fn block_freed(&mut self, block_address: usize) {
+        self.device.block_freed(block_address);
+    }
+
+    /// Start recording the blocks read and written, for the access trace. Only counts
+    /// requests from the file system: journal and write-back traffic is left out.
+    pub(crate) fn record_touched(&self) {
+        *self.touched.borrow_mut() = Some(BTreeSet::new());
+    }
+
+    /// Stop recording, returning where on the device the blocks touched since
+    /// `record_touched` are, ascending
+    pub(crate) fn take_touched(&self) -> Vec<u64> {
+        let touched = self.touched.borrow_mut().take().unwrap_or_default();
+        let physical: BTreeSet<u64> = touched.into_iter().map(|block_address| self.device.physical_block(block_address) as u64).collect();
+        physical.into_iter().collect()
+    }
+
+    fn touch(&self, block_address: usize) {
+        if let Some(touched) = self.touched.borrow_mut().as_mut() {
+            touched.insert(block_address);
+        }
+    }
+
+    /// Write the open transaction to the journal and then in place. Blocks for which `keep`
+    /// is false were freed again during the transaction and are dropped, so replaying it can
+    /// never clobber whatever they hold next. A transaction larger than the journal is dropped
+    /// and fails with OutOfMemory.
+    pub fn commit(&mut self, keep: impl Fn(usize) -> bool) -> std::io::Result<()> {
+        if !self.in_transaction {
+            return self.device.commit(keep);
+        }
+        self.in_transaction = false;
+        let blocks: Vec<(usize, Vec<u8>)> = std::mem::take(&mut self.pending).into_iter()
+            .filter(|(block_address, _)| keep(*block_address))
+            .collect();
+        let (start, journal_blocks) = match self.region {
+            Some(region) if !blocks.is_empty() => region,
+            _ => return Ok(()),
+        };
+
+        // Written in place, a crash could leave only part of it, so it is dropped instead
+        if blocks.len() > self.capacity(journal_blocks) {
+            return Err(Error::new(ErrorKind::OutOfMemory,
+                                  format!("transaction of {} blocks does not fit in the journal", blocks.len())));
+        }
+
+        // File data written so far, and the previous transaction's home locations, have to be
+        // on disk before this transaction takes the journal over
+        self.device.sync()?;
+        let block_size = self.device.block_size();
+        let mut descriptor = vec![0u8; block_size];
+        for (idx, field) in [JOURNAL_MAGIC, BLOCK_TYPE_DESCRIPTOR, self.sequence, blocks.len() as u32].iter().enumerate() {
+            descriptor[(idx * 4)..(idx * 4 + 4)].copy_from_slice(&field.to_le_bytes());
+        }
+        for (idx, (block_address, _)) in blocks.iter().enumerate() {
+            let offset = DESCRIPTOR_HEADER_SIZE + idx * 4;
+            descriptor[offset..(offset + 4)].copy_from_slice(&(*block_address as u32).to_le_bytes());
+        }
+        self.device.block_write(&descriptor, start as usize)?;
+        let mut checksum_input = descriptor;
+        for (idx, (_, data)) in blocks.iter().enumerate() {
+            self.device.block_write(data, start as usize + 1 + idx)?;
+            checksum_input.extend_from_slice(data);
+        }
+
+        let mut commit = vec![0u8; block_size];
+        for (idx, field) in [JOURNAL_MAGIC, BLOCK_TYPE_COMMIT, self.sequence, crc32(&checksum_input)].iter().enumerate() {
+            commit[(idx * 4)..(idx * 4 + 4)].copy_from_slice(&field.to_le_bytes());
+        }
+        self.device.block_write(&commit, start as usize + 1 + blocks.len())?;
+        self.device.sync()?;
+        self.sequence = self.sequence.wrapping_add(1);
+
+        for (block_address, data) in blocks.iter() {
+            self.device.block_write(data, *block_address)?;
+        }
+        Ok(())
+    }
+
+    /// The transaction in the journal, if it was committed completely
+    pub fn committed_transaction(&self) -> std::io::Result<Option<Transaction>> {
+        let (start, journal_blocks) = match self.region {
+            Some(region) => region,
+            None => return Ok(None),
+        };
+        let descriptor = self.device.block_read(start as usize)?;
+        let num_blocks = read_u32(&descriptor, 3) as usize;
+        if read_u32(&descriptor, 0) != JOURNAL_MAGIC || read_u32(&descriptor, 1) != BLOCK_TYPE_DESCRIPTOR
+            || num_blocks == 0 || num_blocks > self.capacity(journal_blocks) {
+            return Ok(None);
+        }
+        let sequence = read_u32(&descriptor, 2);
+
+        let mut blocks = vec![];
+        let mut checksum_input = descriptor.clone();
+        for idx in 0..num_blocks {
+            let data = self.device.block_read(start as usize + 1 + idx)?;
+            checksum_input.extend_from_slice(&data);
+            blocks.push((read_u32(&descriptor, DESCRIPTOR_HEADER_SIZE / 4 + idx) as usize, data));
+        }
+        let commit = self.device.block_read(start as usize + 1 + num_blocks)?;
+        if read_u32(&commit, 0) != JOURNAL_MAGIC || read_u32(&commit, 1) != BLOCK_TYPE_COMMIT
+            || read_u32(&commit, 2) != sequence || read_u32(&commit, 3) != crc32(&checksum_input) {
+            return Ok(None);
+        }
+        Ok(Some((sequence, blocks)))
+    }
+
+    /// Write the committed transaction in the journal, if any, to its home locations, and
+    /// empty the journal. Returns the number of blocks written.
+    pub fn replay(&mut self) -> std::io::Result<usize> {
+        let blocks = match self.committed_transaction()? {
+            Some((_, blocks)) => blocks,
+            None => return Ok(0),
+        };
+        let disk_blocks = self.device.num_blocks();
+        if let Some((block_address, _)) = blocks.iter().find(|(block_address, _)| *block_address >= disk_blocks) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("journal refers to block {} past the end", block_address)));
+        }
+        for (block_address, data) in blocks.iter() {
+            self.device.block_write(data, *block_address)?;
+        }
+        self.device.sync()?;
+        self.clear()?;
+        Ok(blocks.len())
+    }
+
+    /// Forget the transaction in the journal, once its blocks are known to be in place
+    pub fn clear(&mut self) -> std::io::Result<()> {
+        if let Some((start, _)) = self.region {
+            // The checkpoint writes have to land before the journal stops covering them
+            self.device.sync()?;
+            self.device.block_write(vec![0u8; self.device.block_size()], start as usize)?;
+            self.device.sync()?;
+        }
+        Ok(())
+    }
+}
+
+impl <BF: BlockFile> BlockFile for Journal<BF> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.device.num_blocks()
+    }
+
+    fn block_read_in_place<T: AsMut<[u8]>>(&self, mut buf: T, block_address: usize) -> std::io::Result<usize> {
+        self.touch(block_address);
+        match self.pending.get(&block_address) {
+            Some(data) => {
+                let buf = buf.as_mut();
+                let len = buf.len().min(data.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok(len)
+            }
+            None => self.device.block_read_in_place(buf, block_address),
+        }
+    }
+
+    fn block_write<T: AsRef<[u8]>>(&mut self, buf: T, block_address: usize) -> std::io::Result<usize> {
+        self.touch(block_address);
+        if !self.in_transaction {
+            return self.device.block_write(buf, block_address);
+        }
+        if buf.as_ref().len() != self.device.block_size() {
+            return Err(Error::from(ErrorKind::Other));
+        }
+        self.pending.insert(block_address, buf.as_ref().to_vec());
+        Ok(buf.as_ref().len())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.device.sync()
+    }
+
+    fn hint_access(&self, hint: AccessHint) {
+        self.device.hint_access(hint)
+    }
+
+    fn prefetch(&self, block_address: usize, hint: AccessHint) {
+        // Blocks changed by the transaction are not read from the device
+        if !self.pending.contains_key(&block_address) {
+            self.device.prefetch(block_address, hint)
+        }
+    }
+
+    fn cache_stats(&self) -> Vec<PolicyStats> {
+        self.device.cache_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::LearnedFileSystem;
+    use crate::crash_test::{mkfs_image, WorkloadOp};
+    use crate::mkfs::MkfsOptions;
+    use crate::utils::block_file::MemoryBlockFile;
+    use super::*;
+
+    #[test]
+    fn oversized_transactions_fail() {
+        let mut options = MkfsOptions { num_blocks: 512, block_size: 1024, inode_count: Some(256), journal_blocks: Some(MIN_JOURNAL_BLOCKS), ..MkfsOptions::default() };
+        options.set_features("extents,var_dirents,learned_index,has_journal").unwrap();
+        let mut file_system = LearnedFileSystem::new(MemoryBlockFile::new(1024, mkfs_image(&options).unwrap()), String::new());
+        file_system.mount().unwrap();
+        file_system.run_workload_op(&WorkloadOp::Mkdir("/d".to_string())).unwrap();
+
+        // Retraining the learned index of a directory with long names eventually rewrites
+        // more of its blocks than the journal holds
+        let mut created = vec![];
+        let (path, error) = loop {
+            assert!(created.len() < 200, "no transaction outgrew the journal");
+            let path = format!("/d/{}{:03}", "x".repeat(250), created.len());
+            match file_system.run_workload_op(&WorkloadOp::Create(path.clone())) {
+                Ok(()) => created.push(path),
+                Err(e) => break (path, e),
+            }
+        };
+        assert_eq!(error.kind(), ErrorKind::StorageFull);
+        assert!(file_system.lookup_path(&path).is_err());
+        for path in created.iter() {
+            file_system.lookup_path(path).unwrap();
+        }
+        file_system.unmount().unwrap();
+        let report = file_system.fsck(false).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems);
+    }
+}
@@ -0,0 +1,159 @@
+use std::ffi::OsStr;
+use std::io::{Error, ErrorKind};
+use crate::LearnedFileSystem;
+use crate::structs::dir_model::{DIR_MODEL_UNINDEXED_OFFSET, DirModel};
+use crate::structs::dirent::{DirectoryEntry, DirentLookup, DirentSlot, pack_var_dirent_block, parse_var_dirent_block, var_dirent_len};
+use crate::structs::fsinode::{FSINode, INODE_FLAG_LEARNED_INDEX};
+use crate::structs::htree::name_hash;
+use crate::utils::block_file::BlockFile;
+use crate::utils::div_ceil;
+
+/// Data blocks are only filled to this many quarters when the model is trained, leaving
+/// room for new entries in the block the model predicts for them
+const LEARNED_INDEX_FILL_QUARTERS: usize = 3;
+
+/// The model is retrained once more than 1 in this many indexed entries were added
+/// outside their predicted window...
+const LEARNED_INDEX_REBUILD_DIVISOR: u32 = 8;
+/// ...or once that many were, for small directories
+const LEARNED_INDEX_MIN_REBUILD: u32 = 32;
+
+/// Learned lookup for large variable-length directories.
+///
+/// The entries are laid out in name hash order after the model block, so a small
+/// piecewise-linear model (see dir_model.rs) can predict the block of a name within a known
+/// error. New entries go into their predicted window when it has room. Otherwise they go
+/// anywhere and are counted, and lookups that miss fall back to a scan until the model is
+/// retrained.
+impl <BF: BlockFile> LearnedFileSystem<BF> {
+    fn read_dir_model(&self, dir: &FSINode) -> std::io::Result<DirModel> {
+        DirModel::try_from(self.read_dir_block(dir, 0)?.as_slice()).map_err(|_| Error::from(ErrorKind::InvalidData))
+    }
+
+    fn read_dir_window(&self, dir: &FSINode, model: &DirModel, hash: u32) -> std::io::Result<Vec<DirentSlot>> {
+        let mut slots = vec![];
+        for block in model.search_window(hash) {
+            slots.extend(parse_var_dirent_block(&self.read_dir_block(dir, block)?, block as usize * self.block_size));
+        }
+        Ok(slots)
+    }
+
+    /// Like `find_dirent`, but only reads the blocks the model predicts for the name unless
+    /// entries were added outside the model since it was trained
+    pub(crate) fn learned_find_dirent(&self, dir: &FSINode, name: &OsStr) -> std::io::Result<DirentLookup> {
+        let model = self.read_dir_model(dir)?;
+        let window_slots = self.read_dir_window(dir, &model, name_hash(name))?;
+        let found = self.find_dirent_in_list(&window_slots, name);
+        if found.is_some() || model.num_unindexed == 0 {
+            return Ok((window_slots, found));
+        }
+
+        let dirents_incl_gaps = self.get_dirents_incl_gaps(dir)?;
+        let found = self.find_dirent_in_list(&dirents_incl_gaps, name);
+        Ok((dirents_incl_gaps, found))
+    }
+
+    /// NOTE: Does not write back the directory inode itself
+    pub(crate) fn learned_insert_dirent(&mut self, dir: &mut FSINode, dirent: DirectoryEntry) -> std::io::Result<()> {
+        let mut model = self.read_dir_model(dir)?;
+        let needed_len = var_dirent_len(dirent.name.len());
+
+        let window_slots = self.read_dir_window(dir, &model, name_hash(&dirent.name))?;
+        if let Some(idx) = self.first_free_dirent_idx(dir, &window_slots, needed_len) {
+            return self.insert_into_var_slot(dir, &window_slots[idx], dirent);
+        }
+
+        model.num_unindexed += 1;
+        if model.num_unindexed > (model.num_indexed / LEARNED_INDEX_REBUILD_DIVISOR).max(LEARNED_INDEX_MIN_REBUILD) {
+            self.learned_index_build(dir)?;
+            return self.learned_insert_dirent(dir, dirent);
+        }
+
+        // Anywhere but the model block will do
+        let data_slots: Vec<DirentSlot> = self.get_dirents_incl_gaps(dir)?.into_iter()
+            .filter(|slot| slot.offset >= self.block_size)
+            .collect();
+        match self.first_free_dirent_idx(dir, &data_slots, needed_len) {
+            Some(idx) => self.insert_into_var_slot(dir, &data_slots[idx], dirent)?,
+            None => {
+                let block_start = div_ceil(dir.size as usize, self.block_size) * self.block_size;
+                self.write_file_data(dir, block_start, &dirent.to_var_record(self.block_size))?;
+            }
+        }
+        self.write_file_data(dir, DIR_MODEL_UNINDEXED_OFFSET, &model.num_unindexed.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Lay the entries of a variable-length directory out in hash order and train a new
+    /// model on them. Works both on linear directories and on ones that already have a model.
+    /// NOTE: Does not write back the directory inode itself
+    pub(crate) fn learned_index_build(&mut self, dir: &mut FSINode) -> std::io::Result<()> {
+        let mut entries = self.get_valid_dirents(dir)?;
+        entries.sort_by_key(|e| name_hash(&e.name));
+
+        let fill_bytes = self.block_size / 4 * LEARNED_INDEX_FILL_QUARTERS;
+        let mut blocks: Vec<Vec<DirectoryEntry>> = vec![vec![]];
+        let mut block_bytes = 0;
+        let mut keys = vec![];
+        for entry in entries {
+            let len = var_dirent_len(entry.name.len());
+            if block_bytes + len > fill_bytes && !blocks.last().unwrap().is_empty() {
+                blocks.push(vec![]);
+                block_bytes = 0;
+            }
+            block_bytes += len;
+            keys.push((name_hash(&entry.name), blocks.len() as u32));
+            blocks.last_mut().unwrap().push(entry);
+        }
+
+        let old_num_blocks = self.num_dir_blocks(dir);
+        for (block_idx, block) in blocks.iter().enumerate() {
+            self.write_dir_block(dir, block_idx as u32 + 1, &pack_var_dirent_block(block, self.block_size))?;
+        }
+
+        let num_data_blocks = blocks.len() as u32;
+        let model = DirModel::train(&keys, num_data_blocks, self.block_size);
+        self.write_dir_block(dir, 0, &model.to_bytes(self.block_size))?;
+
+        if num_data_blocks + 1 < old_num_blocks {
+            self.truncate_to_num_blocks(dir, num_data_blocks + 1)?;
+            dir.size = (num_data_blocks as u64 + 1) * self.block_size as u64;
+        }
+        dir.flags |= INODE_FLAG_LEARNED_INDEX;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use crate::crash_test::{check_written, run_and_reopen};
+    use crate::mkfs::MkfsOptions;
+
+    #[test]
+    fn learned_index_round_trip() {
+        // Retraining rewrites the whole directory in one transaction, so the journal has to
+        // hold all of it
+        let mut options = MkfsOptions { num_blocks: 2048, block_size: 1024, inode_count: Some(512), journal_blocks: Some(128), ..MkfsOptions::default() };
+        options.set_features("extents,var_dirents,learned_index,has_journal").unwrap();
+        let name = |idx: usize| format!("{}{:04}", "l".repeat(60), idx);
+        let mut workload = String::from("mkdir /d\n");
+        for idx in 0..400 {
+            workload += &format!("create /d/{}\n", name(idx));
+        }
+        for idx in (0..400).step_by(7) {
+            workload += &format!("unlink /d/{}\n", name(idx));
+        }
+        workload += &format!("write /d/{} 0 3000\n", name(1));
+        let file_system = run_and_reopen(&options, &workload);
+
+        let dir = file_system.get_inode(file_system.lookup_path("/d").unwrap()).unwrap();
+        assert!(dir.has_learned_index());
+        assert!(file_system.read_dir_model(&dir).is_ok());
+        for idx in 0..400 {
+            let (_, found) = file_system.learned_find_dirent(&dir, &OsString::from(name(idx))).unwrap();
+            assert_eq!(found.is_some(), idx % 7 != 0, "{}", name(idx));
+        }
+        check_written(&file_system, &format!("/d/{}", name(1)), 0, 3000);
+    }
+}
@@ -1,8 +1,22 @@
 pub mod utils;
 mod structs;
+mod extent_map;
+mod dir_index;
+mod learned_index;
+mod journal;
+mod cow;
+pub mod mkfs;
+pub mod disk_spec;
+pub mod fsck;
+pub mod debug;
+pub mod populate;
+pub mod resize;
+pub mod crash_test;
+pub mod prefetch;
+pub mod trace;
 
 use time::{Duration, get_time, Timespec};
-use fuse::{FileAttr, Filesystem, FileType, FUSE_ROOT_ID, ReplyAttr, ReplyData, ReplyEmpty, ReplyEntry, ReplyWrite, Request};
+use fuse::{FileAttr, Filesystem, FUSE_ROOT_ID, ReplyAttr, ReplyData, ReplyEmpty, ReplyEntry, ReplyWrite, ReplyXattr, Request};
 use utils::bitmask::BitMaskBlock;
 use std::os::raw::c_int;
 use std::collections::BTreeSet;
@@ -12,33 +26,139 @@ use std::io::ErrorKind::{Other, OutOfMemory};
 use std::num::NonZeroU8;
 use std::ops::{Add, Deref};
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::fs::File;
 use fuse::FileType::{Directory, RegularFile};
 use crate::utils::block_file::BlockFile;
-use libc::{EEXIST, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY};
-use structs::dirent::DirectoryEntry;
+use crate::utils::cache_policy::{AccessHint, AccessKind};
+use libc::{EEXIST, EFBIG, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENODATA, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, ENOTSUP, ERANGE, EROFS};
+use structs::dirent::{DirectoryEntry, DirentLookup, DirentSlot, DIRENT_TYPE_DIRECTORY, DIRENT_TYPE_REGULAR, dirent_type_of_mode,
+                      FIXED_DIRENT_SIZE, FIXED_NAME_MAX, free_var_record, NAME_MAX, parse_var_dirent_block, rec_len_to_disk, var_dirent_len};
 use structs::fsinode::FSINode;
-use structs::superblock::FsSuperBlock;
-use crate::structs::fsinode::NUM_POINTERS;
+use structs::superblock::{FsSuperBlock, FEATURE_COMPAT_SUPPORTED, FEATURE_INCOMPAT_EXTENTS, FEATURE_INCOMPAT_RECOVER, FEATURE_INCOMPAT_VAR_DIRENTS,
+                          FEATURE_RO_COMPAT_DIR_INDEX, FEATURE_RO_COMPAT_LEARNED_INDEX, FS_STATE_DIRTY, generate_uuid, is_valid_block_size, MIN_BLOCK_SIZE};
+use crate::structs::fsinode::{BlockPointerLocation, INODE_FLAG_EXTENTS, INODE_FLAG_VAR_DIRENTS, MIN_INODE_SIZE, pointers_per_block};
+use crate::structs::extent::ExtentNode;
 use crate::utils::div_ceil;
-use log::debug;
+use crate::journal::{Journal, MIN_JOURNAL_BLOCKS};
+use crate::cow::{Cow, MIN_COW_BLOCKS};
+use crate::prefetch::Prefetcher;
+use crate::trace::{TraceOp, TraceOptions, TraceWriter};
+use log::{debug, error, info, warn};
 
 
+/// Block size of images that don't record one in the superblock
 const FS_BLOCK_SIZE: usize = 4096;
 const FS_MAGIC_NUM: u32 = 0x30303635;
+/// Inode of the root directory, which FUSE knows as FUSE_ROOT_ID
+pub const ROOT_INODE: u64 = 2;
+/// Setting this extended attribute on the root of a mounted image grows the file system to
+/// the current size of the image file, see LearnedFileSystem::grow_to_device
+pub const GROW_XATTR: &str = "user.lfs.grow";
+/// Reading this extended attribute of the root gives the hit ratios of the block cache, one
+/// line per eviction policy, see CachedBlockFile::add_shadow_policy
+pub const CACHE_STATS_XATTR: &str = "user.lfs.cache_stats";
+/// Reading this extended attribute of the root gives the accuracy and coverage of the
+/// prefetch predictors, one line each, see Prefetcher::add_shadow_predictor
+pub const PREFETCH_STATS_XATTR: &str = "user.lfs.prefetch_stats";
+
+/// Version 1 repurposes the last three inode pointer slots as the single indirect pointer,
+/// the double indirect pointer and the high half of the size, splits the features into
+/// compatible, incompatible and read-only compatible sets, and records the block size, free
+/// counts, UUID, label, mount count, times and a dirty flag in the superblock. Images from
+/// gen-disk.py are version 0 and are upgraded in place. From here on format changes come with
+/// a feature flag, so images with a newer version are mounted as long as their features are known.
+const FS_VERSION: u32 = 1;
+
+/// How newly allocated blocks are picked
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// First free blocks at or after a goal the caller picks, such as the block after the
+    /// last one of the file, so files stay contiguous
+    #[default]
+    Goal,
+    /// Lowest free blocks, whatever the goal
+    FirstFit,
+    /// First free blocks after the previous allocation, whatever the goal
+    NextFit,
+}
 
+/// Names of the allocation policies, as taken by AllocPolicy::from_name
+pub const ALLOC_POLICY_NAMES: [(&str, AllocPolicy); 3] = [
+    ("goal", AllocPolicy::Goal),
+    ("first-fit", AllocPolicy::FirstFit),
+    ("next-fit", AllocPolicy::NextFit),
+];
+
+impl AllocPolicy {
+    pub fn from_name(name: &str) -> std::io::Result<Self> {
+        ALLOC_POLICY_NAMES.iter().find(|(known, _)| *known == name).map(|(_, policy)| *policy)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("unknown allocation policy {}", name)))
+    }
+}
 
 pub struct LearnedFileSystem <BF : BlockFile> {
-    block_system: BF,
+    /// The device, behind the journal when the image has one
+    block_system: Journal<BF>,
     block_allocation_bitmask: BitMaskBlock,
     super_block_index: usize,
-    bit_mask_block_index: usize,
+    /// First block of the allocation bitmap
+    bit_mask_start_block: usize,
+    /// File that the requests served are traced to, or empty for no trace
     logging_path: String,
+    /// How the trace is written
+    trace_options: TraceOptions,
+    /// The trace, from init to destroy
+    trace: Option<TraceWriter>,
+    /// Bytes per block, from the superblock. Has to match the block size of `block_system`
+    block_size: usize,
+    /// Whether new inodes are extent-mapped, from FEATURE_INCOMPAT_EXTENTS in the superblock
+    use_extents: bool,
+    /// Whether new directories use variable-length records, from FEATURE_INCOMPAT_VAR_DIRENTS
+    use_var_dirents: bool,
+    /// Whether variable-length directories get a hash index once they outgrow a block,
+    /// from FEATURE_RO_COMPAT_DIR_INDEX
+    use_dir_index: bool,
+    /// Whether variable-length directories get a learned lookup model once they outgrow a
+    /// block, from FEATURE_RO_COMPAT_LEARNED_INDEX
+    use_learned_index: bool,
+    /// Bytes per inode. A whole block unless the image has an inode table
+    inode_size: usize,
+    /// First block of the inode table, if the image has one
+    inode_table_start_block: usize,
+    /// Tracks which inode table entries are in use. None if the image has no inode table,
+    /// in which case inode numbers are block numbers
+    inode_allocation_bitmask: Option<BitMaskBlock>,
+    inode_bit_mask_start_block: usize,
+    /// Set when asked for with set_read_only, or when the image has read-only compatible
+    /// features we do not know. Every request that would change the image then fails with
+    /// EROFS, and mounting does not touch the superblock.
+    read_only: bool,
+    alloc_policy: AllocPolicy,
+    /// Block after the last one allocated, where AllocPolicy::NextFit looks first
+    next_fit_goal: u32,
+    /// Reads blocks into the block cache ahead of reads and writes, if set
+    prefetcher: Option<Prefetcher>,
+}
+
+/// Attributes a setattr call changes, None for those it leaves alone
+#[derive(Clone, Copy, Debug, Default)]
+struct SetattrRequest {
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    size: Option<u64>,
+    mtime: Option<Timespec>,
+    chgtime: Option<Timespec>,
 }
 
 fn translate_error(e : ErrorKind) -> c_int{
     match e {
         ErrorKind::OutOfMemory => ENOSPC,
         ErrorKind::AlreadyExists => EEXIST,
+        ErrorKind::FileTooLarge => EFBIG,
+        ErrorKind::InvalidFilename => ENAMETOOLONG,
+        ErrorKind::ReadOnlyFilesystem => EROFS,
         _ => EIO
     }
 }
@@ -48,127 +168,518 @@ fn translate_io_error(e: std::io::Error) -> c_int{
 }
 
 fn translate_inode(ino: u64) -> u64{
-    if ino == FUSE_ROOT_ID {2} else {ino}
+    if ino == FUSE_ROOT_ID {ROOT_INODE} else {ino}
+}
+
+/// Block size recorded in the superblock of an image, so the block device can be set up
+/// before mounting it. The superblock always fits in the smallest block size.
+pub fn image_block_size(image: &File) -> std::io::Result<usize> {
+    let mut super_block_bytes = vec![0u8; MIN_BLOCK_SIZE as usize];
+    image.read_exact_at(&mut super_block_bytes, 0)?;
+    Ok(FsSuperBlock::from(super_block_bytes.as_slice()).block_size() as usize)
 }
 
 impl <BF: BlockFile>  LearnedFileSystem<BF> {
     pub fn new(block_system: BF, logging_path: String) -> Self {
         let block_allocation_bitmask = BitMaskBlock::default();
+        let block_size = block_system.block_size();
 
         LearnedFileSystem {
-            block_system,
+            block_system: Journal::new(Cow::new(block_system)),
             block_allocation_bitmask,
             super_block_index: 0,
-            bit_mask_block_index: 1,
+            bit_mask_start_block: 1,
             logging_path,
+            trace_options: TraceOptions::default(),
+            trace: None,
+            block_size,
+            use_extents: false,
+            use_var_dirents: false,
+            use_dir_index: false,
+            use_learned_index: false,
+            inode_size: block_size,
+            inode_table_start_block: 0,
+            inode_allocation_bitmask: None,
+            inode_bit_mask_start_block: 0,
+            read_only: false,
+            alloc_policy: AllocPolicy::default(),
+            next_fit_goal: 0,
+            prefetcher: None,
+        }
+    }
+
+    /// Never write to the image, so it can be opened read-only. Images with unknown
+    /// read-only compatible features are read-only either way.
+    pub fn set_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    pub fn set_alloc_policy(&mut self, alloc_policy: AllocPolicy) {
+        self.alloc_policy = alloc_policy;
+    }
+
+    /// Read the superblock and the allocation bitmaps, and set up everything that depends on
+    /// them. Fails with InvalidData if this is not an image we can use. Returns the superblock.
+    pub fn load(&mut self) -> std::io::Result<FsSuperBlock> {
+        let mut super_block = self.get_superblock()?;
+        if super_block.magic != FS_MAGIC_NUM {
+            return Err(Error::new(ErrorKind::InvalidData, "bad magic number"));
+        }
+        if super_block.unknown_incompat_features() != 0 {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("unknown incompatible features {:#x}", super_block.unknown_incompat_features())));
+        }
+        if super_block.is_cow() {
+            if super_block.has_journal() {
+                return Err(Error::new(ErrorKind::InvalidData, "an image cannot both journal and copy on write"));
+            }
+            if super_block.cow_start == 0 || super_block.cow_blocks < MIN_COW_BLOCKS
+                || super_block.cow_start as u64 + super_block.cow_blocks as u64 > super_block.disk_size as u64 {
+                return Err(Error::new(ErrorKind::InvalidData, "bad copy-on-write area location"));
+            }
+            // Block 0 may hold the older superblock copy
+            self.block_system.device.set_region(Some((super_block.cow_start, super_block.cow_blocks)))?;
+            super_block = self.get_superblock()?;
+        } else {
+            self.block_system.device.set_region(None)?;
+        }
+        if !is_valid_block_size(super_block.block_size()) || super_block.block_size() as usize != self.block_system.block_size() {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  format!("block size {} does not match the device's {}", super_block.block_size(), self.block_system.block_size())));
+        }
+        self.block_size = super_block.block_size() as usize;
+        self.inode_size = self.block_size;
+        if super_block.unknown_ro_compat_features() != 0 {
+            warn!("Mounting read-only, unknown read-only compatible features {:#x}", super_block.unknown_ro_compat_features());
+            self.read_only = true;
+        }
+        if super_block.compat_features & !FEATURE_COMPAT_SUPPORTED != 0 {
+            debug!("Ignoring unknown compatible features {:#x}", super_block.compat_features & !FEATURE_COMPAT_SUPPORTED);
+        }
+
+        self.use_extents = super_block.incompat_features & FEATURE_INCOMPAT_EXTENTS != 0;
+        self.use_var_dirents = super_block.incompat_features & FEATURE_INCOMPAT_VAR_DIRENTS != 0;
+        self.use_dir_index = self.use_var_dirents && super_block.ro_compat_features & FEATURE_RO_COMPAT_DIR_INDEX != 0;
+        self.use_learned_index = self.use_var_dirents && super_block.ro_compat_features & FEATURE_RO_COMPAT_LEARNED_INDEX != 0;
+
+        if super_block.has_journal() {
+            if super_block.journal_blocks < MIN_JOURNAL_BLOCKS || super_block.journal_start as u64 + super_block.journal_blocks as u64 > super_block.disk_size as u64 {
+                return Err(Error::new(ErrorKind::InvalidData, "bad journal location"));
+            }
+            self.block_system.set_region(Some((super_block.journal_start, super_block.journal_blocks)));
+        } else {
+            self.block_system.set_region(None);
+        }
+        // Inside a transaction this is a mounted file system reloading itself, which set the
+        // flag itself
+        if super_block.incompat_features & FEATURE_INCOMPAT_RECOVER != 0 && !self.block_system.in_transaction() {
+            if self.read_only {
+                warn!("The journal needs recovery, which a read-only file system cannot do. Recent changes may be missing");
+            } else {
+                return self.recover();
+            }
+        }
+
+        self.load_bitmaps(&super_block)?;
+        Ok(super_block)
+    }
+
+    /// Read the allocation bitmaps, and the inode table location, that `super_block` describes
+    fn load_bitmaps(&mut self, super_block: &FsSuperBlock) -> std::io::Result<()> {
+        let (bit_mask_start, bit_mask_blocks) = super_block.bitmap_location();
+        if BitMaskBlock::blocks_needed(super_block.disk_size as usize, self.block_size) > bit_mask_blocks as usize {
+            return Err(Error::new(ErrorKind::InvalidData, "allocation bitmap too small"));
+        }
+
+        let mut bitmask_bytes = vec![];
+        for bitmap_block in bit_mask_start..(bit_mask_start + bit_mask_blocks) {
+            bitmask_bytes.extend(self.block_system.block_read(bitmap_block as usize)?);
+        }
+        self.bit_mask_start_block = bit_mask_start as usize;
+        self.block_allocation_bitmask = BitMaskBlock::new(super_block.disk_size as usize, &bitmask_bytes, self.block_size);
+
+        if super_block.has_inode_table() {
+            let inode_size = super_block.inode_size;
+            if inode_size < MIN_INODE_SIZE || !inode_size.is_power_of_two() || inode_size as usize > self.block_size {
+                return Err(Error::new(ErrorKind::InvalidData, format!("bad inode size {}", inode_size)));
+            }
+            if BitMaskBlock::blocks_needed(super_block.inode_count as usize, self.block_size) > super_block.inode_bitmap_blocks as usize {
+                return Err(Error::new(ErrorKind::InvalidData, "inode bitmap too small"));
+            }
+
+            let mut inode_bitmask_bytes = vec![];
+            for bitmap_block in super_block.inode_bitmap_start..(super_block.inode_bitmap_start + super_block.inode_bitmap_blocks) {
+                inode_bitmask_bytes.extend(self.block_system.block_read(bitmap_block as usize)?);
+            }
+            self.inode_size = super_block.inode_size as usize;
+            self.inode_table_start_block = super_block.inode_table_start as usize;
+            self.inode_bit_mask_start_block = super_block.inode_bitmap_start as usize;
+            self.inode_allocation_bitmask = Some(BitMaskBlock::new(super_block.inode_count as usize, &inode_bitmask_bytes, self.block_size));
         }
+        Ok(())
+    }
+
+    /// Replay the journal after an unclean shutdown, mark it as no longer needing recovery
+    /// and load the result
+    fn recover(&mut self) -> std::io::Result<FsSuperBlock> {
+        let num_replayed = self.block_system.replay()?;
+        if num_replayed != 0 {
+            warn!("Replayed {} blocks from the journal", num_replayed);
+        }
+        let mut super_block = self.get_superblock()?;
+        super_block.incompat_features &= !FEATURE_INCOMPAT_RECOVER;
+        let super_block_data: Vec<u8> = super_block.into();
+        self.block_system.block_write(&super_block_data, self.super_block_index)?;
+        self.load()
+    }
+
+    /// Load the file system and mark it as mounted, as FUSE's init does
+    fn mount(&mut self) -> std::io::Result<()> {
+        let mut super_block = self.load()?;
+        if self.read_only {
+            return Ok(());
+        }
+
+        if super_block.state & FS_STATE_DIRTY != 0 {
+            warn!("File system was not cleanly unmounted");
+        }
+        self.upgrade_superblock(&mut super_block)?;
+        super_block.mount_count = super_block.mount_count.wrapping_add(1);
+        super_block.last_mount_time = get_time().sec as u32;
+        super_block.state |= FS_STATE_DIRTY;
+        if super_block.has_journal() {
+            super_block.incompat_features |= FEATURE_INCOMPAT_RECOVER;
+        }
+        self.write_superblock(super_block)
+    }
+
+    /// Mark the file system clean, as FUSE's destroy does
+    fn unmount(&mut self) -> std::io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+
+        // Every transaction is in place by now
+        self.block_system.clear()?;
+        let mut super_block = self.get_superblock()?;
+        super_block.state &= !FS_STATE_DIRTY;
+        super_block.incompat_features &= !FEATURE_INCOMPAT_RECOVER;
+        self.write_superblock(super_block)?;
+        self.block_system.sync()
+    }
+
+    /// Get everything written so far onto the device, for flush and fsync
+    fn do_sync(&mut self) -> Result<(), c_int> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.block_system.sync().map_err(translate_io_error)
+    }
+
+    /// Run `op` as one journal transaction, so that after a crash either all of its metadata
+    /// writes are on disk or none are
+    fn transaction<T>(&mut self, op: impl FnOnce(&mut Self) -> Result<T, c_int>) -> Result<T, c_int> {
+        self.block_system.begin();
+        let result = op(self);
+        let bitmask = &self.block_allocation_bitmask;
+        if let Err(e) = self.block_system.commit(|block| !bitmask.is_free(block as u32)) {
+            error!("Could not commit to the journal: {}", e);
+            // Whatever the transaction allocated or freed is undone on the device, so the
+            // bitmaps have to follow
+            if let Err(e) = self.get_superblock().and_then(|super_block| self.load_bitmaps(&super_block)) {
+                error!("Could not reload the allocation bitmaps: {}", e);
+            }
+            return Err(translate_io_error(e));
+        }
+        result
     }
 
      /// Read Bitmask block from disk, clear all bits given, and write bitmask
      /// block back to disk
-    pub fn free_blocks(&mut self, block_indices: &Vec<u32>) -> std::io::Result<()> {
+    pub fn free_blocks(&mut self, block_indices: &[u32]) -> std::io::Result<()> {
         for block_index in block_indices.iter() {
             if self.block_allocation_bitmask.is_free(*block_index) {return Err(Error::from(Other));}
         }
         for block_index in block_indices.iter() {
             self.block_allocation_bitmask.clear_bit(*block_index);
+            self.block_system.block_freed(*block_index as usize);
         }
 
-        self.block_system.block_write(&self.block_allocation_bitmask, self.bit_mask_block_index)?;
+        self.write_dirty_bitmask_blocks()
+    }
 
+    /// Write back only the bitmap blocks that changed since the last write
+    fn write_dirty_bitmask_blocks(&mut self) -> std::io::Result<()> {
+        for bitmap_block_idx in self.block_allocation_bitmask.take_dirty_blocks() {
+            self.block_system.block_write(self.block_allocation_bitmask.bitmap_block(bitmap_block_idx),
+                                          self.bit_mask_start_block + bitmap_block_idx)?;
+        }
+        if let Some(inode_bitmask) = self.inode_allocation_bitmask.as_mut() {
+            for bitmap_block_idx in inode_bitmask.take_dirty_blocks() {
+                self.block_system.block_write(inode_bitmask.bitmap_block(bitmap_block_idx),
+                                              self.inode_bit_mask_start_block + bitmap_block_idx)?;
+            }
+        }
         Ok(())
     }
 
     /// ASSUMPTION: The relevant block has already been allocated and initialized to 0
-    fn write_file_chunk(&mut self, file: &FSINode, block_num_in_file: usize, offset: usize, data: &[u8]) -> std::io::Result<usize>{
-        if offset + data.len() > FS_BLOCK_SIZE {
+    /// Directory blocks are journaled, the data of regular files is not
+    fn write_file_chunk(&mut self, file: &FSINode, logical_block: usize, physical_block: usize, offset: usize, data: &[u8]) -> std::io::Result<usize>{
+        if offset + data.len() > self.block_size {
             panic!("Tried writing off end of file chunk");
         }
 
-        let physical_block = file.pointers[block_num_in_file] as usize;
-
-        if offset == 0 && data.len() == FS_BLOCK_SIZE{
-            self.block_system.block_write(data, physical_block)
+        let chunk = if offset == 0 && data.len() == self.block_size{
+            data.to_vec()
         } else{
+            self.hint_file_block(file, logical_block);
             let mut pre_existing_chunk = self.block_system.block_read(physical_block)?;
             pre_existing_chunk[offset..(offset+data.len())].copy_from_slice(data);
-            self.block_system.block_write(&pre_existing_chunk, physical_block)
+            pre_existing_chunk
+        };
+        self.hint_file_block(file, logical_block);
+        if file.is_regular_file() {
+            self.block_system.write_data(&chunk, physical_block)
+        } else {
+            self.block_system.block_write(&chunk, physical_block)
         }
+    }
+
+    /// Tell the block cache that the next access is to a block of `file`
+    fn hint_file_block(&self, file: &FSINode, logical_block: usize) {
+        let kind = if file.is_regular_file() { AccessKind::Data } else { AccessKind::Directory };
+        self.block_system.hint_access(AccessHint::file_block(kind, logical_block as u64));
+    }
+
+    /// Read the pointer at index `idx` of the indirect block `block`
+    fn read_indirect_pointer(&self, block: u32, idx: usize) -> std::io::Result<u32> {
+        let indirect_block = self.block_system.block_read(block as usize)?;
+        Ok(u32::from_le_bytes(slice_to_four_bytes(&indirect_block[(idx*4)..])))
+    }
+
+    fn write_indirect_pointer(&mut self, block: u32, idx: usize, value: u32) -> std::io::Result<()> {
+        let mut indirect_block = self.block_system.block_read(block as usize)?;
+        indirect_block[(idx*4)..(idx*4 + 4)].copy_from_slice(&value.to_le_bytes());
+        self.block_system.block_write(&indirect_block, block as usize)?;
+        Ok(())
+    }
+
+    fn read_indirect_block(&self, block: u32) -> std::io::Result<Vec<u32>> {
+        Ok(self.block_system.block_read(block as usize)?
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(slice_to_four_bytes(chunk)))
+            .collect())
+    }
 
+    fn write_indirect_block(&mut self, block: u32, pointers: &[u32]) -> std::io::Result<()> {
+        let data: Vec<u8> = pointers.iter().flat_map(|ptr| ptr.to_le_bytes()).collect();
+        self.block_system.block_write(&data, block as usize)?;
+        Ok(())
+    }
+
+    /// Number of blocks the file takes: its data blocks, and its indirect blocks or the
+    /// nodes of its extent tree below the root
+    fn num_blocks_used(&self, file: &FSINode) -> std::io::Result<u64> {
+        if file.is_extent_mapped() {
+            let (extents, tree_blocks) = self.collect_extents(file)?;
+            return Ok(extents.iter().map(|extent| extent.length as u64).sum::<u64>() + tree_blocks.len() as u64);
+        }
+
+        let count_mapped = |pointers: &[u32]| pointers.iter().filter(|ptr| **ptr != 0).count() as u64;
+        let mut num_blocks = count_mapped(&file.pointers);
+        if file.single_indirect != 0 {
+            num_blocks += 1 + count_mapped(&self.read_indirect_block(file.single_indirect)?);
+        }
+        if file.double_indirect != 0 {
+            num_blocks += 1;
+            for indirect_block in self.read_indirect_block(file.double_indirect)?.into_iter().filter(|block| *block != 0) {
+                num_blocks += 1 + count_mapped(&self.read_indirect_block(indirect_block)?);
+            }
+        }
+        Ok(num_blocks)
+    }
+
+    /// Attributes of inode `ino` as FUSE reports them
+    fn file_attr(&self, ino: u64, file: &FSINode) -> std::io::Result<FileAttr> {
+        Ok(file.to_fileattr(ino, self.num_blocks_used(file)? * (self.block_size / 512) as u64))
+    }
+
+    /// Physical block backing a logical block of the file, or 0 if it is a hole
+    fn get_block_pointer(&self, file: &FSINode, logical_block_num: usize) -> std::io::Result<u32> {
+        if file.is_extent_mapped() {
+            return self.get_extent_block_pointer(file, logical_block_num);
+        }
+
+        match BlockPointerLocation::of_logical_block(file.pointers.len(), pointers_per_block(self.block_size), logical_block_num) {
+            None => Ok(0),
+            Some(BlockPointerLocation::Direct(idx)) => Ok(file.pointers[idx]),
+            Some(BlockPointerLocation::SingleIndirect(idx)) => {
+                if file.single_indirect == 0 {
+                    return Ok(0);
+                }
+                self.read_indirect_pointer(file.single_indirect, idx)
+            }
+            Some(BlockPointerLocation::DoubleIndirect(outer_idx, inner_idx)) => {
+                if file.double_indirect == 0 {
+                    return Ok(0);
+                }
+                let indirect_block = self.read_indirect_pointer(file.double_indirect, outer_idx)?;
+                if indirect_block == 0 {
+                    return Ok(0);
+                }
+                self.read_indirect_pointer(indirect_block, inner_idx)
+            }
+        }
+    }
+
+    /// Point a logical block of the file at `physical_block`. Missing indirect blocks are
+    /// taken from `spare_blocks`, which must already be allocated and zeroed.
+    fn set_block_pointer(&mut self, file: &mut FSINode, logical_block_num: usize, physical_block: u32,
+                         spare_blocks: &mut impl Iterator<Item=u32>) -> std::io::Result<()> {
+        match BlockPointerLocation::of_logical_block(file.pointers.len(), pointers_per_block(self.block_size), logical_block_num) {
+            None => Err(Error::from(ErrorKind::FileTooLarge)),
+            Some(BlockPointerLocation::Direct(idx)) => {
+                file.pointers[idx] = physical_block;
+                Ok(())
+            }
+            Some(BlockPointerLocation::SingleIndirect(idx)) => {
+                if file.single_indirect == 0 {
+                    file.single_indirect = spare_blocks.next().ok_or(Error::from(Other))?;
+                }
+                self.write_indirect_pointer(file.single_indirect, idx, physical_block)
+            }
+            Some(BlockPointerLocation::DoubleIndirect(outer_idx, inner_idx)) => {
+                if file.double_indirect == 0 {
+                    file.double_indirect = spare_blocks.next().ok_or(Error::from(Other))?;
+                }
+                let mut indirect_block = self.read_indirect_pointer(file.double_indirect, outer_idx)?;
+                if indirect_block == 0 {
+                    indirect_block = spare_blocks.next().ok_or(Error::from(Other))?;
+                    self.write_indirect_pointer(file.double_indirect, outer_idx, indirect_block)?;
+                }
+                self.write_indirect_pointer(indirect_block, inner_idx, physical_block)
+            }
+        }
+    }
+
+    /// Number of indirect blocks that have to be allocated before the given (currently
+    /// unmapped) logical blocks can be mapped
+    fn num_indirect_blocks_needed(&self, file: &FSINode, logical_block_nums: &[usize]) -> std::io::Result<usize> {
+        let mut needs_single_indirect = false;
+        let mut needs_double_indirect = false;
+        let mut checked_outer_indices = BTreeSet::new();
+        let mut num_inner_blocks_needed = 0;
+
+        for logical_block_num in logical_block_nums {
+            match BlockPointerLocation::of_logical_block(file.pointers.len(), pointers_per_block(self.block_size), *logical_block_num) {
+                Some(BlockPointerLocation::SingleIndirect(_)) => {
+                    needs_single_indirect |= file.single_indirect == 0;
+                }
+                Some(BlockPointerLocation::DoubleIndirect(outer_idx, _)) => {
+                    if !checked_outer_indices.insert(outer_idx) {
+                        continue;
+                    }
+                    if file.double_indirect == 0 {
+                        needs_double_indirect = true;
+                        num_inner_blocks_needed += 1;
+                    } else if self.read_indirect_pointer(file.double_indirect, outer_idx)? == 0 {
+                        num_inner_blocks_needed += 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(needs_single_indirect as usize + needs_double_indirect as usize + num_inner_blocks_needed)
     }
 
     /// NOTE: Does not write back the inode itself
     fn write_file_data(&mut self, file: &mut FSINode, offset: usize, data: &[u8]) -> std::io::Result<usize>{
+        if file.is_extent_mapped() {
+            return self.write_file_data_extents(file, offset, data);
+        }
+
+        if div_ceil(offset + data.len(), self.block_size) > file.max_file_blocks(self.block_size) {
+            return Err(Error::from(ErrorKind::FileTooLarge));
+        }
+
         let mut operations = vec![];
 
         let mut total_byte_writes_queued= 0;
-        let mut total_allocations_needed = 0;
+        let mut unmapped_blocks = vec![];
         let mut file_ptr = offset;
 
         while total_byte_writes_queued < data.len() {
-            let logical_block_num = file_ptr / FS_BLOCK_SIZE;
-            let block_offset = file_ptr % FS_BLOCK_SIZE;
+            let logical_block_num = file_ptr / self.block_size;
+            let block_offset = file_ptr % self.block_size;
 
-            let write_length = if (FS_BLOCK_SIZE - block_offset) > (data.len() - total_byte_writes_queued) {
+            let write_length = if (self.block_size - block_offset) > (data.len() - total_byte_writes_queued) {
                 data.len() - total_byte_writes_queued
             } else {
-                FS_BLOCK_SIZE - block_offset
+                self.block_size - block_offset
             };
 
-            let allocation_num = if file.pointers[logical_block_num] == 0 {
-                let anum = total_allocations_needed;
-                total_allocations_needed += 1;
-                Some(anum)
+            let physical_block = self.get_block_pointer(file, logical_block_num)?;
+            let allocation_num = if physical_block == 0 {
+                unmapped_blocks.push(logical_block_num);
+                Some(unmapped_blocks.len() - 1)
             } else{
                 None
             };
 
-            operations.push((&data[total_byte_writes_queued..(total_byte_writes_queued+write_length)], logical_block_num, block_offset, allocation_num));
+            operations.push((&data[total_byte_writes_queued..(total_byte_writes_queued+write_length)], logical_block_num, physical_block, block_offset, allocation_num));
 
             total_byte_writes_queued += write_length;
             file_ptr += write_length;
         }
 
-        let allocations = self.allocate_blocks(total_allocations_needed)?;
+        let num_indirect_blocks = self.num_indirect_blocks_needed(file, &unmapped_blocks)?;
+        let allocations = self.allocate_blocks(unmapped_blocks.len() + num_indirect_blocks)?;
+        let mut spare_blocks = allocations[unmapped_blocks.len()..].iter().copied();
 
-        for (data_chunk, logical_blk_num, offset, allocation) in operations{
+        for (data_chunk, logical_blk_num, mut physical_block, offset, allocation) in operations{
             if let Some(allocation_idx) = allocation {
-                    file.pointers[logical_blk_num] = allocations[allocation_idx] as u32;
+                physical_block = allocations[allocation_idx];
+                self.set_block_pointer(file, logical_blk_num, physical_block, &mut spare_blocks)?;
             }
 
-            self.write_file_chunk(file, logical_blk_num, offset, data_chunk)?;
+            self.write_file_chunk(file, logical_blk_num, physical_block as usize, offset, data_chunk)?;
         }
 
-        file.size = file.size.max(file_ptr as u32);
+        file.size = file.size.max(file_ptr as u64);
 
         Ok(total_byte_writes_queued)
     }
 
-    fn read_file_chunk(&self, file: &FSINode, block_num_in_file: usize, offset: usize, mut dest: &mut [u8]){
-        let disk_blknum = file.pointers[block_num_in_file] as usize;
+    fn read_file_chunk(&self, file: &FSINode, block_num_in_file: usize, offset: usize, mut dest: &mut [u8]) -> std::io::Result<()> {
+        let disk_blknum = self.get_block_pointer(file, block_num_in_file)? as usize;
 
         if disk_blknum == 0 { // Handle sparse/unallocated blocks
             dest.fill(0);
-            return;
+            return Ok(());
         }
 
         let len = dest.len();
 
-        if offset + len > FS_BLOCK_SIZE {
+        if offset + len > self.block_size {
             panic!("Tried reading off end of file chunk");
         }
 
+        self.hint_file_block(file, block_num_in_file);
         if offset == 0 && dest.len() == self.block_system.block_size() {
-            self.block_system.block_read_in_place(&mut dest, disk_blknum).unwrap();
+            self.block_system.block_read_in_place(&mut dest, disk_blknum)?;
+            return Ok(());
         }
 
-        let blk = self.block_system.block_read(disk_blknum).unwrap();
-        dest.copy_from_slice(&blk[offset..(offset+dest.len())])
+        let blk = self.block_system.block_read(disk_blknum)?;
+        dest.copy_from_slice(&blk[offset..(offset+dest.len())]);
+        Ok(())
     }
 
-    fn read_file_bytes_in_place(&self, file: &FSINode, offset: usize, dest: &mut [u8]) -> usize {
+    fn read_file_bytes_in_place(&self, file: &FSINode, offset: usize, dest: &mut [u8]) -> std::io::Result<usize> {
         let len = if (dest.len() + offset) > file.size as usize {
-            file.size as usize - offset
+            (file.size as usize).saturating_sub(offset)
         } else {
             dest.len()
         };
@@ -177,435 +688,899 @@ impl <BF: BlockFile>  LearnedFileSystem<BF> {
         let mut total_num_read = 0;
 
         while total_num_read < len {
-            let block_num = file_ptr / FS_BLOCK_SIZE;
-            let block_offset = file_ptr % FS_BLOCK_SIZE;
+            let block_num = file_ptr / self.block_size;
+            let block_offset = file_ptr % self.block_size;
 
-            let read_length = if (FS_BLOCK_SIZE - block_offset) > (len - total_num_read) {
+            let read_length = if (self.block_size - block_offset) > (len - total_num_read) {
                 len - total_num_read
             } else {
-                FS_BLOCK_SIZE - block_offset
+                self.block_size - block_offset
             };
 
-            self.read_file_chunk(file, block_num, block_offset, &mut dest[total_num_read..(total_num_read + read_length)]);
+            self.read_file_chunk(file, block_num, block_offset, &mut dest[total_num_read..(total_num_read + read_length)])?;
             total_num_read += read_length;
             file_ptr += read_length;
         }
 
-        total_num_read
+        Ok(total_num_read)
     }
 
-    fn read_file_bytes(&self, file: &FSINode, offset: usize, len: usize) -> Vec<u8> {
+    fn read_file_bytes(&self, file: &FSINode, offset: usize, len: usize) -> std::io::Result<Vec<u8>> {
         let mut dest = vec![0u8; len];
-        let num_read = self.read_file_bytes_in_place(file, offset, &mut dest);
+        let num_read = self.read_file_bytes_in_place(file, offset, &mut dest)?;
         dest.truncate(num_read);
-        dest
+        Ok(dest)
+    }
+
+    /// Write back the superblock with the free counts and the write time brought up to date
+    fn write_superblock(&mut self, mut super_block: FsSuperBlock) -> std::io::Result<()> {
+        super_block.free_blocks_count = self.block_allocation_bitmask.num_free_indices() as u32;
+        super_block.free_inodes_count = match self.inode_allocation_bitmask.as_ref() {
+            Some(inode_bitmask) => inode_bitmask.num_free_indices() as u32,
+            None => super_block.free_blocks_count,
+        };
+        super_block.last_write_time = get_time().sec as u32;
+        let super_block_data: Vec<u8> = super_block.into();
+        self.block_system.block_write(&super_block_data, self.super_block_index)?;
+        Ok(())
+    }
+
+    /// Bring a superblock from an older version up to date, as written on the next mount
+    fn upgrade_superblock(&self, super_block: &mut FsSuperBlock) -> std::io::Result<()> {
+        if super_block.version < FS_VERSION {
+            super_block.version = FS_VERSION;
+            super_block.block_size = self.block_size as u32;
+            if super_block.uuid == [0u8; 16] {
+                super_block.uuid = generate_uuid()?;
+            }
+        }
+        Ok(())
     }
 
     fn get_superblock(&self) -> std::io::Result<FsSuperBlock>{
         Ok(FsSuperBlock::from(self.block_system.block_read(0)?.as_slice()))
     }
 
+    /// Block holding an inode, and the offset of the inode inside it. Fails for inode
+    /// numbers past the inode table, or past the device on images without one, which only
+    /// a damaged directory entry can hold
+    fn inode_location(&self, inode: u64) -> std::io::Result<(usize, usize)> {
+        let num_inodes = match self.inode_allocation_bitmask.as_ref() {
+            Some(inode_bitmask) => inode_bitmask.num_indices(),
+            None => self.block_allocation_bitmask.num_indices(),
+        };
+        if inode >= num_inodes as u64 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("inode {} out of range", inode)));
+        }
+        if self.inode_allocation_bitmask.is_none() {
+            return Ok((inode as usize, 0));
+        }
+        let byte_offset = inode as usize * self.inode_size;
+        Ok((self.inode_table_start_block + byte_offset / self.block_size, byte_offset % self.block_size))
+    }
+
     fn get_inode(&self, inode: u64) -> std::io::Result<FSINode>{
-        Ok(FSINode::from(self.block_system.block_read(inode as usize)?.as_slice()))
+        let (block, offset) = self.inode_location(inode)?;
+        self.block_system.hint_access(AccessHint::inode(inode));
+        let inode_block = self.block_system.block_read(block)?;
+        Ok(FSINode::from(&inode_block[offset..(offset + self.inode_size)]))
+    }
+
+    fn write_inode(&mut self, inode: u64, node: FSINode) -> std::io::Result<()>{
+        let (block, offset) = self.inode_location(inode)?;
+        let inode_data: Vec<u8> = node.into();
+        self.block_system.hint_access(AccessHint::inode(inode));
+        if inode_data.len() == self.block_size {
+            self.block_system.block_write(&inode_data, block)?;
+        } else {
+            let mut inode_block = self.block_system.block_read(block)?;
+            inode_block[offset..(offset + inode_data.len())].copy_from_slice(&inode_data);
+            self.block_system.hint_access(AccessHint::inode(inode));
+            self.block_system.block_write(&inode_block, block)?;
+        }
+        Ok(())
+    }
+
+    /// Fresh inode with the given mode, laid out the way the image's features ask for
+    pub(crate) fn new_inode(&self, mode: u32, uid: u16, gid: u16) -> FSINode {
+        let now_sec = get_time().sec;
+
+        let mut new_inode = FSINode {
+            size: 0,
+            uid,
+            gid,
+            mode,
+            flags: 0,
+            ctime: now_sec as u32,
+            mtime: now_sec as u32,
+            ..FSINode::empty(self.inode_size)
+        };
+
+        if self.use_extents {
+            new_inode.flags |= INODE_FLAG_EXTENTS;
+            new_inode.set_extent_root(&ExtentNode::empty_leaf());
+        }
+
+        if self.use_var_dirents && dirent_type_of_mode(mode) == DIRENT_TYPE_DIRECTORY {
+            new_inode.flags |= INODE_FLAG_VAR_DIRENTS;
+        }
+        new_inode
+    }
+
+    /// Reserve an inode number. Without an inode table this allocates the block the inode lives in.
+    fn allocate_inode(&mut self) -> std::io::Result<u64>{
+        let inode_bitmask = match self.inode_allocation_bitmask.as_mut() {
+            Some(inode_bitmask) => inode_bitmask,
+            None => return Ok(self.allocate_blocks(1)?[0] as u64),
+        };
+
+        let inode = inode_bitmask.free_block_iter().next().ok_or(Error::from(OutOfMemory))?;
+        inode_bitmask.set_bit(inode);
+        self.write_inode(inode as u64, FSINode::empty(self.inode_size))?;
+        self.write_dirty_bitmask_blocks()?;
+        Ok(inode as u64)
+    }
+
+    fn free_inode(&mut self, inode: u64) -> std::io::Result<()>{
+        let inode_bitmask = match self.inode_allocation_bitmask.as_mut() {
+            Some(inode_bitmask) => inode_bitmask,
+            None => {
+                if let Some(prefetcher) = self.prefetcher.as_mut() {
+                    prefetcher.forget_inode(inode);
+                }
+                return self.free_blocks(&[inode as u32]);
+            }
+        };
+
+        if inode_bitmask.is_free(inode as u32) {return Err(Error::from(Other));}
+        inode_bitmask.clear_bit(inode as u32);
+        if let Some(prefetcher) = self.prefetcher.as_mut() {
+            prefetcher.forget_inode(inode);
+        }
+        self.write_dirty_bitmask_blocks()
     }
 
     fn allocate_blocks(&mut self, num_blocks: usize) -> std::io::Result<Vec<u32>>{
-        let first_n_blocks : Vec<u32> = self.block_allocation_bitmask.free_block_iter().take(num_blocks).collect();
+        self.allocate_blocks_near(num_blocks, 0)
+    }
+
+    /// Allocate the first `num_blocks` free blocks at or after `goal`, so that blocks
+    /// allocated together end up as contiguous as the free space allows. The allocation
+    /// policy may replace the goal.
+    fn allocate_blocks_near(&mut self, num_blocks: usize, goal: u32) -> std::io::Result<Vec<u32>>{
+        let goal = match self.alloc_policy {
+            AllocPolicy::Goal => goal,
+            AllocPolicy::FirstFit => 0,
+            AllocPolicy::NextFit => self.next_fit_goal,
+        };
+        let first_n_blocks : Vec<u32> = self.block_allocation_bitmask.free_block_iter_from(goal).take(num_blocks).collect();
         if first_n_blocks.len() == num_blocks {
             for block in first_n_blocks.iter(){
                 self.block_allocation_bitmask.set_bit(*block);
-                self.block_system.block_write(&[0;FS_BLOCK_SIZE], *block as usize)?;
+                self.block_system.block_allocated(*block as usize);
+                self.block_system.block_write(vec![0; self.block_size], *block as usize)?;
+            }
+            self.write_dirty_bitmask_blocks()?;
+            if let Some(last_block) = first_n_blocks.last() {
+                self.next_fit_goal = last_block + 1;
             }
-            self.block_system.block_write(&self.block_allocation_bitmask, self.bit_mask_block_index)?;
             Ok(first_n_blocks)
         } else{
             Err(Error::from(OutOfMemory))
         }
     }
 
-    fn get_dirents_incl_gaps(&self, block_info: &FSINode) -> Vec<Result<DirectoryEntry, ()>>{
-        let dir_contents = self.read_file_bytes(&block_info, 0, block_info.size as usize);
-        dir_contents.chunks_exact(32).map(DirectoryEntry::try_from).collect()
+    fn get_dirents_incl_gaps(&self, block_info: &FSINode) -> std::io::Result<Vec<DirentSlot>>{
+        let dir_contents = self.read_file_bytes(&block_info, 0, block_info.size as usize)?;
+        Ok(self.parse_dirents(block_info, &dir_contents))
     }
 
-    fn get_valid_dirents(&self, block_info: &FSINode) -> Vec<DirectoryEntry>{
-        self.get_dirents_incl_gaps(block_info).into_iter().filter_map(Result::ok).collect()
+    /// Split the contents of a directory into its entries
+    fn parse_dirents(&self, block_info: &FSINode, dir_contents: &[u8]) -> Vec<DirentSlot>{
+        if block_info.has_var_dirents() {
+            return dir_contents.chunks(self.block_size)
+                .enumerate()
+                .flat_map(|(block_idx, block)| parse_var_dirent_block(block, block_idx * self.block_size))
+                .collect();
+        }
+
+        dir_contents.chunks_exact(FIXED_DIRENT_SIZE)
+            .enumerate()
+            .map(|(idx, dirent)| DirentSlot {
+                offset: idx * FIXED_DIRENT_SIZE,
+                rec_len: FIXED_DIRENT_SIZE,
+                entry: DirectoryEntry::try_from(dirent),
+            })
+            .collect()
     }
 
-    fn find_dirent_in_list(&self, dirents_incl_gaps: &Vec<Result<DirectoryEntry, ()>>, name: &OsStr) -> Option<(usize, DirectoryEntry)>{
+    fn get_valid_dirents(&self, block_info: &FSINode) -> std::io::Result<Vec<DirectoryEntry>>{
+        Ok(self.get_dirents_incl_gaps(block_info)?.into_iter().filter_map(|slot| slot.entry.ok()).collect())
+    }
+
+    fn find_dirent_in_list(&self, dirents_incl_gaps: &[DirentSlot], name: &OsStr) -> Option<(usize, DirectoryEntry)>{
         dirents_incl_gaps.iter()
             .enumerate()
-            .filter_map(|(idx, de)| de.clone().map(|de| (idx, de)).ok())
-            .find(|(idx, de)| de.name == name)
+            .filter_map(|(idx, slot)| slot.entry.clone().map(|de| (idx, de)).ok())
+            .find(|(_, de)| de.name == name)
     }
 
-    fn first_free_dirent_idx(&self, dirent_incl_gaps: &Vec<Result<DirectoryEntry, ()>>) -> usize {
-        dirent_incl_gaps.iter().enumerate().find(|(_, de)| de.is_err()).map(|(idx, _)| idx).unwrap_or(dirent_incl_gaps.len())
-    }
+    /// Look up a name in a directory. Returns the slots that were searched along with the
+    /// index of the matching slot, for use with `remove_dirent` and friends. Indexed
+    /// directories only search the blocks the name hashes to, unless the index is unreadable.
+    fn find_dirent(&self, dir: &FSINode, name: &OsStr) -> std::io::Result<DirentLookup> {
+        if dir.has_htree_index() {
+            match self.htree_find_dirent(dir, name) {
+                Ok(result) => return Ok(result),
+                Err(_) => debug!("Unreadable directory index, falling back to a linear scan"),
+            }
+        }
 
-    fn truncate_to_num_blocks(&mut self, node: &mut FSINode, num_blocks: u32) -> std::io::Result<()> {
-        let mut blocks_to_dealloc = vec![];
-        for block_ptr in node.pointers.iter_mut().skip(num_blocks as usize){
-            if *block_ptr != 0 {
-                blocks_to_dealloc.push(*block_ptr);
-                *block_ptr = 0;
+        if dir.has_learned_index() {
+            match self.learned_find_dirent(dir, name) {
+                Ok(result) => return Ok(result),
+                Err(_) => debug!("Unreadable directory model, falling back to a linear scan"),
             }
         }
 
-        self.free_blocks(&blocks_to_dealloc)
+        let dirents_incl_gaps = self.get_dirents_incl_gaps(dir)?;
+        let found = self.find_dirent_in_list(&dirents_incl_gaps, name);
+        Ok((dirents_incl_gaps, found))
     }
 
-    fn do_unlink(&mut self, _parent: u64, _name: &OsStr, is_dir: bool) -> Result<(), c_int> {
-        let _parent = translate_inode(_parent);
-
-        let mut old_parent_info = self.get_inode(_parent).unwrap();
-        let old_parent_dirents = self.get_dirents_incl_gaps(&old_parent_info);
-
-        match self.find_dirent_in_list(&old_parent_dirents, _name) {
-            Some((old_de_idx, mut dirent)) => {
-                let mut blk_info = self.get_inode(dirent.inode_ptr as u64).unwrap();
+    /// Inode of an absolute path within the image
+    pub fn lookup_path(&self, path: &str) -> std::io::Result<u64> {
+        let mut inode = ROOT_INODE;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let dir = self.get_inode(inode)?;
+            if dirent_type_of_mode(dir.mode) != DIRENT_TYPE_DIRECTORY {
+                return Err(Error::new(ErrorKind::NotADirectory, format!("{}: not a directory", path)));
+            }
+            let (_, found) = self.find_dirent(&dir, OsStr::new(name))?;
+            inode = found.ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{}: no such file or directory", path)))?.1.inode_ptr as u64;
+        }
+        Ok(inode)
+    }
 
-                if is_dir {
-                    if blk_info.to_fileattr(_parent).kind != Directory {
-                        return Result::Err(ENOTDIR)
-                    }
+    /// Longest name that can be stored in the directory
+    fn max_name_len(&self, dir: &FSINode) -> usize {
+        if dir.has_var_dirents() { NAME_MAX } else { FIXED_NAME_MAX }
+    }
 
-                    if self.get_valid_dirents(&blk_info).len() > 0 {
-                        return Result::Err(ENOTEMPTY);
-                    }
-                } else {
-                    if blk_info.to_fileattr(_parent).kind == Directory {
-                        return Result::Err(EISDIR);
-                    }
-                }
+    /// Index of the first slot with room for a record of `needed_len` bytes. For
+    /// variable-length directories that includes the unused tail of a live record.
+    fn first_free_dirent_idx(&self, dir: &FSINode, dirent_incl_gaps: &[DirentSlot], needed_len: usize) -> Option<usize> {
+        dirent_incl_gaps.iter().position(|slot| match &slot.entry {
+            Err(()) => slot.rec_len >= needed_len,
+            Ok(de) => dir.has_var_dirents() && slot.rec_len - var_dirent_len(de.name.len()) >= needed_len,
+        })
+    }
 
-                self.truncate_to_num_blocks(&mut blk_info, 0).map_err(translate_io_error)?;
+    /// Add an entry to a directory, reusing free space where possible.
+    /// NOTE: Does not write back the directory inode itself
+    fn insert_dirent(&mut self, dir: &mut FSINode, dirent_incl_gaps: &[DirentSlot], dirent: DirectoryEntry) -> std::io::Result<()> {
+        if !dir.has_var_dirents() {
+            let idx = self.first_free_dirent_idx(dir, dirent_incl_gaps, FIXED_DIRENT_SIZE).unwrap_or(dirent_incl_gaps.len());
+            let dirent_data: Vec<u8> = dirent.into();
+            self.write_file_data(dir, idx * FIXED_DIRENT_SIZE, &dirent_data)?;
+            return Ok(());
+        }
 
-                self.free_blocks(&vec![dirent.inode_ptr as u32]).map_err(translate_io_error)?;
+        if dir.has_htree_index() {
+            return self.htree_insert_dirent(dir, dirent);
+        }
 
-                self.write_file_data(&mut old_parent_info, old_de_idx * 32, &[0u8; 32]).map_err(translate_io_error)?;
+        if dir.has_learned_index() {
+            return self.learned_insert_dirent(dir, dirent);
+        }
 
-                let parent_inode_data: Vec<u8> = old_parent_info.into();
-                self.block_system.block_write(&parent_inode_data, _parent as usize).map_err(translate_io_error)?;
-                Ok(())
-            },
+        let needed_len = var_dirent_len(dirent.name.len());
+        match self.first_free_dirent_idx(dir, dirent_incl_gaps, needed_len) {
+            Some(idx) => self.insert_into_var_slot(dir, &dirent_incl_gaps[idx], dirent),
+            None if self.use_learned_index && dir.size >= self.block_size as u64 => {
+                self.learned_index_build(dir)?;
+                self.learned_insert_dirent(dir, dirent)
+            }
+            None if self.use_dir_index && dir.size >= self.block_size as u64 => {
+                self.htree_convert(dir)?;
+                self.htree_insert_dirent(dir, dirent)
+            }
             None => {
-                Err(ENOENT)
+                let block_start = div_ceil(dir.size as usize, self.block_size) * self.block_size;
+                self.write_file_data(dir, block_start, &dirent.to_var_record(self.block_size))?;
+                Ok(())
             }
         }
     }
-}
-
-//Main Implementations of the File System for LearnedFileSystem
 
-impl <BF : BlockFile> Filesystem for LearnedFileSystem<BF> {
+    /// Put a variable-length record into a free slot, or into the unused tail of a live one.
+    /// NOTE: Does not write back the directory inode itself
+    fn insert_into_var_slot(&mut self, dir: &mut FSINode, slot: &DirentSlot, dirent: DirectoryEntry) -> std::io::Result<()> {
+        let record = match &slot.entry {
+            Err(()) => dirent.to_var_record(slot.rec_len),
+            Ok(existing) => {
+                // Shrink the live record to its minimum and put the new one in the space after it
+                let existing_len = var_dirent_len(existing.name.len());
+                let mut record = existing.to_var_record(existing_len);
+                record.extend(dirent.to_var_record(slot.rec_len - existing_len));
+                record
+            }
+        };
+        self.write_file_data(dir, slot.offset, &record)?;
+        Ok(())
+    }
 
-    fn init(&mut self, _req: &fuse::Request) -> Result<(), c_int> {
-        let super_block = self.get_superblock().map_err(|e| translate_error(e.kind()))?;
-        if super_block.magic != FS_MAGIC_NUM {return Err(-1)};
+    /// Remove the entry in slot `idx`. In variable-length directories the freed record is
+    /// merged into the record before it in the same block.
+    /// NOTE: Does not write back the directory inode itself
+    fn remove_dirent(&mut self, dir: &mut FSINode, dirent_incl_gaps: &[DirentSlot], idx: usize) -> std::io::Result<()> {
+        let slot = &dirent_incl_gaps[idx];
+        if !dir.has_var_dirents() {
+            self.write_file_data(dir, slot.offset, &[0u8; FIXED_DIRENT_SIZE])?;
+            return Ok(());
+        }
 
-        let bitmask_block = self.block_system.block_read(self.bit_mask_block_index).map_err(|e| translate_error(e.kind()))?;
-        self.block_allocation_bitmask = BitMaskBlock::new(super_block.disk_size as usize, &bitmask_block);
+        let prev_in_block = idx.checked_sub(1)
+            .map(|prev_idx| &dirent_incl_gaps[prev_idx])
+            .filter(|prev| prev.offset / self.block_size == slot.offset / self.block_size);
 
+        match prev_in_block {
+            Some(prev) => {
+                let merged_len = rec_len_to_disk(prev.rec_len + slot.rec_len);
+                self.write_file_data(dir, prev.offset + 4, &merged_len.to_le_bytes())?;
+            }
+            None => {
+                self.write_file_data(dir, slot.offset, &free_var_record(slot.rec_len))?;
+            }
+        }
         Ok(())
     }
 
-    fn lookup(&mut self, _req: &fuse::Request, _parent: u64, _name: &std::ffi::OsStr, reply: fuse::ReplyEntry) {
-        let _ino = translate_inode(_parent);
+    /// Give the entry in slot `idx` a new name, in place if the record has room for it.
+    /// Entries of indexed directories always move, since the new name belongs elsewhere.
+    /// NOTE: Does not write back the directory inode itself
+    fn rename_dirent(&mut self, dir: &mut FSINode, dirent_incl_gaps: &[DirentSlot], idx: usize, dirent: DirectoryEntry) -> std::io::Result<()> {
+        let slot = &dirent_incl_gaps[idx];
+        if !dir.has_var_dirents() {
+            let dirent_data: Vec<u8> = dirent.into();
+            self.write_file_data(dir, slot.offset, &dirent_data)?;
+            return Ok(());
+        }
 
-        let block_info = self.get_inode(_ino).unwrap();
-        if let Some((_, dirent)) = self.find_dirent_in_list(&self.get_dirents_incl_gaps(&block_info), _name){
-            let element_block_info = FSINode::from(self.block_system.block_read(dirent.inode_ptr as usize).unwrap().as_slice());
-            debug!("Response: {:?}", element_block_info.to_fileattr(dirent.inode_ptr as u64));
-            reply.entry(&in_one_sec(), &element_block_info.to_fileattr(dirent.inode_ptr as u64), 0);
-        } else{
-            reply.error(ENOENT);
+        if !dir.has_htree_index() && !dir.has_learned_index() && var_dirent_len(dirent.name.len()) <= slot.rec_len {
+            self.write_file_data(dir, slot.offset, &dirent.to_var_record(slot.rec_len))?;
+            return Ok(());
         }
+
+        self.remove_dirent(dir, dirent_incl_gaps, idx)?;
+        let dirent_incl_gaps = self.get_dirents_incl_gaps(dir)?;
+        self.insert_dirent(dir, &dirent_incl_gaps, dirent)
     }
 
-    fn getattr(&mut self, _req: &fuse::Request, orig_ino: u64, reply: fuse::ReplyAttr) {
-        let _ino = translate_inode(orig_ino);
-        let block_info = self.get_inode(_ino).unwrap();
+    fn truncate_to_num_blocks(&mut self, node: &mut FSINode, num_blocks: u32) -> std::io::Result<()> {
+        if node.is_extent_mapped() {
+            return self.truncate_extents(node, num_blocks);
+        }
+
+        let num_blocks = num_blocks as usize;
+        let mut blocks_to_dealloc = vec![];
+        for block_ptr in node.pointers.iter_mut().skip(num_blocks){
+            if *block_ptr != 0 {
+                blocks_to_dealloc.push(*block_ptr);
+                *block_ptr = 0;
+            }
+        }
+
+        let single_indirect_start = node.pointers.len();
+        if node.single_indirect != 0 {
+            let keep = num_blocks.saturating_sub(single_indirect_start);
+            if self.truncate_indirect_block(node.single_indirect, keep, &mut blocks_to_dealloc)? {
+                node.single_indirect = 0;
+            }
+        }
+
+        let pointers_per_block = pointers_per_block(self.block_size);
+        let double_indirect_start = single_indirect_start + pointers_per_block;
+        if node.double_indirect != 0 {
+            let mut outer_pointers = self.read_indirect_block(node.double_indirect)?;
+            let mut outer_changed = false;
+            for (outer_idx, outer_ptr) in outer_pointers.iter_mut().enumerate() {
+                let start = double_indirect_start + outer_idx * pointers_per_block;
+                if *outer_ptr == 0 || start + pointers_per_block <= num_blocks {
+                    continue;
+                }
+                if self.truncate_indirect_block(*outer_ptr, num_blocks.saturating_sub(start), &mut blocks_to_dealloc)? {
+                    *outer_ptr = 0;
+                    outer_changed = true;
+                }
+            }
+
+            if num_blocks <= double_indirect_start {
+                blocks_to_dealloc.push(node.double_indirect);
+                node.double_indirect = 0;
+            } else if outer_changed {
+                self.write_indirect_block(node.double_indirect, &outer_pointers)?;
+            }
+        }
 
-        debug!("Response: {:?}", block_info.to_fileattr(orig_ino));
-        reply.attr(&in_one_sec(), &block_info.to_fileattr(orig_ino))
+        self.free_blocks(&blocks_to_dealloc)
     }
 
-    fn mknod(&mut self, _req: &Request, _orig_parent: u64, _name: &OsStr, _mode: u32, _rdev: u32, reply: ReplyEntry) {
-        self.mkdir(_req, _orig_parent, _name, _mode, reply) // Dir vs file is controlled by _mode
+    /// Queue every pointer of an indirect block from index `keep` onwards for deallocation.
+    /// If nothing is kept the indirect block itself is queued as well and true is returned.
+    fn truncate_indirect_block(&mut self, block: u32, keep: usize, blocks_to_dealloc: &mut Vec<u32>) -> std::io::Result<bool> {
+        let mut pointers = self.read_indirect_block(block)?;
+        let mut changed = false;
+        for ptr in pointers.iter_mut().skip(keep) {
+            if *ptr != 0 {
+                blocks_to_dealloc.push(*ptr);
+                *ptr = 0;
+                changed = true;
+            }
+        }
+
+        if keep == 0 {
+            blocks_to_dealloc.push(block);
+            Ok(true)
+        } else {
+            if changed {
+                self.write_indirect_block(block, &pointers)?;
+            }
+            Ok(false)
+        }
     }
 
-    fn setattr(&mut self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+    fn do_setattr(&mut self, _ino: u64, request: SetattrRequest) -> Result<FileAttr, c_int> {
+        if self.read_only {
+            return Err(EROFS);
+        }
         let _ino = translate_inode(_ino);
+        // Inodes only have room for 16 bit ids
+        if request.uid.is_some_and(|uid| uid > u16::MAX as u32) || request.gid.is_some_and(|gid| gid > u16::MAX as u32) {
+            return Err(EINVAL);
+        }
 
-        let mut block_info = self.get_inode(_ino).unwrap();
+        let mut block_info = self.get_inode(_ino).map_err(translate_io_error)?;
 
-        if let Some(newmode) = _mode{
+        if let Some(newmode) = request.mode {
             debug!("Setting mode {newmode:o}");
             block_info.mode = newmode;
         }
 
-        if let Some(newuid) = _uid {
+        if let Some(newuid) = request.uid {
             debug!("Setting uid {newuid}");
             block_info.uid = newuid as u16;
         }
 
-        if let Some(newgid) = _gid {
+        if let Some(newgid) = request.gid {
             debug!("Setting gid {newgid}");
             block_info.gid = newgid as u16;
         }
 
-        if let Some(newsize) = _size {
+        if let Some(newsize) = request.size {
             debug!("Changing size from {} to {newsize}", block_info.size);
             // If newsize is large, we don't need to worry since we'll just get a sparse file.
             // Subsequent reads will just return 0s for those indices
-            if newsize < block_info.size as u64 {
-                let new_num_blocks = div_ceil(newsize, FS_BLOCK_SIZE as u64);
-                if let Err(e) = self.truncate_to_num_blocks(&mut block_info, new_num_blocks as u32).map_err(|e| translate_error(e.kind())){
-                    reply.error(e);
-                    return;
-                }
+            let new_num_blocks = div_ceil(newsize, self.block_size as u64);
+            if new_num_blocks > block_info.max_file_blocks(self.block_size) as u64 {
+                return Err(EFBIG);
+            }
+            if newsize < block_info.size {
+                self.truncate_to_num_blocks(&mut block_info, new_num_blocks as u32).map_err(translate_io_error)?;
             }
-            block_info.size = newsize as u32;
+            block_info.size = newsize;
         }
 
-        if let Some(new_mtime) = _mtime{
+        if let Some(new_mtime) = request.mtime {
             debug!("Changing mtime {}", new_mtime.sec);
             block_info.mtime = new_mtime.sec as u32;
         }
 
-        if let Some(new_ctime) = _chgtime {
+        if let Some(new_ctime) = request.chgtime {
             debug!("Changing ctime {}", new_ctime.sec);
             block_info.ctime = new_ctime.sec as u32;
         }
 
-        let newattr = block_info.to_fileattr(_ino);
+        let newattr = self.file_attr(_ino, &block_info).map_err(translate_io_error)?;
+
+        self.write_inode(_ino, block_info).map_err(translate_io_error)?;
+        Ok(newattr)
+    }
 
-        let blkdata: Vec<u8> = block_info.into();
 
-        if let Err(e) = self.block_system.block_write(&blkdata, _ino as usize){
-            reply.error(translate_error(e.kind()));
-            return;
+    fn do_rename(&mut self, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr) -> Result<(), c_int> {
+        if self.read_only {
+            return Err(EROFS);
+        }
+        let parent_ino = translate_inode(_parent);
+        let new_parent_ino = translate_inode(_newparent);
+
+        let mut old_parent_info = self.get_inode(parent_ino).map_err(translate_io_error)?;
+        let (old_parent_dirents, found) = self.find_dirent(&old_parent_info, _name).map_err(translate_io_error)?;
+
+        match found {
+            Some((old_de_idx, mut dirent)) => {
+                dirent.name = OsString::from(_newname);
+
+                if new_parent_ino == parent_ino {
+                    if _newname == _name {
+                        return Ok(());
+                    }
+
+                    if _newname.as_bytes().len() > self.max_name_len(&old_parent_info) {
+                        return Err(ENAMETOOLONG);
+                    }
+
+                    self.rename_dirent(&mut old_parent_info, &old_parent_dirents, old_de_idx, dirent).map_err(translate_io_error)?;
+
+                    self.write_inode(parent_ino, old_parent_info).map_err(translate_io_error)?;
+
+                    Ok(())
+                } else {
+                    let mut new_parent_info = self.get_inode(new_parent_ino).map_err(translate_io_error)?;
+                    if _newname.as_bytes().len() > self.max_name_len(&new_parent_info) {
+                        return Err(ENAMETOOLONG);
+                    }
+
+                    let (new_parent_dirents, existing) = self.find_dirent(&new_parent_info, _newname).map_err(translate_io_error)?;
+
+                    if existing.is_some() {
+                        return Err(EEXIST);
+                    }
+
+
+                    self.remove_dirent(&mut old_parent_info, &old_parent_dirents, old_de_idx).map_err(translate_io_error)?;
+
+                    self.write_inode(parent_ino, old_parent_info).map_err(translate_io_error)?;
+
+                    self.insert_dirent(&mut new_parent_info, &new_parent_dirents, dirent).map_err(translate_io_error)?;
+
+                    self.write_inode(new_parent_ino, new_parent_info).map_err(translate_io_error)?;
+                    Ok(())
+                }
+            }
+            None => Err(ENOENT)
         }
-        reply.attr(&in_one_sec(), &newattr);
     }
 
-    fn mkdir(&mut self, _req: &Request, _orig_parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
-        let _parent = translate_inode(_orig_parent);
-        if _name.as_bytes().len() > 27 {
-            reply.error(ENAMETOOLONG);
-            return;
+
+    fn do_write(&mut self, _orig_ino: u64, _offset: i64, _data: &[u8]) -> Result<usize, c_int> {
+        if self.read_only {
+            return Err(EROFS);
         }
+        let _ino = translate_inode(_orig_ino);
 
-        let mut parent_inode = self.get_inode(_parent).unwrap();
-        let parent_dirents = self.get_dirents_incl_gaps(&parent_inode);
-        let first_free_parent_dirent_idx = self.first_free_dirent_idx(&parent_dirents);
+        let mut block_info = self.get_inode(_ino).map_err(translate_io_error)?;
 
-        if let Some(_) = self.find_dirent_in_list(&parent_dirents, _name) {
-            reply.error(EEXIST);
-            return;
+        let bytes_written = self.write_file_data(&mut block_info, _offset as usize, _data).map_err(translate_io_error)?;
+        self.write_inode(_ino, block_info).map_err(translate_io_error)?;
+        Ok(bytes_written)
+    }
+
+    fn do_unlink(&mut self, _parent: u64, _name: &OsStr, is_dir: bool) -> Result<(), c_int> {
+        if self.read_only {
+            return Err(EROFS);
         }
+        let _parent = translate_inode(_parent);
 
-        match self.allocate_blocks(1){
-            Ok(newdir_blocks) => {
+        let mut old_parent_info = self.get_inode(_parent).map_err(translate_io_error)?;
+        let (old_parent_dirents, found) = self.find_dirent(&old_parent_info, _name).map_err(translate_io_error)?;
 
-                let newdir_inode_blknum = newdir_blocks[0];
-                let now_sec = get_time().sec;
+        match found {
+            Some((old_de_idx, mut dirent)) => {
+                let mut blk_info = self.get_inode(dirent.inode_ptr as u64).map_err(translate_io_error)?;
 
-                let new_inode = FSINode {
-                    pointers: [0u32; NUM_POINTERS],
-                    size: 0,
-                    uid: _req.uid() as u16,
-                    gid: _req.gid() as u16,
-                    mode: _mode,
-                    ctime: now_sec as u32,
-                    mtime: now_sec as u32
-                };
+                if is_dir {
+                    if blk_info.file_type() != Directory {
+                        return Result::Err(ENOTDIR)
+                    }
 
-                let ino_data: Vec<u8> = new_inode.clone().into();
-                if let Err(e) = self.block_system.block_write(&ino_data, newdir_inode_blknum as usize){
-                    reply.error(translate_error(e.kind()));
-                    return;
+                    if !self.get_valid_dirents(&blk_info).map_err(translate_io_error)?.is_empty() {
+                        return Result::Err(ENOTEMPTY);
+                    }
+                } else {
+                    if blk_info.file_type() == Directory {
+                        return Result::Err(EISDIR);
+                    }
                 }
 
-                let dirent = DirectoryEntry{
-                    inode_ptr: newdir_inode_blknum as u32,
-                    name: OsString::from(_name)
-                };
+                self.truncate_to_num_blocks(&mut blk_info, 0).map_err(translate_io_error)?;
 
-                let dirent_data: Vec<u8> = dirent.into();
-                if let Err(e) = self.write_file_data(&mut parent_inode, first_free_parent_dirent_idx*32, &dirent_data){
-                    reply.error(translate_error(e.kind()));
-                    return;
-                }
+                self.free_inode(dirent.inode_ptr as u64).map_err(translate_io_error)?;
 
-                let parent_inode_data : Vec<u8> = parent_inode.into();
-                if let Err(e) = self.block_system.block_write(&parent_inode_data, _parent as usize){
-                    reply.error(translate_error(e.kind()));
-                    return;
-                }
-                debug!("New file: {:?}", new_inode.to_fileattr(newdir_inode_blknum as u64));
-                reply.entry(&in_one_sec(), &new_inode.to_fileattr(newdir_inode_blknum as u64), 0)
-            }
-            Err(e) => {
-                reply.error(translate_error(e.kind()))
+                self.remove_dirent(&mut old_parent_info, &old_parent_dirents, old_de_idx).map_err(translate_io_error)?;
+
+                self.write_inode(_parent, old_parent_info).map_err(translate_io_error)?;
+                Ok(())
+            },
+            None => {
+                Err(ENOENT)
             }
         }
     }
 
-    fn rename(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, reply: ReplyEmpty) {
-        let parent_ino = translate_inode(_parent);
-        let new_parent_ino = translate_inode(_newparent);
+    /// Create an empty file or directory called `name` in `parent` and link it in
+    /// NOTE: Writes back both the new inode and the parent
+    fn create_inode(&mut self, parent: u64, name: &OsStr, mode: u32, uid: u16, gid: u16) -> std::io::Result<(u64, FSINode)> {
+        let mut parent_inode = self.get_inode(parent)?;
+        if name.as_bytes().len() > self.max_name_len(&parent_inode) {
+            return Err(Error::from(ErrorKind::InvalidFilename));
+        }
 
-        if _newname.as_bytes().len() > 27 {
-            reply.error(ENAMETOOLONG);
-            return;
+        let (parent_dirents, existing) = self.find_dirent(&parent_inode, name)?;
+        if existing.is_some() {
+            return Err(Error::from(ErrorKind::AlreadyExists));
         }
 
-        let mut old_parent_info = self.get_inode(parent_ino).unwrap();
-        let old_parent_dirents = self.get_dirents_incl_gaps(&old_parent_info);
+        let inode = self.allocate_inode()?;
+        let new_inode = self.new_inode(mode, uid, gid);
+        self.write_inode(inode, new_inode.clone())?;
 
-        match self.find_dirent_in_list(&old_parent_dirents, _name){
-            Some((old_de_idx, mut dirent)) => {
-                dirent.name = OsString::from(_newname);
+        let dirent = DirectoryEntry{
+            inode_ptr: inode as u32,
+            name: OsString::from(name),
+            file_type: dirent_type_of_mode(mode),
+        };
+        self.insert_dirent(&mut parent_inode, &parent_dirents, dirent)?;
+        self.write_inode(parent, parent_inode)?;
+        Ok((inode, new_inode))
+    }
 
-                if new_parent_ino == parent_ino {
-                    if _newname == _name {
-                        reply.ok();
-                        return;
-                    }
+    /// Create a file or directory, as mknod and mkdir do, and return its attributes
+    fn do_mknod(&mut self, req: &Request, orig_parent: u64, name: &OsStr, mode: u32) -> Result<FileAttr, c_int> {
+        if self.read_only {
+            return Err(EROFS);
+        }
 
+        let parent = translate_inode(orig_parent);
+        let (new_inode_num, new_inode) = self.transaction(|fs| fs.create_inode(parent, name, mode, req.uid() as u16, req.gid() as u16).map_err(translate_io_error))?;
+        self.file_attr(new_inode_num, &new_inode).map_err(translate_io_error)
+    }
+}
 
-                    let dirent_data: Vec<u8> = dirent.into();
-                    if let Err(e) = self.write_file_data(&mut old_parent_info, old_de_idx*32, &dirent_data){
-                        reply.error(translate_error(e.kind()));
-                        return;
-                    }
 
-                    let parent_inode_data : Vec<u8> = old_parent_info.into();
-                    if let Err(e) = self.block_system.block_write(&parent_inode_data, parent_ino as usize){
-                        reply.error(translate_error(e.kind()));
-                        return;
-                    }
+//Main Implementations of the File System for LearnedFileSystem
 
-                    reply.ok();
-                    return;
-                } else {
-                    let mut new_parent_info = self.get_inode(new_parent_ino).unwrap();
-                    let new_parent_dirents = self.get_dirents_incl_gaps(&new_parent_info);
+impl <BF : BlockFile> Filesystem for LearnedFileSystem<BF> {
 
-                    if let Some(_) = self.find_dirent_in_list(&new_parent_dirents, _name) {
-                        reply.error(EEXIST);
-                        return;
-                    }
+    fn init(&mut self, _req: &fuse::Request) -> Result<(), c_int> {
+        if let Err(e) = self.open_trace() {
+            error!("Refusing to mount, could not open the trace: {}", e);
+            return Err(translate_io_error(e));
+        }
+        self.traced(_req, TraceOp::Init, 0, 0, 0, |fs| fs.mount().map_err(|e| {
+            error!("Refusing to mount: {}", e);
+            translate_io_error(e)
+        }))
+    }
 
+    fn destroy(&mut self, _req: &Request) {
+        let _ = self.traced(_req, TraceOp::Destroy, 0, 0, 0, |fs| fs.unmount().map_err(|e| {
+            error!("Could not mark the file system clean: {}", e);
+            translate_io_error(e)
+        }));
+        self.close_trace();
+        for predictor_stats in self.prefetch_stats() {
+            info!("Prefetch {}", predictor_stats);
+        }
+    }
 
-                    if let Err(e) = self.write_file_data(&mut old_parent_info, old_de_idx*32, &[0u8; 32]){
-                        reply.error(translate_error(e.kind()));
-                        return;
-                    }
+    fn lookup(&mut self, _req: &fuse::Request, _parent: u64, _name: &std::ffi::OsStr, reply: fuse::ReplyEntry) {
+        let _ino = translate_inode(_parent);
 
-                    let parent_inode_data : Vec<u8> = old_parent_info.into();
-                    if let Err(e) = self.block_system.block_write(&parent_inode_data, parent_ino as usize){
-                        reply.error(translate_error(e.kind()));
-                        return;
-                    }
+        match self.traced(_req, TraceOp::Lookup, _ino, 0, 0, |fs| {
+            let block_info = fs.get_inode(_ino).map_err(translate_io_error)?;
+            if let (_, Some((_, dirent))) = fs.find_dirent(&block_info, _name).map_err(translate_io_error)? {
+                let element_block_info = fs.get_inode(dirent.inode_ptr as u64).map_err(translate_io_error)?;
+                fs.file_attr(dirent.inode_ptr as u64, &element_block_info).map_err(translate_io_error)
+            } else{
+                Err(ENOENT)
+            }
+        }) {
+            Ok(attr) => {
+                debug!("Response: {:?}", attr);
+                reply.entry(&in_one_sec(), &attr, 0);
+            }
+            Err(e) => reply.error(e)
+        }
+    }
 
-                    let first_free_new_parent_dirent_idx = self.first_free_dirent_idx(&new_parent_dirents);
-                    let dirent_data: Vec<u8> = dirent.into();
-                    if let Err(e) = self.write_file_data(&mut new_parent_info, first_free_new_parent_dirent_idx*32, &dirent_data){
-                        reply.error(translate_error(e.kind()));
-                        return;
-                    }
+    fn getattr(&mut self, _req: &fuse::Request, orig_ino: u64, reply: fuse::ReplyAttr) {
+        let _ino = translate_inode(orig_ino);
+        match self.traced(_req, TraceOp::Getattr, _ino, 0, 0, |fs| fs.get_inode(_ino).and_then(|node| fs.file_attr(orig_ino, &node)).map_err(translate_io_error)) {
+            Ok(attr) => {
+                debug!("Response: {:?}", attr);
+                reply.attr(&in_one_sec(), &attr)
+            }
+            Err(e) => reply.error(e)
+        }
+    }
 
-                    let new_parent_inode_data : Vec<u8> = new_parent_info.into();
-                    if let Err(e) = self.block_system.block_write(&new_parent_inode_data, new_parent_ino as usize){
-                        reply.error(translate_error(e.kind()));
-                        return;
-                    }
-                    reply.ok()
-                }
+    fn mknod(&mut self, _req: &Request, _orig_parent: u64, _name: &OsStr, _mode: u32, _rdev: u32, reply: ReplyEntry) {
+        // Dir vs file is controlled by _mode
+        match self.traced(_req, TraceOp::Mknod, translate_inode(_orig_parent), 0, 0, |fs| fs.do_mknod(_req, _orig_parent, _name, _mode)) {
+            Ok(attr) => {
+                debug!("New file: {:?}", attr);
+                reply.entry(&in_one_sec(), &attr, 0)
             }
-            None => {
-                reply.error(ENOENT);
+            Err(e) => reply.error(e)
+        }
+    }
+
+    fn setattr(&mut self, _req: &Request, _ino: u64, _mode: Option<u32>, _uid: Option<u32>, _gid: Option<u32>, _size: Option<u64>, _atime: Option<Timespec>, _mtime: Option<Timespec>, _fh: Option<u64>, _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>, _flags: Option<u32>, reply: ReplyAttr) {
+        match self.traced(_req, TraceOp::Setattr, translate_inode(_ino), 0, _size.unwrap_or(0), |fs| {
+            let request = SetattrRequest { mode: _mode, uid: _uid, gid: _gid, size: _size, mtime: _mtime, chgtime: _chgtime };
+            fs.transaction(|fs| fs.do_setattr(_ino, request))
+        }) {
+            Ok(newattr) => reply.attr(&in_one_sec(), &newattr),
+            Err(e) => reply.error(e)
+        }
+    }
+
+    fn mkdir(&mut self, _req: &Request, _orig_parent: u64, _name: &OsStr, _mode: u32, reply: ReplyEntry) {
+        match self.traced(_req, TraceOp::Mkdir, translate_inode(_orig_parent), 0, 0, |fs| fs.do_mknod(_req, _orig_parent, _name, _mode)) {
+            Ok(attr) => {
+                debug!("New file: {:?}", attr);
+                reply.entry(&in_one_sec(), &attr, 0)
+            }
+            Err(e) => {
+                reply.error(e)
             }
         }
     }
 
+    fn rename(&mut self, _req: &Request, _parent: u64, _name: &OsStr, _newparent: u64, _newname: &OsStr, reply: ReplyEmpty) {
+        match self.traced(_req, TraceOp::Rename, translate_inode(_parent), 0, 0, |fs| fs.transaction(|fs| fs.do_rename(_parent, _name, _newparent, _newname))) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e)
+        }
+    }
+
     fn read(&mut self, _req: &Request, _orig_ino: u64, _fh: u64, _offset: i64, _size: u32, reply: ReplyData) {
+        let _ino = translate_inode(_orig_ino);
+        match self.traced(_req, TraceOp::Read, _ino, _offset, _size as u64, |fs| {
+            let block_info = fs.get_inode(_ino).map_err(translate_io_error)?;
+            fs.read_file_bytes(&block_info, _offset as usize, _size as usize).map_err(translate_io_error)
+        }) {
+            Ok(data) => {
+                reply.data(&data);
+                self.prefetch_after(_ino, _offset as u64, data.len(), false);
+            }
+            Err(e) => reply.error(e)
+        }
+    }
 
-        //logging
-        let mut contents = String::from("R");
-        contents.push_str(_orig_ino.to_string().as_str());
-        contents.push_str("#");
-        contents.push_str(_offset.to_string().as_str());
-        std::fs::write(&self.logging_path, contents);
+    fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        match self.traced(_req, TraceOp::Flush, translate_inode(_ino), 0, 0, |fs| fs.do_sync()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e)
+        }
+    }
 
+    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.traced(_req, TraceOp::Fsync, translate_inode(_ino), 0, 0, |fs| fs.do_sync()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e)
+        }
+    }
 
-        let _ino = translate_inode(_orig_ino);
-        let block_info = self.get_inode(_ino).unwrap();
-        let data = self.read_file_bytes(&block_info, _offset as usize, _size as usize);
-        reply.data(&data)
+    fn fsyncdir(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.traced(_req, TraceOp::Fsyncdir, translate_inode(_ino), 0, 0, |fs| fs.do_sync()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e)
+        }
     }
 
     fn rmdir(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        match self.do_unlink(_parent, _name, true) {
+        match self.traced(_req, TraceOp::Rmdir, translate_inode(_parent), 0, 0, |fs| fs.transaction(|fs| fs.do_unlink(_parent, _name, true))) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e)
         }
     }
 
     fn unlink(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: ReplyEmpty) {
-        match self.do_unlink(_parent, _name, false) {
+        match self.traced(_req, TraceOp::Unlink, translate_inode(_parent), 0, 0, |fs| fs.transaction(|fs| fs.do_unlink(_parent, _name, false))) {
             Ok(()) => reply.ok(),
             Err(e) => reply.error(e)
         }
     }
 
     fn write(&mut self, _req: &Request, _orig_ino: u64, _fh: u64, _offset: i64, _data: &[u8], _flags: u32, reply: ReplyWrite) {
-
-
-        //logging
-        let mut contents = String::from("W");
-        contents.push_str(_orig_ino.to_string().as_str());
-        contents.push_str("#");
-        contents.push_str(_offset.to_string().as_str());
-        std::fs::write(&self.logging_path, contents);
-
-        let _ino = translate_inode(_orig_ino);
-
-        let mut block_info = self.get_inode(_ino).unwrap();
-
-        match self.write_file_data(&mut block_info, _offset as usize, _data){
-            Err(e) => {reply.error(translate_error(e.kind()))},
+        match self.traced(_req, TraceOp::Write, translate_inode(_orig_ino), _offset, _data.len() as u64, |fs| fs.transaction(|fs| fs.do_write(_orig_ino, _offset, _data))) {
             Ok(bytes_written) => {
-                let parent_inode_data : Vec<u8> = block_info.into();
-                if let Err(e) = self.block_system.block_write(&parent_inode_data, _ino as usize){
-                    reply.error(translate_error(e.kind()));
-                    return;
-                }
-
                 reply.written(bytes_written as u32);
+                self.prefetch_after(translate_inode(_orig_ino), _offset as u64, bytes_written, true);
             }
+            Err(e) => reply.error(e)
         }
     }
 
     fn readdir(&mut self, _req: &fuse::Request, _ino: u64, _fh: u64, _offset: i64, mut reply: fuse::ReplyDirectory) {
-        let _ino = if _ino == FUSE_ROOT_ID {2} else {_ino};
-        let block_info = self.get_inode(_ino).unwrap();
-        for (off, dirent) in self.get_valid_dirents(&block_info).into_iter().enumerate().skip(_offset as usize) {
-            reply.add(dirent.inode_ptr as u64, (off + 1) as i64, Directory, &dirent.name);
+        let _ino = translate_inode(_ino);
+        let dirents = match self.traced(_req, TraceOp::Readdir, _ino, _offset, 0, |fs| {
+            let block_info = fs.get_inode(_ino).map_err(translate_io_error)?;
+            fs.get_valid_dirents(&block_info).map_err(translate_io_error)
+        }) {
+            Ok(dirents) => dirents,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+        for (off, dirent) in dirents.into_iter().enumerate().skip(_offset as usize) {
+            let kind = if dirent.file_type == DIRENT_TYPE_REGULAR { RegularFile } else { Directory };
+            reply.add(dirent.inode_ptr as u64, (off + 1) as i64, kind, &dirent.name);
         }
         reply.ok()
     }
 
     fn statfs(&mut self, _req: &fuse::Request, _ino: u64, reply: fuse::ReplyStatfs) {
-        let super_block = self.get_superblock().unwrap();
+        let counts = self.traced(_req, TraceOp::Statfs, translate_inode(_ino), 0, 0, |fs| {
+            let super_block = fs.get_superblock().map_err(translate_io_error)?;
+            let (_, bit_mask_blocks) = super_block.bitmap_location();
+            let journal_blocks = if super_block.has_journal() { super_block.journal_blocks } else { 0 };
+            let cow_blocks = if super_block.is_cow() { super_block.cow_blocks } else { 0 };
+            let usable_blocks = (super_block.disk_size - 1 - bit_mask_blocks
+                - super_block.inode_table_blocks - super_block.inode_bitmap_blocks - journal_blocks - cow_blocks) as u64;
+            let (num_inodes, num_free_inodes) = match fs.inode_allocation_bitmask.as_ref() {
+                Some(inode_bitmask) => (super_block.inode_count as u64, inode_bitmask.num_free_indices() as u64),
+                None => (usable_blocks, fs.block_allocation_bitmask.num_free_indices() as u64),
+            };
+            Ok((usable_blocks, num_inodes, num_free_inodes))
+        });
+        let (usable_blocks, num_inodes, num_free_inodes) = match counts {
+            Ok(counts) => counts,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+
+        reply.statfs(usable_blocks, self.block_allocation_bitmask.num_free_indices() as u64,
+                     self.block_allocation_bitmask.num_free_indices() as u64, num_inodes,
+                     num_free_inodes, self.block_size as u32, if self.use_var_dirents { NAME_MAX } else { FIXED_NAME_MAX } as u32,
+                     self.block_size as u32);
+    }
 
-        reply.statfs((super_block.disk_size - 2) as u64, self.block_allocation_bitmask.num_free_indices() as u64,
-                     self.block_allocation_bitmask.num_free_indices() as u64, (super_block.disk_size - 2) as u64,
-                     self.block_allocation_bitmask.num_free_indices() as u64, FS_BLOCK_SIZE as u32, 27,
-                     FS_BLOCK_SIZE as u32);
+    fn getxattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, _size: u32, reply: ReplyXattr) {
+        let value = self.traced(_req, TraceOp::Getxattr, translate_inode(_ino), 0, _size as u64, |fs| match _name.to_str() {
+            _ if translate_inode(_ino) != ROOT_INODE => Err(ENODATA),
+            Some(CACHE_STATS_XATTR) => Ok(fs.block_system.cache_stats().iter().map(|policy_stats| format!("{}\n", policy_stats)).collect::<String>()),
+            Some(PREFETCH_STATS_XATTR) => Ok(fs.prefetch_stats().iter().map(|predictor_stats| format!("{}\n", predictor_stats)).collect()),
+            _ => Err(ENODATA),
+        });
+        let value = match value {
+            Ok(value) => value,
+            Err(e) => {
+                reply.error(e);
+                return;
+            }
+        };
+        if _size == 0 {
+            reply.size(value.len() as u32);
+        } else if value.len() > _size as usize {
+            reply.error(ERANGE);
+        } else {
+            reply.data(value.as_bytes());
+        }
+    }
+
+    fn setxattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, _value: &[u8], _flags: u32, _position: u32, reply: ReplyEmpty) {
+        match self.traced(_req, TraceOp::Setxattr, translate_inode(_ino), 0, _value.len() as u64, |fs| {
+            if translate_inode(_ino) != ROOT_INODE || _name != GROW_XATTR {
+                return Err(ENOTSUP);
+            }
+            fs.transaction(|fs| fs.grow_to_device().map_err(|e| {
+                error!("Growing failed: {}", e);
+                translate_io_error(e)
+            }))
+        }) {
+            Ok(num_blocks) => {
+                debug!("Grew to {} blocks", num_blocks);
+                reply.ok()
+            }
+            Err(e) => reply.error(e)
+        }
     }
 }
 
@@ -629,3 +1604,72 @@ fn in_one_sec() -> Timespec{
     time::get_time().add(Duration::seconds(1))
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::crash_test::{check_written, run_and_reopen};
+    use crate::mkfs::{mkfs, MkfsOptions};
+    use crate::utils::block_file::MemoryBlockFile;
+    use super::*;
+
+    const BLOCK_SIZE: usize = 1024;
+
+    fn file_system(features: &str) -> LearnedFileSystem<MemoryBlockFile> {
+        let mut options = MkfsOptions { num_blocks: 4096, block_size: BLOCK_SIZE as u32, inode_size: 128, inode_count: Some(64), ..MkfsOptions::default() };
+        options.set_features(features).unwrap();
+        mkfs(MemoryBlockFile::new(BLOCK_SIZE, vec![0u8; 4096 * BLOCK_SIZE]), &options).unwrap()
+    }
+
+    #[test]
+    fn attributes_count_metadata_blocks() {
+        let sectors = |num_blocks: u64| num_blocks * (BLOCK_SIZE / 512) as u64;
+
+        let mut fs = file_system("none");
+        let (ino, mut file) = fs.create_inode(ROOT_INODE, OsStr::new("f"), 0o100644, 0, 0).unwrap();
+        let pointers_per_block = pointers_per_block(BLOCK_SIZE);
+        // Every direct pointer, the whole single indirect block and three blocks past it
+        let num_data_blocks = file.pointers.len() + pointers_per_block + 3;
+        fs.write_file_data(&mut file, 0, &vec![1u8; num_data_blocks * BLOCK_SIZE]).unwrap();
+        assert_eq!(fs.file_attr(ino, &file).unwrap().blocks, sectors(num_data_blocks as u64 + 3));
+        // A hole takes no space, but a second indirect block below the double indirect one does
+        fs.write_file_data(&mut file, (num_data_blocks + pointers_per_block) * BLOCK_SIZE, b"x").unwrap();
+        assert_eq!(fs.file_attr(ino, &file).unwrap().blocks, sectors(num_data_blocks as u64 + 5));
+
+        let mut fs = file_system("extents");
+        let (ino, mut file) = fs.create_inode(ROOT_INODE, OsStr::new("f"), 0o100644, 0, 0).unwrap();
+        fs.write_file_data(&mut file, 0, &vec![1u8; 3 * BLOCK_SIZE]).unwrap();
+        assert_eq!(fs.file_attr(ino, &file).unwrap().blocks, sectors(3));
+        // Every other block, until the extents no longer fit in the inode
+        let num_extents = ExtentNode::max_entries(file.block_map_size()) + 1;
+        for idx in 1..num_extents {
+            fs.write_file_data(&mut file, (2 + idx * 2) * BLOCK_SIZE, b"x").unwrap();
+        }
+        // The data blocks and the leaf the extents moved to
+        assert_eq!(file.extent_root().unwrap().depth, 1);
+        assert_eq!(fs.file_attr(ino, &file).unwrap().blocks, sectors(3 + (num_extents as u64 - 1) + 1));
+    }
+
+    #[test]
+    fn inode_table_round_trip() {
+        let options = MkfsOptions { num_blocks: 1024, block_size: BLOCK_SIZE as u32, inode_size: 128, inode_count: Some(64), ..MkfsOptions::default() };
+        let mut workload = String::from("mkdir /d\n");
+        for idx in 0..40 {
+            workload += &format!("create /d/f{}\nwrite /d/f{} 0 {}\n", idx, idx, 100 * idx + 1);
+        }
+        workload += "unlink /d/f0\nrename /d/f1 /f1\n";
+        let file_system = run_and_reopen(&options, &workload);
+
+        let super_block = file_system.get_superblock().unwrap();
+        // Sixteen 128 byte inodes per block, instead of a block for every inode
+        assert_eq!(super_block.inode_table_blocks, 8);
+        // Inodes below the root are never used, and the root, /d and 39 files are
+        assert_eq!(super_block.free_inodes_count, 64 - ROOT_INODE as u32 - 41);
+        let inode = file_system.lookup_path("/f1").unwrap();
+        assert!(inode < 64);
+        check_written(&file_system, "/f1", 0, 101);
+        for idx in 2..40 {
+            check_written(&file_system, &format!("/d/f{}", idx), 0, 100 * idx + 1);
+        }
+        assert!(file_system.lookup_path("/d/f0").is_err());
+    }
+}
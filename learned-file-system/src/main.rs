@@ -1,43 +1,211 @@
-use fuse::mount;
-use std::env;
+use fuse::Session;
 use std::ffi::OsStr;
+use std::path::Path;
 use std::process::exit;
-use learned_file_system::LearnedFileSystem;
+use learned_file_system::{image_block_size, AllocPolicy, LearnedFileSystem, ALLOC_POLICY_NAMES};
 
-use std::fs::{File, OpenOptions};
+use std::fs::OpenOptions;
 
-use learned_file_system::utils::block_file::BlockFileWrapper;
+use learned_file_system::utils::block_file::{BlockFile, BlockFileWrapper};
+use learned_file_system::trace::{TraceFormat, TraceOptions, DEFAULT_MAX_TRACE_FILES};
+use learned_file_system::prefetch::{predictor_from_name, Prefetcher, PREDICTOR_NAMES};
+use learned_file_system::utils::cache_policy::{policy_from_name, POLICY_NAMES};
+use learned_file_system::utils::cached_block_file::{CachedBlockFile, DEFAULT_CACHE_BLOCKS};
 
+fn usage() -> ! {
+    println!("usage: learned-file-system [options] image mountpoint");
+    println!("             image         - image file to mount");
+    println!("             mountpoint    - directory to mount it on");
+    println!("options:");
+    println!("             -t trace-file - record every request served to trace-file");
+    println!("             -T format     - trace format: binary, csv or jsonl (default: csv for .csv files, jsonl for");
+    println!("                             .jsonl files, else binary)");
+    println!("             -R size[:n]   - rotate the trace once it reaches size bytes (K, M or G suffixes work),");
+    println!("                             keeping n older files (default {})", DEFAULT_MAX_TRACE_FILES);
+    println!("             -r            - mount read-only, opening the image read-only too");
+    println!("             -f            - stay in the foreground instead of running as a daemon");
+    println!("             -a            - allow other users to access the mount (allow_other)");
+    println!("             -n fsname     - file system name shown by mount and df (default: the image path)");
+    println!("             -o options    - comma separated mount options passed on to FUSE");
+    println!("             -l log-level  - error, warn, info, debug or trace (default: RUST_LOG, or warn)");
+    println!("             -c cache      - block cache: none, or policy[:blocks] with policy one of {} (default lru:{})",
+             POLICY_NAMES.join(", "), DEFAULT_CACHE_BLOCKS);
+    println!("             -P predictor  - prefetch into the block cache: none, {} (default none)", PREDICTOR_NAMES.join(", "));
+    println!("             -s            - also simulate the other cache policies and prefetch predictors, to compare");
+    println!("                             them in the user.lfs.cache_stats and user.lfs.prefetch_stats extended");
+    println!("                             attributes of the root");
+    println!("             -p policy     - block allocation policy: {} (default goal)",
+             ALLOC_POLICY_NAMES.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", "));
+    println!("             -h            - show this help");
+    exit(1);
+}
 
-const BLOCK_SIZE: usize = 4096;
+fn fail(message: String) -> ! {
+    eprintln!("learned-file-system: {}", message);
+    exit(1);
+}
 
-fn main() {
-    let args: Vec<String> = std::env::args().collect();
-
-    if args.len() != 4 {
-        println!("usage: ./lab1fuse -image disk.img directory");
-        println!("             disk.img  - name of the image file to mount");
-        println!("             directory - directory to mount it on");
-        exit(1);
+/// Eviction policy and number of blocks to cache for a -c argument, or None for no cache
+fn parse_cache(value: &str) -> Option<(&'static str, usize)> {
+    if value == "none" {
+        return None;
     }
+    let (policy, blocks) = value.split_once(':').map_or((value, None), |(policy, blocks)| (policy, Some(blocks)));
+    let policy = POLICY_NAMES.iter().find(|name| **name == policy).unwrap_or_else(|| fail(format!("unknown cache policy {}", policy)));
+    let blocks = match blocks.map(str::parse::<usize>) {
+        None => DEFAULT_CACHE_BLOCKS,
+        Some(Ok(blocks)) if blocks > 0 => blocks,
+        Some(_) => fail(format!("invalid cache size {}", blocks.unwrap())),
+    };
+    Some((policy, blocks))
+}
 
+/// Size to rotate the trace at and number of rotated files to keep for a -R argument
+fn parse_rotation(value: &str) -> (u64, usize) {
+    let (size, files) = value.split_once(':').map_or((value, None), |(size, files)| (size, Some(files)));
+    let (digits, multiplier) = match size.chars().last() {
+        Some('K') | Some('k') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    let size = match digits.parse::<u64>() {
+        Ok(size) if size > 0 => size * multiplier,
+        _ => fail(format!("invalid trace size {}", size)),
+    };
+    let files = files.map_or(DEFAULT_MAX_TRACE_FILES, |files| files.parse().unwrap_or_else(|_| fail(format!("invalid number {}", files))));
+    (size, files)
+}
 
-    let image_name = args.get(2).unwrap();
+fn new_file_system<BF: BlockFile>(block_device: BF, trace_path: String, trace_options: TraceOptions, alloc_policy: AllocPolicy, read_only: bool) -> LearnedFileSystem<BF> {
+    let mut file_system = LearnedFileSystem::new(block_device, trace_path);
+    file_system.set_trace_options(trace_options);
+    file_system.set_alloc_policy(alloc_policy);
+    if read_only {
+        file_system.set_read_only();
+    }
+    file_system
+}
 
-    let image = OpenOptions::new().read(true).write(true).open(image_name).unwrap();
-    let block_device = BlockFileWrapper::new(BLOCK_SIZE, image);
+/// Mount `file_system` and serve requests until it is unmounted
+fn run<BF: BlockFile>(file_system: LearnedFileSystem<BF>, mountpoint: &str, options: &[&OsStr], foreground: bool) {
+    let mut session = Session::new(file_system, Path::new(mountpoint), options)
+        .unwrap_or_else(|e| fail(format!("{}: {}", mountpoint, e)));
+    // Only detach once the mount worked, so mount errors still reach the terminal
+    // SAFETY: no other threads have been started yet
+    if !foreground && unsafe { libc::daemon(0, 0) } != 0 {
+        fail(format!("cannot run as a daemon: {}", std::io::Error::last_os_error()));
+    }
+    session.run().unwrap_or_else(|e| fail(e.to_string()));
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut trace_path = String::new();
+    let mut trace_options = TraceOptions::default();
+    let mut read_only = false;
+    let mut foreground = false;
+    let mut allow_other = false;
+    let mut fsname = None;
+    let mut mount_options = vec![];
+    let mut log_level = None;
+    let mut alloc_policy = AllocPolicy::default();
+    let mut cache = Some(("lru", DEFAULT_CACHE_BLOCKS));
+    let mut predictor = None;
+    let mut shadow_policies = false;
+    let mut positional = vec![];
 
-    let mountpoint = args.get(3).unwrap();
+    let mut arg_idx = 1;
+    while arg_idx < args.len() {
+        let arg = args[arg_idx].as_str();
+        match arg {
+            "-r" => read_only = true,
+            "-f" => foreground = true,
+            "-a" => allow_other = true,
+            "-s" => shadow_policies = true,
+            "-h" => usage(),
+            "-t" | "-T" | "-R" | "-n" | "-o" | "-l" | "-c" | "-P" | "-p" => {
+                let value = args.get(arg_idx + 1).unwrap_or_else(|| usage()).clone();
+                match arg {
+                    // Absolute, since a daemon runs from /
+                    "-t" => trace_path = std::env::current_dir().map(|dir| dir.join(&value).to_string_lossy().into_owned())
+                        .unwrap_or_else(|e| fail(e.to_string())),
+                    "-T" => trace_options.format = Some(TraceFormat::from_name(&value).unwrap_or_else(|e| fail(e.to_string()))),
+                    "-R" => {
+                        let (size, files) = parse_rotation(&value);
+                        trace_options.max_bytes = Some(size);
+                        trace_options.max_files = files;
+                    }
+                    "-n" => fsname = Some(value),
+                    "-o" => mount_options.push(value),
+                    "-l" => log_level = Some(value),
+                    "-c" => cache = parse_cache(&value),
+                    "-P" if value == "none" => predictor = None,
+                    "-P" => predictor = Some(PREDICTOR_NAMES.iter().find(|name| **name == value)
+                        .unwrap_or_else(|| fail(format!("unknown prefetch predictor {}", value)))),
+                    _ => alloc_policy = AllocPolicy::from_name(&value).unwrap_or_else(|e| fail(e.to_string())),
+                }
+                arg_idx += 1;
+            }
+            arg if arg.starts_with('-') => usage(),
+            arg => positional.push(arg.to_string()),
+        }
+        arg_idx += 1;
+    }
+    let (image_name, mountpoint) = match positional.as_slice() {
+        [image_name, mountpoint] => (image_name, mountpoint),
+        _ => usage(),
+    };
+    if predictor.is_some() && cache.is_none() {
+        fail("prefetching needs a block cache".to_string());
+    }
 
-    let logging_path = args.get(4).unwrap();
+    let mut logger = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    if let Some(log_level) = log_level {
+        logger.parse_filters(&log_level);
+    }
+    logger.init();
 
-    env_logger::init();
+    let image = OpenOptions::new().read(true).write(!read_only).open(image_name)
+        .unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let block_size = image_block_size(&image).unwrap_or_else(|e| fail(format!("{}: {}", image_name, e)));
+    let block_device = BlockFileWrapper::new(block_size, image);
 
-    let l = LearnedFileSystem::new(block_device, logging_path.clone());
-    let options = ["-o", "fsname=hello", "defaultpermissions", "auto_unmount"]
-        .iter()
-        .map(|o| o.as_ref())
-        .collect::<Vec<&OsStr>>();
+    let mut fuse_options = vec![
+        format!("fsname={}", fsname.unwrap_or_else(|| image_name.clone())),
+        "default_permissions".to_string(),
+        "auto_unmount".to_string(),
+    ];
+    if read_only {
+        fuse_options.push("ro".to_string());
+    }
+    if allow_other {
+        fuse_options.push("allow_other".to_string());
+    }
+    fuse_options.extend(mount_options);
+    let fuse_options = fuse_options.join(",");
+    let options = [OsStr::new("-o"), OsStr::new(&fuse_options)];
 
-    mount(l, mountpoint, &options).unwrap();
-}
\ No newline at end of file
+    match cache {
+        Some((policy, cache_blocks)) => {
+            let mut block_device = CachedBlockFile::with_policy(block_device, policy_from_name(policy, cache_blocks).unwrap());
+            if shadow_policies {
+                for shadow in POLICY_NAMES.iter().filter(|name| **name != policy) {
+                    block_device.add_shadow_policy(policy_from_name(shadow, cache_blocks).unwrap());
+                }
+            }
+            let mut file_system = new_file_system(block_device, trace_path, trace_options, alloc_policy, read_only);
+            if let Some(predictor) = predictor {
+                let mut prefetcher = Prefetcher::new(predictor_from_name(predictor).unwrap());
+                if shadow_policies {
+                    for shadow in PREDICTOR_NAMES.iter().filter(|name| *name != predictor) {
+                        prefetcher.add_shadow_predictor(predictor_from_name(shadow).unwrap());
+                    }
+                }
+                file_system.set_prefetcher(prefetcher);
+            }
+            run(file_system, mountpoint, &options, foreground)
+        }
+        None => run(new_file_system(block_device, trace_path, trace_options, alloc_policy, read_only), mountpoint, &options, foreground),
+    }
+}
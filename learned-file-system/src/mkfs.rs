@@ -0,0 +1,319 @@
+use std::io::{Error, ErrorKind};
+use crate::{FS_BLOCK_SIZE, FS_MAGIC_NUM, FS_VERSION, LearnedFileSystem, ROOT_INODE};
+use crate::cow::MIN_COW_BLOCKS;
+use crate::journal::MIN_JOURNAL_BLOCKS;
+use crate::structs::superblock::{FsSuperBlock, FEATURE_COMPAT_HAS_JOURNAL, FEATURE_INCOMPAT_COW, FEATURE_INCOMPAT_EXTENTS, FEATURE_INCOMPAT_VAR_DIRENTS, FEATURE_RO_COMPAT_DIR_INDEX,
+                                 FEATURE_RO_COMPAT_LEARNED_INDEX, generate_uuid, is_valid_block_size, LABEL_SIZE};
+use crate::structs::fsinode::MIN_INODE_SIZE;
+use crate::utils::bitmask::BitMaskBlock;
+use crate::utils::block_file::BlockFile;
+use crate::utils::div_ceil;
+
+/// Features that can be asked for by name, as (name, incompatible bits, read-only compatible
+/// bits, compatible bits)
+pub const FEATURE_NAMES: [(&str, u32, u32, u32); 6] = [
+    ("extents", FEATURE_INCOMPAT_EXTENTS, 0, 0),
+    ("var_dirents", FEATURE_INCOMPAT_VAR_DIRENTS, 0, 0),
+    ("dir_index", 0, FEATURE_RO_COMPAT_DIR_INDEX, 0),
+    ("learned_index", 0, FEATURE_RO_COMPAT_LEARNED_INDEX, 0),
+    ("has_journal", 0, 0, FEATURE_COMPAT_HAS_JOURNAL),
+    ("cow", FEATURE_INCOMPAT_COW, 0, 0),
+];
+
+pub const DEFAULT_INODE_SIZE: u32 = 256;
+/// Without an explicit inode count, the image gets one inode per this many bytes
+const BYTES_PER_INODE: u64 = 16384;
+/// Inodes 0 and 1 are never handed out, so the root directory can be inode 2
+const NUM_RESERVED_INODES: u32 = 2;
+/// Without an explicit size, the journal or copy-on-write area gets one block per this many
+/// blocks, within its minimum and MAX_DEFAULT_JOURNAL_BLOCKS
+const BLOCKS_PER_JOURNAL_BLOCK: u32 = 64;
+const MAX_DEFAULT_JOURNAL_BLOCKS: u32 = 1024;
+
+/// What to put on a new image
+#[derive(Clone, Debug)]
+pub struct MkfsOptions {
+    pub num_blocks: u32,
+    pub block_size: u32,
+    pub label: String,
+    /// None picks one inode per 16 KiB. Some(0) leaves out the inode table, in which case
+    /// every inode takes the block with the same number, like images from gen-disk.py
+    pub inode_count: Option<u32>,
+    pub inode_size: u32,
+    /// Bitmask of FEATURE_INCOMPAT_* flags
+    pub incompat_features: u32,
+    /// Bitmask of FEATURE_RO_COMPAT_* flags
+    pub ro_compat_features: u32,
+    /// Bitmask of FEATURE_COMPAT_* flags
+    pub compat_features: u32,
+    /// Size of the journal, if FEATURE_COMPAT_HAS_JOURNAL is set. None picks one block per
+    /// 64, from 16 up to 1024
+    pub journal_blocks: Option<u32>,
+    /// Size of the copy-on-write area, including the second superblock copy, if
+    /// FEATURE_INCOMPAT_COW is set. None picks one block per 64, from 32 up to 1024
+    pub cow_blocks: Option<u32>,
+    /// Owner of the root directory
+    pub root_uid: u16,
+    pub root_gid: u16,
+}
+
+impl Default for MkfsOptions {
+    fn default() -> Self {
+        MkfsOptions {
+            num_blocks: 0,
+            block_size: FS_BLOCK_SIZE as u32,
+            label: String::new(),
+            inode_count: None,
+            inode_size: DEFAULT_INODE_SIZE,
+            incompat_features: FEATURE_INCOMPAT_EXTENTS | FEATURE_INCOMPAT_VAR_DIRENTS,
+            ro_compat_features: FEATURE_RO_COMPAT_DIR_INDEX,
+            compat_features: FEATURE_COMPAT_HAS_JOURNAL,
+            journal_blocks: None,
+            cow_blocks: None,
+            root_uid: 0,
+            root_gid: 0,
+        }
+    }
+}
+
+impl MkfsOptions {
+    /// Replace the features with a comma separated list of names from FEATURE_NAMES, or "none"
+    pub fn set_features(&mut self, names: &str) -> std::io::Result<()> {
+        self.incompat_features = 0;
+        self.ro_compat_features = 0;
+        self.compat_features = 0;
+        for name in names.split(',').filter(|name| !name.is_empty() && *name != "none") {
+            let (_, incompat, ro_compat, compat) = FEATURE_NAMES.iter().find(|(known, _, _, _)| *known == name)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("unknown feature {}", name)))?;
+            self.incompat_features |= incompat;
+            self.ro_compat_features |= ro_compat;
+            self.compat_features |= compat;
+        }
+        Ok(())
+    }
+
+    /// Check everything but the size, which is only known to fit once the layout is worked out
+    pub fn validate(&self) -> std::io::Result<()> {
+        if !is_valid_block_size(self.block_size) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid block size {}", self.block_size)));
+        }
+        if !self.inode_size.is_power_of_two() || self.inode_size < MIN_INODE_SIZE || self.inode_size > self.block_size {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("invalid inode size {}", self.inode_size)));
+        }
+        if self.label.len() > LABEL_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("label is longer than {} bytes", LABEL_SIZE)));
+        }
+        if self.journal_blocks.is_some_and(|journal_blocks| journal_blocks < MIN_JOURNAL_BLOCKS) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("the journal needs at least {} blocks", MIN_JOURNAL_BLOCKS)));
+        }
+        if self.cow_blocks.is_some_and(|cow_blocks| cow_blocks < MIN_COW_BLOCKS) {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("the copy-on-write area needs at least {} blocks", MIN_COW_BLOCKS)));
+        }
+        if self.incompat_features & FEATURE_INCOMPAT_COW != 0 && self.compat_features & FEATURE_COMPAT_HAS_JOURNAL != 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "has_journal and cow cannot be combined"));
+        }
+        Ok(())
+    }
+
+    fn inode_count(&self) -> u32 {
+        match self.inode_count {
+            Some(inode_count) => inode_count,
+            None => (self.num_blocks as u64 * self.block_size as u64 / BYTES_PER_INODE) as u32,
+        }
+    }
+
+    fn journal_blocks(&self) -> u32 {
+        if self.compat_features & FEATURE_COMPAT_HAS_JOURNAL == 0 {
+            return 0;
+        }
+        match self.journal_blocks {
+            Some(journal_blocks) => journal_blocks,
+            None => (self.num_blocks / BLOCKS_PER_JOURNAL_BLOCK).clamp(MIN_JOURNAL_BLOCKS, MAX_DEFAULT_JOURNAL_BLOCKS),
+        }
+    }
+
+    fn cow_blocks(&self) -> u32 {
+        if self.incompat_features & FEATURE_INCOMPAT_COW == 0 {
+            return 0;
+        }
+        match self.cow_blocks {
+            Some(cow_blocks) => cow_blocks,
+            None => (self.num_blocks / BLOCKS_PER_JOURNAL_BLOCK).clamp(MIN_COW_BLOCKS, MAX_DEFAULT_JOURNAL_BLOCKS),
+        }
+    }
+}
+
+/// Where the metadata goes on a new image
+struct Layout {
+    bitmap_start: u32,
+    bitmap_blocks: u32,
+    inode_count: u32,
+    inode_bitmap_start: u32,
+    inode_bitmap_blocks: u32,
+    inode_table_start: u32,
+    inode_table_blocks: u32,
+    journal_start: u32,
+    journal_blocks: u32,
+    cow_start: u32,
+    cow_blocks: u32,
+    /// Everything before this block is metadata
+    first_data_block: u32,
+}
+
+impl Layout {
+    fn new(options: &MkfsOptions) -> std::io::Result<Self> {
+        let block_size = options.block_size as usize;
+        let bitmap_blocks = BitMaskBlock::blocks_needed(options.num_blocks as usize, block_size) as u32;
+        let inode_count = options.inode_count();
+
+        let mut layout = if inode_count == 0 {
+            // The root directory inode takes block 2, so a bitmap that does not fit in block 1
+            // goes after it
+            let bitmap_start = if bitmap_blocks == 1 { 1 } else { ROOT_INODE as u32 + 1 };
+            Layout {
+                bitmap_start, bitmap_blocks, inode_count: 0,
+                inode_bitmap_start: 0, inode_bitmap_blocks: 0, inode_table_start: 0, inode_table_blocks: 0,
+                journal_start: 0, journal_blocks: 0, cow_start: 0, cow_blocks: 0,
+                first_data_block: (bitmap_start + bitmap_blocks).max(ROOT_INODE as u32 + 1),
+            }
+        } else {
+            // Round up to fill the last inode table block
+            let inodes_per_block = options.block_size / options.inode_size;
+            let inode_table_blocks = div_ceil(inode_count.max(NUM_RESERVED_INODES + 1), inodes_per_block);
+            let inode_count = inode_table_blocks * inodes_per_block;
+            let inode_bitmap_start = 1 + bitmap_blocks;
+            let inode_bitmap_blocks = BitMaskBlock::blocks_needed(inode_count as usize, block_size) as u32;
+            let inode_table_start = inode_bitmap_start + inode_bitmap_blocks;
+            Layout {
+                bitmap_start: 1, bitmap_blocks, inode_count,
+                inode_bitmap_start, inode_bitmap_blocks, inode_table_start, inode_table_blocks,
+                journal_start: 0, journal_blocks: 0, cow_start: 0, cow_blocks: 0,
+                first_data_block: inode_table_start + inode_table_blocks,
+            }
+        };
+        // The journal or copy-on-write area goes right after the rest of the metadata
+        layout.journal_blocks = options.journal_blocks();
+        if layout.journal_blocks != 0 {
+            layout.journal_start = layout.first_data_block;
+            layout.first_data_block += layout.journal_blocks;
+        }
+        layout.cow_blocks = options.cow_blocks();
+        if layout.cow_blocks != 0 {
+            layout.cow_start = layout.first_data_block;
+            layout.first_data_block += layout.cow_blocks;
+        }
+
+        if layout.first_data_block as u64 >= options.num_blocks as u64 {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("{} blocks are too few, the metadata alone takes {}", options.num_blocks, layout.first_data_block)));
+        }
+        Ok(layout)
+    }
+}
+
+/// Bitmap blocks tracking `num_indices` entries with the first `num_used` taken
+fn initial_bitmap(num_indices: u32, num_bitmap_blocks: u32, num_used: u32, block_size: usize) -> BitMaskBlock {
+    let mut bitmap = BitMaskBlock::new(num_indices as usize, &vec![0u8; num_bitmap_blocks as usize * block_size], block_size);
+    for index in 0..num_used {
+        bitmap.set_bit(index);
+    }
+    bitmap
+}
+
+/// Write an empty file system with just the root directory onto `device`, and return it
+/// loaded and ready to be filled or mounted.
+pub fn mkfs<BF: BlockFile>(mut device: BF, options: &MkfsOptions) -> std::io::Result<LearnedFileSystem<BF>> {
+    options.validate()?;
+    let layout = Layout::new(options)?;
+    let block_size = options.block_size as usize;
+    if device.block_size() != block_size || device.num_blocks() < options.num_blocks as usize {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("device does not have {} blocks of {} bytes", options.num_blocks, block_size)));
+    }
+
+    for block in 1..layout.first_data_block {
+        device.block_write(vec![0u8; block_size], block as usize)?;
+    }
+
+    let mut bitmap = initial_bitmap(options.num_blocks, layout.bitmap_blocks, layout.first_data_block, block_size);
+    if layout.bitmap_start > 1 {
+        // The bitmap went after the root inode, so block 1 is an ordinary free block
+        bitmap.clear_bit(1);
+    }
+    for bitmap_block_idx in 0..layout.bitmap_blocks {
+        device.block_write(bitmap.bitmap_block(bitmap_block_idx as usize), (layout.bitmap_start + bitmap_block_idx) as usize)?;
+    }
+    if layout.inode_count != 0 {
+        let inode_bitmap = initial_bitmap(layout.inode_count, layout.inode_bitmap_blocks, ROOT_INODE as u32 + 1, block_size);
+        for bitmap_block_idx in 0..layout.inode_bitmap_blocks {
+            device.block_write(inode_bitmap.bitmap_block(bitmap_block_idx as usize), (layout.inode_bitmap_start + bitmap_block_idx) as usize)?;
+        }
+    }
+
+    let mut super_block = FsSuperBlock {
+        magic: FS_MAGIC_NUM,
+        disk_size: options.num_blocks,
+        version: FS_VERSION,
+        incompat_features: options.incompat_features,
+        bitmap_start: layout.bitmap_start,
+        bitmap_blocks: layout.bitmap_blocks,
+        inode_table_start: layout.inode_table_start,
+        inode_table_blocks: layout.inode_table_blocks,
+        inode_bitmap_start: layout.inode_bitmap_start,
+        inode_bitmap_blocks: layout.inode_bitmap_blocks,
+        inode_size: if layout.inode_count == 0 { 0 } else { options.inode_size },
+        inode_count: layout.inode_count,
+        ro_compat_features: options.ro_compat_features,
+        compat_features: options.compat_features,
+        journal_start: layout.journal_start,
+        journal_blocks: layout.journal_blocks,
+        cow_start: layout.cow_start,
+        cow_blocks: layout.cow_blocks,
+        block_size: options.block_size,
+        uuid: generate_uuid()?,
+        ..FsSuperBlock::default()
+    };
+    super_block.set_label(&options.label)?;
+    if super_block.is_cow() {
+        super_block.cow_checksum = super_block.compute_checksum();
+    }
+    let super_block_data: Vec<u8> = super_block.into();
+    device.block_write(&super_block_data, 0)?;
+
+    // From here on the image is valid apart from the root inode, so the regular code paths
+    // can finish the job
+    let mut file_system = LearnedFileSystem::new(device, String::new());
+    let super_block = file_system.load()?;
+    let root = file_system.new_inode(0o40755, options.root_uid, options.root_gid);
+    file_system.write_inode(ROOT_INODE, root)?;
+    file_system.write_superblock(super_block)?;
+    Ok(file_system)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::structs::superblock::{MAX_BLOCK_SIZE, MIN_BLOCK_SIZE};
+    use crate::utils::block_file::MemoryBlockFile;
+    use super::*;
+
+    #[test]
+    fn new_images_are_clean() {
+        let mut block_size = MIN_BLOCK_SIZE;
+        while block_size <= MAX_BLOCK_SIZE {
+            // Big enough for a bitmap of several blocks where that does not take too much memory
+            let num_blocks = if block_size <= 4096 { block_size * 8 + 64 } else { 2048 };
+            for inode_count in [Some(0), None] {
+                for features in ["none", "extents,var_dirents,dir_index,has_journal", "extents,var_dirents,learned_index,cow"] {
+                    let mut options = MkfsOptions { num_blocks, block_size, inode_count, ..MkfsOptions::default() };
+                    options.set_features(features).unwrap();
+                    let device = MemoryBlockFile::new(block_size as usize, vec![0u8; num_blocks as usize * block_size as usize]);
+                    let mut file_system = mkfs(device, &options).unwrap();
+                    let report = file_system.fsck(false).unwrap();
+                    assert!(report.problems.is_empty(), "{} byte blocks, inode count {:?}, {}: {:?}",
+                            block_size, inode_count, features, report.problems);
+                }
+            }
+            block_size *= 2;
+        }
+    }
+}
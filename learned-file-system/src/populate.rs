@@ -0,0 +1,203 @@
+use std::fs::{self, File, Metadata};
+use std::io::{Error, ErrorKind};
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use crate::LearnedFileSystem;
+use crate::structs::fsinode::FSINode;
+use crate::utils::block_file::BlockFile;
+use crate::utils::div_ceil;
+
+/// Host files are copied this many blocks at a time
+const COPY_CHUNK_BLOCKS: usize = 256;
+
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+/// What `populate` copied
+#[derive(Clone, Debug, Default)]
+pub struct PopulateReport {
+    pub num_files: usize,
+    pub num_dirs: usize,
+    /// Host paths that are neither regular files nor directories, such as symlinks and devices,
+    /// which the image cannot hold
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Prefix the error with the path it is about
+fn with_path(path: &Path, e: Error) -> Error {
+    Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+/// Owner of a host file, which must fit in the 16-bit inode fields
+fn host_owner(path: &Path, metadata: &Metadata) -> std::io::Result<(u16, u16)> {
+    let uid = u16::try_from(metadata.uid())
+        .map_err(|_| Error::new(ErrorKind::InvalidData, format!("{}: uid {} does not fit in 16 bits", path.display(), metadata.uid())))?;
+    let gid = u16::try_from(metadata.gid())
+        .map_err(|_| Error::new(ErrorKind::InvalidData, format!("{}: gid {} does not fit in 16 bits", path.display(), metadata.gid())))?;
+    Ok((uid, gid))
+}
+
+/// Give a host file or directory the mtime, owner and mode of an inode. The owner is only
+/// set when running as root, and the mode goes last since chown clears setuid bits.
+fn set_host_attributes(host_file: &File, node: &FSINode) -> std::io::Result<()> {
+    host_file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(node.mtime as u64))?;
+    // SAFETY: geteuid cannot fail
+    if unsafe { libc::geteuid() } == 0 {
+        std::os::unix::fs::fchown(host_file, Some(node.uid as u32), Some(node.gid as u32))?;
+    }
+    host_file.set_permissions(fs::Permissions::from_mode(node.mode & 0o7777))
+}
+
+/// Copying directory trees between the host and an image, without going through FUSE. Files
+/// and directories are created with the same code the FUSE handlers use, so the result is
+/// what copying the tree onto the mounted image would give.
+impl <BF: BlockFile> LearnedFileSystem<BF> {
+    /// Copy the contents of `host_dir` into directory `dir` of the image, keeping modes, owners,
+    /// mtimes and holes. Blocks of zeroes become holes too. Directories that already exist in
+    /// the image are merged into, files that already exist are an error.
+    pub fn populate(&mut self, host_dir: &Path, dir: u64) -> std::io::Result<PopulateReport> {
+        if self.read_only {
+            return Err(Error::new(ErrorKind::ReadOnlyFilesystem, "the file system is read-only"));
+        }
+        let mut report = PopulateReport::default();
+        self.populate_dir(host_dir, dir, &mut report)?;
+        self.copy_host_attributes(host_dir, dir)?;
+
+        let super_block = self.get_superblock()?;
+        self.write_superblock(super_block)?;
+        Ok(report)
+    }
+
+    fn populate_dir(&mut self, host_dir: &Path, dir: u64, report: &mut PopulateReport) -> std::io::Result<()> {
+        let mut entries = fs::read_dir(host_dir).map_err(|e| with_path(host_dir, e))?
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(|e| with_path(host_dir, e))?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let metadata = fs::symlink_metadata(&path).map_err(|e| with_path(&path, e))?;
+            let mode = if metadata.is_dir() {
+                S_IFDIR
+            } else if metadata.is_file() {
+                S_IFREG
+            } else {
+                report.skipped.push(path);
+                continue;
+            } | (metadata.mode() & 0o7777);
+
+            let dir_node = self.get_inode(dir)?;
+            let (_, existing) = self.find_dirent(&dir_node, &entry.file_name())?;
+            // Fixed-size entries don't record the type, so go by the inode
+            let existing_dir = existing.map(|(_, dirent)| dirent.inode_ptr as u64)
+                .filter(|inode| metadata.is_dir() && self.get_inode(*inode).is_ok_and(|node| node.mode & S_IFMT == S_IFDIR));
+            let inode = match existing_dir {
+                Some(inode) => inode,
+                _ => {
+                    let (uid, gid) = host_owner(&path, &metadata)?;
+                    self.create_inode(dir, &entry.file_name(), mode, uid, gid).map_err(|e| with_path(&path, e))?.0
+                }
+            };
+
+            if metadata.is_dir() {
+                report.num_dirs += 1;
+                self.populate_dir(&path, inode, report)?;
+            } else {
+                report.num_files += 1;
+                self.copy_host_file(&path, inode)?;
+            }
+            self.copy_host_attributes(&path, inode)?;
+        }
+        Ok(())
+    }
+
+    fn copy_host_file(&mut self, path: &Path, inode: u64) -> std::io::Result<()> {
+        let host_file = File::open(path).map_err(|e| with_path(path, e))?;
+        let mut node = self.get_inode(inode)?;
+        let mut chunk = vec![0u8; COPY_CHUNK_BLOCKS * self.block_size];
+        let mut chunk_start = 0;
+        loop {
+            let mut len = 0;
+            while len < chunk.len() {
+                match host_file.read_at(&mut chunk[len..], (chunk_start + len) as u64).map_err(|e| with_path(path, e))? {
+                    0 => break,
+                    num_read => len += num_read,
+                }
+            }
+            if len == 0 {
+                break;
+            }
+
+            // Write each run of blocks that are not all zeroes in one go
+            let mut run_start = None;
+            for (block_idx, block) in chunk[..len].chunks(self.block_size).enumerate() {
+                let is_hole = block.iter().all(|byte| *byte == 0);
+                match (run_start, is_hole) {
+                    (None, false) => run_start = Some(block_idx),
+                    (Some(start), true) => {
+                        let run = &chunk[(start * self.block_size)..(block_idx * self.block_size)];
+                        self.write_file_data(&mut node, chunk_start + start * self.block_size, run).map_err(|e| with_path(path, e))?;
+                        run_start = None;
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(start) = run_start {
+                let run = &chunk[(start * self.block_size)..len];
+                self.write_file_data(&mut node, chunk_start + start * self.block_size, run).map_err(|e| with_path(path, e))?;
+            }
+            chunk_start += len;
+        }
+
+        // Covers trailing holes, which are never written
+        node.size = chunk_start as u64;
+        self.write_inode(inode, node)
+    }
+
+    fn copy_host_attributes(&mut self, path: &Path, inode: u64) -> std::io::Result<()> {
+        let metadata = fs::metadata(path).map_err(|e| with_path(path, e))?;
+        let mut node = self.get_inode(inode)?;
+        node.mode = (node.mode & S_IFMT) | (metadata.mode() & 0o7777);
+        (node.uid, node.gid) = host_owner(path, &metadata)?;
+        node.mtime = u32::try_from(metadata.mtime())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, format!("{}: mtime {} is out of range", path.display(), metadata.mtime())))?;
+        self.write_inode(inode, node)
+    }
+
+    /// Copy the contents of directory `dir` of the image into `host_dir`, which is created if
+    /// it does not exist. Modes and mtimes are kept, and so are owners when running as root.
+    /// Holes stay holes as far as the host file system allows.
+    pub fn extract(&self, dir: u64, host_dir: &Path) -> std::io::Result<()> {
+        if !host_dir.exists() {
+            fs::create_dir(host_dir).map_err(|e| with_path(host_dir, e))?;
+        }
+        let dir_node = self.get_inode(dir)?;
+        for dirent in self.get_valid_dirents(&dir_node)? {
+            let path = host_dir.join(&dirent.name);
+            let node = self.get_inode(dirent.inode_ptr as u64)?;
+            match node.mode & S_IFMT {
+                S_IFDIR => self.extract(dirent.inode_ptr as u64, &path)?,
+                _ => self.extract_file(&node, &path)?,
+            }
+        }
+
+        // Last, since a read-only mode would keep the entries above from being created
+        File::open(host_dir).and_then(|host_dir_file| set_host_attributes(&host_dir_file, &dir_node)).map_err(|e| with_path(host_dir, e))
+    }
+
+    fn extract_file(&self, node: &FSINode, path: &Path) -> std::io::Result<()> {
+        let host_file = File::create(path).map_err(|e| with_path(path, e))?;
+        for block_idx in 0..div_ceil(node.size as usize, self.block_size) {
+            if self.get_block_pointer(node, block_idx)? == 0 {
+                continue;
+            }
+            let offset = block_idx * self.block_size;
+            let data = self.read_file_bytes(node, offset, self.block_size).map_err(|e| with_path(path, e))?;
+            host_file.write_all_at(&data, offset as u64).map_err(|e| with_path(path, e))?;
+        }
+        host_file.set_len(node.size).map_err(|e| with_path(path, e))?;
+        set_host_attributes(&host_file, node).map_err(|e| with_path(path, e))
+    }
+}
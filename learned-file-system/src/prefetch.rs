@@ -0,0 +1,409 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use crate::LearnedFileSystem;
+use crate::utils::block_file::BlockFile;
+use crate::utils::cache_policy::{AccessHint, AccessKind};
+
+/// A block of a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FileBlock {
+    pub inode: u64,
+    pub block: u64,
+}
+
+/// A read or write of the blocks `first_block..=last_block` of a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileAccess {
+    pub inode: u64,
+    pub first_block: u64,
+    pub last_block: u64,
+    pub write: bool,
+}
+
+impl FileAccess {
+    fn blocks(&self) -> impl Iterator<Item = FileBlock> + '_ {
+        (self.first_block..=self.last_block).map(|block| FileBlock { inode: self.inode, block })
+    }
+}
+
+/// Guesses which file blocks are used next from the reads and writes so far
+pub trait Predictor {
+    fn name(&self) -> &'static str;
+
+    /// Learn from an access and predict the blocks used after it, most likely first
+    fn access(&mut self, access: &FileAccess) -> Vec<FileBlock>;
+
+    /// Drop what was learned about a deleted inode, whose number may be reused
+    fn forget_inode(&mut self, inode: u64);
+}
+
+/// Prefetch predictors by name, for command lines
+pub const PREDICTOR_NAMES: [&str; 2] = ["stride", "markov"];
+
+/// A new predictor by its name in `PREDICTOR_NAMES`
+pub fn predictor_from_name(name: &str) -> std::io::Result<Box<dyn Predictor>> {
+    Ok(match name {
+        "stride" => Box::new(StridePredictor::default()),
+        "markov" => Box::new(MarkovPredictor::default()),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, format!("unknown prefetch predictor {}", name))),
+    })
+}
+
+/// Blocks read ahead when a sequential or strided stream is first seen
+const INITIAL_WINDOW: u64 = 2;
+/// Most blocks a stream is read ahead by
+const MAX_WINDOW: u64 = 32;
+/// Most streams the stride predictor follows at once
+const MAX_STREAMS: usize = 4096;
+
+struct Stream {
+    last_first_block: u64,
+    last_block: u64,
+    /// Distance between the first blocks of the last two accesses
+    stride: i64,
+    window: u64,
+    /// Blocks up to here have been predicted already
+    predicted_to: u64,
+}
+
+/// Read ahead on every file whose accesses go up by the same stride each time, doubling
+/// the read-ahead window while the pattern holds, like the read-ahead of most kernels.
+/// Sequential access is the stride of the access length.
+#[derive(Default)]
+pub struct StridePredictor {
+    streams: HashMap<u64, Stream>,
+}
+
+impl Predictor for StridePredictor {
+    fn name(&self) -> &'static str {
+        "stride"
+    }
+
+    fn access(&mut self, access: &FileAccess) -> Vec<FileBlock> {
+        if self.streams.len() >= MAX_STREAMS && !self.streams.contains_key(&access.inode) {
+            self.streams.clear();
+        }
+        let len = access.last_block - access.first_block + 1;
+        let stream = self.streams.entry(access.inode).or_insert(Stream {
+            last_first_block: access.first_block,
+            last_block: access.last_block,
+            stride: len as i64,
+            window: 0,
+            predicted_to: access.last_block,
+        });
+        let stride = access.first_block as i64 - stream.last_first_block as i64;
+        let sequential = access.first_block == stream.last_block + 1;
+        let strided = stride != 0 && stride == stream.stride;
+        stream.window = if sequential || strided || (access.first_block == 0 && stream.window == 0) {
+            (stream.window * 2).clamp(INITIAL_WINDOW, MAX_WINDOW)
+        } else {
+            0
+        };
+        stream.stride = if sequential { len as i64 } else { stride };
+        stream.last_first_block = access.first_block;
+        stream.last_block = access.last_block;
+        if stream.window == 0 {
+            stream.predicted_to = access.last_block;
+            return vec![];
+        }
+
+        let mut predictions = vec![];
+        if sequential || stream.stride == len as i64 {
+            let from = stream.predicted_to.max(access.last_block) + 1;
+            let to = access.last_block + stream.window;
+            predictions.extend((from..=to).map(|block| FileBlock { inode: access.inode, block }));
+            stream.predicted_to = stream.predicted_to.max(to);
+        } else {
+            // The next few accesses at the same stride, each as long as this one
+            let num_accesses = stream.window.div_ceil(len);
+            for step in 1..=num_accesses as i64 {
+                let first = access.first_block as i64 + step * stream.stride;
+                if first >= 0 {
+                    predictions.extend((first as u64..first as u64 + len).map(|block| FileBlock { inode: access.inode, block }));
+                }
+            }
+        }
+        predictions
+    }
+
+    fn forget_inode(&mut self, inode: u64) {
+        self.streams.remove(&inode);
+    }
+}
+
+/// Most successors kept per context of the Markov predictor
+const MAX_SUCCESSORS: usize = 4;
+/// Contexts not seen for this many block accesses are forgotten once there are this many
+const MAX_CONTEXTS: usize = 1 << 16;
+/// Times a context has to be seen before its order 2 statistics are used
+const MIN_ORDER2_COUNT: u32 = 2;
+/// Least share of the transitions out of a context a successor needs to be predicted
+const MIN_CONFIDENCE: f64 = 0.4;
+/// Most blocks the Markov predictor predicts ahead, following its most likely chain
+const MARKOV_DEPTH: usize = 8;
+
+#[derive(Default)]
+struct Successors {
+    counts: Vec<(FileBlock, u32)>,
+    total: u32,
+    /// Block access counter value when the context was last seen
+    last_seen: u64,
+}
+
+impl Successors {
+    fn add(&mut self, next: FileBlock, now: u64) {
+        self.total += 1;
+        self.last_seen = now;
+        if let Some((_, count)) = self.counts.iter_mut().find(|(block, _)| *block == next) {
+            *count += 1;
+        } else if self.counts.len() < MAX_SUCCESSORS {
+            self.counts.push((next, 1));
+        } else {
+            // Replace the rarest, so new patterns can take over from old ones
+            let rarest = self.counts.iter_mut().min_by_key(|(_, count)| *count).unwrap();
+            *rarest = (next, 1);
+        }
+    }
+
+    /// The most likely successor, if it is likely enough
+    fn best(&self) -> Option<FileBlock> {
+        let (block, count) = self.counts.iter().max_by_key(|(_, count)| *count)?;
+        (*count as f64 / self.total as f64 >= MIN_CONFIDENCE).then_some(*block)
+    }
+}
+
+/// Learns which block follows which, across files, as a Markov model of order 2 that falls
+/// back to order 1 for contexts it has seen too little of. Predicts the most likely chain of
+/// blocks after the current one, as far as every step is likely enough. Picks up any
+/// pattern that repeats, such as a program reading the same files in the same order each
+/// time it starts, but needs to see a pattern once before it can predict it.
+#[derive(Default)]
+pub struct MarkovPredictor {
+    order1: HashMap<FileBlock, Successors>,
+    order2: HashMap<(FileBlock, FileBlock), Successors>,
+    /// The last two blocks accessed, most recent last
+    history: (Option<FileBlock>, Option<FileBlock>),
+    clock: u64,
+}
+
+impl MarkovPredictor {
+    fn learn(&mut self, block: FileBlock) {
+        self.clock += 1;
+        if let Some(previous) = self.history.1 {
+            self.order1.entry(previous).or_default().add(block, self.clock);
+            if let Some(before) = self.history.0 {
+                self.order2.entry((before, previous)).or_default().add(block, self.clock);
+            }
+        }
+        self.history = (self.history.1, Some(block));
+
+        if self.order1.len() > MAX_CONTEXTS || self.order2.len() > MAX_CONTEXTS {
+            let oldest = self.clock.saturating_sub(MAX_CONTEXTS as u64 / 2);
+            self.order1.retain(|_, successors| successors.last_seen > oldest);
+            self.order2.retain(|_, successors| successors.last_seen > oldest);
+        }
+    }
+
+    fn next(&self, before: Option<FileBlock>, previous: FileBlock) -> Option<FileBlock> {
+        before.and_then(|before| self.order2.get(&(before, previous)))
+            .filter(|successors| successors.total >= MIN_ORDER2_COUNT)
+            .or_else(|| self.order1.get(&previous))
+            .and_then(Successors::best)
+    }
+}
+
+impl Predictor for MarkovPredictor {
+    fn name(&self) -> &'static str {
+        "markov"
+    }
+
+    fn access(&mut self, access: &FileAccess) -> Vec<FileBlock> {
+        for block in access.blocks() {
+            self.learn(block);
+        }
+
+        let mut predictions = vec![];
+        let (mut before, mut previous) = self.history;
+        while let (Some(last), true) = (previous, predictions.len() < MARKOV_DEPTH) {
+            match self.next(before, last) {
+                Some(next) if !predictions.contains(&next) => {
+                    predictions.push(next);
+                    (before, previous) = (Some(last), Some(next));
+                }
+                _ => break,
+            }
+        }
+        predictions
+    }
+
+    fn forget_inode(&mut self, inode: u64) {
+        let mentions = |block: &FileBlock| block.inode == inode;
+        self.order1.retain(|block, successors| {
+            successors.counts.retain(|(next, _)| !mentions(next));
+            !mentions(block)
+        });
+        self.order2.retain(|(before, previous), successors| {
+            successors.counts.retain(|(next, _)| !mentions(next));
+            !mentions(before) && !mentions(previous)
+        });
+        if self.history.0.as_ref().is_some_and(mentions) || self.history.1.as_ref().is_some_and(mentions) {
+            self.history = (None, None);
+        }
+    }
+}
+
+/// How the predictions of one predictor turned out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PredictorStats {
+    pub predictor: &'static str,
+    /// Only scored next to the predictor whose predictions are prefetched, see
+    /// `Prefetcher::add_shadow_predictor`
+    pub shadow: bool,
+    /// Blocks read or written
+    pub accesses: u64,
+    /// Distinct blocks predicted
+    pub predictions: u64,
+    /// Predicted blocks that were used before the prediction expired
+    pub useful: u64,
+}
+
+impl PredictorStats {
+    /// Share of the predictions that were used
+    pub fn accuracy(&self) -> f64 {
+        if self.predictions == 0 { 0.0 } else { self.useful as f64 / self.predictions as f64 }
+    }
+
+    /// Share of the accesses that were predicted
+    pub fn coverage(&self) -> f64 {
+        if self.accesses == 0 { 0.0 } else { self.useful as f64 / self.accesses as f64 }
+    }
+}
+
+impl fmt::Display for PredictorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}: {} accesses, {} predictions, {} useful ({:.1}% accuracy, {:.1}% coverage)",
+               self.predictor, if self.shadow { " (shadow)" } else { "" }, self.accesses, self.predictions, self.useful,
+               self.accuracy() * 100.0, self.coverage() * 100.0)
+    }
+}
+
+/// Predictions not used within this many newer predictions count as wrong
+const PREDICTION_WINDOW: usize = 1024;
+
+struct ScoredPredictor {
+    predictor: Box<dyn Predictor>,
+    /// Predictions not used yet
+    pending: HashSet<FileBlock>,
+    /// Order the pending predictions were made in, oldest first. May hold used ones too.
+    order: VecDeque<FileBlock>,
+    stats: PredictorStats,
+}
+
+impl ScoredPredictor {
+    fn new(predictor: Box<dyn Predictor>, shadow: bool) -> Self {
+        let stats = PredictorStats { predictor: predictor.name(), shadow, accesses: 0, predictions: 0, useful: 0 };
+        ScoredPredictor { predictor, pending: HashSet::new(), order: VecDeque::new(), stats }
+    }
+
+    fn access(&mut self, access: &FileAccess) -> Vec<FileBlock> {
+        for block in access.blocks() {
+            self.stats.accesses += 1;
+            if self.pending.remove(&block) {
+                self.stats.useful += 1;
+            }
+        }
+        let predictions = self.predictor.access(access);
+        for block in &predictions {
+            if self.pending.insert(*block) {
+                self.stats.predictions += 1;
+                self.order.push_back(*block);
+            }
+        }
+        while self.order.len() > PREDICTION_WINDOW {
+            let expired = self.order.pop_front().unwrap();
+            self.pending.remove(&expired);
+        }
+        predictions
+    }
+}
+
+/// Watches the reads and writes of a mounted file system and reads the blocks a predictor
+/// expects next into the block cache, see `BlockFile::prefetch`
+pub struct Prefetcher {
+    predictor: ScoredPredictor,
+    shadows: Vec<ScoredPredictor>,
+}
+
+impl Prefetcher {
+    pub fn new(predictor: Box<dyn Predictor>) -> Self {
+        Prefetcher { predictor: ScoredPredictor::new(predictor, false), shadows: vec![] }
+    }
+
+    /// Also score `predictor` on every access, to compare it with the one in use on the
+    /// same workload. Its predictions are not prefetched.
+    pub fn add_shadow_predictor(&mut self, predictor: Box<dyn Predictor>) {
+        self.shadows.push(ScoredPredictor::new(predictor, true));
+    }
+
+    /// The predictor in use first, then the shadow predictors
+    pub fn stats(&self) -> Vec<PredictorStats> {
+        std::iter::once(&self.predictor).chain(&self.shadows).map(|scored| scored.stats).collect()
+    }
+
+    /// Blocks to prefetch after `access`
+    pub fn access(&mut self, access: &FileAccess) -> Vec<FileBlock> {
+        for shadow in &mut self.shadows {
+            shadow.access(access);
+        }
+        self.predictor.access(access)
+    }
+
+    pub fn forget_inode(&mut self, inode: u64) {
+        for scored in std::iter::once(&mut self.predictor).chain(&mut self.shadows) {
+            scored.predictor.forget_inode(inode);
+            scored.pending.retain(|block| block.inode != inode);
+        }
+    }
+}
+
+/// Prefetching the blocks reads and writes are about to use.
+impl <BF: BlockFile> LearnedFileSystem<BF> {
+    /// Prefetch with `prefetcher` from now on, which only pays off with a block cache
+    pub fn set_prefetcher(&mut self, prefetcher: Prefetcher) {
+        self.prefetcher = Some(prefetcher);
+    }
+
+    pub fn prefetch_stats(&self) -> Vec<PredictorStats> {
+        self.prefetcher.as_ref().map_or(vec![], Prefetcher::stats)
+    }
+
+    /// Tell the prefetcher about a read or write of `len` bytes at `offset` of `inode`, and
+    /// prefetch the blocks it predicts that are mapped
+    pub(crate) fn prefetch_after(&mut self, inode: u64, offset: u64, len: usize, write: bool) {
+        if len == 0 {
+            return;
+        }
+        let block_size = self.block_size as u64;
+        let access = FileAccess { inode, first_block: offset / block_size, last_block: (offset + len as u64 - 1) / block_size, write };
+        let predictions = match self.prefetcher.as_mut() {
+            Some(prefetcher) => prefetcher.access(&access),
+            None => return,
+        };
+
+        let mut file = None;
+        for FileBlock { inode, block } in predictions {
+            if file.as_ref().map(|(file_inode, _)| *file_inode) != Some(inode) {
+                file = self.get_inode(inode).ok().map(|node| (inode, node));
+            }
+            let Some((_, node)) = file.as_ref() else { continue };
+            if block * block_size >= node.size {
+                continue;
+            }
+            if let Ok(physical_block @ 1..) = self.get_block_pointer(node, block as usize) {
+                let kind = if node.is_regular_file() { AccessKind::Data } else { AccessKind::Directory };
+                self.block_system.prefetch(physical_block as usize, AccessHint { kind, inode: Some(inode), logical_block: block });
+            }
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use std::collections::BTreeSet;
+use std::io::{Error, ErrorKind};
+use crate::LearnedFileSystem;
+use crate::fsck::{FsckProblem, METADATA_OWNER};
+use crate::structs::extent::extents_from_blocks;
+use crate::structs::superblock::{FsSuperBlock, FEATURE_INCOMPAT_RECOVER, FS_STATE_DIRTY};
+use crate::utils::bitmask::BitMaskBlock;
+use crate::utils::block_file::BlockFile;
+
+/// Changing the number of blocks of a file system. Growing only touches the bitmap and the
+/// superblock, so it also works while mounted. Shrinking first moves every block past the new end into free
+/// blocks below it and points the inodes at the copies.
+impl <BF: BlockFile> LearnedFileSystem<BF> {
+    /// Resize the unmounted file system to `new_num_blocks` blocks. The device has to hold at least
+    /// as many blocks as the larger of the old and the new size, so grow the device before
+    /// and shrink it after. Shrinking fails with OutOfMemory if the data does not fit.
+    /// An image that is mounted or was not cleanly unmounted is refused unless `force` is
+    /// set, since loading it would replay the journal under the mounted file system.
+    pub fn resize(&mut self, new_num_blocks: u32, force: bool) -> std::io::Result<()> {
+        // Checked before loading, which replays and clears the journal
+        if !force && self.get_superblock()?.incompat_features & FEATURE_INCOMPAT_RECOVER != 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "the file system is mounted or needs recovery"));
+        }
+        if !force && self.load()?.state & FS_STATE_DIRTY != 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "the file system is mounted or was not cleanly unmounted"));
+        }
+        self.change_size(new_num_blocks)
+    }
+
+    /// Resize without checking whether the file system is mounted
+    fn change_size(&mut self, new_num_blocks: u32) -> std::io::Result<()> {
+        let super_block = self.load()?;
+        if self.read_only {
+            return Err(Error::new(ErrorKind::ReadOnlyFilesystem, "the file system is read-only"));
+        }
+        let old_num_blocks = super_block.disk_size;
+        if self.block_system.num_blocks() < new_num_blocks.max(old_num_blocks) as usize {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("the device only holds {} blocks", self.block_system.num_blocks())));
+        }
+        if new_num_blocks == old_num_blocks {
+            return Ok(());
+        }
+
+        if new_num_blocks < old_num_blocks {
+            self.move_blocks_below(&super_block, new_num_blocks)?;
+        }
+        self.resize_bitmap(super_block, new_num_blocks)?;
+        self.load()?;
+        Ok(())
+    }
+
+    /// Grow the file system to the number of blocks the device holds now, typically after
+    /// the image file was extended while mounted. Returns the new number of blocks, which is
+    /// the old one if the device did not grow.
+    pub fn grow_to_device(&mut self) -> std::io::Result<u32> {
+        if self.read_only {
+            return Err(Error::new(ErrorKind::ReadOnlyFilesystem, "the file system is read-only"));
+        }
+        let num_blocks = self.get_superblock()?.disk_size;
+        let device_blocks = self.block_system.num_blocks().min(u32::MAX as usize) as u32;
+        if device_blocks <= num_blocks {
+            return Ok(num_blocks);
+        }
+        self.change_size(device_blocks)?;
+        Ok(device_blocks)
+    }
+
+    /// Move every block in use at or past `new_end` to a free block below it
+    fn move_blocks_below(&mut self, super_block: &FsSuperBlock, new_end: u32) -> std::io::Result<()> {
+        // Moving blocks around an inconsistent image would only make things worse
+        let report = self.fsck(false)?;
+        if report.problems.iter().any(|problem| !matches!(problem, FsckProblem::FreeCounts { .. })) {
+            return Err(Error::new(ErrorKind::InvalidData, "the file system has errors, run fsck.lfs -r first"));
+        }
+
+        let (bitmap_start, bitmap_blocks) = super_block.bitmap_location();
+        let new_bitmap_blocks = BitMaskBlock::blocks_needed(new_end as usize, self.block_size) as u32;
+        let bitmap_moves = bitmap_start + new_bitmap_blocks > new_end;
+
+        let mut inodes_to_move = BTreeSet::new();
+        let mut num_to_move = 0;
+        for (block, owner) in self.block_owners()?.into_iter().filter(|(block, _)| *block >= new_end) {
+            if owner == METADATA_OWNER {
+                if block >= bitmap_start && block < bitmap_start + bitmap_blocks {
+                    continue;
+                }
+                return Err(Error::new(ErrorKind::InvalidInput, format!("block {} holds file system metadata", block)));
+            }
+            if self.inode_allocation_bitmask.is_none() && owner == block as u64 {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("inode {} is block {} on this image and cannot move", owner, block)));
+            }
+            inodes_to_move.insert(owner);
+            num_to_move += 1;
+        }
+
+        let num_free_below = self.block_allocation_bitmask.free_block_iter().take_while(|block| *block < new_end).count();
+        let num_needed = num_to_move + if bitmap_moves { new_bitmap_blocks as usize } else { 0 };
+        if num_needed > num_free_below {
+            return Err(Error::new(ErrorKind::OutOfMemory,
+                                  format!("{} blocks have to move below block {}, but only {} are free there", num_needed, new_end, num_free_below)));
+        }
+
+        // Keep the allocator away from the blocks that are about to go
+        let fenced: Vec<u32> = self.block_allocation_bitmask.free_block_iter().skip_while(|block| *block < new_end).collect();
+        for block in fenced.iter() {
+            self.block_allocation_bitmask.set_bit(*block);
+        }
+        let result = inodes_to_move.into_iter().try_for_each(|inode| self.move_inode_blocks(inode, new_end));
+        for block in fenced.iter() {
+            self.block_allocation_bitmask.clear_bit(*block);
+        }
+        self.write_dirty_bitmask_blocks()?;
+        result
+    }
+
+    /// Move the data and mapping blocks of an inode that lie at or past `new_end`
+    fn move_inode_blocks(&mut self, inode: u64, new_end: u32) -> std::io::Result<()> {
+        let mut node = self.get_inode(inode)?;
+        let mut old_blocks = vec![];
+        let mut goal = 0;
+
+        if node.is_extent_mapped() {
+            let (extents, tree_blocks) = self.collect_extents(&node)?;
+            let mut blocks = vec![];
+            for extent in extents {
+                for idx in 0..extent.length {
+                    blocks.push((extent.logical_start + idx, extent.physical_start + idx));
+                }
+            }
+            for (_, physical_block) in blocks.iter_mut() {
+                if *physical_block >= new_end {
+                    old_blocks.push(*physical_block);
+                    *physical_block = self.relocate_block(*physical_block, &mut goal)?;
+                }
+            }
+            // store_extents allocates replacements for the tree blocks left out
+            let (moved_tree_blocks, kept_tree_blocks): (Vec<u32>, Vec<u32>) = tree_blocks.into_iter().partition(|block| *block >= new_end);
+            old_blocks.extend(moved_tree_blocks);
+            self.store_extents(&mut node, extents_from_blocks(&blocks), kept_tree_blocks)?;
+        } else {
+            for idx in 0..node.pointers.len() {
+                if node.pointers[idx] >= new_end {
+                    old_blocks.push(node.pointers[idx]);
+                    node.pointers[idx] = self.relocate_block(node.pointers[idx], &mut goal)?;
+                }
+            }
+            if node.single_indirect != 0 {
+                node.single_indirect = self.move_indirect_blocks(node.single_indirect, 1, new_end, &mut goal, &mut old_blocks)?;
+            }
+            if node.double_indirect != 0 {
+                node.double_indirect = self.move_indirect_blocks(node.double_indirect, 2, new_end, &mut goal, &mut old_blocks)?;
+            }
+        }
+
+        self.write_inode(inode, node)?;
+        self.free_blocks(&old_blocks)
+    }
+
+    /// Move an indirect block of the given level and the blocks below it that lie at or past
+    /// `new_end`. Returns where the indirect block ended up.
+    fn move_indirect_blocks(&mut self, block: u32, level: u32, new_end: u32, goal: &mut u32, old_blocks: &mut Vec<u32>) -> std::io::Result<u32> {
+        let block = if block >= new_end {
+            old_blocks.push(block);
+            self.relocate_block(block, goal)?
+        } else {
+            block
+        };
+
+        let mut pointers = self.read_indirect_block(block)?;
+        let mut changed = false;
+        for pointer in pointers.iter_mut().filter(|pointer| **pointer != 0) {
+            let moved = if level > 1 {
+                self.move_indirect_blocks(*pointer, level - 1, new_end, goal, old_blocks)?
+            } else if *pointer >= new_end {
+                old_blocks.push(*pointer);
+                self.relocate_block(*pointer, goal)?
+            } else {
+                *pointer
+            };
+            if moved != *pointer {
+                *pointer = moved;
+                changed = true;
+            }
+        }
+        if changed {
+            self.write_indirect_block(block, &pointers)?;
+        }
+        Ok(block)
+    }
+
+    /// Copy a block into a newly allocated one, preferably at `goal`, and move `goal` past
+    /// it so the blocks of a file stay together. The old block is left allocated.
+    fn relocate_block(&mut self, block: u32, goal: &mut u32) -> std::io::Result<u32> {
+        let new_block = self.allocate_blocks_near(1, *goal)?[0];
+        let data = self.block_system.block_read(block as usize)?;
+        self.block_system.block_write(&data, new_block as usize)?;
+        *goal = new_block + 1;
+        Ok(new_block)
+    }
+
+    /// Write a bitmap of `new_num_blocks` bits and a superblock to match. The bitmap stays
+    /// where it is if it fits, and otherwise moves to the first free run large enough,
+    /// looking past the old end first.
+    fn resize_bitmap(&mut self, mut super_block: FsSuperBlock, new_num_blocks: u32) -> std::io::Result<()> {
+        let old_num_blocks = super_block.disk_size;
+        let (old_start, old_blocks) = super_block.bitmap_location();
+        let new_blocks = BitMaskBlock::blocks_needed(new_num_blocks as usize, self.block_size) as u32;
+
+        let mut bitmap = BitMaskBlock::new(new_num_blocks as usize, &vec![0u8; new_blocks as usize * self.block_size], self.block_size);
+        for block in 0..old_num_blocks.min(new_num_blocks) {
+            if !self.block_allocation_bitmask.is_free(block) {
+                bitmap.set_bit(block);
+            }
+        }
+
+        for block in old_start..(old_start + old_blocks).min(new_num_blocks) {
+            bitmap.clear_bit(block);
+        }
+        let new_start = if new_blocks <= old_blocks && old_start + new_blocks <= new_num_blocks {
+            old_start
+        } else {
+            find_free_run(&bitmap, new_blocks, old_num_blocks.min(new_num_blocks))
+                .ok_or(Error::new(ErrorKind::OutOfMemory, format!("no room for {} bitmap blocks", new_blocks)))?
+        };
+        for block in new_start..(new_start + new_blocks) {
+            bitmap.set_bit(block);
+        }
+
+        for bitmap_block_idx in 0..(new_blocks as usize) {
+            self.block_system.block_write(bitmap.bitmap_block(bitmap_block_idx), new_start as usize + bitmap_block_idx)?;
+        }
+        bitmap.take_dirty_blocks();
+        self.block_allocation_bitmask = bitmap;
+        self.bit_mask_start_block = new_start as usize;
+
+        self.upgrade_superblock(&mut super_block)?;
+        super_block.disk_size = new_num_blocks;
+        super_block.bitmap_start = new_start;
+        super_block.bitmap_blocks = new_blocks;
+        self.write_superblock(super_block)
+    }
+}
+
+/// First run of `len` free blocks, looking at or after `goal` before wrapping around
+fn find_free_run(bitmap: &BitMaskBlock, len: u32, goal: u32) -> Option<u32> {
+    let mut run_start = 0;
+    let mut run_len = 0;
+    for block in bitmap.free_block_iter_from(goal) {
+        if run_len == 0 || block != run_start + run_len {
+            run_start = block;
+            run_len = 0;
+        }
+        run_len += 1;
+        if run_len == len {
+            return Some(run_start);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crash_test::{check_written, reopen_clean, WorkloadOp};
+    use crate::mkfs::{mkfs, MkfsOptions};
+    use crate::utils::block_file::MemoryBlockFile;
+    use super::*;
+
+    fn run_workload(file_system: &mut LearnedFileSystem<MemoryBlockFile>, workload: &str) {
+        file_system.mount().unwrap();
+        for op in WorkloadOp::parse_workload(workload).unwrap() {
+            file_system.run_workload_op(&op).unwrap();
+        }
+        file_system.unmount().unwrap();
+    }
+
+    #[test]
+    fn mounted_images_need_force() {
+        let mut options = MkfsOptions { num_blocks: 1024, block_size: 1024, ..MkfsOptions::default() };
+        options.set_features("extents,var_dirents,dir_index,has_journal").unwrap();
+        let mut file_system = mkfs(MemoryBlockFile::new(1024, vec![0u8; 2048 * 1024]), &options).unwrap();
+        file_system.mount().unwrap();
+
+        assert_eq!(file_system.resize(2048, false).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert_eq!(file_system.get_superblock().unwrap().disk_size, 1024);
+        file_system.resize(2048, true).unwrap();
+        assert_eq!(file_system.get_superblock().unwrap().disk_size, 2048);
+    }
+
+    #[test]
+    fn grow_and_shrink_round_trip() {
+        let mut options = MkfsOptions { num_blocks: 1024, block_size: 1024, ..MkfsOptions::default() };
+        options.set_features("extents,var_dirents,dir_index,has_journal").unwrap();
+        let mut file_system = mkfs(MemoryBlockFile::new(1024, vec![0u8; 2048 * 1024]), &options).unwrap();
+        run_workload(&mut file_system, "
+            mkdir /d
+            create /d/f
+            write /d/f 0 300000
+        ");
+
+        file_system.resize(2048, false).unwrap();
+        let mut file_system = reopen_clean(file_system.block_system.device.device);
+        assert_eq!(file_system.get_superblock().unwrap().disk_size, 2048);
+        check_written(&file_system, "/d/f", 0, 300000);
+        // The filler takes the low blocks, so most of /g lands past where the image ends
+        // after shrinking
+        run_workload(&mut file_system, "
+            create /filler
+            write /filler 0 900000
+            create /d/g
+            write /d/g 0 500000
+            unlink /filler
+        ");
+        let inode = file_system.lookup_path("/d/g").unwrap();
+        let node = file_system.get_inode(inode).unwrap();
+        assert!(file_system.mapped_blocks(inode, &node).iter().any(|(_, block)| *block >= 1200));
+
+        file_system.resize(1200, false).unwrap();
+        let mut data = file_system.block_system.device.device.into_data();
+        data.truncate(1200 * 1024);
+        let file_system = reopen_clean(MemoryBlockFile::new(1024, data));
+        assert_eq!(file_system.get_superblock().unwrap().disk_size, 1200);
+        check_written(&file_system, "/d/f", 0, 300000);
+        check_written(&file_system, "/d/g", 0, 500000);
+        assert!(file_system.lookup_path("/filler").is_err());
+    }
+
+    #[test]
+    fn online_grow_round_trip() {
+        let mut options = MkfsOptions { num_blocks: 1024, block_size: 1024, ..MkfsOptions::default() };
+        options.set_features("extents,var_dirents,dir_index,has_journal").unwrap();
+        let mut file_system = mkfs(MemoryBlockFile::new(1024, vec![0u8; 1024 * 1024]), &options).unwrap();
+        file_system.mount().unwrap();
+        file_system.run_workload_op(&WorkloadOp::Create("/f".to_string())).unwrap();
+        file_system.run_workload_op(&WorkloadOp::Write { path: "/f".to_string(), offset: 0, len: 600000 }).unwrap();
+        assert_eq!(file_system.grow_to_device().unwrap(), 1024);
+
+        // Extend the image file under the mounted file system
+        let device = std::mem::replace(&mut file_system.block_system.device.device, MemoryBlockFile::new(1024, vec![]));
+        let mut data = device.into_data();
+        data.resize(3072 * 1024, 0);
+        file_system.block_system.device.device = MemoryBlockFile::new(1024, data);
+        assert_eq!(file_system.grow_to_device().unwrap(), 3072);
+        // More than fit before the grow
+        file_system.run_workload_op(&WorkloadOp::Create("/g".to_string())).unwrap();
+        file_system.run_workload_op(&WorkloadOp::Write { path: "/g".to_string(), offset: 0, len: 1500000 }).unwrap();
+        file_system.unmount().unwrap();
+
+        let file_system = reopen_clean(file_system.block_system.device.device);
+        assert_eq!(file_system.get_superblock().unwrap().disk_size, 3072);
+        check_written(&file_system, "/f", 0, 600000);
+        check_written(&file_system, "/g", 0, 1500000);
+    }
+}
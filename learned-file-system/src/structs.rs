@@ -1,3 +1,6 @@
+pub mod dir_model;
 pub mod dirent;
+pub mod extent;
 pub mod fsinode;
+pub mod htree;
 pub mod superblock;
\ No newline at end of file
@@ -0,0 +1,184 @@
+use crate::structs::dirent::rec_len_to_disk;
+
+/// Marks the first block of a directory as holding a learned lookup model ("LIDX")
+pub const DIR_MODEL_MAGIC: u32 = 0x5844494C;
+
+/// The model block starts with a free directory record spanning the whole block, so a
+/// linear scan of the directory skips it. The model header follows that record.
+const DIR_MODEL_FAKE_DIRENT_SIZE: usize = 8;
+const DIR_MODEL_HEADER_SIZE: usize = 32;
+const DIR_MODEL_SEGMENT_SIZE: usize = 12;
+
+/// Byte offset of `num_unindexed` in the model block, so it can be updated on its own
+pub const DIR_MODEL_UNINDEXED_OFFSET: usize = 28;
+
+/// Error bound the model is first trained for, in blocks. Doubled until the model fits.
+const INITIAL_TARGET_ERROR: u32 = 1;
+
+/// One linear piece of the model, used for hashes from `first_hash` up to the
+/// `first_hash` of the next segment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModelSegment {
+    pub first_hash: u32,
+    /// Blocks per unit of hash
+    pub slope: f32,
+    /// Predicted block at `first_hash`
+    pub intercept: f32,
+}
+
+/// Piecewise-linear model from name hash to the block of the directory holding the name.
+/// It lives in logical block 0 of the directory, and is trained on the entries laid out in
+/// hash order in the blocks after it.
+///
+/// Layout after the fake directory record: magic (u32), number of segments (u16), padding,
+/// max error (u32), number of data blocks (u32), entries indexed (u32), entries added since
+/// (u32), then (first hash, slope, intercept) segments.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirModel {
+    pub segments: Vec<ModelSegment>,
+    /// Every indexed entry is at most this many blocks away from its prediction
+    pub max_error: u32,
+    /// The model covers logical blocks 1 up to and including this one
+    pub num_data_blocks: u32,
+    /// Entries the model was trained on
+    pub num_indexed: u32,
+    /// Entries placed outside their predicted window since the model was trained. While
+    /// this is nonzero, a name the model cannot find might still be somewhere else.
+    pub num_unindexed: u32,
+}
+
+impl ModelSegment {
+    fn predict(&self, hash: u32) -> f64 {
+        self.intercept as f64 + self.slope as f64 * (hash as f64 - self.first_hash as f64)
+    }
+}
+
+/// Greedily split `keys` into segments, each a line through its first key that stays within
+/// `target_error` blocks of the rest of its keys
+fn fit_segments(keys: &[(u32, u32)], target_error: u32) -> Vec<ModelSegment> {
+    let target_error = target_error as f64;
+    let mut segments = vec![];
+    let mut start = 0;
+
+    while start < keys.len() {
+        let (origin_hash, origin_block) = keys[start];
+        // Range of slopes that keep every key of the segment so far within the error bound
+        let (mut min_slope, mut max_slope) = (0f64, f64::INFINITY);
+        let mut end = start + 1;
+        while end < keys.len() {
+            let (hash, block) = keys[end];
+            let dx = hash as f64 - origin_hash as f64;
+            let dy = block as f64 - origin_block as f64;
+            if dx == 0.0 {
+                if dy.abs() > target_error {
+                    break;
+                }
+            } else {
+                let new_min = min_slope.max((dy - target_error) / dx);
+                let new_max = max_slope.min((dy + target_error) / dx);
+                if new_min > new_max {
+                    break;
+                }
+                min_slope = new_min;
+                max_slope = new_max;
+            }
+            end += 1;
+        }
+
+        let slope = if max_slope.is_finite() { (min_slope + max_slope) / 2.0 } else { min_slope };
+        segments.push(ModelSegment { first_hash: origin_hash, slope: slope as f32, intercept: origin_block as f32 });
+        start = end;
+    }
+
+    segments
+}
+
+impl DirModel {
+    /// How many segments fit in a model block of `block_size` bytes
+    pub fn max_segments(block_size: usize) -> usize {
+        (block_size - DIR_MODEL_HEADER_SIZE) / DIR_MODEL_SEGMENT_SIZE
+    }
+
+    /// Train a model on `keys`, (name hash, block) pairs sorted by hash. The error bound is
+    /// loosened until the model fits in a block, and the recorded bound is the one measured
+    /// on the keys, so it holds whatever the float rounding did.
+    pub fn train(keys: &[(u32, u32)], num_data_blocks: u32, block_size: usize) -> Self {
+        let mut target_error = INITIAL_TARGET_ERROR;
+        loop {
+            let segments = fit_segments(keys, target_error);
+            // An error bound of num_data_blocks always gives a single segment
+            if segments.len() <= Self::max_segments(block_size) {
+                let mut model = DirModel { segments, max_error: 0, num_data_blocks, num_indexed: keys.len() as u32, num_unindexed: 0 };
+                model.max_error = keys.iter().map(|(hash, block)| model.predict(*hash).abs_diff(*block)).max().unwrap_or(0);
+                return model;
+            }
+            target_error *= 2;
+        }
+    }
+
+    /// Predicted block for a name hash, clamped to the data blocks
+    pub fn predict(&self, hash: u32) -> u32 {
+        let segment_idx = self.segments.partition_point(|s| s.first_hash <= hash).max(1) - 1;
+        match self.segments.get(segment_idx) {
+            Some(segment) => segment.predict(hash).round().clamp(1.0, self.num_data_blocks.max(1) as f64) as u32,
+            None => 1,
+        }
+    }
+
+    /// Blocks that hold a name with the given hash, if it is indexed
+    pub fn search_window(&self, hash: u32) -> std::ops::RangeInclusive<u32> {
+        let predicted = self.predict(hash);
+        predicted.saturating_sub(self.max_error).max(1)..=(predicted + self.max_error).min(self.num_data_blocks)
+    }
+
+    pub fn to_bytes(&self, block_size: usize) -> Vec<u8> {
+        let mut dest = vec![0u8; block_size];
+        dest[4..6].copy_from_slice(&rec_len_to_disk(block_size).to_le_bytes());
+        dest[8..12].copy_from_slice(&DIR_MODEL_MAGIC.to_le_bytes());
+        dest[12..14].copy_from_slice(&(self.segments.len() as u16).to_le_bytes());
+        dest[16..20].copy_from_slice(&self.max_error.to_le_bytes());
+        dest[20..24].copy_from_slice(&self.num_data_blocks.to_le_bytes());
+        dest[24..28].copy_from_slice(&self.num_indexed.to_le_bytes());
+        dest[DIR_MODEL_UNINDEXED_OFFSET..(DIR_MODEL_UNINDEXED_OFFSET + 4)].copy_from_slice(&self.num_unindexed.to_le_bytes());
+
+        for (segment_idx, segment) in self.segments.iter().enumerate() {
+            let dest_idx = DIR_MODEL_HEADER_SIZE + segment_idx * DIR_MODEL_SEGMENT_SIZE;
+            dest[dest_idx..(dest_idx + 4)].copy_from_slice(&segment.first_hash.to_le_bytes());
+            dest[(dest_idx + 4)..(dest_idx + 8)].copy_from_slice(&segment.slope.to_le_bytes());
+            dest[(dest_idx + 8)..(dest_idx + 12)].copy_from_slice(&segment.intercept.to_le_bytes());
+        }
+
+        dest
+    }
+}
+
+impl TryFrom<&[u8]> for DirModel {
+    type Error = ();
+
+    fn try_from(block: &[u8]) -> Result<Self, Self::Error> {
+        let fake_dirent_inode = u32::from_le_bytes(crate::slice_to_four_bytes(&block[0..4]));
+        let magic = u32::from_le_bytes(crate::slice_to_four_bytes(&block[DIR_MODEL_FAKE_DIRENT_SIZE..]));
+        let num_segments = u16::from_le_bytes(crate::slice_to_two_bytes(&block[12..14])) as usize;
+
+        if fake_dirent_inode != 0 || magic != DIR_MODEL_MAGIC || num_segments == 0 || num_segments > Self::max_segments(block.len()) {
+            return Err(());
+        }
+
+        let segments = block[DIR_MODEL_HEADER_SIZE..].chunks_exact(DIR_MODEL_SEGMENT_SIZE)
+            .take(num_segments)
+            .map(|segment| ModelSegment {
+                first_hash: u32::from_le_bytes(crate::slice_to_four_bytes(&segment[0..4])),
+                slope: f32::from_le_bytes(crate::slice_to_four_bytes(&segment[4..8])),
+                intercept: f32::from_le_bytes(crate::slice_to_four_bytes(&segment[8..12])),
+            })
+            .collect();
+
+        Ok(DirModel {
+            segments,
+            max_error: u32::from_le_bytes(crate::slice_to_four_bytes(&block[16..20])),
+            num_data_blocks: u32::from_le_bytes(crate::slice_to_four_bytes(&block[20..24])),
+            num_indexed: u32::from_le_bytes(crate::slice_to_four_bytes(&block[24..28])),
+            num_unindexed: u32::from_le_bytes(crate::slice_to_four_bytes(&block[DIR_MODEL_UNINDEXED_OFFSET..])),
+        })
+    }
+}
@@ -1,4 +1,4 @@
-use std::ffi::{CString, OsString};
+use std::ffi::{CString, OsStr, OsString};
 use std::num::NonZeroU8;
 use std::ops::Deref;
 use std::os::unix::ffi::OsStrExt;
@@ -8,10 +8,136 @@ pub struct DirectoryBlock {
     pub directory_entries: [DirectoryEntry; (FS_BLOCK_SIZE/4)],
 }
 
+/// Size of an entry in a fixed-format directory
+pub const FIXED_DIRENT_SIZE: usize = 32;
+/// Longest name a fixed-format directory entry can hold
+pub const FIXED_NAME_MAX: usize = 27;
+/// Longest name a variable-length directory record can hold
+pub const NAME_MAX: usize = 255;
+
+/// Bytes before the name in a variable-length record: inode (u32), record length (u16),
+/// name length (u8) and file type (u8)
+const VAR_DIRENT_HEADER_SIZE: usize = 8;
+
+pub const DIRENT_TYPE_UNKNOWN: u8 = 0;
+pub const DIRENT_TYPE_REGULAR: u8 = 1;
+pub const DIRENT_TYPE_DIRECTORY: u8 = 2;
+
 #[derive(Clone, Debug)]
 pub struct DirectoryEntry {
     pub inode_ptr: u32,
     pub name: OsString,
+    /// One of the DIRENT_TYPE_* values. Fixed-format directories don't record it
+    pub file_type: u8,
+}
+
+/// A directory entry together with where it lives in the directory file. Free slots keep
+/// their place so the space can be reused.
+#[derive(Clone, Debug)]
+pub struct DirentSlot {
+    /// Byte offset of the record in the directory file
+    pub offset: usize,
+    /// Bytes taken by the record, including any free space after the entry
+    pub rec_len: usize,
+    pub entry: Result<DirectoryEntry, ()>,
+}
+
+/// Slots searched by a directory lookup, along with the index and entry of the match if any
+pub type DirentLookup = (Vec<DirentSlot>, Option<(usize, DirectoryEntry)>);
+
+pub fn dirent_type_of_mode(mode: u32) -> u8 {
+    match mode & 0o170000 {
+        0o040000 => DIRENT_TYPE_DIRECTORY,
+        0o100000 => DIRENT_TYPE_REGULAR,
+        _ => DIRENT_TYPE_UNKNOWN,
+    }
+}
+
+/// On-disk form of a record length. Lengths are multiples of 4, so the two low bits carry
+/// bits 16 and 17 instead, which lets a single record span a whole 64 KiB block.
+pub fn rec_len_to_disk(rec_len: usize) -> u16 {
+    ((rec_len & 0xFFFC) | ((rec_len >> 16) & 3)) as u16
+}
+
+pub fn rec_len_from_disk(disk_rec_len: u16) -> usize {
+    let disk_rec_len = disk_rec_len as usize;
+    (disk_rec_len & 0xFFFC) | ((disk_rec_len & 3) << 16)
+}
+
+/// Smallest record that can hold a name of `name_len` bytes, records are 4 byte aligned
+pub fn var_dirent_len(name_len: usize) -> usize {
+    (VAR_DIRENT_HEADER_SIZE + name_len + 3) & !3
+}
+
+impl DirectoryEntry {
+    /// Serialize as a variable-length record taking `rec_len` bytes
+    pub fn to_var_record(&self, rec_len: usize) -> Vec<u8> {
+        let name = self.name.as_bytes();
+        let mut dest = vec![0u8; rec_len];
+        dest[0..4].copy_from_slice(&self.inode_ptr.to_le_bytes());
+        dest[4..6].copy_from_slice(&rec_len_to_disk(rec_len).to_le_bytes());
+        dest[6] = name.len() as u8;
+        dest[7] = self.file_type;
+        dest[VAR_DIRENT_HEADER_SIZE..(VAR_DIRENT_HEADER_SIZE + name.len())].copy_from_slice(name);
+        dest
+    }
+}
+
+/// Header of a free variable-length record taking `rec_len` bytes
+pub fn free_var_record(rec_len: usize) -> Vec<u8> {
+    let mut dest = vec![0u8; VAR_DIRENT_HEADER_SIZE];
+    dest[4..6].copy_from_slice(&rec_len_to_disk(rec_len).to_le_bytes());
+    dest
+}
+
+/// A block of a variable-length directory holding `entries` packed from the start, with the
+/// last record stretched to the end of the block. The entries must fit.
+pub fn pack_var_dirent_block(entries: &[DirectoryEntry], block_size: usize) -> Vec<u8> {
+    if entries.is_empty() {
+        let mut dest = free_var_record(block_size);
+        dest.resize(block_size, 0);
+        return dest;
+    }
+
+    let mut dest = vec![];
+    for (entry_idx, entry) in entries.iter().enumerate() {
+        let rec_len = if entry_idx + 1 == entries.len() {
+            block_size - dest.len()
+        } else {
+            var_dirent_len(entry.name.len())
+        };
+        dest.extend(entry.to_var_record(rec_len));
+    }
+    dest
+}
+
+/// Split a block of a variable-length directory into its records. `block_offset` is the
+/// offset of the block in the directory file. A corrupt record length ends the block.
+pub fn parse_var_dirent_block(block: &[u8], block_offset: usize) -> Vec<DirentSlot> {
+    let mut slots = vec![];
+    let mut offset = 0;
+    while offset + VAR_DIRENT_HEADER_SIZE <= block.len() {
+        let inode_ptr = u32::from_le_bytes(crate::slice_to_four_bytes(&block[offset..(offset + 4)]));
+        let rec_len = rec_len_from_disk(u16::from_le_bytes(crate::slice_to_two_bytes(&block[(offset + 4)..(offset + 6)])));
+        let name_len = block[offset + 6] as usize;
+        let file_type = block[offset + 7];
+
+        if rec_len < VAR_DIRENT_HEADER_SIZE || offset + rec_len > block.len() || var_dirent_len(name_len) > rec_len {
+            break;
+        }
+
+        let entry = if inode_ptr != 0 {
+            let name_start = offset + VAR_DIRENT_HEADER_SIZE;
+            let name = OsStr::from_bytes(&block[name_start..(name_start + name_len)]).to_os_string();
+            Ok(DirectoryEntry { inode_ptr, name, file_type })
+        } else {
+            Err(())
+        };
+
+        slots.push(DirentSlot { offset: block_offset + offset, rec_len, entry });
+        offset += rec_len;
+    }
+    slots
 }
 
 impl TryFrom<&[u8]> for DirectoryEntry{
@@ -26,7 +152,7 @@ impl TryFrom<&[u8]> for DirectoryEntry{
             let cname = CString::from(name_nonzero);
             let name = OsString::from(cname.to_string_lossy().deref());
             Ok(DirectoryEntry{
-                inode_ptr, name
+                inode_ptr, name, file_type: DIRENT_TYPE_UNKNOWN
             })
         } else {
             Err(())
@@ -36,11 +162,46 @@ impl TryFrom<&[u8]> for DirectoryEntry{
 
 impl Into<Vec<u8>> for DirectoryEntry{
     fn into(self) -> Vec<u8> {
-        let mut dest = vec![0u8; 32];
+        let mut dest = vec![0u8; FIXED_DIRENT_SIZE];
         dest[0..4].copy_from_slice(&((self.inode_ptr << 1) + 1).to_le_bytes());
-        for (ch_idx, ch) in self.name.as_bytes().iter().enumerate().take(27){
+        for (ch_idx, ch) in self.name.as_bytes().iter().enumerate().take(FIXED_NAME_MAX){
             dest[4+ch_idx] = *ch
         }
         dest
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::crash_test::{check_written, run_and_reopen};
+    use crate::mkfs::MkfsOptions;
+
+    #[test]
+    fn var_dirents_round_trip() {
+        let mut options = MkfsOptions { num_blocks: 1024, block_size: 1024, ..MkfsOptions::default() };
+        options.set_features("var_dirents").unwrap();
+        let names: Vec<String> = [1, 2, 3, 4, 5, 63, 64, 128, 200, 254, 255].iter().enumerate()
+            .map(|(idx, len)| ((b'a' + idx as u8) as char).to_string().repeat(*len))
+            .collect();
+        let mut workload = String::from("mkdir /d\n");
+        for (idx, name) in names.iter().enumerate() {
+            workload += &format!("create /d/{}\nwrite /d/{} 0 {}\n", name, name, idx + 1);
+        }
+        // Leave gaps between records, fill one again and move a short name into a long one
+        let long_name = "z".repeat(255);
+        workload += &format!("unlink /d/{}\nunlink /d/{}\ncreate /d/y\nrename /d/{} /d/{}\n", names[3], names[6], names[0], long_name);
+        let file_system = run_and_reopen(&options, &workload);
+
+        // Records only take the space their names need, so the ten entries fit in two blocks
+        let dir = file_system.get_inode(file_system.lookup_path("/d").unwrap()).unwrap();
+        assert!(dir.size <= 2 * 1024, "{}", dir.size);
+        for (idx, name) in names.iter().enumerate() {
+            match idx {
+                0 | 3 | 6 => assert!(file_system.lookup_path(&format!("/d/{}", name)).is_err()),
+                _ => check_written(&file_system, &format!("/d/{}", name), 0, idx + 1),
+            }
+        }
+        check_written(&file_system, &format!("/d/{}", long_name), 0, 1);
+        file_system.lookup_path("/d/y").unwrap();
+    }
+}
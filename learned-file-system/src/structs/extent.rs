@@ -0,0 +1,199 @@
+/// Magic number at the start of every extent node, so a stray pointer block is never
+/// mistaken for one
+pub const EXTENT_MAGIC: u16 = 0xF30A;
+
+const EXTENT_HEADER_SIZE: usize = 8;
+const EXTENT_ENTRY_SIZE: usize = 12;
+
+/// A run of `length` logical blocks starting at `logical_start`, stored contiguously on
+/// disk starting at `physical_start`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extent {
+    pub logical_start: u32,
+    pub physical_start: u32,
+    pub length: u32,
+}
+
+/// Entry of an interior extent node. Every logical block at or after `logical_start` (and
+/// before the next entry) is mapped by the subtree rooted at `child_block`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtentIndex {
+    pub logical_start: u32,
+    pub child_block: u32,
+}
+
+/// Node of an extent tree. The root lives in the block map area of the inode, every other
+/// node fills a whole block.
+///
+/// Layout: magic (u16), number of entries (u16), max entries (u16), depth (u16), followed by
+/// 12 byte entries. Leaves (depth 0) hold (logical start, physical start, length) records,
+/// interior nodes hold (logical start, child block, unused) records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExtentNode {
+    pub depth: u16,
+    /// Only used when depth == 0
+    pub extents: Vec<Extent>,
+    /// Only used when depth > 0
+    pub children: Vec<ExtentIndex>,
+}
+
+impl Extent {
+    pub fn logical_end(&self) -> u32 {
+        self.logical_start + self.length
+    }
+
+    pub fn physical_end(&self) -> u32 {
+        self.physical_start + self.length
+    }
+
+    pub fn contains(&self, logical_block_num: u32) -> bool {
+        self.logical_start <= logical_block_num && logical_block_num < self.logical_end()
+    }
+}
+
+impl ExtentNode {
+    /// How many entries fit in a node stored in `node_bytes` bytes
+    pub fn max_entries(node_bytes: usize) -> usize {
+        (node_bytes - EXTENT_HEADER_SIZE) / EXTENT_ENTRY_SIZE
+    }
+
+    pub fn empty_leaf() -> Self {
+        ExtentNode { depth: 0, extents: vec![], children: vec![] }
+    }
+
+    pub fn num_entries(&self) -> usize {
+        if self.depth == 0 { self.extents.len() } else { self.children.len() }
+    }
+
+    /// First logical block of the node, as its parent records it
+    pub fn logical_start(&self) -> u32 {
+        if self.depth == 0 {
+            self.extents.first().map_or(0, |e| e.logical_start)
+        } else {
+            self.children.first().map_or(0, |c| c.logical_start)
+        }
+    }
+
+    /// Move the upper half of the entries into a new sibling
+    pub fn split_off_half(&mut self) -> ExtentNode {
+        let mut right = ExtentNode { depth: self.depth, extents: vec![], children: vec![] };
+        if self.depth == 0 {
+            right.extents = self.extents.split_off(self.extents.len() / 2);
+        } else {
+            right.children = self.children.split_off(self.children.len() / 2);
+        }
+        right
+    }
+
+    pub fn to_bytes(&self, node_bytes: usize) -> Vec<u8> {
+        let mut dest = vec![0u8; node_bytes];
+        dest[0..2].copy_from_slice(&EXTENT_MAGIC.to_le_bytes());
+        dest[2..4].copy_from_slice(&(self.num_entries() as u16).to_le_bytes());
+        dest[4..6].copy_from_slice(&(Self::max_entries(node_bytes) as u16).to_le_bytes());
+        dest[6..8].copy_from_slice(&self.depth.to_le_bytes());
+
+        let entries: Vec<[u32; 3]> = if self.depth == 0 {
+            self.extents.iter().map(|e| [e.logical_start, e.physical_start, e.length]).collect()
+        } else {
+            self.children.iter().map(|c| [c.logical_start, c.child_block, 0]).collect()
+        };
+
+        for (entry_idx, entry) in entries.iter().enumerate() {
+            let dest_idx = EXTENT_HEADER_SIZE + entry_idx * EXTENT_ENTRY_SIZE;
+            for (word_idx, word) in entry.iter().enumerate() {
+                dest[(dest_idx + word_idx*4)..(dest_idx + word_idx*4 + 4)].copy_from_slice(&word.to_le_bytes());
+            }
+        }
+
+        dest
+    }
+}
+
+impl TryFrom<&[u8]> for ExtentNode {
+    type Error = ();
+
+    fn try_from(node_bytes: &[u8]) -> Result<Self, Self::Error> {
+        let magic = u16::from_le_bytes(crate::slice_to_two_bytes(&node_bytes[0..2]));
+        let num_entries = u16::from_le_bytes(crate::slice_to_two_bytes(&node_bytes[2..4])) as usize;
+        let depth = u16::from_le_bytes(crate::slice_to_two_bytes(&node_bytes[6..8]));
+
+        if magic != EXTENT_MAGIC || num_entries > Self::max_entries(node_bytes.len()) {
+            return Err(());
+        }
+
+        let entries: Vec<[u32; 3]> = node_bytes[EXTENT_HEADER_SIZE..].chunks_exact(EXTENT_ENTRY_SIZE)
+            .take(num_entries)
+            .map(|entry| [
+                u32::from_le_bytes(crate::slice_to_four_bytes(&entry[0..4])),
+                u32::from_le_bytes(crate::slice_to_four_bytes(&entry[4..8])),
+                u32::from_le_bytes(crate::slice_to_four_bytes(&entry[8..12])),
+            ])
+            .collect();
+
+        let mut node = ExtentNode { depth, extents: vec![], children: vec![] };
+        if depth == 0 {
+            node.extents = entries.into_iter()
+                .map(|[logical_start, physical_start, length]| Extent { logical_start, physical_start, length })
+                .collect();
+        } else {
+            node.children = entries.into_iter()
+                .map(|[logical_start, child_block, _]| ExtentIndex { logical_start, child_block })
+                .collect();
+        }
+
+        Ok(node)
+    }
+}
+
+/// Physical block backing `logical_block_num` in a sorted extent list, if it is mapped
+pub fn lookup_extent(extents: &[Extent], logical_block_num: u32) -> Option<u32> {
+    let idx = extents.partition_point(|e| e.logical_start <= logical_block_num);
+    if idx == 0 {
+        return None;
+    }
+    let extent = &extents[idx - 1];
+    if extent.contains(logical_block_num) {
+        Some(extent.physical_start + (logical_block_num - extent.logical_start))
+    } else {
+        None
+    }
+}
+
+/// Insert an extent that does not overlap any existing one into a sorted extent list,
+/// merging it with its neighbours when they are contiguous both logically and physically
+pub fn insert_extent(extents: &mut Vec<Extent>, new_extent: Extent) {
+    let mut idx = extents.partition_point(|e| e.logical_start < new_extent.logical_start);
+    extents.insert(idx, new_extent);
+
+    if idx > 0 {
+        let prev = extents[idx - 1];
+        if prev.logical_end() == new_extent.logical_start && prev.physical_end() == new_extent.physical_start {
+            extents[idx - 1].length += new_extent.length;
+            extents.remove(idx);
+            idx -= 1;
+        }
+    }
+
+    if idx + 1 < extents.len() {
+        let cur = extents[idx];
+        let next = extents[idx + 1];
+        if cur.logical_end() == next.logical_start && cur.physical_end() == next.physical_start {
+            extents[idx].length += next.length;
+            extents.remove(idx + 1);
+        }
+    }
+}
+
+/// Group (logical block, physical block) pairs, sorted by logical block, into extents
+pub fn extents_from_blocks(blocks: &[(u32, u32)]) -> Vec<Extent> {
+    let mut extents: Vec<Extent> = vec![];
+    for (logical_block_num, physical_block) in blocks.iter().copied() {
+        match extents.last_mut() {
+            Some(last) if last.logical_end() == logical_block_num && last.physical_end() == physical_block => {
+                last.length += 1;
+            }
+            _ => extents.push(Extent { logical_start: logical_block_num, physical_start: physical_block, length: 1 }),
+        }
+    }
+    extents
+}
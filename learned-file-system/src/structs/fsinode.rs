@@ -1,26 +1,117 @@
-use fuse::FileAttr;
+use fuse::{FileAttr, FileType};
 use fuse::FileType::{Directory, RegularFile};
-use crate::{div_ceil, FS_BLOCK_SIZE};
+use std::io::{Error, ErrorKind};
+use crate::structs::extent::ExtentNode;
 
-pub const NUM_POINTERS: usize = ((FS_BLOCK_SIZE - 20)/4) as usize;
+/// Bytes of an inode outside the direct pointers: uid, gid, mode, ctime, mtime and the low
+/// half of the size before them, the single indirect pointer, the double indirect pointer
+/// and the high half of the size after them
+const INODE_FIXED_BYTES: usize = 32;
+
+/// Smallest inode size an image can have, leaving room for 24 direct pointers
+pub const MIN_INODE_SIZE: u32 = 128;
+
+/// Number of block pointers that fit in one indirect block
+pub fn pointers_per_block(block_size: usize) -> usize {
+    block_size / 4
+}
+
+/// Inode flags live in the upper half of the on-disk mode word, which the mode itself never uses
+pub const INODE_FLAG_EXTENTS: u16 = 0x0001;
+/// Directory made of variable-length records instead of fixed 32 byte entries
+pub const INODE_FLAG_VAR_DIRENTS: u16 = 0x0002;
+/// Variable-length directory whose first block is the root of a hash index (see htree.rs)
+pub const INODE_FLAG_HTREE: u16 = 0x0004;
+/// Variable-length directory whose first block holds a learned lookup model (see dir_model.rs)
+pub const INODE_FLAG_LEARNED_INDEX: u16 = 0x0008;
+
+/// Number of direct pointers in an inode stored in `inode_size` bytes. An inode that takes a
+/// whole 4 KiB block has 1016.
+pub fn num_direct_pointers(inode_size: usize) -> usize {
+    (inode_size - INODE_FIXED_BYTES) / 4
+}
 
 #[derive(Clone, Debug)]
 pub struct FSINode {
     pub uid: u16,
     pub gid: u16,
     pub mode: u32,
+    pub flags: u16,
     pub ctime: u32,
     pub mtime: u32,
-    pub size: u32,
-    pub pointers: [u32; NUM_POINTERS],
+    pub size: u64,
+    /// How many there are depends on the size of the inode on disk
+    pub pointers: Vec<u32>,
+    pub single_indirect: u32,
+    pub double_indirect: u32,
+}
+
+/// Where the pointer to a logical block of a file lives
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockPointerLocation {
+    /// Index into `FSINode::pointers`
+    Direct(usize),
+    /// Index into the single indirect block
+    SingleIndirect(usize),
+    /// Index into the double indirect block, then into the indirect block it points to
+    DoubleIndirect(usize, usize),
+}
+
+impl BlockPointerLocation {
+    /// Returns None if the logical block is past the largest file we can address
+    pub fn of_logical_block(num_direct_pointers: usize, pointers_per_block: usize, logical_block_num: usize) -> Option<Self> {
+        if logical_block_num < num_direct_pointers {
+            return Some(BlockPointerLocation::Direct(logical_block_num));
+        }
+        let logical_block_num = logical_block_num - num_direct_pointers;
+        if logical_block_num < pointers_per_block {
+            return Some(BlockPointerLocation::SingleIndirect(logical_block_num));
+        }
+        let logical_block_num = logical_block_num - pointers_per_block;
+        if logical_block_num < pointers_per_block * pointers_per_block {
+            return Some(BlockPointerLocation::DoubleIndirect(logical_block_num / pointers_per_block,
+                                                             logical_block_num % pointers_per_block));
+        }
+        None
+    }
 }
 
 impl FSINode{
-    pub fn to_fileattr(&self, node_num: u64) -> FileAttr {
-        let dir_mask = 0o40000;
+    /// An inode with every field and pointer zeroed, stored in `inode_size` bytes
+    pub fn empty(inode_size: usize) -> Self {
+        FSINode {
+            uid: 0, gid: 0, mode: 0, flags: 0, ctime: 0, mtime: 0, size: 0,
+            pointers: vec![0; num_direct_pointers(inode_size)],
+            single_indirect: 0,
+            double_indirect: 0,
+        }
+    }
+
+    /// Number of bytes this inode takes on disk
+    pub fn inode_size(&self) -> usize {
+        INODE_FIXED_BYTES + self.pointers.len() * 4
+    }
+
+    /// Size of the area holding the direct and indirect pointers. In an extent-mapped inode
+    /// this area holds the root of the extent tree instead.
+    pub fn block_map_size(&self) -> usize {
+        (self.pointers.len() + 2) * 4
+    }
 
-        let ftype = if self.mode & dir_mask != 0 { Directory } else { RegularFile };
+    /// Largest number of blocks the file can address through its pointers
+    pub fn max_file_blocks(&self, block_size: usize) -> usize {
+        let pointers_per_block = pointers_per_block(block_size);
+        self.pointers.len() + pointers_per_block + pointers_per_block * pointers_per_block
+    }
+
+    pub fn file_type(&self) -> FileType {
+        let dir_mask = 0o40000;
+        if self.mode & dir_mask != 0 { Directory } else { RegularFile }
+    }
 
+    /// Attributes of the inode. `blocks` is the space the file takes in 512 byte units,
+    /// indirect blocks and extent tree nodes included, which the inode alone cannot tell.
+    pub fn to_fileattr(&self, node_num: u64, blocks: u64) -> FileAttr {
         FileAttr{
             ino: node_num,
             uid: self.uid as u32,
@@ -29,56 +120,113 @@ impl FSINode{
             ctime: crate::time_to_timespec(self.ctime),
             crtime: crate::time_to_timespec(self.ctime),
             atime: crate::time_to_timespec(self.mtime),
-            size: self.size as u64,
-            blocks: self.pointers.clone().into_iter().filter(|ptr| *ptr != 0).sum::<u32>() as u64, // Because the file might be sparse
+            size: self.size,
+            blocks,
             nlink: 1,
             rdev: 0,
             flags: 0,
-            kind: ftype,
+            kind: self.file_type(),
             perm: self.mode as u16
         }
     }
+
+    pub fn is_regular_file(&self) -> bool {
+        self.mode & 0o170000 == 0o100000
+    }
+
+    pub fn is_extent_mapped(&self) -> bool {
+        self.flags & INODE_FLAG_EXTENTS != 0
+    }
+
+    pub fn has_var_dirents(&self) -> bool {
+        self.flags & INODE_FLAG_VAR_DIRENTS != 0
+    }
+
+    pub fn has_htree_index(&self) -> bool {
+        self.has_var_dirents() && self.flags & INODE_FLAG_HTREE != 0
+    }
+
+    pub fn has_learned_index(&self) -> bool {
+        self.has_var_dirents() && self.flags & INODE_FLAG_LEARNED_INDEX != 0
+    }
+
+    fn block_map_bytes(&self) -> Vec<u8> {
+        self.pointers.iter()
+            .chain([self.single_indirect, self.double_indirect].iter())
+            .flat_map(|ptr| ptr.to_le_bytes())
+            .collect()
+    }
+
+    fn set_block_map_bytes(&mut self, block_map: &[u8]) {
+        let mut words = block_map.chunks_exact(4).map(|chunk| u32::from_le_bytes(crate::slice_to_four_bytes(chunk)));
+        for ptr in self.pointers.iter_mut() {
+            *ptr = words.next().unwrap();
+        }
+        self.single_indirect = words.next().unwrap();
+        self.double_indirect = words.next().unwrap();
+    }
+
+    /// Root of the extent tree of an extent-mapped inode
+    pub fn extent_root(&self) -> std::io::Result<ExtentNode> {
+        ExtentNode::try_from(self.block_map_bytes().as_slice()).map_err(|_| Error::from(ErrorKind::InvalidData))
+    }
+
+    pub fn set_extent_root(&mut self, root: &ExtentNode) {
+        self.set_block_map_bytes(&root.to_bytes(self.block_map_size()));
+    }
 }
 
 
 impl From<&[u8]> for FSINode {
+    /// The number of direct pointers follows from the length of `inode_bytes`
     fn from(inode_bytes: &[u8]) -> Self {
+        let single_indirect_offset = inode_bytes.len() - 12;
+        let double_indirect_offset = inode_bytes.len() - 8;
+        let size_high_offset = inode_bytes.len() - 4;
+
         let uid =  u16::from_le_bytes(crate::slice_to_two_bytes(&inode_bytes[0..2]));
         let gid =  u16::from_le_bytes(crate::slice_to_two_bytes(&inode_bytes[2..4]));
-        let mode = u32::from_le_bytes(crate::slice_to_four_bytes(&inode_bytes[4..8]));
+        let mode_word = u32::from_le_bytes(crate::slice_to_four_bytes(&inode_bytes[4..8]));
+        let mode = mode_word & 0xFFFF;
+        let flags = (mode_word >> 16) as u16;
         let ctime = u32::from_le_bytes(crate::slice_to_four_bytes(&inode_bytes[8..12]));
         let mtime = u32::from_le_bytes(crate::slice_to_four_bytes(&inode_bytes[12..16]));
-        let size = u32::from_le_bytes(crate::slice_to_four_bytes(&inode_bytes[16..20]));
+        let size_low = u32::from_le_bytes(crate::slice_to_four_bytes(&inode_bytes[16..20]));
+        let single_indirect = u32::from_le_bytes(crate::slice_to_four_bytes(&inode_bytes[single_indirect_offset..]));
+        let double_indirect = u32::from_le_bytes(crate::slice_to_four_bytes(&inode_bytes[double_indirect_offset..]));
+        let size_high = u32::from_le_bytes(crate::slice_to_four_bytes(&inode_bytes[size_high_offset..]));
+        let size = ((size_high as u64) << 32) | size_low as u64;
 
-
-        let mut pointers = [0u32; NUM_POINTERS];
-        let pointer_vec: Vec<u32> = inode_bytes[20..].chunks_exact(4)
+        let pointers: Vec<u32> = inode_bytes[20..single_indirect_offset].chunks_exact(4)
             .map(|chunk| u32::from_le_bytes(crate::slice_to_four_bytes(chunk)))
             .collect();
 
-        pointers.copy_from_slice(&pointer_vec);
-
         FSINode{
-            uid, gid, mode, ctime, mtime, size, pointers,
+            uid, gid, mode, flags, ctime, mtime, size, pointers, single_indirect, double_indirect,
         }
     }
 }
 
 impl Into<Vec<u8>> for FSINode{
     fn into(self) -> Vec<u8> {
-        let mut dest = vec![0u8; FS_BLOCK_SIZE];
+        let inode_size = self.inode_size();
+        let mut dest = vec![0u8; inode_size];
         dest[0..2].copy_from_slice(&self.uid.to_le_bytes());
         dest[2..4].copy_from_slice(&self.gid.to_le_bytes());
-        dest[4..8].copy_from_slice(&self.mode.to_le_bytes());
+        dest[4..8].copy_from_slice(&((self.mode & 0xFFFF) | ((self.flags as u32) << 16)).to_le_bytes());
         dest[8..12].copy_from_slice(&self.ctime.to_le_bytes());
         dest[12..16].copy_from_slice(&self.mtime.to_le_bytes());
-        dest[16..20].copy_from_slice(&self.size.to_le_bytes());
+        dest[16..20].copy_from_slice(&(self.size as u32).to_le_bytes());
 
         for (ptr_idx, ptr_val) in self.pointers.iter().enumerate() {
             let dest_idx = 20 + (ptr_idx * 4);
             dest[dest_idx..(dest_idx+4)].copy_from_slice(&ptr_val.to_le_bytes());
         }
 
+        dest[(inode_size-12)..(inode_size-8)].copy_from_slice(&self.single_indirect.to_le_bytes());
+        dest[(inode_size-8)..(inode_size-4)].copy_from_slice(&self.double_indirect.to_le_bytes());
+        dest[(inode_size-4)..inode_size].copy_from_slice(&((self.size >> 32) as u32).to_le_bytes());
+
         dest
     }
 }
@@ -0,0 +1,99 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use crate::structs::dirent::rec_len_to_disk;
+
+/// Marks a directory block as a hash tree index node ("HTRE")
+pub const HTREE_MAGIC: u32 = 0x45525448;
+
+/// Index nodes start with a free directory record spanning the whole block, so a linear
+/// scan of an indexed directory skips them. The index header follows that record.
+const HTREE_FAKE_DIRENT_SIZE: usize = 8;
+const HTREE_HEADER_SIZE: usize = 24;
+const HTREE_ENTRY_SIZE: usize = 8;
+
+/// Every leaf block holds the entries whose name hash is between the hash of its index
+/// entry and the hash of the next one. The first entry of a node always has hash 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HTreeEntry {
+    pub hash: u32,
+    /// Logical block of the directory file holding the child
+    pub block: u32,
+}
+
+/// Index node of a hashed directory. The root is always logical block 0 of the directory.
+///
+/// Layout after the fake directory record: magic (u32), depth (u16), number of entries
+/// (u16), max entries (u16), padding up to 24 bytes, then (hash, block) pairs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HTreeNode {
+    /// 0 if the entries point at leaf blocks, 1 if they point at index nodes
+    pub depth: u16,
+    pub entries: Vec<HTreeEntry>,
+}
+
+/// 32 bit FNV-1a hash of a file name
+pub fn name_hash(name: &OsStr) -> u32 {
+    name.as_bytes().iter().fold(0x811c9dc5u32, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193))
+}
+
+impl HTreeNode {
+    /// How many entries fit in an index node of `block_size` bytes
+    pub fn max_entries(block_size: usize) -> usize {
+        (block_size - HTREE_HEADER_SIZE) / HTREE_ENTRY_SIZE
+    }
+
+    /// Indices of the entries whose child may hold names with the given hash. Usually just
+    /// one, but a run of equal hashes may have been split across several children.
+    pub fn candidate_entries(&self, hash: u32) -> std::ops::Range<usize> {
+        let first = self.entries.partition_point(|e| e.hash < hash).saturating_sub(1);
+        let last = self.entries.partition_point(|e| e.hash <= hash).max(1);
+        first..last
+    }
+
+    /// Index of the entry whose child a name with the given hash should be inserted into
+    pub fn insertion_entry(&self, hash: u32) -> usize {
+        self.entries.partition_point(|e| e.hash <= hash).max(1) - 1
+    }
+
+    pub fn to_bytes(&self, block_size: usize) -> Vec<u8> {
+        let mut dest = vec![0u8; block_size];
+        dest[4..6].copy_from_slice(&rec_len_to_disk(block_size).to_le_bytes());
+        dest[8..12].copy_from_slice(&HTREE_MAGIC.to_le_bytes());
+        dest[12..14].copy_from_slice(&self.depth.to_le_bytes());
+        dest[14..16].copy_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        dest[16..18].copy_from_slice(&(Self::max_entries(block_size) as u16).to_le_bytes());
+
+        for (entry_idx, entry) in self.entries.iter().enumerate() {
+            let dest_idx = HTREE_HEADER_SIZE + entry_idx * HTREE_ENTRY_SIZE;
+            dest[dest_idx..(dest_idx + 4)].copy_from_slice(&entry.hash.to_le_bytes());
+            dest[(dest_idx + 4)..(dest_idx + 8)].copy_from_slice(&entry.block.to_le_bytes());
+        }
+
+        dest
+    }
+}
+
+impl TryFrom<&[u8]> for HTreeNode {
+    type Error = ();
+
+    fn try_from(block: &[u8]) -> Result<Self, Self::Error> {
+        let fake_dirent_inode = u32::from_le_bytes(crate::slice_to_four_bytes(&block[0..4]));
+        let magic = u32::from_le_bytes(crate::slice_to_four_bytes(&block[HTREE_FAKE_DIRENT_SIZE..]));
+        let depth = u16::from_le_bytes(crate::slice_to_two_bytes(&block[12..14]));
+        let num_entries = u16::from_le_bytes(crate::slice_to_two_bytes(&block[14..16])) as usize;
+
+        if fake_dirent_inode != 0 || magic != HTREE_MAGIC || num_entries == 0 || num_entries > Self::max_entries(block.len()) {
+            return Err(());
+        }
+
+        let entries = block[HTREE_HEADER_SIZE..].chunks_exact(HTREE_ENTRY_SIZE)
+            .take(num_entries)
+            .map(|entry| HTreeEntry {
+                hash: u32::from_le_bytes(crate::slice_to_four_bytes(&entry[0..4])),
+                block: u32::from_le_bytes(crate::slice_to_four_bytes(&entry[4..8])),
+            })
+            .collect();
+
+        Ok(HTreeNode { depth, entries })
+    }
+}
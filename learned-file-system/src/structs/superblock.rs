@@ -1,15 +1,311 @@
+use std::io::{Error, ErrorKind, Read};
+use crate::FS_BLOCK_SIZE;
+
+/// Incompatible features: a driver that does not know one of these must not mount the image.
+/// New inodes are created extent-mapped instead of pointer-mapped
+pub const FEATURE_INCOMPAT_EXTENTS: u32 = 0x0001;
+/// New directories use variable-length records, allowing names of up to 255 bytes
+pub const FEATURE_INCOMPAT_VAR_DIRENTS: u32 = 0x0002;
+/// Set while a journaled file system is mounted read-write. The journal may then hold a
+/// transaction that has to be replayed before the image is consistent.
+pub const FEATURE_INCOMPAT_RECOVER: u32 = 0x0004;
+/// Metadata is updated copy-on-write, see cow_start. The superblock alternates between
+/// block 0 and cow_start, and the one with the higher cow_generation is current.
+pub const FEATURE_INCOMPAT_COW: u32 = 0x0008;
+pub const FEATURE_INCOMPAT_SUPPORTED: u32 = FEATURE_INCOMPAT_EXTENTS | FEATURE_INCOMPAT_VAR_DIRENTS | FEATURE_INCOMPAT_RECOVER
+    | FEATURE_INCOMPAT_COW;
+
+/// Read-only compatible features: a driver that does not know one of these can still read
+/// the image, but writing would break it.
+/// Variable-length directories get a hash index once they outgrow a block
+pub const FEATURE_RO_COMPAT_DIR_INDEX: u32 = 0x0001;
+/// Variable-length directories get a learned lookup model once they outgrow a block. Takes
+/// precedence over FEATURE_RO_COMPAT_DIR_INDEX for new indexes.
+pub const FEATURE_RO_COMPAT_LEARNED_INDEX: u32 = 0x0002;
+pub const FEATURE_RO_COMPAT_SUPPORTED: u32 = FEATURE_RO_COMPAT_DIR_INDEX | FEATURE_RO_COMPAT_LEARNED_INDEX;
+
+/// Compatible features: safe to ignore.
+/// Metadata writes go through a journal, see journal_start. A driver without journal support
+/// can still use a cleanly unmounted image, since FEATURE_INCOMPAT_RECOVER is clear then.
+pub const FEATURE_COMPAT_HAS_JOURNAL: u32 = 0x0001;
+pub const FEATURE_COMPAT_SUPPORTED: u32 = FEATURE_COMPAT_HAS_JOURNAL;
+
+/// Block sizes we can format and mount. Every power of two in between works too
+pub const MIN_BLOCK_SIZE: u32 = 1024;
+pub const MAX_BLOCK_SIZE: u32 = 65536;
+
+pub fn is_valid_block_size(block_size: u32) -> bool {
+    block_size.is_power_of_two() && (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size)
+}
+
+/// Set while the file system is mounted read-write and cleared on a clean unmount, so a
+/// set flag at mount time means the last session did not finish
+pub const FS_STATE_DIRTY: u16 = 0x0001;
+
+/// Bytes available for the volume label
+pub const LABEL_SIZE: usize = 32;
+
+/// Random version 4 UUID for a new or upgraded file system
+pub fn generate_uuid() -> std::io::Result<[u8; 16]> {
+    let mut uuid = [0u8; 16];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    Ok(uuid)
+}
+
+/// Standard 8-4-4-4-12 hex form of a UUID
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: Vec<String> = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(), hex[8..10].concat(), hex[10..16].concat())
+}
+
 /// Block of the file system with inumber 0
 /// Records meta-data about the entire file system
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct FsSuperBlock {
     pub magic: u32,
     pub disk_size: u32,
+    /// On-disk format revision. Images made by gen-disk.py leave this 0
+    pub version: u32,
+    /// Bitmask of FEATURE_INCOMPAT_* flags
+    pub incompat_features: u32,
+    /// First block of the allocation bitmap. 0 on older images, where it is block 1
+    pub bitmap_start: u32,
+    /// Number of allocation bitmap blocks. 0 on older images, where it is 1
+    pub bitmap_blocks: u32,
+    /// First block of the inode table. Only meaningful if inode_count is not 0
+    pub inode_table_start: u32,
+    pub inode_table_blocks: u32,
+    /// First block of the inode allocation bitmap
+    pub inode_bitmap_start: u32,
+    pub inode_bitmap_blocks: u32,
+    /// Size in bytes of one inode table entry
+    pub inode_size: u32,
+    /// Number of inodes in the inode table. 0 means there is no inode table and every
+    /// inode takes the whole block with the same number instead
+    pub inode_count: u32,
+    /// Bitmask of FEATURE_COMPAT_* flags
+    pub compat_features: u32,
+    /// Bitmask of FEATURE_RO_COMPAT_* flags
+    pub ro_compat_features: u32,
+    /// Bytes per block. 0 on older images, where it is 4096
+    pub block_size: u32,
+    /// Free blocks and inodes as of the last time the superblock was written. The bitmaps are
+    /// authoritative, these are for tools that only read the superblock
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    pub uuid: [u8; 16],
+    /// Volume label, padded with NUL bytes
+    pub label: [u8; LABEL_SIZE],
+    /// Number of times the file system was mounted since it was created
+    pub mount_count: u16,
+    /// Bitmask of FS_STATE_* flags
+    pub state: u16,
+    /// Seconds since the epoch
+    pub last_mount_time: u32,
+    pub last_write_time: u32,
+    /// First block of the journal, if FEATURE_COMPAT_HAS_JOURNAL is set
+    pub journal_start: u32,
+    pub journal_blocks: u32,
+    /// Second superblock copy, followed by the blocks metadata is copied into, if
+    /// FEATURE_INCOMPAT_COW is set
+    pub cow_start: u32,
+    pub cow_blocks: u32,
+    /// Bumped on every copy-on-write commit
+    pub cow_generation: u32,
+    /// First block of the map from home locations to copies, 0 if it is empty
+    pub cow_map: u32,
+    /// crc32 of the superblock with this field zeroed. Only kept on copy-on-write images
+    pub cow_checksum: u32,
+}
+
+impl FsSuperBlock {
+    /// (first block, number of blocks) of the allocation bitmap
+    pub fn bitmap_location(&self) -> (u32, u32) {
+        if self.bitmap_blocks == 0 {
+            (1, 1)
+        } else {
+            (self.bitmap_start, self.bitmap_blocks)
+        }
+    }
+
+    pub fn has_inode_table(&self) -> bool {
+        self.inode_count != 0
+    }
+
+    pub fn has_journal(&self) -> bool {
+        self.compat_features & FEATURE_COMPAT_HAS_JOURNAL != 0
+    }
+
+    pub fn is_cow(&self) -> bool {
+        self.incompat_features & FEATURE_INCOMPAT_COW != 0
+    }
+
+    pub fn compute_checksum(&self) -> u32 {
+        let mut super_block = self.clone();
+        super_block.cow_checksum = 0;
+        let super_block_data: Vec<u8> = super_block.into();
+        crate::utils::crc32(&super_block_data)
+    }
+
+    pub fn block_size(&self) -> u32 {
+        if self.block_size == 0 { FS_BLOCK_SIZE as u32 } else { self.block_size }
+    }
+
+    /// Incompatible features this driver does not know
+    pub fn unknown_incompat_features(&self) -> u32 {
+        self.incompat_features & !FEATURE_INCOMPAT_SUPPORTED
+    }
+
+    /// Read-only compatible features this driver does not know
+    pub fn unknown_ro_compat_features(&self) -> u32 {
+        self.ro_compat_features & !FEATURE_RO_COMPAT_SUPPORTED
+    }
+
+    /// The label up to the first NUL byte
+    pub fn label(&self) -> String {
+        let len = self.label.iter().position(|b| *b == 0).unwrap_or(LABEL_SIZE);
+        String::from_utf8_lossy(&self.label[..len]).into_owned()
+    }
+
+    pub fn set_label(&mut self, label: &str) -> std::io::Result<()> {
+        if label.len() > LABEL_SIZE {
+            return Err(Error::from(ErrorKind::InvalidInput));
+        }
+        self.label = [0u8; LABEL_SIZE];
+        self.label[..label.len()].copy_from_slice(label.as_bytes());
+        Ok(())
+    }
 }
 
 impl From<&[u8]> for FsSuperBlock {
     fn from(super_block_bytes: &[u8]) -> Self {
         let magic = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[0..4]));
         let disk_size = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[4..8]));
-        FsSuperBlock { magic, disk_size }
+        let version = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[8..12]));
+        let incompat_features = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[12..16]));
+        let bitmap_start = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[16..20]));
+        let bitmap_blocks = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[20..24]));
+        let inode_table_start = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[24..28]));
+        let inode_table_blocks = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[28..32]));
+        let inode_bitmap_start = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[32..36]));
+        let inode_bitmap_blocks = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[36..40]));
+        let inode_size = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[40..44]));
+        let inode_count = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[44..48]));
+        let compat_features = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[48..52]));
+        let ro_compat_features = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[52..56]));
+        let block_size = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[56..60]));
+        let free_blocks_count = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[60..64]));
+        let free_inodes_count = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[64..68]));
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&super_block_bytes[68..84]);
+        let mut label = [0u8; LABEL_SIZE];
+        label.copy_from_slice(&super_block_bytes[84..116]);
+        let mount_count = u16::from_le_bytes(crate::slice_to_two_bytes(&super_block_bytes[116..118]));
+        let state = u16::from_le_bytes(crate::slice_to_two_bytes(&super_block_bytes[118..120]));
+        let last_mount_time = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[120..124]));
+        let last_write_time = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[124..128]));
+        let journal_start = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[128..132]));
+        let journal_blocks = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[132..136]));
+        let cow_start = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[136..140]));
+        let cow_blocks = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[140..144]));
+        let cow_generation = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[144..148]));
+        let cow_map = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[148..152]));
+        let cow_checksum = u32::from_le_bytes(crate::slice_to_four_bytes(&super_block_bytes[152..156]));
+        FsSuperBlock {
+            magic, disk_size, version, incompat_features, bitmap_start, bitmap_blocks, inode_table_start,
+            inode_table_blocks, inode_bitmap_start, inode_bitmap_blocks, inode_size, inode_count,
+            compat_features, ro_compat_features, block_size, free_blocks_count, free_inodes_count,
+            uuid, label, mount_count, state, last_mount_time, last_write_time, journal_start, journal_blocks,
+            cow_start, cow_blocks, cow_generation, cow_map, cow_checksum,
+        }
+    }
+}
+
+impl Into<Vec<u8>> for FsSuperBlock {
+    fn into(self) -> Vec<u8> {
+        let mut dest = vec![0u8; self.block_size() as usize];
+        dest[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        dest[4..8].copy_from_slice(&self.disk_size.to_le_bytes());
+        dest[8..12].copy_from_slice(&self.version.to_le_bytes());
+        dest[12..16].copy_from_slice(&self.incompat_features.to_le_bytes());
+        dest[16..20].copy_from_slice(&self.bitmap_start.to_le_bytes());
+        dest[20..24].copy_from_slice(&self.bitmap_blocks.to_le_bytes());
+        dest[24..28].copy_from_slice(&self.inode_table_start.to_le_bytes());
+        dest[28..32].copy_from_slice(&self.inode_table_blocks.to_le_bytes());
+        dest[32..36].copy_from_slice(&self.inode_bitmap_start.to_le_bytes());
+        dest[36..40].copy_from_slice(&self.inode_bitmap_blocks.to_le_bytes());
+        dest[40..44].copy_from_slice(&self.inode_size.to_le_bytes());
+        dest[44..48].copy_from_slice(&self.inode_count.to_le_bytes());
+        dest[48..52].copy_from_slice(&self.compat_features.to_le_bytes());
+        dest[52..56].copy_from_slice(&self.ro_compat_features.to_le_bytes());
+        dest[56..60].copy_from_slice(&self.block_size.to_le_bytes());
+        dest[60..64].copy_from_slice(&self.free_blocks_count.to_le_bytes());
+        dest[64..68].copy_from_slice(&self.free_inodes_count.to_le_bytes());
+        dest[68..84].copy_from_slice(&self.uuid);
+        dest[84..116].copy_from_slice(&self.label);
+        dest[116..118].copy_from_slice(&self.mount_count.to_le_bytes());
+        dest[118..120].copy_from_slice(&self.state.to_le_bytes());
+        dest[120..124].copy_from_slice(&self.last_mount_time.to_le_bytes());
+        dest[124..128].copy_from_slice(&self.last_write_time.to_le_bytes());
+        dest[128..132].copy_from_slice(&self.journal_start.to_le_bytes());
+        dest[132..136].copy_from_slice(&self.journal_blocks.to_le_bytes());
+        dest[136..140].copy_from_slice(&self.cow_start.to_le_bytes());
+        dest[140..144].copy_from_slice(&self.cow_blocks.to_le_bytes());
+        dest[144..148].copy_from_slice(&self.cow_generation.to_le_bytes());
+        dest[148..152].copy_from_slice(&self.cow_map.to_le_bytes());
+        dest[152..156].copy_from_slice(&self.cow_checksum.to_le_bytes());
+        dest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FS_VERSION, LearnedFileSystem};
+    use crate::crash_test::{check_written, mkfs_image, reopen_clean, WorkloadOp};
+    use crate::mkfs::MkfsOptions;
+    use crate::utils::block_file::MemoryBlockFile;
+    use super::*;
+
+    #[test]
+    fn superblock_round_trip() {
+        let mut options = MkfsOptions { num_blocks: 1024, block_size: 2048, label: "scratch space".to_string(), ..MkfsOptions::default() };
+        options.set_features("extents,var_dirents,learned_index,has_journal").unwrap();
+        let image = mkfs_image(&options).unwrap();
+        let formatted = FsSuperBlock::from(&image[..2048]);
+        let mut file_system = LearnedFileSystem::new(MemoryBlockFile::new(2048, image), String::new());
+        file_system.mount().unwrap();
+        for op in WorkloadOp::parse_workload("mkdir /d\ncreate /d/f\nwrite /d/f 0 10000").unwrap() {
+            file_system.run_workload_op(&op).unwrap();
+        }
+        file_system.unmount().unwrap();
+        let file_system = reopen_clean(file_system.block_system.device.device);
+
+        let super_block = file_system.get_superblock().unwrap();
+        assert_eq!(super_block.version, FS_VERSION);
+        assert_eq!(super_block.block_size(), 2048);
+        assert_eq!(super_block.label(), "scratch space");
+        assert_ne!(super_block.uuid, [0u8; 16]);
+        assert_eq!(super_block.uuid, formatted.uuid);
+        assert_eq!(super_block.incompat_features, FEATURE_INCOMPAT_EXTENTS | FEATURE_INCOMPAT_VAR_DIRENTS);
+        assert_eq!(super_block.ro_compat_features, FEATURE_RO_COMPAT_LEARNED_INDEX);
+        assert_eq!(super_block.compat_features, FEATURE_COMPAT_HAS_JOURNAL);
+        assert_eq!((super_block.mount_count, super_block.state), (1, 0));
+        assert!(super_block.last_mount_time >= formatted.last_write_time);
+        check_written(&file_system, "/d/f", 0, 10000);
+
+        // Every field survives being written out and read back
+        let bytes: Vec<u8> = super_block.clone().into();
+        assert_eq!(format!("{:?}", FsSuperBlock::from(bytes.as_slice())), format!("{:?}", super_block));
+
+        // A feature this driver does not know keeps the image from loading
+        let mut image = file_system.block_system.device.device.into_data();
+        let mut unknown = super_block;
+        unknown.incompat_features |= 0x8000;
+        let unknown_bytes: Vec<u8> = unknown.into();
+        image[..2048].copy_from_slice(&unknown_bytes);
+        let mut file_system = LearnedFileSystem::new(MemoryBlockFile::new(2048, image), String::new());
+        assert_eq!(file_system.mount().unwrap_err().kind(), ErrorKind::InvalidData);
     }
 }
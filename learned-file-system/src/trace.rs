@@ -0,0 +1,773 @@
+//! Append-only trace of the requests a mounted file system serves.
+//!
+//! A trace is one file, plus up to `TraceOptions::max_files` older ones when it is rotated:
+//! once the file reaches `TraceOptions::max_bytes`, `trace.N` becomes `trace.N+1`, `trace`
+//! becomes `trace.1` and a new `trace` is started. Each file holds records in one of three
+//! formats.
+//!
+//! Binary, the default. All integers are little-endian. The file starts with a 24 byte header:
+//!
+//! | offset | size | field                                                        |
+//! |--------|------|--------------------------------------------------------------|
+//! | 0      | 8    | magic, `LFSTRACE`                                            |
+//! | 8      | 4    | format version, 1                                            |
+//! | 12     | 4    | reserved, 0                                                  |
+//! | 16     | 8    | wall clock time the trace was started, in ns since the epoch |
+//!
+//! Each record follows as
+//!
+//! | offset | size | field                                                  |
+//! |--------|------|--------------------------------------------------------|
+//! | 0      | 4    | length of the rest of the record, 57 + 8 * num_blocks  |
+//! | 4      | 8    | timestamp, ns since the trace was started (monotonic)  |
+//! | 12     | 8    | latency, ns                                            |
+//! | 20     | 8    | inode                                                  |
+//! | 28     | 8    | offset                                                 |
+//! | 36     | 8    | length                                                 |
+//! | 44     | 4    | pid                                                    |
+//! | 48     | 4    | uid                                                    |
+//! | 52     | 4    | errno the request failed with, 0 if it succeeded       |
+//! | 56     | 4    | num_blocks                                             |
+//! | 60     | 1    | op, see `TraceOp`                                      |
+//! | 61     | 8 each | block numbers read or written, ascending             |
+//!
+//! A record cut short by a crash ends the trace.
+//!
+//! CSV, with a header line naming the columns `timestamp_ns,op,inode,offset,length,pid,uid,
+//! blocks,latency_ns,errno`. `op` is the name of the op and `blocks` the block numbers
+//! separated by spaces.
+//!
+//! JSON lines, one object per record with the same keys as the CSV columns and `blocks` as
+//! an array.
+//!
+//! Only the binary format records the wall clock start time. Timestamps always count from
+//! it, also in rotated files.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use fuse::Request;
+use log::{error, warn};
+use std::os::raw::c_int;
+use crate::LearnedFileSystem;
+use crate::utils::block_file::BlockFile;
+
+const TRACE_MAGIC: &[u8; 8] = b"LFSTRACE";
+const TRACE_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 24;
+/// Size of a binary record without its length field and block numbers
+const RECORD_FIXED_SIZE: usize = 57;
+/// First line of a CSV trace
+pub const CSV_HEADER: &str = "timestamp_ns,op,inode,offset,length,pid,uid,blocks,latency_ns,errno";
+/// Rotated files kept when none is given
+pub const DEFAULT_MAX_TRACE_FILES: usize = 4;
+
+/// The FUSE request a trace record is for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TraceOp {
+    Init = 1,
+    Destroy = 2,
+    Lookup = 3,
+    Getattr = 4,
+    Setattr = 5,
+    Mknod = 6,
+    Mkdir = 7,
+    Unlink = 8,
+    Rmdir = 9,
+    Rename = 10,
+    Read = 11,
+    Write = 12,
+    Flush = 13,
+    Fsync = 14,
+    Fsyncdir = 15,
+    Readdir = 16,
+    Statfs = 17,
+    Getxattr = 18,
+    Setxattr = 19,
+}
+
+const TRACE_OPS: [(TraceOp, &str); 19] = [
+    (TraceOp::Init, "init"),
+    (TraceOp::Destroy, "destroy"),
+    (TraceOp::Lookup, "lookup"),
+    (TraceOp::Getattr, "getattr"),
+    (TraceOp::Setattr, "setattr"),
+    (TraceOp::Mknod, "mknod"),
+    (TraceOp::Mkdir, "mkdir"),
+    (TraceOp::Unlink, "unlink"),
+    (TraceOp::Rmdir, "rmdir"),
+    (TraceOp::Rename, "rename"),
+    (TraceOp::Read, "read"),
+    (TraceOp::Write, "write"),
+    (TraceOp::Flush, "flush"),
+    (TraceOp::Fsync, "fsync"),
+    (TraceOp::Fsyncdir, "fsyncdir"),
+    (TraceOp::Readdir, "readdir"),
+    (TraceOp::Statfs, "statfs"),
+    (TraceOp::Getxattr, "getxattr"),
+    (TraceOp::Setxattr, "setxattr"),
+];
+
+impl TraceOp {
+    pub fn name(self) -> &'static str {
+        TRACE_OPS.iter().find(|(op, _)| *op == self).unwrap().1
+    }
+
+    pub fn from_name(name: &str) -> std::io::Result<Self> {
+        TRACE_OPS.iter().find(|(_, op_name)| *op_name == name).map(|(op, _)| *op)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("unknown trace op {}", name)))
+    }
+
+    fn from_code(code: u8) -> std::io::Result<Self> {
+        TRACE_OPS.iter().find(|(op, _)| *op as u8 == code).map(|(op, _)| *op)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("unknown trace op code {}", code)))
+    }
+}
+
+/// One request the file system served
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// When the request came in, in ns since the trace was started
+    pub timestamp_ns: u64,
+    pub op: TraceOp,
+    /// Inode the request is about, the parent directory for requests by name, 0 for none
+    pub inode: u64,
+    /// Byte offset for reads and writes, entry offset for readdir, 0 for other requests
+    pub offset: u64,
+    /// Bytes asked for by reads, bytes written by writes, 0 for other requests
+    pub length: u64,
+    pub pid: u32,
+    pub uid: u32,
+    /// Blocks the file system read or wrote to serve the request, ascending
+    pub blocks: Vec<u64>,
+    /// Time spent in the file system, not counting the reply to the kernel
+    pub latency_ns: u64,
+    /// errno the request failed with, 0 if it succeeded
+    pub errno: u32,
+}
+
+impl TraceRecord {
+    fn to_binary(&self) -> Vec<u8> {
+        let mut record = Vec::with_capacity(4 + RECORD_FIXED_SIZE + 8 * self.blocks.len());
+        record.extend(((RECORD_FIXED_SIZE + 8 * self.blocks.len()) as u32).to_le_bytes());
+        record.extend(self.timestamp_ns.to_le_bytes());
+        record.extend(self.latency_ns.to_le_bytes());
+        record.extend(self.inode.to_le_bytes());
+        record.extend(self.offset.to_le_bytes());
+        record.extend(self.length.to_le_bytes());
+        record.extend(self.pid.to_le_bytes());
+        record.extend(self.uid.to_le_bytes());
+        record.extend(self.errno.to_le_bytes());
+        record.extend((self.blocks.len() as u32).to_le_bytes());
+        record.push(self.op as u8);
+        for block in &self.blocks {
+            record.extend(block.to_le_bytes());
+        }
+        record
+    }
+
+    /// Parse a binary record, without its length field
+    fn from_binary(record: &[u8]) -> std::io::Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "malformed trace record");
+        if record.len() < RECORD_FIXED_SIZE {
+            return Err(invalid());
+        }
+        let u64_at = |offset: usize| u64::from_le_bytes(record[offset..(offset + 8)].try_into().unwrap());
+        let u32_at = |offset: usize| u32::from_le_bytes(record[offset..(offset + 4)].try_into().unwrap());
+        let num_blocks = u32_at(52) as usize;
+        if record.len() != RECORD_FIXED_SIZE + 8 * num_blocks {
+            return Err(invalid());
+        }
+        Ok(TraceRecord {
+            timestamp_ns: u64_at(0),
+            latency_ns: u64_at(8),
+            inode: u64_at(16),
+            offset: u64_at(24),
+            length: u64_at(32),
+            pid: u32_at(40),
+            uid: u32_at(44),
+            errno: u32_at(48),
+            op: TraceOp::from_code(record[56])?,
+            blocks: (0..num_blocks).map(|idx| u64_at(RECORD_FIXED_SIZE + 8 * idx)).collect(),
+        })
+    }
+
+    /// The record as a line of a CSV trace
+    pub fn to_csv(&self) -> String {
+        let blocks: Vec<String> = self.blocks.iter().map(u64::to_string).collect();
+        format!("{},{},{},{},{},{},{},{},{},{}\n", self.timestamp_ns, self.op.name(), self.inode, self.offset, self.length,
+                self.pid, self.uid, blocks.join(" "), self.latency_ns, self.errno)
+    }
+
+    fn from_csv(line: &str) -> std::io::Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("malformed trace line {:?}", line));
+        let fields: Vec<&str> = line.split(',').collect();
+        let [timestamp_ns, op, inode, offset, length, pid, uid, blocks, latency_ns, errno] = fields.as_slice() else {
+            return Err(invalid());
+        };
+        let number = |field: &str| field.parse::<u64>().map_err(|_| invalid());
+        Ok(TraceRecord {
+            timestamp_ns: number(timestamp_ns)?,
+            op: TraceOp::from_name(op)?,
+            inode: number(inode)?,
+            offset: number(offset)?,
+            length: number(length)?,
+            pid: number(pid)? as u32,
+            uid: number(uid)? as u32,
+            blocks: blocks.split_whitespace().map(number).collect::<std::io::Result<_>>()?,
+            latency_ns: number(latency_ns)?,
+            errno: number(errno)? as u32,
+        })
+    }
+
+    /// The record as a line of a JSON lines trace
+    pub fn to_json(&self) -> String {
+        let blocks: Vec<String> = self.blocks.iter().map(u64::to_string).collect();
+        format!("{{\"timestamp_ns\":{},\"op\":\"{}\",\"inode\":{},\"offset\":{},\"length\":{},\"pid\":{},\"uid\":{},\"blocks\":[{}],\"latency_ns\":{},\"errno\":{}}}\n",
+                self.timestamp_ns, self.op.name(), self.inode, self.offset, self.length, self.pid, self.uid,
+                blocks.join(","), self.latency_ns, self.errno)
+    }
+
+    /// Parse a line as written by `to_json`. Not a general JSON parser: the keys may come in
+    /// any order, but values have to be numbers, strings without escapes or arrays of numbers.
+    fn from_json(line: &str) -> std::io::Result<Self> {
+        let invalid = || Error::new(ErrorKind::InvalidData, format!("malformed trace line {:?}", line));
+        let mut rest = line.trim().strip_prefix('{').and_then(|rest| rest.strip_suffix('}')).ok_or_else(invalid)?.trim();
+        let mut record = TraceRecord { timestamp_ns: 0, op: TraceOp::Init, inode: 0, offset: 0, length: 0, pid: 0, uid: 0, blocks: vec![], latency_ns: 0, errno: 0 };
+        let number = |value: &str| value.trim().parse::<u64>().map_err(|_| invalid());
+        while !rest.is_empty() {
+            let (key, after_key) = rest.strip_prefix('"').and_then(|rest| rest.split_once("\":")).ok_or_else(invalid)?;
+            let after_key = after_key.trim_start();
+            let value_len = match after_key.chars().next() {
+                Some('"') => after_key[1..].find('"').ok_or_else(invalid)? + 2,
+                Some('[') => after_key.find(']').ok_or_else(invalid)? + 1,
+                _ => after_key.find(',').unwrap_or(after_key.len()),
+            };
+            let value = &after_key[..value_len];
+            match key {
+                "timestamp_ns" => record.timestamp_ns = number(value)?,
+                "op" => record.op = TraceOp::from_name(value.trim_matches('"'))?,
+                "inode" => record.inode = number(value)?,
+                "offset" => record.offset = number(value)?,
+                "length" => record.length = number(value)?,
+                "pid" => record.pid = number(value)? as u32,
+                "uid" => record.uid = number(value)? as u32,
+                "blocks" => record.blocks = value[1..(value.len() - 1)].split(',').filter(|block| !block.trim().is_empty())
+                    .map(number).collect::<std::io::Result<_>>()?,
+                "latency_ns" => record.latency_ns = number(value)?,
+                "errno" => record.errno = number(value)? as u32,
+                _ => {}
+            }
+            rest = after_key[value_len..].trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+        }
+        Ok(record)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    #[default]
+    Binary,
+    Csv,
+    Jsonl,
+}
+
+impl TraceFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceFormat::Binary => "binary",
+            TraceFormat::Csv => "csv",
+            TraceFormat::Jsonl => "jsonl",
+        }
+    }
+
+    pub fn from_name(name: &str) -> std::io::Result<Self> {
+        match name {
+            "binary" => Ok(TraceFormat::Binary),
+            "csv" => Ok(TraceFormat::Csv),
+            "jsonl" => Ok(TraceFormat::Jsonl),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown trace format {}", name))),
+        }
+    }
+
+    /// CSV or JSON lines for files named so, binary for anything else
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => TraceFormat::Csv,
+            Some("jsonl") => TraceFormat::Jsonl,
+            _ => TraceFormat::Binary,
+        }
+    }
+}
+
+/// How a trace is written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceOptions {
+    /// None picks the format from the file name, see `TraceFormat::from_path`
+    pub format: Option<TraceFormat>,
+    /// Size to rotate the trace at, or None to let it grow
+    pub max_bytes: Option<u64>,
+    /// Rotated files to keep
+    pub max_files: usize,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        TraceOptions { format: None, max_bytes: None, max_files: DEFAULT_MAX_TRACE_FILES }
+    }
+}
+
+/// The file `path` is rotated to the `generation`th time
+fn rotated_path(path: &Path, generation: usize) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", generation));
+    PathBuf::from(rotated)
+}
+
+/// The files of the trace at `path` that exist, oldest first
+pub fn trace_files(path: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = (1..).map(|generation| rotated_path(path, generation)).take_while(|rotated| rotated.exists()).collect();
+    files.reverse();
+    if path.exists() {
+        files.push(path.to_path_buf());
+    }
+    files
+}
+
+/// Appends records to a trace through a buffer, which is written out when full, when
+/// rotating, on `flush` and when dropped
+pub struct TraceWriter {
+    path: PathBuf,
+    options: TraceOptions,
+    format: TraceFormat,
+    file: BufWriter<File>,
+    /// Bytes in the current file, including those still buffered
+    file_size: u64,
+    started: Instant,
+    /// Timestamp of `started`, 0 unless an existing trace was appended to
+    resumed_ns: u64,
+    /// Wall clock time of timestamp 0, in ns since the epoch
+    start_time_ns: u64,
+}
+
+impl TraceWriter {
+    /// Start tracing to `path`, appending if it already holds a trace. Timestamps carry on
+    /// from the start time in its header for a binary trace, and from its last record for
+    /// the text formats, which do not record one. Fails if the trace is in another format.
+    pub fn open(path: &Path, options: TraceOptions) -> std::io::Result<Self> {
+        let now_ns = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos() as u64);
+        let started = Instant::now();
+        let format = options.format.unwrap_or_else(|| TraceFormat::from_path(path));
+        let (start_time_ns, resumed_ns) = match std::fs::metadata(path) {
+            Ok(metadata) if metadata.len() > 0 => {
+                let (header_start_ns, last_timestamp_ns) = Self::resume(path, format)?;
+                match header_start_ns {
+                    Some(start_time_ns) => (start_time_ns, now_ns.saturating_sub(start_time_ns).max(last_timestamp_ns)),
+                    None => (now_ns.saturating_sub(last_timestamp_ns), last_timestamp_ns),
+                }
+            }
+            _ => (now_ns, 0),
+        };
+        let (file, file_size) = Self::open_file(path, format, start_time_ns)?;
+        Ok(TraceWriter { path: path.to_path_buf(), options, format, file, file_size, started, resumed_ns, start_time_ns })
+    }
+
+    /// Check that the trace at `path` is in `format` and cut off a record a crash left
+    /// incomplete, so appending to it keeps it readable. Returns the start time from its
+    /// header, if the format has one, and the timestamp of its last record.
+    fn resume(path: &Path, format: TraceFormat) -> std::io::Result<(Option<u64>, u64)> {
+        let mut reader = TraceReader::open(path).map_err(|e| Error::new(e.kind(), format!("cannot append to {}: {}", path.display(), e)))?;
+        if reader.format() != format {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("cannot append to {}: it holds a {} trace, not {}",
+                                                                   path.display(), reader.format().name(), format.name())));
+        }
+        let mut last_timestamp_ns = 0;
+        while let Some(record) = reader.next_record()? {
+            last_timestamp_ns = last_timestamp_ns.max(record.timestamp_ns);
+        }
+        let file = OpenOptions::new().write(true).open(path)?;
+        let file_size = file.metadata()?.len();
+        if file_size > reader.position {
+            warn!("Dropping an incomplete record at the end of {}", path.display());
+            file.set_len(reader.position)?;
+        }
+        Ok((reader.start_time_ns(), last_timestamp_ns))
+    }
+
+    fn open_file(path: &Path, format: TraceFormat, start_time_ns: u64) -> std::io::Result<(BufWriter<File>, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut file_size = file.metadata()?.len();
+        let mut file = BufWriter::new(file);
+        if file_size == 0 {
+            let header = match format {
+                TraceFormat::Binary => {
+                    let mut header = TRACE_MAGIC.to_vec();
+                    header.extend(TRACE_VERSION.to_le_bytes());
+                    header.extend(0u32.to_le_bytes());
+                    header.extend(start_time_ns.to_le_bytes());
+                    header
+                }
+                TraceFormat::Csv => format!("{}\n", CSV_HEADER).into_bytes(),
+                TraceFormat::Jsonl => vec![],
+            };
+            file.write_all(&header)?;
+            file_size = header.len() as u64;
+        }
+        Ok((file, file_size))
+    }
+
+    /// Nanoseconds from the start of the trace to `instant`
+    pub fn timestamp(&self, instant: Instant) -> u64 {
+        self.resumed_ns + instant.saturating_duration_since(self.started).as_nanos() as u64
+    }
+
+    pub fn start_time_ns(&self) -> u64 {
+        self.start_time_ns
+    }
+
+    pub fn write(&mut self, record: &TraceRecord) -> std::io::Result<()> {
+        let bytes = match self.format {
+            TraceFormat::Binary => record.to_binary(),
+            TraceFormat::Csv => record.to_csv().into_bytes(),
+            TraceFormat::Jsonl => record.to_json().into_bytes(),
+        };
+        self.file.write_all(&bytes)?;
+        self.file_size += bytes.len() as u64;
+        if self.options.max_bytes.is_some_and(|max_bytes| self.file_size >= max_bytes) {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Move the current file aside as `path.1`, shifting older ones, and start a new one
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.options.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(rotated_path(&self.path, self.options.max_files));
+            for generation in (1..self.options.max_files).rev() {
+                let older = rotated_path(&self.path, generation);
+                if older.exists() {
+                    std::fs::rename(&older, rotated_path(&self.path, generation + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        (self.file, self.file_size) = Self::open_file(&self.path, self.format, self.start_time_ns)?;
+        Ok(())
+    }
+}
+
+/// Reads the records of one trace file in any of the formats, telling them apart by how
+/// the file starts
+pub struct TraceReader<R: Read> {
+    reader: BufReader<R>,
+    format: TraceFormat,
+    start_time_ns: Option<u64>,
+    /// Bytes of the header and the complete records read so far
+    position: u64,
+    done: bool,
+}
+
+impl TraceReader<File> {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Self::new(File::open(path)?)
+    }
+}
+
+impl <R: Read> TraceReader<R> {
+    pub fn new(reader: R) -> std::io::Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let start = reader.fill_buf()?;
+        let (format, start_time_ns, position) = if start.starts_with(TRACE_MAGIC) {
+            let mut header = [0u8; HEADER_SIZE];
+            reader.read_exact(&mut header)?;
+            let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+            if version != TRACE_VERSION {
+                return Err(Error::new(ErrorKind::InvalidData, format!("unsupported trace version {}", version)));
+            }
+            (TraceFormat::Binary, Some(u64::from_le_bytes(header[16..24].try_into().unwrap())), HEADER_SIZE as u64)
+        } else if start.starts_with(CSV_HEADER.as_bytes()) {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            (TraceFormat::Csv, None, header.len() as u64)
+        } else if start.is_empty() || start.starts_with(b"{") {
+            (TraceFormat::Jsonl, None, 0)
+        } else {
+            return Err(Error::new(ErrorKind::InvalidData, "not a trace"));
+        };
+        Ok(TraceReader { reader, format, start_time_ns, position, done: false })
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    /// Wall clock time the trace was started, in ns since the epoch, if the format has it
+    pub fn start_time_ns(&self) -> Option<u64> {
+        self.start_time_ns
+    }
+
+    fn next_record(&mut self) -> std::io::Result<Option<TraceRecord>> {
+        if self.format == TraceFormat::Binary {
+            let mut len = [0u8; 4];
+            match self.reader.read_exact(&mut len) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                result => result?,
+            }
+            let mut record = vec![0u8; u32::from_le_bytes(len) as usize];
+            match self.reader.read_exact(&mut record) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                result => result?,
+            }
+            let record = TraceRecord::from_binary(&record)?;
+            self.position += 4 + u32::from_le_bytes(len) as u64;
+            return Ok(Some(record));
+        }
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            // A line cut short by a crash ends the trace
+            if !line.ends_with('\n') {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                break;
+            }
+            self.position += line.len() as u64;
+        }
+        let record = match self.format {
+            TraceFormat::Csv => TraceRecord::from_csv(line.trim_end())?,
+            _ => TraceRecord::from_json(&line)?,
+        };
+        self.position += line.len() as u64;
+        Ok(Some(record))
+    }
+}
+
+impl <R: Read> Iterator for TraceReader<R> {
+    type Item = std::io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.next_record().transpose();
+        self.done = !matches!(record, Some(Ok(_)));
+        record
+    }
+}
+
+/// Every record of the trace at `path`, rotated files included, oldest first
+pub fn read_trace(path: &Path) -> std::io::Result<Vec<TraceRecord>> {
+    let files = trace_files(path);
+    if files.is_empty() {
+        return Err(Error::from(ErrorKind::NotFound));
+    }
+    let mut records = vec![];
+    for file in files {
+        for record in TraceReader::open(&file)? {
+            records.push(record?);
+        }
+    }
+    Ok(records)
+}
+
+/// Tracing the requests the FUSE handlers serve.
+impl <BF: BlockFile> LearnedFileSystem<BF> {
+    /// How the trace is written, when a trace file was given to `new`
+    pub fn set_trace_options(&mut self, options: TraceOptions) {
+        self.trace_options = options;
+    }
+
+    /// Start tracing, if a trace file was given
+    pub(crate) fn open_trace(&mut self) -> std::io::Result<()> {
+        if !self.logging_path.is_empty() && self.trace.is_none() {
+            let path = PathBuf::from(&self.logging_path);
+            self.trace = Some(TraceWriter::open(&path, self.trace_options)
+                .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?);
+        }
+        Ok(())
+    }
+
+    /// Write out and stop the trace
+    pub(crate) fn close_trace(&mut self) {
+        if let Some(mut trace) = self.trace.take() {
+            if let Err(e) = trace.flush() {
+                error!("Could not write the trace: {}", e);
+            }
+        }
+    }
+
+    /// Serve a request with `handler`, adding a record of it to the trace
+    pub(crate) fn traced<T>(&mut self, req: &Request, op: TraceOp, inode: u64, offset: i64, length: u64,
+                            handler: impl FnOnce(&mut Self) -> Result<T, c_int>) -> Result<T, c_int> {
+        if self.trace.is_none() {
+            return handler(self);
+        }
+
+        let started = Instant::now();
+        self.block_system.record_touched();
+        let result = handler(self);
+        let latency_ns = started.elapsed().as_nanos() as u64;
+        let blocks = self.block_system.take_touched();
+        let Some(trace) = self.trace.as_mut() else { return result };
+        let record = TraceRecord {
+            timestamp_ns: trace.timestamp(started),
+            op,
+            inode,
+            offset: offset.max(0) as u64,
+            length,
+            pid: req.pid(),
+            uid: req.uid(),
+            blocks,
+            latency_ns,
+            errno: result.as_ref().err().map_or(0, |errno| *errno as u32),
+        };
+        if let Err(e) = trace.write(&record) {
+            error!("Could not write the trace, stopping it: {}", e);
+            self.trace = None;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for each test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lfs-trace-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn records(num_records: u64) -> Vec<TraceRecord> {
+        (0..num_records).map(|idx| TraceRecord {
+            timestamp_ns: idx * 1000,
+            op: [TraceOp::Read, TraceOp::Write, TraceOp::Lookup, TraceOp::Setxattr][idx as usize % 4],
+            inode: idx + 2,
+            offset: idx * 4096,
+            length: 4096,
+            pid: 77,
+            uid: 1000,
+            blocks: (0..(idx % 3)).map(|block| block * 10 + idx).collect(),
+            latency_ns: 123 + idx,
+            errno: if idx % 5 == 0 { 2 } else { 0 },
+        }).collect()
+    }
+
+    fn write_all(path: &Path, options: TraceOptions, records: &[TraceRecord]) {
+        let mut writer = TraceWriter::open(path, options).unwrap();
+        for record in records {
+            writer.write(record).unwrap();
+        }
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        let dir = test_dir("formats");
+        let records = records(50);
+        for (name, format) in [("trace", TraceFormat::Binary), ("trace.csv", TraceFormat::Csv), ("trace.jsonl", TraceFormat::Jsonl)] {
+            let path = dir.join(name);
+            write_all(&path, TraceOptions::default(), &records[..25]);
+            write_all(&path, TraceOptions::default(), &records[25..]);
+            assert_eq!(TraceReader::open(&path).unwrap().format(), format);
+            assert_eq!(read_trace(&path).unwrap(), records, "{}", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn binary_layout() {
+        let record = &records(2)[1];
+        let bytes = record.to_binary();
+        assert_eq!(bytes.len(), 4 + RECORD_FIXED_SIZE + 8);
+        assert_eq!(bytes[..4], ((RECORD_FIXED_SIZE + 8) as u32).to_le_bytes());
+        assert_eq!(bytes[4..12], 1000u64.to_le_bytes());
+        assert_eq!(bytes[60], TraceOp::Write as u8);
+        assert_eq!(bytes[61..], 1u64.to_le_bytes());
+        assert_eq!(TraceRecord::from_binary(&bytes[4..]).unwrap(), *record);
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = test_dir("rotation");
+        let path = dir.join("trace");
+        let records = records(50);
+        write_all(&path, TraceOptions { format: None, max_bytes: Some(1000), max_files: 2 }, &records);
+        assert_eq!(trace_files(&path), [dir.join("trace.2"), dir.join("trace.1"), path.clone()]);
+        let kept = read_trace(&path).unwrap();
+        assert!(kept.len() < records.len());
+        assert_eq!(kept, records[(records.len() - kept.len())..]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn appending_keeps_the_format_and_timestamps() {
+        let dir = test_dir("append");
+        let path = dir.join("trace.csv");
+        write_all(&path, TraceOptions::default(), &records(3));
+        let binary = TraceOptions { format: Some(TraceFormat::Binary), ..TraceOptions::default() };
+        assert_eq!(TraceWriter::open(&path, binary).err().unwrap().kind(), ErrorKind::InvalidInput);
+
+        // Text traces carry on from their last record
+        let writer = TraceWriter::open(&path, TraceOptions::default()).unwrap();
+        assert!(writer.timestamp(Instant::now()) >= 2000);
+
+        // Binary traces carry on from the start time in their header
+        let path = dir.join("trace");
+        let writer = TraceWriter::open(&path, TraceOptions::default()).unwrap();
+        let start_time_ns = writer.start_time_ns();
+        drop(writer);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let writer = TraceWriter::open(&path, TraceOptions::default()).unwrap();
+        assert_eq!(writer.start_time_ns(), start_time_ns);
+        assert!(writer.timestamp(Instant::now()) >= 20_000_000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_records_are_dropped() {
+        let dir = test_dir("torn");
+        let records = records(10);
+        for name in ["trace", "trace.csv", "trace.jsonl"] {
+            let path = dir.join(name);
+            write_all(&path, TraceOptions::default(), &records[..5]);
+            let data = std::fs::read(&path).unwrap();
+            std::fs::write(&path, &data[..(data.len() - 5)]).unwrap();
+            assert_eq!(read_trace(&path).unwrap(), records[..4], "{}", name);
+            // Appending cuts off the torn record first
+            let mut appended = records[..4].to_vec();
+            appended.extend(records[5..].iter().cloned());
+            write_all(&path, TraceOptions::default(), &records[5..]);
+            assert_eq!(read_trace(&path).unwrap(), appended, "{}", name);
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn text_parsing() {
+        let record = &records(5)[4];
+        assert_eq!(TraceRecord::from_csv(record.to_csv().trim_end()).unwrap(), *record);
+        assert_eq!(TraceRecord::from_json(&record.to_json()).unwrap(), *record);
+        let reordered = r#"{"op":"read","blocks":[ 3, 4 ],"inode":7,"timestamp_ns":1,"offset":0,"length":2,"pid":3,"uid":4,"latency_ns":5,"errno":0}"#;
+        assert_eq!(TraceRecord::from_json(reordered).unwrap().blocks, [3, 4]);
+        assert!(TraceRecord::from_csv("1,read,2").is_err());
+        assert!(TraceRecord::from_json(r#"{"op":"chmod"}"#).is_err());
+    }
+}
@@ -1,8 +1,28 @@
 use std::ops::{Add, Div, Sub};
 
 pub mod block_file;
+pub mod cache_policy;
+pub mod cached_block_file;
+pub mod crash_block_file;
 pub mod bitmask;
 
 pub fn div_ceil<T : Add<Output=T> + Sub<Output=T> + Div<Output=T> + Copy + From<u8>>(n: T, d: T) -> T {
     (n + d - (T::from(1u8)))/d
 }
+
+/// CRC-32 (IEEE 802.3), as used by zlib and ext4
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Little-endian word `idx` of an on-disk block, such as a header field or block number
+pub fn read_u32(block: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes(crate::slice_to_four_bytes(&block[(idx * 4)..]))
+}
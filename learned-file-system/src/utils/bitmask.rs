@@ -1,21 +1,25 @@
 use std::collections::BTreeSet;
 
-const FS_BLOCK_SIZE: u32 = 4096; // TODO make this a runtime value, or maybe const generic?
-
-/// Block of the file system with inumber 1
-/// Maintains which blocks are empty
+/// Blocks of the file system starting at the block recorded in the superblock (inumber 1 on
+/// older images). Maintains which blocks are empty.
+///
+/// Changes are tracked per bitmap block so only the blocks that actually changed have to be
+/// written back.
 pub struct BitMaskBlock<> {
-    bit_mask: [u8; FS_BLOCK_SIZE as usize],
+    bit_mask: Vec<u8>,
     free_indices: BTreeSet<u32>,
-    num_indices: usize
+    num_indices: usize,
+    dirty_blocks: BTreeSet<usize>,
+    /// Bytes per bitmap block
+    block_size: usize,
 }
 
 impl Default for BitMaskBlock {
     fn default() -> Self {
-        let bit_mask = [255u8; FS_BLOCK_SIZE as usize];
-        let mut free_indices = BTreeSet::new();
+        let bit_mask = vec![255u8; crate::FS_BLOCK_SIZE];
+        let free_indices = BTreeSet::new();
 
-        BitMaskBlock { bit_mask, free_indices, num_indices: (FS_BLOCK_SIZE * 8) as usize }
+        BitMaskBlock { bit_mask, free_indices, num_indices: crate::FS_BLOCK_SIZE * 8, dirty_blocks: BTreeSet::new(), block_size: crate::FS_BLOCK_SIZE }
     }
 }
 
@@ -26,9 +30,18 @@ impl AsRef<[u8]> for BitMaskBlock{
 }
 
 impl BitMaskBlock {
-    pub fn new(num_blocks: usize, bit_mask_bytes: &[u8]) -> Self {
-        let mut bit_mask = [0u8; FS_BLOCK_SIZE as usize];
-        bit_mask.copy_from_slice(bit_mask_bytes);
+    /// Number of bitmap blocks of `block_size` bytes needed to track `num_blocks` blocks
+    pub fn blocks_needed(num_blocks: usize, block_size: usize) -> usize {
+        crate::utils::div_ceil(num_blocks, block_size * 8)
+    }
+
+    /// `bit_mask_bytes` is the content of all bitmap blocks, in order. It must be able to
+    /// hold `num_blocks` bits.
+    pub fn new(num_blocks: usize, bit_mask_bytes: &[u8], block_size: usize) -> Self {
+        if num_blocks > bit_mask_bytes.len() * 8 {
+            panic!("Bitmap of {} bytes cannot track {} blocks", bit_mask_bytes.len(), num_blocks);
+        }
+        let bit_mask = bit_mask_bytes.to_vec();
 
         let mut free_indices = BTreeSet::<u32>::new();
 
@@ -40,7 +53,7 @@ impl BitMaskBlock {
             }
         }
         BitMaskBlock {
-            bit_mask, free_indices, num_indices: num_blocks
+            bit_mask, free_indices, num_indices: num_blocks, dirty_blocks: BTreeSet::new(), block_size
         }
     }
 
@@ -52,6 +65,7 @@ impl BitMaskBlock {
         let byte_offset = index%8;
         self.bit_mask[byte_index as usize] |= 1 << byte_offset;
         self.free_indices.remove(&index);
+        self.dirty_blocks.insert(byte_index as usize / self.block_size);
     }
 
     pub fn is_free(&self, index: u32) -> bool {
@@ -62,6 +76,10 @@ impl BitMaskBlock {
         self.free_indices.len()
     }
 
+    pub fn num_indices(&self) -> usize {
+        self.num_indices
+    }
+
     pub fn clear_bit(&mut self, index: u32) {
         if index >= self.num_indices as u32 {
             panic!("Trying to clear bit {}, which is larger than {}", index, self.num_indices);
@@ -70,9 +88,52 @@ impl BitMaskBlock {
         let byte_offset = index%8;
         self.bit_mask[byte_index as usize] &= !(1u8 << (byte_offset));
         self.free_indices.insert(index);
+        self.dirty_blocks.insert(byte_index as usize / self.block_size);
     }
 
     pub fn free_block_iter<'a>(&'a self) -> impl Iterator<Item=u32> + 'a {
-        self.free_indices.iter().map(|a| *a)
+        self.free_indices.iter().copied()
+    }
+
+    /// Free blocks in increasing order starting at `goal`, wrapping around to the start
+    pub fn free_block_iter_from<'a>(&'a self, goal: u32) -> impl Iterator<Item=u32> + 'a {
+        self.free_indices.range(goal..).chain(self.free_indices.range(..goal)).copied()
     }
-}
\ No newline at end of file
+
+    /// Content of the bitmap block with index `bitmap_block_idx` (relative to the first
+    /// bitmap block)
+    pub fn bitmap_block(&self, bitmap_block_idx: usize) -> &[u8] {
+        let start = bitmap_block_idx * self.block_size;
+        &self.bit_mask[start..(start + self.block_size)]
+    }
+
+    /// Indices (relative to the first bitmap block) of the bitmap blocks changed since the
+    /// last call, in increasing order
+    pub fn take_dirty_blocks(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.dirty_blocks).into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crash_test::{check_written, run_and_reopen};
+    use crate::mkfs::MkfsOptions;
+    use super::*;
+
+    #[test]
+    fn bitmap_spanning_blocks_round_trip() {
+        // 12 MiB of 1 KiB blocks needs two bitmap blocks, and the file reaches into the second
+        let options = MkfsOptions { num_blocks: 12 * 1024, block_size: 1024, ..MkfsOptions::default() };
+        let file_system = run_and_reopen(&options, "
+            create /f
+            write /f 0 9437184
+            create /g
+            write /g 0 5000
+        ");
+        assert_eq!(file_system.get_superblock().unwrap().bitmap_location().1, 2);
+        assert_eq!(BitMaskBlock::blocks_needed(12 * 1024, 1024), 2);
+        assert!((8 * 1024..12 * 1024).any(|block| !file_system.block_allocation_bitmask.is_free(block)));
+        check_written(&file_system, "/f", 0, 9437184);
+        check_written(&file_system, "/g", 0, 5000);
+    }
+}
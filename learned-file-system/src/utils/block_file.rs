@@ -3,6 +3,8 @@ use std::io::{Read, Write, Seek, SeekFrom, Error};
 use std::os::unix::fs::FileExt;
 use std::fs::File;
 use crate::div_ceil;
+use crate::utils::cache_policy::AccessHint;
+use crate::utils::cached_block_file::PolicyStats;
 
 
 pub trait BlockFile {
@@ -19,20 +21,35 @@ pub trait BlockFile {
     
 
     fn block_write<T : AsRef<[u8]>>(&mut self, buf: T, block_address: usize) -> std::io::Result<usize>;
+
+    /// Wait until every write so far has reached stable storage
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Say what the next access is for, so a cache below can make use of it. Layers on
+    /// top of another device pass this on.
+    fn hint_access(&self, _hint: AccessHint) {}
+
+    /// Read a block into a cache below ahead of time, if there is one. `hint` says what it
+    /// is, as with `hint_access`.
+    fn prefetch(&self, _block_address: usize, _hint: AccessHint) {}
+
+    /// Hit ratios of the block cache below, if there is one
+    fn cache_stats(&self) -> Vec<PolicyStats> {
+        vec![]
+    }
 }
 
 pub struct BlockFileWrapper{
     block_size: usize,
-    num_blocks: usize,
     file: File
 }
 
 impl BlockFileWrapper {
     pub fn new(block_size: usize, file: File) -> Self{
-        let fsize = file.metadata().unwrap().len() as usize;
-        let num_blocks = div_ceil(fsize, block_size);
         BlockFileWrapper {
-            block_size, num_blocks, file
+            block_size, file
         }
     }
 }
@@ -42,8 +59,9 @@ impl BlockFile for BlockFileWrapper {
         self.block_size
     }
 
+    /// Taken from the file every time, so an image file extended while mounted shows up
     fn num_blocks(&self) -> usize {
-        self.num_blocks
+        self.file.metadata().map(|metadata| div_ceil(metadata.len() as usize, self.block_size)).unwrap_or(0)
     }
 
     fn block_read_in_place<T: AsMut<[u8]>>(&self, mut buf: T, block_address: usize) -> std::io::Result<usize>{
@@ -58,6 +76,10 @@ impl BlockFile for BlockFileWrapper {
         let start = block_address*self.block_size;
         self.file.write_at(buf.as_ref(), start as u64)
     }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
 }
 
 pub struct LoggingBlockFileWrapper<T : BlockFile, W: Write>{
@@ -97,4 +119,57 @@ impl <T: BlockFile, W: Write> BlockFile for LoggingBlockFileWrapper<T, W>{
         self.logger.borrow_mut().write(format!("W {}", block_address.to_string()).as_bytes())?;
         self.inner.block_write(buf, block_address)
     }
-}
\ No newline at end of file
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.inner.sync()
+    }
+}
+/// Image held in memory, for building images and for testing
+pub struct MemoryBlockFile {
+    block_size: usize,
+    data: Vec<u8>,
+}
+
+impl MemoryBlockFile {
+    pub fn new(block_size: usize, data: Vec<u8>) -> Self {
+        MemoryBlockFile { block_size, data }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl BlockFile for MemoryBlockFile {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.data.len() / self.block_size
+    }
+
+    fn block_read_in_place<T: AsMut<[u8]>>(&self, mut buf: T, block_address: usize) -> std::io::Result<usize> {
+        let buf = buf.as_mut();
+        let start = block_address * self.block_size;
+        let end = (start + buf.len()).min(self.data.len());
+        if start >= end {
+            return Ok(0);
+        }
+        buf[..(end - start)].copy_from_slice(&self.data[start..end]);
+        Ok(end - start)
+    }
+
+    fn block_write<T: AsRef<[u8]>>(&mut self, buf: T, block_address: usize) -> std::io::Result<usize> {
+        if buf.as_ref().len() != self.block_size || block_address >= self.num_blocks() {
+            return Err(Error::from(std::io::ErrorKind::Other));
+        }
+        let start = block_address * self.block_size;
+        self.data[start..(start + self.block_size)].copy_from_slice(buf.as_ref());
+        Ok(self.block_size)
+    }
+}
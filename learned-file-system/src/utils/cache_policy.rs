@@ -0,0 +1,393 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind};
+
+/// What the file system is reading or writing a block for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AccessKind {
+    /// Superblock, bitmaps, indirect blocks, extent tree, journal and the like
+    #[default]
+    Metadata,
+    Inode,
+    Directory,
+    Data,
+}
+
+/// Context for the next block access, given to the device by `BlockFile::hint_access`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AccessHint {
+    pub kind: AccessKind,
+    /// Inode the access is for. None keeps the inode of the previous hint, so that reading
+    /// an inode and then its blocks only needs the inode number once.
+    pub inode: Option<u64>,
+    /// Block number inside the file, for directory and data blocks
+    pub logical_block: u64,
+}
+
+impl AccessHint {
+    pub fn inode(inode: u64) -> Self {
+        AccessHint { kind: AccessKind::Inode, inode: Some(inode), logical_block: 0 }
+    }
+
+    pub fn file_block(kind: AccessKind, logical_block: u64) -> Self {
+        AccessHint { kind, inode: None, logical_block }
+    }
+}
+
+/// One block access, as seen by an eviction policy
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+    pub block_address: usize,
+    pub write: bool,
+    pub kind: AccessKind,
+    /// 0 when unknown
+    pub inode: u64,
+    pub logical_block: u64,
+}
+
+/// Decides which blocks a cache of fixed capacity keeps. The policy tracks which blocks
+/// are cached itself: every block it is told about with `miss` is cached from then on,
+/// until the policy picks it as a victim.
+pub trait EvictionPolicy {
+    fn name(&self) -> &'static str;
+
+    /// A cached block was used again
+    fn hit(&mut self, access: &Access);
+
+    /// A block that was not cached was used and is cached now. Returns the block to evict
+    /// to make room for it, which is never the block of `access`, if the cache was full.
+    fn miss(&mut self, access: &Access) -> Option<usize>;
+}
+
+/// Cache policies by name, for command lines
+pub const POLICY_NAMES: [&str; 3] = ["lru", "arc", "learned"];
+
+/// A new policy for a cache of `capacity` blocks, by its name in `POLICY_NAMES`
+pub fn policy_from_name(name: &str, capacity: usize) -> std::io::Result<Box<dyn EvictionPolicy>> {
+    let capacity = capacity.max(1);
+    Ok(match name {
+        "lru" => Box::new(LruPolicy::new(capacity)),
+        "arc" => Box::new(ArcPolicy::new(capacity)),
+        "learned" => Box::new(LearnedPolicy::new(capacity)),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, format!("unknown cache policy {}", name))),
+    })
+}
+
+/// Blocks in order of their last use
+#[derive(Default)]
+struct LruList {
+    /// Block numbers by the access counter value of their last use, least recent first
+    by_time: BTreeMap<u64, usize>,
+    times: HashMap<usize, u64>,
+    clock: u64,
+}
+
+impl LruList {
+    fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    fn contains(&self, block_address: usize) -> bool {
+        self.times.contains_key(&block_address)
+    }
+
+    /// Add a block as the most recently used one, moving it if it is already there
+    fn push(&mut self, block_address: usize) {
+        self.remove(block_address);
+        self.clock += 1;
+        self.by_time.insert(self.clock, block_address);
+        self.times.insert(block_address, self.clock);
+    }
+
+    fn remove(&mut self, block_address: usize) -> bool {
+        match self.times.remove(&block_address) {
+            Some(time) => {
+                self.by_time.remove(&time);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pop_lru(&mut self) -> Option<usize> {
+        let (_, block_address) = self.by_time.pop_first()?;
+        self.times.remove(&block_address);
+        Some(block_address)
+    }
+
+    /// Least recently used first
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.by_time.values().copied()
+    }
+}
+
+/// Evict the least recently used block
+pub struct LruPolicy {
+    capacity: usize,
+    blocks: LruList,
+}
+
+impl LruPolicy {
+    pub fn new(capacity: usize) -> Self {
+        LruPolicy { capacity, blocks: LruList::default() }
+    }
+}
+
+impl EvictionPolicy for LruPolicy {
+    fn name(&self) -> &'static str {
+        "lru"
+    }
+
+    fn hit(&mut self, access: &Access) {
+        self.blocks.push(access.block_address);
+    }
+
+    fn miss(&mut self, access: &Access) -> Option<usize> {
+        let victim = if self.blocks.len() >= self.capacity { self.blocks.pop_lru() } else { None };
+        self.blocks.push(access.block_address);
+        victim
+    }
+}
+
+/// Adaptive Replacement Cache (Megiddo and Modha, FAST 2003). Keeps blocks used once (T1)
+/// apart from blocks used more than once (T2), and remembers recently evicted blocks of
+/// both (B1, B2) to tune how much of the cache goes to each.
+pub struct ArcPolicy {
+    capacity: usize,
+    /// Target size of T1
+    p: usize,
+    t1: LruList,
+    t2: LruList,
+    b1: LruList,
+    b2: LruList,
+}
+
+impl ArcPolicy {
+    pub fn new(capacity: usize) -> Self {
+        ArcPolicy { capacity, p: 0, t1: LruList::default(), t2: LruList::default(), b1: LruList::default(), b2: LruList::default() }
+    }
+
+    /// Evict from T1 or T2 into its ghost list, if the cache is full
+    fn replace(&mut self, in_b2: bool) -> Option<usize> {
+        if self.t1.len() + self.t2.len() < self.capacity {
+            return None;
+        }
+        let t1_len = self.t1.len();
+        if t1_len > 0 && (t1_len > self.p || (in_b2 && t1_len == self.p) || self.t2.len() == 0) {
+            let victim = self.t1.pop_lru()?;
+            self.b1.push(victim);
+            Some(victim)
+        } else {
+            let victim = self.t2.pop_lru()?;
+            self.b2.push(victim);
+            Some(victim)
+        }
+    }
+}
+
+impl EvictionPolicy for ArcPolicy {
+    fn name(&self) -> &'static str {
+        "arc"
+    }
+
+    fn hit(&mut self, access: &Access) {
+        self.t1.remove(access.block_address);
+        self.t2.push(access.block_address);
+    }
+
+    fn miss(&mut self, access: &Access) -> Option<usize> {
+        let block_address = access.block_address;
+        if self.b1.contains(block_address) {
+            let delta = (self.b2.len() / self.b1.len()).max(1);
+            self.p = (self.p + delta).min(self.capacity);
+            let victim = self.replace(false);
+            self.b1.remove(block_address);
+            self.t2.push(block_address);
+            return victim;
+        }
+        if self.b2.contains(block_address) {
+            let delta = (self.b1.len() / self.b2.len()).max(1);
+            self.p = self.p.saturating_sub(delta);
+            let victim = self.replace(true);
+            self.b2.remove(block_address);
+            self.t2.push(block_address);
+            return victim;
+        }
+
+        let l1_len = self.t1.len() + self.b1.len();
+        let victim = if l1_len >= self.capacity {
+            if self.t1.len() < self.capacity {
+                self.b1.pop_lru();
+                self.replace(false)
+            } else {
+                self.t1.pop_lru()
+            }
+        } else {
+            let total_len = l1_len + self.t2.len() + self.b2.len();
+            if total_len >= self.capacity {
+                if total_len >= 2 * self.capacity {
+                    self.b2.pop_lru();
+                }
+                self.replace(false)
+            } else {
+                None
+            }
+        };
+        self.t1.push(block_address);
+        victim
+    }
+}
+
+/// Number of features the learned policy predicts reuse distance from
+const NUM_FEATURES: usize = 10;
+/// Least recently used cached blocks the learned policy picks a victim among
+const EVICTION_CANDIDATES: usize = 32;
+/// Evicted blocks the learned policy keeps the access history of, per cached block
+const HISTORY_FACTOR: usize = 4;
+const LEARNING_RATE: f64 = 0.1;
+/// Weight of a new reuse distance in the running average of an inode
+const INODE_AVERAGE_WEIGHT: f64 = 0.1;
+/// Scale of the log2 features, to keep them around 0..1
+const LOG_SCALE: f64 = 16.0;
+
+struct BlockHistory {
+    /// Access counter value at the last access
+    last_used: u64,
+    num_accesses: u64,
+    inode: u64,
+    /// Features of the last access, which the next reuse distance trains the model on
+    features: [f64; NUM_FEATURES],
+}
+
+/// Evict the cached block predicted to be used again furthest in the future, in the spirit
+/// of Belady's algorithm. An online linear model predicts the log2 of the number of accesses
+/// until a block is used again from the kind of access, whether it was a write, the
+/// previous reuse distance of the block, how often it was used, its block number in its
+/// file and a running average of the reuse distances seen in its inode. The model learns
+/// from every reuse the cache sees, including those of blocks it evicted.
+///
+/// Only the `EVICTION_CANDIDATES` least recently used blocks are considered, which keeps
+/// an eviction cheap and makes the policy fall back to LRU while the model knows nothing.
+pub struct LearnedPolicy {
+    capacity: usize,
+    cached: LruList,
+    history: HashMap<usize, BlockHistory>,
+    /// Evicted blocks whose history is kept, oldest first
+    evicted: LruList,
+    inode_reuse: HashMap<u64, f64>,
+    mean_reuse: f64,
+    weights: [f64; NUM_FEATURES],
+    clock: u64,
+}
+
+impl LearnedPolicy {
+    pub fn new(capacity: usize) -> Self {
+        LearnedPolicy {
+            capacity,
+            cached: LruList::default(),
+            history: HashMap::new(),
+            evicted: LruList::default(),
+            inode_reuse: HashMap::new(),
+            mean_reuse: 0.0,
+            weights: [0.0; NUM_FEATURES],
+            clock: 0,
+        }
+    }
+
+    fn predict(&self, features: &[f64; NUM_FEATURES]) -> f64 {
+        self.weights.iter().zip(features).map(|(weight, feature)| weight * feature).sum()
+    }
+
+    /// Normalised least mean squares step towards predicting `target` from `features`
+    fn train(&mut self, features: &[f64; NUM_FEATURES], target: f64) {
+        let error = target - self.predict(features);
+        let norm = 1.0 + features.iter().map(|feature| feature * feature).sum::<f64>();
+        for (weight, feature) in self.weights.iter_mut().zip(features) {
+            *weight += LEARNING_RATE * error * feature / norm;
+        }
+    }
+
+    /// Learn from the reuse distance this access ends, and remember its features
+    fn record(&mut self, access: &Access) {
+        self.clock += 1;
+        let (num_accesses, last_reuse) = match self.history.get(&access.block_address) {
+            Some(history) => {
+                let reuse = ((self.clock - history.last_used) as f64).log2();
+                let (features, inode) = (history.features, history.inode);
+                let num_accesses = history.num_accesses;
+                self.train(&features, reuse);
+                let average = self.inode_reuse.entry(inode).or_insert(reuse);
+                *average += INODE_AVERAGE_WEIGHT * (reuse - *average);
+                self.mean_reuse += INODE_AVERAGE_WEIGHT * (reuse - self.mean_reuse);
+                (num_accesses, Some(reuse))
+            }
+            None => (0, None),
+        };
+
+        let inode_reuse = self.inode_reuse.get(&access.inode).copied().unwrap_or(self.mean_reuse);
+        let features = [
+            1.0,
+            (access.kind == AccessKind::Inode) as u8 as f64,
+            (access.kind == AccessKind::Directory) as u8 as f64,
+            (access.kind == AccessKind::Data) as u8 as f64,
+            access.write as u8 as f64,
+            last_reuse.unwrap_or(0.0) / LOG_SCALE,
+            last_reuse.is_none() as u8 as f64,
+            ((num_accesses + 1) as f64).log2() / LOG_SCALE,
+            ((access.logical_block + 1) as f64).log2() / LOG_SCALE,
+            inode_reuse / LOG_SCALE,
+        ];
+        self.history.insert(access.block_address, BlockHistory {
+            last_used: self.clock,
+            num_accesses: num_accesses + 1,
+            inode: access.inode,
+            features,
+        });
+    }
+
+    /// Accesses until a cached block is predicted to be used again. Blocks already past
+    /// their predicted reuse get the time since their last use instead.
+    fn predicted_remaining(&self, block_address: usize) -> f64 {
+        let history = &self.history[&block_address];
+        let next_use = history.last_used as f64 + self.predict(&history.features).exp2();
+        let now = self.clock as f64;
+        if next_use > now { next_use - now } else { now - history.last_used as f64 }
+    }
+
+    fn evict(&mut self) -> Option<usize> {
+        let victim = self.cached.iter()
+            .take(EVICTION_CANDIDATES)
+            .map(|block_address| (block_address, self.predicted_remaining(block_address)))
+            // The first of equals, so the least recently used one
+            .fold(None, |best: Option<(usize, f64)>, candidate| match best {
+                Some(best) if best.1 >= candidate.1 => Some(best),
+                _ => Some(candidate),
+            })?.0;
+        self.cached.remove(victim);
+        self.evicted.push(victim);
+        if self.evicted.len() > HISTORY_FACTOR * self.capacity {
+            if let Some(forgotten) = self.evicted.pop_lru() {
+                self.history.remove(&forgotten);
+            }
+        }
+        Some(victim)
+    }
+}
+
+impl EvictionPolicy for LearnedPolicy {
+    fn name(&self) -> &'static str {
+        "learned"
+    }
+
+    fn hit(&mut self, access: &Access) {
+        self.record(access);
+        self.cached.push(access.block_address);
+    }
+
+    fn miss(&mut self, access: &Access) -> Option<usize> {
+        self.record(access);
+        let victim = if self.cached.len() >= self.capacity { self.evict() } else { None };
+        self.evicted.remove(access.block_address);
+        self.cached.push(access.block_address);
+        victim
+    }
+}
@@ -0,0 +1,440 @@
+use std::cell::{Ref, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Error;
+use log::{debug, error, info};
+use crate::utils::block_file::BlockFile;
+use crate::utils::cache_policy::{Access, AccessHint, EvictionPolicy, LruPolicy};
+
+/// Cache size used when none is given
+pub const DEFAULT_CACHE_BLOCKS: usize = 1024;
+
+/// How a CachedBlockFile has done so far
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Dirty blocks written to the device, on eviction or sync
+    pub write_backs: u64,
+    /// Blocks read into the cache by `prefetch`
+    pub prefetches: u64,
+    /// Hits on prefetched blocks, counting each prefetch once
+    pub prefetch_hits: u64,
+}
+
+impl CacheStats {
+    /// Share of reads and writes that found their block in the cache
+    pub fn hit_ratio(&self) -> f64 {
+        if self.hits + self.misses == 0 { 0.0 } else { self.hits as f64 / (self.hits + self.misses) as f64 }
+    }
+
+    /// Share of the prefetched blocks that were used before being evicted
+    pub fn prefetch_accuracy(&self) -> f64 {
+        if self.prefetches == 0 { 0.0 } else { self.prefetch_hits as f64 / self.prefetches as f64 }
+    }
+
+    /// Share of the misses there would have been without prefetching that it avoided
+    pub fn prefetch_coverage(&self) -> f64 {
+        if self.prefetch_hits + self.misses == 0 { 0.0 } else { self.prefetch_hits as f64 / (self.prefetch_hits + self.misses) as f64 }
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hits, {} misses ({:.1}% hit ratio), {} evictions, {} write-backs",
+               self.hits, self.misses, self.hit_ratio() * 100.0, self.evictions, self.write_backs)?;
+        if self.prefetches > 0 {
+            write!(f, ", {} prefetches ({:.1}% accuracy, {:.1}% coverage)",
+                   self.prefetches, self.prefetch_accuracy() * 100.0, self.prefetch_coverage() * 100.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// How one eviction policy has done on the accesses a cache saw
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PolicyStats {
+    pub policy: &'static str,
+    /// Only simulated next to the policy the cache really uses, see
+    /// `CachedBlockFile::add_shadow_policy`. These never write back.
+    pub shadow: bool,
+    pub stats: CacheStats,
+}
+
+impl fmt::Display for PolicyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}: {}", self.policy, if self.shadow { " (shadow)" } else { "" }, self.stats)
+    }
+}
+
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    /// Prefetched and not used since
+    prefetched: bool,
+    /// Evicted by the policy, but kept until writing it back works. The next access brings
+    /// it back into the cache as a miss.
+    evicted: bool,
+}
+
+/// A policy run on the same accesses as the real one, keeping track of the blocks it would
+/// have cached without holding their data
+struct ShadowCache {
+    policy: Box<dyn EvictionPolicy>,
+    cached: HashSet<usize>,
+    /// Prefetched and not used since
+    prefetched: HashSet<usize>,
+    stats: CacheStats,
+}
+
+impl ShadowCache {
+    fn access(&mut self, access: &Access, prefetch: bool) {
+        let block_address = access.block_address;
+        if self.cached.contains(&block_address) {
+            if !prefetch {
+                self.stats.hits += 1;
+                self.stats.prefetch_hits += self.prefetched.remove(&block_address) as u64;
+                self.policy.hit(access);
+            }
+            return;
+        }
+        if prefetch {
+            self.stats.prefetches += 1;
+            self.prefetched.insert(block_address);
+        } else {
+            self.stats.misses += 1;
+        }
+        self.cached.insert(block_address);
+        if let Some(victim) = self.policy.miss(access) {
+            self.cached.remove(&victim);
+            self.prefetched.remove(&victim);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+struct CacheState {
+    blocks: HashMap<usize, CachedBlock>,
+    policy: Box<dyn EvictionPolicy>,
+    shadows: Vec<ShadowCache>,
+    /// Context from the last hint: the inode sticks, the rest is for the next access only
+    hint: AccessHint,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    /// The next access, using up the hint for it
+    fn access(&mut self, block_address: usize, write: bool) -> Access {
+        let hint = self.hint;
+        self.hint = AccessHint { inode: hint.inode, ..AccessHint::default() };
+        let access = Access { block_address, write, kind: hint.kind, inode: hint.inode.unwrap_or(0), logical_block: hint.logical_block };
+        for shadow in &mut self.shadows {
+            shadow.access(&access, false);
+        }
+        access
+    }
+}
+
+/// Write-back buffer cache in front of a device, with an `EvictionPolicy` deciding which
+/// blocks it keeps. Writes only reach the device when a dirty block is evicted or on
+/// `sync`, so `sync` keeps working as a barrier for the journal and copy-on-write commits
+/// above.
+///
+/// Dirty blocks still cached when the cache is dropped are written back then.
+pub struct CachedBlockFile<BF: BlockFile> {
+    device: RefCell<BF>,
+    state: RefCell<CacheState>,
+}
+
+impl <BF: BlockFile> CachedBlockFile<BF> {
+    /// A cache of `capacity` blocks evicting the least recently used one
+    pub fn new(device: BF, capacity: usize) -> Self {
+        Self::with_policy(device, Box::new(LruPolicy::new(capacity.max(1))))
+    }
+
+    /// A cache as large as `policy` was made for
+    pub fn with_policy(device: BF, policy: Box<dyn EvictionPolicy>) -> Self {
+        CachedBlockFile {
+            device: RefCell::new(device),
+            state: RefCell::new(CacheState {
+                blocks: HashMap::new(),
+                policy,
+                shadows: vec![],
+                hint: AccessHint::default(),
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    /// Also run `policy` on every access, to compare its hit ratio with that of the policy
+    /// in use on the same workload
+    pub fn add_shadow_policy(&mut self, policy: Box<dyn EvictionPolicy>) {
+        self.state.get_mut().shadows.push(ShadowCache { policy, cached: HashSet::new(), prefetched: HashSet::new(), stats: CacheStats::default() });
+    }
+
+    /// The device behind the cache, which lacks any dirty blocks not yet written back
+    pub fn get_ref(&self) -> Ref<'_, BF> {
+        self.device.borrow()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.borrow().stats
+    }
+
+    /// Number of blocks cached right now
+    pub fn len(&self) -> usize {
+        self.state.borrow().blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write back every dirty block, lowest block number first, dropping evicted ones once
+    /// they are written
+    fn write_back(&self) -> std::io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let mut dirty: Vec<usize> = state.blocks.iter().filter(|(_, block)| block.dirty).map(|(block_address, _)| *block_address).collect();
+        dirty.sort_unstable();
+        for block_address in dirty {
+            let block = state.blocks.get_mut(&block_address).unwrap();
+            self.device.borrow_mut().block_write(&block.data, block_address)?;
+            block.dirty = false;
+            if block.evicted {
+                state.blocks.remove(&block_address);
+            }
+            state.stats.write_backs += 1;
+        }
+        Ok(())
+    }
+
+    /// Add a block the policy has not seen, evicting the victim it picks to make room. The
+    /// block is added even if writing back the victim fails, in which case the victim stays
+    /// too, see `CachedBlock::evicted`.
+    fn insert(&self, access: &Access, data: Vec<u8>, dirty: bool, prefetched: bool) -> std::io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        if prefetched {
+            state.stats.prefetches += 1;
+        } else {
+            state.stats.misses += 1;
+        }
+        let victim = state.policy.miss(access);
+        state.blocks.insert(access.block_address, CachedBlock { data, dirty, prefetched, evicted: false });
+        let Some(victim) = victim else { return Ok(()) };
+        state.stats.evictions += 1;
+        let block = state.blocks.get_mut(&victim).unwrap();
+        if block.dirty {
+            if let Err(e) = self.device.borrow_mut().block_write(&block.data, victim) {
+                block.evicted = true;
+                return Err(e);
+            }
+            state.stats.write_backs += 1;
+        }
+        state.blocks.remove(&victim);
+        Ok(())
+    }
+}
+
+impl <BF: BlockFile> BlockFile for CachedBlockFile<BF> {
+    fn block_size(&self) -> usize {
+        self.device.borrow().block_size()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.device.borrow().num_blocks()
+    }
+
+    fn block_read_in_place<T: AsMut<[u8]>>(&self, mut buf: T, block_address: usize) -> std::io::Result<usize> {
+        let buf = buf.as_mut();
+        let (access, evicted) = {
+            let mut state = self.state.borrow_mut();
+            let access = state.access(block_address, false);
+            let CacheState { blocks, policy, stats, .. } = &mut *state;
+            match blocks.get_mut(&block_address) {
+                Some(block) if !block.evicted => {
+                    let len = buf.len().min(block.data.len());
+                    buf[..len].copy_from_slice(&block.data[..len]);
+                    stats.hits += 1;
+                    stats.prefetch_hits += std::mem::take(&mut block.prefetched) as u64;
+                    policy.hit(&access);
+                    return Ok(len);
+                }
+                _ => (access, blocks.remove(&block_address)),
+            }
+        };
+
+        // An evicted block not yet written back is newer than the device
+        let (data, dirty) = match evicted {
+            Some(block) => (block.data, true),
+            None => (self.device.borrow().block_read(block_address)?, false),
+        };
+        let len = buf.len().min(data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.insert(&access, data, dirty, false)?;
+        Ok(len)
+    }
+
+    fn block_write<T: AsRef<[u8]>>(&mut self, buf: T, block_address: usize) -> std::io::Result<usize> {
+        let buf = buf.as_ref();
+        if buf.len() != self.block_size() {
+            return Err(Error::from(std::io::ErrorKind::Other));
+        }
+        let access = {
+            let state = self.state.get_mut();
+            let access = state.access(block_address, true);
+            match state.blocks.get_mut(&block_address) {
+                Some(block) if !block.evicted => {
+                    block.data.copy_from_slice(buf);
+                    block.dirty = true;
+                    state.stats.hits += 1;
+                    state.stats.prefetch_hits += std::mem::take(&mut block.prefetched) as u64;
+                    state.policy.hit(&access);
+                    return Ok(buf.len());
+                }
+                // Overwritten before it could be written back
+                _ => state.blocks.remove(&block_address),
+            };
+            access
+        };
+        self.insert(&access, buf.to_vec(), true, false)?;
+        Ok(buf.len())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.write_back()?;
+        self.device.borrow_mut().sync()
+    }
+
+    /// Errors are only logged, since nothing is waiting for the block
+    fn prefetch(&self, block_address: usize, hint: AccessHint) {
+        let access = {
+            let mut state = self.state.borrow_mut();
+            let access = Access { block_address, write: false, kind: hint.kind, inode: hint.inode.unwrap_or(0), logical_block: hint.logical_block };
+            for shadow in &mut state.shadows {
+                shadow.access(&access, true);
+            }
+            if state.blocks.contains_key(&block_address) {
+                return;
+            }
+            access
+        };
+        let result = self.device.borrow().block_read(block_address)
+            .and_then(|data| self.insert(&access, data, false, true));
+        if let Err(e) = result {
+            debug!("Could not prefetch block {}: {}", block_address, e);
+        }
+    }
+
+    fn hint_access(&self, hint: AccessHint) {
+        let mut state = self.state.borrow_mut();
+        state.hint = AccessHint { inode: hint.inode.or(state.hint.inode), ..hint };
+    }
+
+    /// The policy in use first, then the shadow policies
+    fn cache_stats(&self) -> Vec<PolicyStats> {
+        let state = self.state.borrow();
+        std::iter::once(PolicyStats { policy: state.policy.name(), shadow: false, stats: state.stats })
+            .chain(state.shadows.iter().map(|shadow| PolicyStats { policy: shadow.policy.name(), shadow: true, stats: shadow.stats }))
+            .collect()
+    }
+}
+
+impl <BF: BlockFile> Drop for CachedBlockFile<BF> {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            error!("Could not write back the block cache: {}", e);
+        }
+        for policy_stats in self.cache_stats() {
+            info!("Block cache {}", policy_stats);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use crate::utils::block_file::MemoryBlockFile;
+
+    /// Memory device whose writes fail while the shared flag is set
+    struct FailingBlockFile {
+        device: MemoryBlockFile,
+        fail_writes: Rc<Cell<bool>>,
+    }
+
+    impl BlockFile for FailingBlockFile {
+        fn block_size(&self) -> usize {
+            self.device.block_size()
+        }
+
+        fn num_blocks(&self) -> usize {
+            self.device.num_blocks()
+        }
+
+        fn block_read_in_place<T: AsMut<[u8]>>(&self, buf: T, block_address: usize) -> std::io::Result<usize> {
+            self.device.block_read_in_place(buf, block_address)
+        }
+
+        fn block_write<T: AsRef<[u8]>>(&mut self, buf: T, block_address: usize) -> std::io::Result<usize> {
+            if self.fail_writes.get() {
+                return Err(Error::from(std::io::ErrorKind::Other));
+            }
+            self.device.block_write(buf, block_address)
+        }
+    }
+
+    fn cache(capacity: usize) -> (CachedBlockFile<FailingBlockFile>, Rc<Cell<bool>>) {
+        let fail_writes = Rc::new(Cell::new(false));
+        let device = FailingBlockFile { device: MemoryBlockFile::new(4, vec![0u8; 64]), fail_writes: fail_writes.clone() };
+        (CachedBlockFile::new(device, capacity), fail_writes)
+    }
+
+    #[test]
+    fn write_back() {
+        let (mut cache, _) = cache(2);
+        cache.block_write([1; 4], 0).unwrap();
+        cache.block_write([2; 4], 1).unwrap();
+        assert_eq!(cache.get_ref().device.data()[..8], [0; 8]);
+        cache.block_write([3; 4], 2).unwrap();
+        assert_eq!(cache.get_ref().device.data()[..12], [1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(cache.block_read(1).unwrap(), [2; 4]);
+        cache.sync().unwrap();
+        assert_eq!(cache.get_ref().device.data()[..12], [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3]);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 3, evictions: 1, write_backs: 3, ..CacheStats::default() });
+    }
+
+    #[test]
+    fn failed_eviction_keeps_the_victim() {
+        let (mut cache, fail_writes) = cache(2);
+        cache.block_write([1; 4], 0).unwrap();
+        cache.block_write([2; 4], 1).unwrap();
+        fail_writes.set(true);
+        assert!(cache.block_write([3; 4], 2).is_err());
+        fail_writes.set(false);
+        // Block 0 still reads as written, and comes back into the cache
+        assert_eq!(cache.block_read(0).unwrap(), [1; 4]);
+        assert_eq!(cache.block_read(2).unwrap(), [3; 4]);
+        for block_address in 3..16 {
+            cache.block_write([block_address as u8; 4], block_address).unwrap();
+        }
+        cache.sync().unwrap();
+        assert_eq!(cache.len(), 2);
+        let data = cache.get_ref().device.data().to_vec();
+        assert_eq!(data.chunks(4).map(|block| block[0]).collect::<Vec<_>>(), [1, 2, 3, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+    }
+
+    #[test]
+    fn sync_drops_written_back_victims() {
+        let (mut cache, fail_writes) = cache(1);
+        cache.block_write([1; 4], 0).unwrap();
+        fail_writes.set(true);
+        assert!(cache.block_read(1).is_err());
+        assert!(cache.sync().is_err());
+        fail_writes.set(false);
+        cache.sync().unwrap();
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.get_ref().device.data()[..4], [1; 4]);
+    }
+}
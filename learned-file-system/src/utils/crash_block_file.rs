@@ -0,0 +1,199 @@
+use crate::utils::block_file::BlockFile;
+use crate::utils::cache_policy::AccessHint;
+use crate::utils::cached_block_file::PolicyStats;
+
+/// One `block_write` seen by a CrashBlockFile
+#[derive(Clone, Debug)]
+pub struct RecordedWrite {
+    pub block_address: usize,
+    pub data: Vec<u8>,
+    /// Number of syncs before this write
+    pub epoch: usize,
+}
+
+/// Image state a crash could leave behind
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CrashPoint {
+    /// The first this many writes reached the disk, in order
+    Prefix(usize),
+    /// Every write before `epoch`, and out of the writes of `epoch` only those at the given
+    /// indexes into `writes`. Writes between two syncs may reach the disk in any order.
+    Reordered { epoch: usize, applied: Vec<usize> },
+}
+
+/// Device wrapper that records every block write, so the image can be rebuilt as it would
+/// be after a crash at any point. Writes go on to the wrapped device as well.
+///
+/// The flush model is that of a disk with a volatile write cache: `sync` is a barrier,
+/// and writes between two syncs may land in any order or not at all.
+pub struct CrashBlockFile<BF: BlockFile> {
+    device: BF,
+    /// The image before the first recorded write
+    initial: Vec<u8>,
+    writes: Vec<RecordedWrite>,
+    epoch: usize,
+}
+
+impl <BF: BlockFile> CrashBlockFile<BF> {
+    /// Start recording, with the current contents of `device` as the initial image
+    pub fn new(device: BF) -> std::io::Result<Self> {
+        let mut initial = Vec::with_capacity(device.num_blocks() * device.block_size());
+        for block_address in 0..device.num_blocks() {
+            initial.extend(device.block_read(block_address)?);
+        }
+        Ok(CrashBlockFile { device, initial, writes: vec![], epoch: 0 })
+    }
+
+    pub fn writes(&self) -> &[RecordedWrite] {
+        &self.writes
+    }
+
+    /// Number of epochs with writes in them
+    pub fn num_epochs(&self) -> usize {
+        self.writes.last().map_or(0, |write| write.epoch + 1)
+    }
+
+    pub fn into_inner(self) -> BF {
+        self.device
+    }
+
+    /// Indexes into `writes` of the writes between the given sync and the next
+    fn epoch_writes(&self, epoch: usize) -> Vec<usize> {
+        (0..self.writes.len()).filter(|idx| self.writes[*idx].epoch == epoch).collect()
+    }
+
+    /// Every crash point worth checking: each prefix of the writes, and for every epoch
+    /// with at most `max_reordered` writes, each subset of its writes that is not a
+    /// prefix. Larger epochs only get the variants with one write left out.
+    pub fn crash_points(&self, max_reordered: usize) -> Vec<CrashPoint> {
+        let mut points: Vec<CrashPoint> = (0..=self.writes.len()).map(CrashPoint::Prefix).collect();
+        for epoch in 0..self.num_epochs() {
+            let epoch_writes = self.epoch_writes(epoch);
+            let subsets: Vec<Vec<usize>> = if epoch_writes.len() <= max_reordered {
+                (0..(1usize << epoch_writes.len()))
+                    .filter(|mask| (mask + 1) & mask != 0)
+                    .map(|mask| epoch_writes.iter().enumerate().filter(|(bit, _)| mask & (1 << bit) != 0).map(|(_, idx)| *idx).collect())
+                    .collect()
+            } else {
+                (0..(epoch_writes.len() - 1))
+                    .map(|left_out| epoch_writes.iter().enumerate().filter(|(pos, _)| *pos != left_out).map(|(_, idx)| *idx).collect())
+                    .collect()
+            };
+            points.extend(subsets.into_iter().map(|applied| CrashPoint::Reordered { epoch, applied }));
+        }
+        points
+    }
+
+    /// The image as a crash at `point` would leave it
+    pub fn image_at(&self, point: &CrashPoint) -> Vec<u8> {
+        let block_size = self.device.block_size();
+        let mut image = self.initial.clone();
+        let mut apply = |write: &RecordedWrite| {
+            let start = write.block_address * block_size;
+            if image.len() < start + block_size {
+                image.resize(start + block_size, 0);
+            }
+            image[start..(start + block_size)].copy_from_slice(&write.data);
+        };
+        match point {
+            CrashPoint::Prefix(num_writes) => self.writes[..*num_writes].iter().for_each(apply),
+            CrashPoint::Reordered { epoch, applied } => {
+                self.writes.iter().take_while(|write| write.epoch < *epoch).for_each(&mut apply);
+                applied.iter().for_each(|idx| apply(&self.writes[*idx]));
+            }
+        }
+        image
+    }
+}
+
+impl <BF: BlockFile> BlockFile for CrashBlockFile<BF> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.device.num_blocks()
+    }
+
+    fn block_read_in_place<T: AsMut<[u8]>>(&self, buf: T, block_address: usize) -> std::io::Result<usize> {
+        self.device.block_read_in_place(buf, block_address)
+    }
+
+    fn block_write<T: AsRef<[u8]>>(&mut self, buf: T, block_address: usize) -> std::io::Result<usize> {
+        let num_written = self.device.block_write(buf.as_ref(), block_address)?;
+        self.writes.push(RecordedWrite { block_address, data: buf.as_ref().to_vec(), epoch: self.epoch });
+        Ok(num_written)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        if self.writes.last().is_some_and(|write| write.epoch == self.epoch) {
+            self.epoch += 1;
+        }
+        self.device.sync()
+    }
+
+    fn hint_access(&self, hint: AccessHint) {
+        self.device.hint_access(hint)
+    }
+
+    fn prefetch(&self, block_address: usize, hint: AccessHint) {
+        self.device.prefetch(block_address, hint)
+    }
+
+    fn cache_stats(&self) -> Vec<PolicyStats> {
+        self.device.cache_stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::block_file::MemoryBlockFile;
+
+    /// Two writes, a sync, then three more, on a device of four 2 byte blocks
+    fn recorded() -> CrashBlockFile<MemoryBlockFile> {
+        let mut device = CrashBlockFile::new(MemoryBlockFile::new(2, vec![0u8; 8])).unwrap();
+        device.block_write([1, 1], 0).unwrap();
+        device.block_write([2, 2], 1).unwrap();
+        device.sync().unwrap();
+        device.sync().unwrap();
+        device.block_write([3, 3], 0).unwrap();
+        device.block_write([4, 4], 2).unwrap();
+        device.block_write([5, 5], 3).unwrap();
+        device
+    }
+
+    #[test]
+    fn epochs() {
+        let device = recorded();
+        assert_eq!(device.num_epochs(), 2);
+        assert_eq!(device.writes().iter().map(|write| write.epoch).collect::<Vec<_>>(), [0, 0, 1, 1, 1]);
+        assert_eq!(device.into_inner().into_data(), [3, 3, 2, 2, 4, 4, 5, 5]);
+    }
+
+    #[test]
+    fn crash_points() {
+        let device = recorded();
+        let mut expected: Vec<CrashPoint> = (0..=5).map(CrashPoint::Prefix).collect();
+        expected.push(CrashPoint::Reordered { epoch: 0, applied: vec![1] });
+        // Too many writes in epoch 1 for every subset, so only one is left out at a time
+        expected.push(CrashPoint::Reordered { epoch: 1, applied: vec![3, 4] });
+        expected.push(CrashPoint::Reordered { epoch: 1, applied: vec![2, 4] });
+        assert_eq!(device.crash_points(2), expected);
+
+        let all_subsets = device.crash_points(3);
+        assert_eq!(all_subsets.len(), 6 + 1 + 4);
+        assert!(all_subsets.contains(&CrashPoint::Reordered { epoch: 1, applied: vec![4] }));
+        assert!(!all_subsets.contains(&CrashPoint::Reordered { epoch: 1, applied: vec![2] }));
+    }
+
+    #[test]
+    fn images() {
+        let device = recorded();
+        assert_eq!(device.image_at(&CrashPoint::Prefix(0)), [0; 8]);
+        assert_eq!(device.image_at(&CrashPoint::Prefix(1)), [1, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(device.image_at(&CrashPoint::Prefix(5)), [3, 3, 2, 2, 4, 4, 5, 5]);
+        assert_eq!(device.image_at(&CrashPoint::Reordered { epoch: 0, applied: vec![1] }), [0, 0, 2, 2, 0, 0, 0, 0]);
+        assert_eq!(device.image_at(&CrashPoint::Reordered { epoch: 1, applied: vec![4] }), [1, 1, 2, 2, 0, 0, 5, 5]);
+    }
+}